@@ -0,0 +1,95 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+use ntex::time::Millis;
+
+/// Reconnect backoff policy
+///
+/// Delay grows exponentially from `min` to `max` value, every delay
+/// is randomized by `jitter` fraction.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    min: Millis,
+    max: Millis,
+    factor: u32,
+    jitter: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff { min: Millis(500), max: Millis(30_000), factor: 2, jitter: 0.25 }
+    }
+}
+
+impl Backoff {
+    /// Create new backoff policy with min and max delays
+    pub fn new(min: Millis, max: Millis) -> Self {
+        Backoff { min, max: Millis(std::cmp::max(min.0, max.0)), ..Default::default() }
+    }
+
+    /// Set delay multiplier, default value is 2
+    ///
+    /// panics if factor is 0
+    pub fn factor(mut self, factor: u32) -> Self {
+        assert!(factor != 0, "factor 0 is not allowed");
+        self.factor = factor;
+        self
+    }
+
+    /// Set randomization fraction of the delay, default value is 0.25
+    ///
+    /// Value is clamped to `0.0..=1.0` range.
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Delay for specified reconnect attempt
+    pub(crate) fn delay(&self, attempt: u32) -> Millis {
+        let delay = (self.factor as u64)
+            .checked_pow(attempt)
+            .and_then(|f| self.min.0.checked_mul(f))
+            .map(|d| std::cmp::min(d, self.max.0))
+            .unwrap_or(self.max.0);
+
+        if self.jitter > 0.0 {
+            let spread = delay as f64 * self.jitter;
+            Millis((delay as f64 - spread + spread * 2.0 * random()) as u64)
+        } else {
+            Millis(delay)
+        }
+    }
+}
+
+/// Random value in `0.0..1.0` range
+fn random() -> f64 {
+    let val = RandomState::new().build_hasher().finish();
+    (val >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay() {
+        let backoff = Backoff::new(Millis(100), Millis(1000)).jitter(0.0);
+        assert_eq!(backoff.delay(0), Millis(100));
+        assert_eq!(backoff.delay(1), Millis(200));
+        assert_eq!(backoff.delay(3), Millis(800));
+        assert_eq!(backoff.delay(4), Millis(1000));
+        assert_eq!(backoff.delay(100), Millis(1000));
+
+        let backoff = Backoff::new(Millis(100), Millis(1000)).factor(3).jitter(0.0);
+        assert_eq!(backoff.delay(2), Millis(900));
+    }
+
+    #[test]
+    fn test_jitter() {
+        let backoff = Backoff::new(Millis(1000), Millis(1000)).jitter(0.5);
+        for _ in 0..100 {
+            let delay = backoff.delay(1);
+            assert!(delay >= Millis(500) && delay <= Millis(1500));
+        }
+    }
+}
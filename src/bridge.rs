@@ -0,0 +1,433 @@
+//! Mqtt bridge
+use std::{fmt, future::Future, pin::Pin, rc::Rc};
+
+use ntex::codec::{AsyncRead, AsyncWrite};
+use ntex::connect::{self, Address, Connect};
+use ntex::service::Service;
+use ntex::util::{join, ByteString};
+
+use crate::sessions::Message;
+use crate::topic::Topic;
+use crate::types::QoS;
+use crate::{v3, v5};
+
+/// Forwarding direction of bridged topic
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Direction {
+    /// Forward messages from local to remote endpoint
+    Out,
+    /// Forward messages from remote to local endpoint
+    In,
+    /// Forward messages in both directions
+    Both,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Side {
+    Local,
+    Remote,
+}
+
+/// Bridged topic
+///
+/// Endpoint subscribes to the topic filter with endpoint's prefix, prefix
+/// of received message topic is replaced with prefix of the other endpoint.
+#[derive(Debug, Clone)]
+pub struct BridgeTopic {
+    filter: ByteString,
+    direction: Direction,
+    qos: QoS,
+    local_prefix: ByteString,
+    remote_prefix: ByteString,
+}
+
+impl BridgeTopic {
+    /// Create bridged topic
+    ///
+    /// By default subscription qos is `AtMostOnce` and prefixes are empty.
+    pub fn new(filter: ByteString, direction: Direction) -> Self {
+        BridgeTopic {
+            filter,
+            direction,
+            qos: QoS::AtMostOnce,
+            local_prefix: ByteString::new(),
+            remote_prefix: ByteString::new(),
+        }
+    }
+
+    /// Set max qos of forwarded messages
+    pub fn qos(mut self, qos: QoS) -> Self {
+        self.qos = qos;
+        self
+    }
+
+    /// Set topic prefix on local endpoint
+    pub fn local_prefix(mut self, prefix: ByteString) -> Self {
+        self.local_prefix = prefix;
+        self
+    }
+
+    /// Set topic prefix on remote endpoint
+    pub fn remote_prefix(mut self, prefix: ByteString) -> Self {
+        self.remote_prefix = prefix;
+        self
+    }
+
+    fn forwards(&self, from: Side) -> bool {
+        match self.direction {
+            Direction::Both => true,
+            Direction::Out => from == Side::Local,
+            Direction::In => from == Side::Remote,
+        }
+    }
+
+    fn prefix(&self, side: Side) -> &ByteString {
+        match side {
+            Side::Local => &self.local_prefix,
+            Side::Remote => &self.remote_prefix,
+        }
+    }
+
+    fn subscription(&self, side: Side) -> ByteString {
+        ByteString::from(format!("{}{}", self.prefix(side), self.filter))
+    }
+}
+
+#[derive(Clone)]
+enum Sink {
+    V3(v3::client::ManagedSink),
+    V5(v5::client::ManagedSink),
+}
+
+impl Sink {
+    fn is_open(&self) -> bool {
+        match self {
+            Sink::V3(ref sink) => sink.is_open(),
+            Sink::V5(ref sink) => sink.is_open(),
+        }
+    }
+
+    /// Subscribe, subscriptions are re-issued by managed sink after reconnect
+    async fn subscribe(self, filter: ByteString, qos: QoS) {
+        loop {
+            let res = match self {
+                Sink::V3(ref sink) => {
+                    if !sink.connected().await {
+                        return;
+                    }
+                    sink.subscribe().topic_filter(filter.clone(), qos).send().await.map(|_| ())
+                }
+                Sink::V5(ref sink) => {
+                    if !sink.connected().await {
+                        return;
+                    }
+                    // messages published by bridge are not received back
+                    let opts = v5::codec::SubscriptionOptions {
+                        qos,
+                        no_local: true,
+                        retain_as_published: true,
+                        retain_handling: v5::codec::RetainHandling::AtSubscribe,
+                    };
+                    sink.subscribe(None)
+                        .topic_filter(filter.clone(), opts)
+                        .send()
+                        .await
+                        .map(|_| ())
+                }
+            };
+            match res {
+                Ok(_) => return,
+                Err(err) => {
+                    log::error!("Cannot subscribe to {:?}: {:?}", filter, err);
+                    // retry after reconnect
+                    if self.is_open() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Publish message, future resolves once message is acknowledged
+    async fn publish(self, msg: Message) {
+        let res = match self {
+            Sink::V3(ref sink) => {
+                if !sink.ready().await {
+                    return;
+                }
+                let mut builder = sink.sink().publish(msg.topic, msg.payload);
+                if msg.retain {
+                    builder = builder.retain();
+                }
+                match msg.qos {
+                    QoS::AtMostOnce => builder.send_at_most_once(),
+                    QoS::AtLeastOnce => builder.send_at_least_once().await,
+                    QoS::ExactlyOnce => builder.send_exactly_once().await,
+                }
+                .map_err(|e| format!("{:?}", e))
+            }
+            Sink::V5(ref sink) => {
+                if !sink.ready().await {
+                    return;
+                }
+                let properties = msg.properties;
+                let mut builder = sink
+                    .sink()
+                    .publish(msg.topic, msg.payload)
+                    .properties(|props| *props = properties);
+                if msg.retain {
+                    builder = builder.retain();
+                }
+                match msg.qos {
+                    QoS::AtMostOnce => {
+                        builder.send_at_most_once().map_err(|e| format!("{:?}", e))
+                    }
+                    QoS::AtLeastOnce => builder
+                        .send_at_least_once()
+                        .await
+                        .map(|_| ())
+                        .map_err(|e| format!("{:?}", e)),
+                    QoS::ExactlyOnce => builder
+                        .send_exactly_once()
+                        .await
+                        .map(|_| ())
+                        .map_err(|e| format!("{:?}", e)),
+                }
+            }
+        };
+        if let Err(err) = res {
+            log::error!("Cannot forward message: {}", err);
+        }
+    }
+}
+
+struct Router {
+    local: Sink,
+    remote: Sink,
+    topics: Vec<(BridgeTopic, Topic, Topic)>,
+}
+
+impl Router {
+    /// Forward message received from endpoint to the other endpoint
+    fn forward(&self, from: Side, mut msg: Message) -> Option<impl Future<Output = ()>> {
+        let (to, sink) = match from {
+            Side::Local => (Side::Remote, self.remote.clone()),
+            Side::Remote => (Side::Local, self.local.clone()),
+        };
+
+        let item = self.topics.iter().find(|(topic, local, remote)| {
+            topic.forwards(from)
+                && match from {
+                    Side::Local => local.matches_str(&msg.topic),
+                    Side::Remote => remote.matches_str(&msg.topic),
+                }
+        });
+
+        if let Some((topic, _, _)) = item {
+            // "prefix/#" filter matches bare "prefix" topic as well
+            let name = if let Some(name) = msg.topic.strip_prefix(topic.prefix(from).as_str()) {
+                name
+            } else {
+                log::trace!("Message topic does not have bridge prefix: {:?}", msg.topic);
+                return None;
+            };
+            msg.topic = ByteString::from(format!("{}{}", topic.prefix(to), name));
+            log::trace!("Forward message to {:?} endpoint: {:?}", to, msg.topic);
+            Some(sink.publish(msg))
+        } else {
+            log::trace!("Message does not match bridged topics: {:?}", msg.topic);
+            None
+        }
+    }
+}
+
+type Runner = Box<dyn FnOnce(Rc<Router>, Side) -> Pin<Box<dyn Future<Output = ()>>>>;
+
+/// Bridge endpoint
+///
+/// Endpoint is a managed client, so it reconnects on connection failure
+/// with client's backoff policy. Endpoint is stopped by closing client's
+/// managed sink.
+pub struct Endpoint {
+    sink: Sink,
+    run: Runner,
+}
+
+impl Endpoint {
+    /// Create endpoint from v3 managed client
+    ///
+    /// v3 protocol cannot prevent echo of forwarded messages, topics that
+    /// are forwarded in both directions must use different prefixes.
+    pub fn v3<A, T>(client: v3::client::ManagedClient<A, T>) -> Self
+    where
+        A: Address + Clone + 'static,
+        T: Service<Request = Connect<A>, Error = connect::ConnectError> + 'static,
+        T::Response: AsyncRead + AsyncWrite + Unpin + 'static,
+    {
+        Endpoint {
+            sink: Sink::V3(client.sink()),
+            run: Box::new(move |router, side| {
+                Box::pin(client.start(move |client| {
+                    let router = router.clone();
+                    client.start(move |msg| {
+                        let router = router.clone();
+                        async move {
+                            match msg {
+                                v3::client::ControlMessage::Publish(p) => {
+                                    if let Some(fut) =
+                                        router.forward(side, Message::from(p.packet()))
+                                    {
+                                        fut.await
+                                    }
+                                    Ok::<_, ()>(p.ack())
+                                }
+                                _ => Ok(msg.disconnect()),
+                            }
+                        }
+                    })
+                }))
+            }),
+        }
+    }
+
+    /// Create endpoint from v5 managed client
+    pub fn v5<A, T>(client: v5::client::ManagedClient<A, T>) -> Self
+    where
+        A: Address + Clone + 'static,
+        T: Service<Request = Connect<A>, Error = connect::ConnectError> + 'static,
+        T::Response: AsyncRead + AsyncWrite + Unpin + 'static,
+    {
+        Endpoint {
+            sink: Sink::V5(client.sink()),
+            run: Box::new(move |router, side| {
+                Box::pin(client.start(move |client| {
+                    let router = router.clone();
+                    client.start(move |msg| {
+                        let router = router.clone();
+                        async move {
+                            match msg {
+                                v5::client::ControlMessage::Publish(p) => {
+                                    if let Some(fut) =
+                                        router.forward(side, Message::from(p.packet()))
+                                    {
+                                        fut.await
+                                    }
+                                    Ok::<_, ()>(if p.packet().qos == QoS::AtMostOnce {
+                                        p.ack_qos0()
+                                    } else {
+                                        p.ack(v5::codec::PublishAckReason::Success)
+                                    })
+                                }
+                                _ => Ok(msg.disconnect(v5::codec::Disconnect::default())),
+                            }
+                        }
+                    })
+                }))
+            }),
+        }
+    }
+}
+
+impl fmt::Debug for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let proto = match self.sink {
+            Sink::V3(_) => "v3",
+            Sink::V5(_) => "v5",
+        };
+        f.debug_struct("Endpoint").field("protocol", &proto).finish()
+    }
+}
+
+/// Bridge between two mqtt endpoints
+///
+/// Bridge subscribes to bridged topics on one endpoint and republishes
+/// received messages on the other endpoint with same qos and retain flag.
+/// Received message is acknowledged after it is forwarded. v5 endpoint
+/// subscribes with `no_local` option, so messages forwarded by the bridge
+/// are not received back.
+///
+/// ```rust,no_run
+/// use ntex_mqtt::bridge::{Bridge, BridgeTopic, Direction, Endpoint};
+/// use ntex_mqtt::{types::QoS, v3, v5};
+///
+/// #[ntex::main]
+/// async fn main() {
+///     let local = v5::client::MqttConnector::new("127.0.0.1:1883").client_id("bridge");
+///     let remote = v3::client::MqttConnector::new("10.0.0.1:1883").client_id("edge-1");
+///
+///     Bridge::new(
+///         Endpoint::v5(v5::client::ManagedClient::new(local)),
+///         Endpoint::v3(v3::client::ManagedClient::new(remote)),
+///     )
+///     .topic(
+///         BridgeTopic::new("sensors/#".into(), Direction::Out)
+///             .qos(QoS::AtLeastOnce)
+///             .remote_prefix("edge-1/".into()),
+///     )
+///     .start()
+///     .await
+/// }
+/// ```
+pub struct Bridge {
+    local: Endpoint,
+    remote: Endpoint,
+    topics: Vec<BridgeTopic>,
+}
+
+impl Bridge {
+    /// Create bridge between local and remote endpoints
+    pub fn new(local: Endpoint, remote: Endpoint) -> Self {
+        Bridge { local, remote, topics: Vec::new() }
+    }
+
+    /// Add bridged topic
+    pub fn topic(mut self, topic: BridgeTopic) -> Self {
+        self.topics.push(topic);
+        self
+    }
+
+    /// Run bridge
+    ///
+    /// Bridge runs until both endpoints are stopped. Topics with invalid
+    /// filters are ignored.
+    pub async fn start(self) {
+        let mut topics = Vec::new();
+        for topic in self.topics {
+            let local = topic.subscription(Side::Local).parse::<Topic>();
+            let remote = topic.subscription(Side::Remote).parse::<Topic>();
+            match (local, remote) {
+                (Ok(local), Ok(remote)) => topics.push((topic, local, remote)),
+                _ => log::error!("Invalid bridged topic filter: {:?}", topic.filter),
+            }
+        }
+
+        let router =
+            Rc::new(Router { local: self.local.sink, remote: self.remote.sink, topics });
+        for (topic, _, _) in &router.topics {
+            for side in &[Side::Local, Side::Remote] {
+                // subscribe on endpoint messages are forwarded from
+                if topic.forwards(*side) {
+                    let sink = match side {
+                        Side::Local => router.local.clone(),
+                        Side::Remote => router.remote.clone(),
+                    };
+                    ntex::rt::spawn(sink.subscribe(topic.subscription(*side), topic.qos));
+                }
+            }
+        }
+
+        let local = (self.local.run)(router.clone(), Side::Local);
+        let remote = (self.remote.run)(router.clone(), Side::Remote);
+        let _ = join(local, remote).await;
+    }
+}
+
+impl fmt::Debug for Bridge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Bridge")
+            .field("local", &self.local)
+            .field("remote", &self.remote)
+            .field("topics", &self.topics)
+            .finish()
+    }
+}
@@ -0,0 +1,437 @@
+//! Embedded mqtt broker
+use std::cell::{Cell, RefCell};
+use std::{collections::HashMap, convert::TryFrom, fmt, rc::Rc};
+
+use ntex::codec::{AsyncRead, AsyncWrite};
+use ntex::service::{fn_factory_with_config, fn_service, ServiceFactory};
+use ntex::util::Ready;
+
+use crate::error::MqttError;
+use crate::registry::ClientRegistry;
+use crate::retain::{self, min_qos, MemoryRetainStore, RetainStore};
+use crate::sessions::Message;
+use crate::stats::Stats;
+use crate::subs::{Subscription, Subscriptions};
+use crate::topic::{SharedTopic, Topic};
+use crate::types::QoS;
+use crate::will::WillManager;
+use crate::{v3, v5};
+
+/// Broker service error
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BrokerError;
+
+impl fmt::Display for BrokerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Broker error")
+    }
+}
+
+impl std::error::Error for BrokerError {}
+
+impl TryFrom<BrokerError> for v5::PublishAck {
+    type Error = BrokerError;
+
+    fn try_from(err: BrokerError) -> Result<Self, Self::Error> {
+        Err(err)
+    }
+}
+
+#[derive(Clone)]
+enum Client {
+    V3(v3::MqttSink),
+    V5(v5::MqttSink),
+}
+
+enum Filter {
+    Topic(Topic),
+    Shared(SharedTopic),
+}
+
+impl Filter {
+    fn parse(topic: &str) -> Option<Filter> {
+        if SharedTopic::is_shared(topic) {
+            topic.parse().ok().map(Filter::Shared)
+        } else {
+            topic.parse().ok().map(Filter::Topic)
+        }
+    }
+}
+
+struct Inner {
+    subs: RefCell<Subscriptions<usize>>,
+    clients: RefCell<HashMap<usize, Client>>,
+    retained: MemoryRetainStore,
+    registry: ClientRegistry,
+    stats: Stats,
+    counter: Cell<usize>,
+}
+
+/// Embedded mqtt broker
+///
+/// Broker accepts v3 and v5 connections, routes publishes to matching
+/// subscriptions (including shared subscriptions), keeps retained messages
+/// and publishes last will of abnormally closed connections. Messages are
+/// delivered with qos 0 or 1, qos 2 publishes are downgraded. Sessions are
+/// not persisted, every connection starts clean session.
+///
+/// Broker state is not shared between workers, server must be started
+/// with single worker.
+///
+/// ```rust,no_run
+/// use ntex_mqtt::broker::Broker;
+///
+/// #[ntex::main]
+/// async fn main() -> std::io::Result<()> {
+///     ntex::server::Server::build()
+///         .bind("mqtt", "127.0.0.1:1883", || Broker::new().server())?
+///         .workers(1)
+///         .run()
+///         .await
+/// }
+/// ```
+#[derive(Clone)]
+pub struct Broker(Rc<Inner>);
+
+impl Default for Broker {
+    fn default() -> Self {
+        Broker(Rc::new(Inner {
+            subs: RefCell::new(Subscriptions::new()),
+            clients: RefCell::new(HashMap::new()),
+            retained: MemoryRetainStore::new(),
+            registry: ClientRegistry::new(),
+            stats: Stats::new(),
+            counter: Cell::new(0),
+        }))
+    }
+}
+
+impl fmt::Debug for Broker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Broker")
+            .field("clients", &self.0.clients.borrow().len())
+            .field("retained", &self.0.retained.len())
+            .finish()
+    }
+}
+
+impl Broker {
+    /// Create new broker
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of connected clients
+    pub fn clients(&self) -> usize {
+        self.0.clients.borrow().len()
+    }
+
+    /// Retained messages store
+    pub fn retained(&self) -> &MemoryRetainStore {
+        &self.0.retained
+    }
+
+    /// Broker statistics
+    pub fn stats(&self) -> &Stats {
+        &self.0.stats
+    }
+
+    /// Publish message to subscribers
+    pub fn publish(&self, msg: Message) {
+        self.route(None, msg)
+    }
+
+    /// Create mqtt server factory
+    pub fn server<Io>(
+        &self,
+    ) -> impl ServiceFactory<
+        Config = (),
+        Request = Io,
+        Response = (),
+        Error = MqttError<BrokerError>,
+        InitError = (),
+    >
+    where
+        Io: AsyncRead + AsyncWrite + Unpin + 'static,
+    {
+        crate::MqttServer::new().v3(self.v3()).v5(self.v5())
+    }
+
+    fn will_manager(&self) -> WillManager {
+        let broker = self.clone();
+        WillManager::new(move |_, msg| broker.publish(msg))
+    }
+
+    fn next_id(&self) -> usize {
+        let id = self.0.counter.get().wrapping_add(1);
+        self.0.counter.set(id);
+        id
+    }
+
+    fn v3<Io>(
+        &self,
+    ) -> v3::MqttServer<
+        Io,
+        usize,
+        impl ServiceFactory<
+                Config = (),
+                Request = v3::Handshake<Io>,
+                Response = v3::HandshakeAck<Io, usize>,
+                Error = BrokerError,
+                InitError = (),
+            > + 'static,
+        impl ServiceFactory<
+                Config = v3::Session<usize>,
+                Request = v3::ControlMessage<BrokerError>,
+                Response = v3::ControlResult,
+                Error = BrokerError,
+                InitError = BrokerError,
+            > + 'static,
+        impl ServiceFactory<
+                Config = v3::Session<usize>,
+                Request = v3::Publish,
+                Response = (),
+                Error = BrokerError,
+                InitError = BrokerError,
+            > + 'static,
+    >
+    where
+        Io: AsyncRead + AsyncWrite + Unpin + 'static,
+    {
+        let (broker, broker2, broker3) = (self.clone(), self.clone(), self.clone());
+
+        v3::MqttServer::new(fn_service(move |hnd: v3::Handshake<Io>| {
+            Ready::<_, BrokerError>::Ok(hnd.ack(broker.next_id(), false))
+        }))
+        .will_manager(self.will_manager())
+        .client_registry(self.0.registry.clone())
+        .stats(self.0.stats.clone())
+        .control(fn_factory_with_config(move |session: v3::Session<usize>| {
+            let broker = broker2.clone();
+            let id = *session.state();
+            let sink = session.sink().clone();
+            broker.0.clients.borrow_mut().insert(id, Client::V3(sink.clone()));
+
+            Ready::Ok(fn_service(move |msg| {
+                Ready::Ok(match msg {
+                    v3::ControlMessage::Subscribe(mut msg) => {
+                        for mut s in &mut msg {
+                            let qos = min_qos(s.qos(), QoS::AtLeastOnce);
+                            if let Some(filter) = Filter::parse(s.topic()) {
+                                broker.subscribe(id, &filter, qos.into());
+                                // retained messages are not sent for shared subscriptions
+                                if let Filter::Topic(ref filter) = filter {
+                                    let store = &broker.0.retained;
+                                    ntex::rt::spawn(retain::deliver_v3(
+                                        store, &sink, filter, qos,
+                                    ));
+                                }
+                                s.confirm(qos);
+                            } else {
+                                s.fail();
+                            }
+                        }
+                        msg.ack()
+                    }
+                    v3::ControlMessage::Unsubscribe(msg) => {
+                        for topic in msg.iter() {
+                            broker.unsubscribe(id, topic);
+                        }
+                        msg.ack()
+                    }
+                    v3::ControlMessage::Ping(msg) => msg.ack(),
+                    v3::ControlMessage::Disconnect(msg) => msg.ack(),
+                    v3::ControlMessage::Closed(msg) => {
+                        broker.remove(id);
+                        msg.ack()
+                    }
+                    v3::ControlMessage::Error(msg) => msg.ack(),
+                    v3::ControlMessage::ProtocolError(msg) => msg.ack(),
+                })
+            }))
+        }))
+        .publish(fn_factory_with_config(move |session: v3::Session<usize>| {
+            let broker = broker3.clone();
+            let id = *session.state();
+
+            Ready::Ok(fn_service(move |p: v3::Publish| {
+                broker.route(Some(id), Message::from(&p));
+                Ready::Ok(())
+            }))
+        }))
+    }
+
+    fn v5<Io>(
+        &self,
+    ) -> v5::MqttServer<
+        Io,
+        usize,
+        impl ServiceFactory<
+                Config = (),
+                Request = v5::Handshake<Io>,
+                Response = v5::HandshakeAck<Io, usize>,
+                Error = BrokerError,
+                InitError = (),
+            > + 'static,
+        impl ServiceFactory<
+                Config = v5::Session<usize>,
+                Request = v5::ControlMessage<BrokerError>,
+                Response = v5::ControlResult,
+                Error = BrokerError,
+                InitError = BrokerError,
+            > + 'static,
+        impl ServiceFactory<
+                Config = v5::Session<usize>,
+                Request = v5::Publish,
+                Response = v5::PublishAck,
+                Error = BrokerError,
+                InitError = BrokerError,
+            > + 'static,
+    >
+    where
+        Io: AsyncRead + AsyncWrite + Unpin + 'static,
+    {
+        let (broker, broker2, broker3) = (self.clone(), self.clone(), self.clone());
+
+        v5::MqttServer::new(fn_service(move |hnd: v5::Handshake<Io>| {
+            Ready::<_, BrokerError>::Ok(hnd.ack(broker.next_id()))
+        }))
+        .max_qos(QoS::AtLeastOnce)
+        .will_manager(self.will_manager())
+        .client_registry(self.0.registry.clone())
+        .stats(self.0.stats.clone())
+        .control(fn_factory_with_config(move |session: v5::Session<usize>| {
+            let broker = broker2.clone();
+            let id = *session.state();
+            let sink = session.sink().clone();
+            broker.0.clients.borrow_mut().insert(id, Client::V5(sink.clone()));
+
+            Ready::Ok(fn_service(move |msg| {
+                Ready::Ok(match msg {
+                    v5::ControlMessage::Subscribe(mut msg) => {
+                        for mut s in &mut msg {
+                            let qos = min_qos(s.options().qos, QoS::AtLeastOnce);
+                            let sub = s.subscription(qos);
+                            if let Some(filter) = Filter::parse(s.topic()) {
+                                let is_new = broker.subscribe(id, &filter, sub.clone());
+                                // retained messages are not sent for shared subscriptions
+                                if let Filter::Topic(ref filter) = filter {
+                                    let store = &broker.0.retained;
+                                    ntex::rt::spawn(retain::deliver_v5(
+                                        store, &sink, filter, &sub, is_new,
+                                    ));
+                                }
+                                s.confirm(qos);
+                            } else {
+                                s.fail(v5::codec::SubscribeAckReason::TopicFilterInvalid);
+                            }
+                        }
+                        msg.ack()
+                    }
+                    v5::ControlMessage::Unsubscribe(mut msg) => {
+                        for mut s in &mut msg {
+                            if broker.unsubscribe(id, s.topic()) {
+                                s.success();
+                            } else {
+                                s.fail(v5::codec::UnsubscribeAckReason::NoSubscriptionExisted);
+                            }
+                        }
+                        msg.ack()
+                    }
+                    v5::ControlMessage::Ping(msg) => msg.ack(),
+                    v5::ControlMessage::Disconnect(msg) => msg.ack(),
+                    v5::ControlMessage::Closed(msg) => {
+                        broker.remove(id);
+                        msg.ack()
+                    }
+                    v5::ControlMessage::Error(msg) => {
+                        msg.ack(v5::codec::DisconnectReasonCode::UnspecifiedError)
+                    }
+                    v5::ControlMessage::ProtocolError(msg) => msg.ack(),
+                    msg => msg.disconnect(),
+                })
+            }))
+        }))
+        .publish(fn_factory_with_config(move |session: v5::Session<usize>| {
+            let broker = broker3.clone();
+            let id = *session.state();
+
+            Ready::Ok(fn_service(move |p: v5::Publish| {
+                broker.route(Some(id), Message::from(&p));
+                Ready::Ok(p.ack())
+            }))
+        }))
+    }
+
+    fn subscribe(&self, id: usize, filter: &Filter, sub: Subscription) -> bool {
+        let mut subs = self.0.subs.borrow_mut();
+        match filter {
+            Filter::Topic(filter) => subs.subscribe(id, filter, sub),
+            Filter::Shared(filter) => subs.subscribe_shared(id, filter, sub),
+        }
+    }
+
+    fn unsubscribe(&self, id: usize, topic: &str) -> bool {
+        match Filter::parse(topic) {
+            Some(Filter::Topic(filter)) => self.0.subs.borrow_mut().unsubscribe(&id, &filter),
+            Some(Filter::Shared(filter)) => {
+                self.0.subs.borrow_mut().unsubscribe_shared(&id, &filter)
+            }
+            None => false,
+        }
+    }
+
+    fn remove(&self, id: usize) {
+        self.0.subs.borrow_mut().remove(&id);
+        self.0.clients.borrow_mut().remove(&id);
+    }
+
+    /// Deliver message to matching subscriptions
+    fn route(&self, sender: Option<usize>, msg: Message) {
+        if msg.retain {
+            self.0.retained.update(msg.clone());
+        }
+
+        let targets: Vec<_> = {
+            let subs = self.0.subs.borrow();
+            let clients = self.0.clients.borrow();
+            subs.matches(&msg.topic)
+                .into_iter()
+                .filter(|(id, sub)| !(sub.options.no_local && Some(**id) == sender))
+                .filter_map(|(id, sub)| clients.get(id).map(|c| (c.clone(), sub.clone())))
+                .collect()
+        };
+
+        for (client, sub) in targets {
+            let qos = min_qos(msg.qos, sub.qos());
+            match client {
+                Client::V3(sink) => {
+                    let mut builder = sink.publish(msg.topic.clone(), msg.payload.clone());
+                    if sub.retain(msg.retain) {
+                        builder = builder.retain();
+                    }
+                    if qos == QoS::AtMostOnce {
+                        let _ = builder.send_at_most_once();
+                    } else {
+                        ntex::rt::spawn(builder.send_at_least_once());
+                    }
+                }
+                Client::V5(sink) => {
+                    let mut properties = msg.properties.clone();
+                    properties.subscription_ids = sub.id.map(|id| vec![id]);
+                    let mut builder = sink
+                        .publish(msg.topic.clone(), msg.payload.clone())
+                        .properties(|props| *props = properties);
+                    if sub.retain(msg.retain) {
+                        builder = builder.retain();
+                    }
+                    if qos == QoS::AtMostOnce {
+                        let _ = builder.send_at_most_once();
+                    } else {
+                        ntex::rt::spawn(builder.send_at_least_once());
+                    }
+                }
+            }
+        }
+    }
+}
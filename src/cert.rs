@@ -0,0 +1,385 @@
+//! TLS client certificate identity
+use std::task::{Context, Poll};
+use std::{convert::TryFrom, fmt, future::Future, net::IpAddr, pin::Pin, rc::Rc};
+
+use ntex::service::{IntoServiceFactory, Service, ServiceFactory};
+use ntex::util::Bytes;
+
+use crate::info::ConnectionInfo;
+use crate::{v3, v5};
+
+#[cfg(feature = "openssl")]
+use openssl::{hash::MessageDigest, nid::Nid, x509::X509Ref};
+
+/// Identity of the peer certificate
+///
+/// Identity is extracted from the leaf certificate of openssl and
+/// rustls streams (`openssl` and `rustls` features) and is available
+/// via `Handshake::peer_identity()`.
+#[derive(Clone, Debug, Default)]
+pub struct CertIdentity {
+    common_name: Option<String>,
+    dns_names: Vec<String>,
+    emails: Vec<String>,
+    uris: Vec<String>,
+    ips: Vec<IpAddr>,
+    fingerprint: Bytes,
+}
+
+impl CertIdentity {
+    /// Subject common name
+    pub fn common_name(&self) -> Option<&str> {
+        self.common_name.as_deref()
+    }
+
+    /// DNS names of subject alternative name extension
+    pub fn dns_names(&self) -> &[String] {
+        &self.dns_names
+    }
+
+    /// E-mail addresses of subject alternative name extension
+    pub fn emails(&self) -> &[String] {
+        &self.emails
+    }
+
+    /// URIs of subject alternative name extension
+    pub fn uris(&self) -> &[String] {
+        &self.uris
+    }
+
+    /// IP addresses of subject alternative name extension
+    pub fn ips(&self) -> &[IpAddr] {
+        &self.ips
+    }
+
+    /// SHA-256 fingerprint of DER encoded certificate
+    pub fn fingerprint(&self) -> &[u8] {
+        &self.fingerprint
+    }
+
+    /// SHA-256 fingerprint as lowercase hex string
+    pub fn fingerprint_hex(&self) -> String {
+        self.fingerprint.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Check if identity matches subject common name or any of
+    /// dns, e-mail or uri alternative names
+    pub fn matches(&self, id: &str) -> bool {
+        self.common_name() == Some(id)
+            || self.dns_names.iter().any(|n| n == id)
+            || self.emails.iter().any(|n| n == id)
+            || self.uris.iter().any(|n| n == id)
+    }
+
+    #[cfg(feature = "openssl")]
+    pub(crate) fn from_openssl(cert: &X509Ref) -> Self {
+        let mut id = CertIdentity {
+            common_name: cert
+                .subject_name()
+                .entries_by_nid(Nid::COMMONNAME)
+                .next()
+                .and_then(|e| e.data().as_utf8().ok())
+                .map(|s| s.to_string()),
+            fingerprint: cert
+                .digest(MessageDigest::sha256())
+                .map(|d| Bytes::copy_from_slice(&d))
+                .unwrap_or_default(),
+            ..Default::default()
+        };
+
+        if let Some(names) = cert.subject_alt_names() {
+            for name in names.iter() {
+                if let Some(n) = name.dnsname() {
+                    id.dns_names.push(n.to_string());
+                } else if let Some(n) = name.email() {
+                    id.emails.push(n.to_string());
+                } else if let Some(n) = name.uri() {
+                    id.uris.push(n.to_string());
+                } else if let Some(ip) = name.ipaddress().and_then(ip_from_bytes) {
+                    id.ips.push(ip);
+                }
+            }
+        }
+        id
+    }
+
+    #[cfg(feature = "rustls")]
+    pub(crate) fn from_der(der: &[u8]) -> Option<Self> {
+        use sha2::Digest;
+        use x509_parser::extensions::GeneralName;
+
+        let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+        let mut id = CertIdentity {
+            common_name: cert
+                .subject()
+                .iter_common_name()
+                .next()
+                .and_then(|a| a.as_str().ok())
+                .map(|s| s.to_string()),
+            fingerprint: Bytes::copy_from_slice(&sha2::Sha256::digest(der)),
+            ..Default::default()
+        };
+
+        if let Ok(Some(ext)) = cert.subject_alternative_name() {
+            for name in &ext.value.general_names {
+                match name {
+                    GeneralName::DNSName(n) => id.dns_names.push(n.to_string()),
+                    GeneralName::RFC822Name(n) => id.emails.push(n.to_string()),
+                    GeneralName::URI(n) => id.uris.push(n.to_string()),
+                    GeneralName::IPAddress(ip) => id.ips.extend(ip_from_bytes(ip)),
+                    _ => (),
+                }
+            }
+        }
+        Some(id)
+    }
+}
+
+#[cfg_attr(not(any(feature = "openssl", feature = "rustls")), allow(dead_code))]
+fn ip_from_bytes(b: &[u8]) -> Option<IpAddr> {
+    match b.len() {
+        4 => Some(IpAddr::from(<[u8; 4]>::try_from(b).ok()?)),
+        16 => Some(IpAddr::from(<[u8; 16]>::try_from(b).ok()?)),
+        _ => None,
+    }
+}
+
+/// Mqtt connect packet field that must match certificate identity
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IdentityField {
+    /// Client identifier
+    ClientId,
+    /// User name
+    Username,
+}
+
+/// Handshake packet that could be checked against certificate identity
+pub trait CertHandshake<Ack> {
+    /// Peer connection info
+    fn connection_info(&self) -> &ConnectionInfo;
+
+    /// Value of the connect packet field
+    fn field(&self, field: IdentityField) -> Option<&str>;
+
+    /// Reject connection with "not authorized" reason
+    fn not_authorized(self) -> Ack;
+}
+
+impl<Io, St> CertHandshake<v3::HandshakeAck<Io, St>> for v3::Handshake<Io> {
+    fn connection_info(&self) -> &ConnectionInfo {
+        v3::Handshake::connection_info(self)
+    }
+
+    fn field(&self, field: IdentityField) -> Option<&str> {
+        match field {
+            IdentityField::ClientId => Some(self.packet().client_id.as_ref()),
+            IdentityField::Username => self.packet().username.as_deref(),
+        }
+    }
+
+    fn not_authorized(self) -> v3::HandshakeAck<Io, St> {
+        v3::Handshake::not_authorized(self)
+    }
+}
+
+impl<Io, St> CertHandshake<v5::HandshakeAck<Io, St>> for v5::Handshake<Io> {
+    fn connection_info(&self) -> &ConnectionInfo {
+        v5::Handshake::connection_info(self)
+    }
+
+    fn field(&self, field: IdentityField) -> Option<&str> {
+        match field {
+            IdentityField::ClientId => Some(self.packet().client_id.as_ref()),
+            IdentityField::Username => self.packet().username.as_deref(),
+        }
+    }
+
+    fn not_authorized(self) -> v5::HandshakeAck<Io, St> {
+        self.failed(v5::codec::ConnectAckReason::NotAuthorized)
+    }
+}
+
+/// Handshake service that requires certificate identity
+///
+/// Connection is rejected with "not authorized" reason if peer did not
+/// present a certificate or if connect packet's client id (or user name)
+/// does not match certificate identity (see `CertIdentity::matches()`).
+/// Otherwise handshake is passed to the inner service.
+///
+/// ```rust,ignore
+/// MqttServer::new(cert::RequireCertIdentity::client_id(handshake))
+/// ```
+pub struct RequireCertIdentity<F> {
+    factory: F,
+    field: IdentityField,
+}
+
+impl<F> RequireCertIdentity<F> {
+    /// Require client id to match certificate identity
+    pub fn client_id<U>(factory: U) -> Self
+    where
+        F: ServiceFactory,
+        U: IntoServiceFactory<F>,
+    {
+        Self::new(IdentityField::ClientId, factory)
+    }
+
+    /// Require user name to match certificate identity
+    pub fn username<U>(factory: U) -> Self
+    where
+        F: ServiceFactory,
+        U: IntoServiceFactory<F>,
+    {
+        Self::new(IdentityField::Username, factory)
+    }
+
+    /// Require specified connect packet field to match certificate identity
+    pub fn new<U>(field: IdentityField, factory: U) -> Self
+    where
+        F: ServiceFactory,
+        U: IntoServiceFactory<F>,
+    {
+        RequireCertIdentity { field, factory: factory.into_factory() }
+    }
+}
+
+impl<F> ServiceFactory for RequireCertIdentity<F>
+where
+    F: ServiceFactory,
+    F::Request: CertHandshake<F::Response>,
+    F::Future: 'static,
+    F::Service: 'static,
+{
+    type Config = F::Config;
+    type Request = F::Request;
+    type Response = F::Response;
+    type Error = F::Error;
+    type Service = RequireCertIdentityService<F::Service>;
+    type InitError = F::InitError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Service, Self::InitError>>>>;
+
+    fn new_service(&self, cfg: F::Config) -> Self::Future {
+        let field = self.field;
+        let fut = self.factory.new_service(cfg);
+
+        Box::pin(async move {
+            Ok(RequireCertIdentityService { field, service: Rc::new(fut.await?) })
+        })
+    }
+}
+
+/// Certificate identity check service
+pub struct RequireCertIdentityService<S> {
+    service: Rc<S>,
+    field: IdentityField,
+}
+
+impl<S> Service for RequireCertIdentityService<S>
+where
+    S: Service + 'static,
+    S::Request: CertHandshake<S::Response>,
+{
+    type Request = S::Request;
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>>>>;
+
+    #[inline]
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(&self, cx: &mut Context<'_>, is_error: bool) -> Poll<()> {
+        self.service.poll_shutdown(cx, is_error)
+    }
+
+    fn call(&self, req: S::Request) -> Self::Future {
+        let matched = match (req.connection_info().peer_identity(), req.field(self.field)) {
+            (Some(id), Some(value)) => id.matches(value),
+            _ => false,
+        };
+
+        if matched {
+            let service = self.service.clone();
+            Box::pin(async move { service.call(req).await })
+        } else {
+            log::trace!("Peer certificate does not match {:?}", self.field);
+            let ack = req.not_authorized();
+            Box::pin(async move { Ok(ack) })
+        }
+    }
+}
+
+impl<F> fmt::Debug for RequireCertIdentity<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RequireCertIdentity").field("field", &self.field).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        let id = CertIdentity {
+            common_name: Some("device-1".to_string()),
+            dns_names: vec!["device-1.example.com".to_string()],
+            fingerprint: Bytes::from_static(&[0x0a, 0xff]),
+            ..Default::default()
+        };
+        assert!(id.matches("device-1"));
+        assert!(id.matches("device-1.example.com"));
+        assert!(!id.matches("device-2"));
+        assert_eq!(id.fingerprint_hex(), "0aff");
+    }
+
+    #[test]
+    fn test_ip_from_bytes() {
+        assert_eq!(ip_from_bytes(&[127, 0, 0, 1]), Some(IpAddr::from([127, 0, 0, 1])));
+        assert_eq!(ip_from_bytes(&[0; 16]), Some(IpAddr::from([0u8; 16])));
+        assert_eq!(ip_from_bytes(&[1, 2]), None);
+    }
+
+    #[cfg(all(feature = "openssl", feature = "rustls"))]
+    #[test]
+    fn test_extractors() {
+        use openssl::x509::{extension::SubjectAlternativeName, X509NameBuilder, X509};
+        use openssl::{asn1::Asn1Time, pkey::PKey, rsa::Rsa};
+
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "device-1").unwrap();
+        let name = name.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        let san = SubjectAlternativeName::new()
+            .dns("device-1.example.com")
+            .email("device-1@example.com")
+            .uri("spiffe://example.com/device-1")
+            .ip("10.0.0.1")
+            .build(&builder.x509v3_context(None, None))
+            .unwrap();
+        builder.append_extension(san).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        let cert = builder.build();
+
+        let id1 = CertIdentity::from_openssl(&cert);
+        let id2 = CertIdentity::from_der(&cert.to_der().unwrap()).unwrap();
+        assert_eq!(id1.fingerprint(), id2.fingerprint());
+        for id in &[id1, id2] {
+            assert_eq!(id.common_name(), Some("device-1"));
+            assert_eq!(id.dns_names(), &["device-1.example.com".to_string()]);
+            assert_eq!(id.emails(), &["device-1@example.com".to_string()]);
+            assert_eq!(id.uris(), &["spiffe://example.com/device-1".to_string()]);
+            assert_eq!(id.ips(), &[IpAddr::from([10, 0, 0, 1])]);
+            assert_eq!(id.fingerprint().len(), 32);
+        }
+    }
+}
@@ -84,6 +84,15 @@ impl From<Either<DecodeError, io::Error>> for ProtocolError {
     }
 }
 
+impl From<Either<EncodeError, io::Error>> for ProtocolError {
+    fn from(err: Either<EncodeError, io::Error>) -> Self {
+        match err {
+            Either::Left(err) => ProtocolError::Encode(err),
+            Either::Right(err) => ProtocolError::Io(err),
+        }
+    }
+}
+
 #[derive(Debug, Display, From)]
 pub enum DecodeError {
     InvalidProtocol,
@@ -97,6 +106,7 @@ pub enum DecodeError {
     // MQTT v3 only
     PacketIdRequired,
     MaxSizeExceeded,
+    InvalidProxyHeader,
     Utf8Error(std::str::Utf8Error),
 }
 
@@ -127,6 +137,7 @@ impl PartialEq for DecodeError {
             (DecodeError::PacketIdRequired, DecodeError::PacketIdRequired) => true,
             (DecodeError::MaxSizeExceeded, DecodeError::MaxSizeExceeded) => true,
             (DecodeError::MalformedPacket, DecodeError::MalformedPacket) => true,
+            (DecodeError::InvalidProxyHeader, DecodeError::InvalidProxyHeader) => true,
             (DecodeError::Utf8Error(_), _) => false,
             _ => false,
         }
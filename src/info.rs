@@ -0,0 +1,186 @@
+//! Peer connection info
+use std::{any::Any, fmt, net::SocketAddr, time::SystemTime};
+
+use ntex::rt::net::TcpStream;
+use ntex::util::Bytes;
+
+use crate::cert::CertIdentity;
+use crate::proxy::ProxyHeader;
+use crate::ws::WsStream;
+
+#[cfg(feature = "openssl")]
+use ntex::server::openssl::{ssl::NameType, SslStream};
+
+#[cfg(feature = "rustls")]
+use ntex::server::rustls::TlsStream;
+
+/// Mqtt protocol version
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    /// MQTT 3.1
+    MQTT31,
+    /// MQTT 3.1.1
+    MQTT311,
+    /// MQTT 5
+    MQTT5,
+}
+
+/// Peer connection info
+///
+/// Info is captured at handshake time. Socket addresses are available
+/// for `TcpStream` and for tls and websocket streams on top of it, peer
+/// certificate chain and SNI are available for openssl and rustls streams
+/// (`openssl` and `rustls` features).
+#[derive(Clone)]
+pub struct ConnectionInfo {
+    protocol: Protocol,
+    connected: SystemTime,
+    peer_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
+    peer_certs: Vec<Bytes>,
+    peer_identity: Option<CertIdentity>,
+    sni: Option<String>,
+    proxy: Option<ProxyHeader>,
+}
+
+impl ConnectionInfo {
+    pub(crate) fn new<Io: 'static>(
+        io: &Io,
+        protocol: Protocol,
+        proxy: Option<ProxyHeader>,
+    ) -> Self {
+        let mut info = ConnectionInfo {
+            protocol,
+            proxy,
+            connected: SystemTime::now(),
+            peer_addr: None,
+            local_addr: None,
+            peer_certs: Vec::new(),
+            peer_identity: None,
+            sni: None,
+        };
+        info.read_io(io);
+        info
+    }
+
+    /// Mqtt protocol version
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// Time of connection handshake
+    pub fn connected(&self) -> SystemTime {
+        self.connected
+    }
+
+    /// Peer address
+    ///
+    /// Original client address is returned if PROXY protocol
+    /// header is received.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.proxy.as_ref().and_then(|p| p.source()).or(self.peer_addr)
+    }
+
+    /// Local address
+    ///
+    /// Original destination address is returned if PROXY protocol
+    /// header is received.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.proxy.as_ref().and_then(|p| p.destination()).or(self.local_addr)
+    }
+
+    /// Peer certificate chain, DER encoded, peer certificate comes first
+    pub fn peer_certs(&self) -> &[Bytes] {
+        &self.peer_certs
+    }
+
+    /// Identity of the peer certificate
+    pub fn peer_identity(&self) -> Option<&CertIdentity> {
+        self.peer_identity.as_ref()
+    }
+
+    /// Server name requested by the client (TLS SNI)
+    pub fn sni(&self) -> Option<&str> {
+        self.sni.as_deref()
+    }
+
+    /// PROXY protocol header
+    pub fn proxy_header(&self) -> Option<&ProxyHeader> {
+        self.proxy.as_ref()
+    }
+
+    fn read_io(&mut self, io: &dyn Any) {
+        if let Some(io) = io.downcast_ref::<TcpStream>() {
+            self.read_tcp(io);
+        } else if let Some(io) = io.downcast_ref::<WsStream<TcpStream>>() {
+            self.read_tcp(io.get_ref());
+        }
+
+        #[cfg(feature = "openssl")]
+        {
+            if let Some(io) = io.downcast_ref::<SslStream<TcpStream>>() {
+                self.read_openssl(io);
+            } else if let Some(io) = io.downcast_ref::<WsStream<SslStream<TcpStream>>>() {
+                self.read_openssl(io.get_ref());
+            }
+        }
+
+        #[cfg(feature = "rustls")]
+        {
+            if let Some(io) = io.downcast_ref::<TlsStream<TcpStream>>() {
+                self.read_rustls(io);
+            } else if let Some(io) = io.downcast_ref::<WsStream<TlsStream<TcpStream>>>() {
+                self.read_rustls(io.get_ref());
+            }
+        }
+    }
+
+    fn read_tcp(&mut self, io: &TcpStream) {
+        self.peer_addr = io.peer_addr().ok();
+        self.local_addr = io.local_addr().ok();
+    }
+
+    #[cfg(feature = "openssl")]
+    fn read_openssl(&mut self, io: &SslStream<TcpStream>) {
+        self.read_tcp(io.get_ref());
+
+        let ssl = io.ssl();
+        if let Some(cert) = ssl.peer_certificate() {
+            if let Ok(der) = cert.to_der() {
+                self.peer_certs.push(Bytes::from(der));
+            }
+            self.peer_identity = Some(CertIdentity::from_openssl(&cert));
+            // server side chain does not include peer certificate
+            if let Some(chain) = ssl.peer_cert_chain() {
+                self.peer_certs
+                    .extend(chain.iter().filter_map(|c| c.to_der().ok()).map(Bytes::from));
+            }
+        }
+        self.sni = ssl.servername(NameType::HOST_NAME).map(|s| s.to_string());
+    }
+
+    #[cfg(feature = "rustls")]
+    fn read_rustls(&mut self, io: &TlsStream<TcpStream>) {
+        let (io, session) = io.get_ref();
+        self.read_tcp(io);
+
+        if let Some(certs) = session.peer_certificates() {
+            self.peer_certs = certs.iter().map(|c| Bytes::copy_from_slice(&c.0)).collect();
+            self.peer_identity = certs.first().and_then(|c| CertIdentity::from_der(&c.0));
+        }
+        self.sni = session.sni_hostname().map(|s| s.to_string());
+    }
+}
+
+impl fmt::Debug for ConnectionInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnectionInfo")
+            .field("protocol", &self.protocol)
+            .field("peer_addr", &self.peer_addr())
+            .field("local_addr", &self.local_addr())
+            .field("peer_certs", &self.peer_certs.len())
+            .field("peer_identity", &self.peer_identity)
+            .field("sni", &self.sni)
+            .finish()
+    }
+}
@@ -9,21 +9,36 @@ mod topic;
 #[macro_use]
 mod utils;
 
+pub mod cert;
 pub mod error;
+pub mod info;
 pub mod v3;
 pub mod v5;
+pub mod ws;
 
+#[cfg(feature = "broker")]
+pub mod broker;
+
+mod backoff;
+pub mod bridge;
 mod io;
+pub mod proxy;
+pub mod registry;
+pub mod retain;
 mod server;
 mod service;
 mod session;
+pub mod sessions;
+pub mod stats;
+pub mod subs;
 pub mod types;
 mod version;
+pub mod will;
 
 pub use self::error::MqttError;
 pub use self::server::MqttServer;
 pub use self::session::Session;
-pub use self::topic::{Level as TopicLevel, Topic};
+pub use self::topic::{Level as TopicLevel, SharedTopic, Topic};
 
 // http://www.iana.org/assignments/service-names-port-numbers/service-names-port-numbers.xhtml
 pub const TCP_PORT: u16 = 1883;
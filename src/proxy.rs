@@ -0,0 +1,413 @@
+//! PROXY protocol support
+//!
+//! Load balancers (HAProxy, AWS ELB/NLB etc) could prepend PROXY protocol
+//! header to the stream, it carries original client and destination
+//! addresses and optional TLVs. Header parsing is enabled with
+//! `MqttServer::proxy_protocol()`, parsed header is available from
+//! `v3::Handshake::proxy_header()` and `v5::Handshake::proxy_header()`.
+use std::convert::TryInto;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::{fmt, str};
+
+use ntex::codec::Decoder;
+use ntex::util::{Buf, Bytes, BytesMut};
+
+use crate::error::DecodeError;
+
+/// Application-Layer Protocol Negotiation
+pub const TLV_ALPN: u8 = 0x01;
+/// Host name provided by the client (TLS SNI)
+pub const TLV_AUTHORITY: u8 = 0x02;
+/// CRC32c checksum of the header
+pub const TLV_CRC32C: u8 = 0x03;
+/// Padding
+pub const TLV_NOOP: u8 = 0x04;
+/// Opaque connection identifier
+pub const TLV_UNIQUE_ID: u8 = 0x05;
+/// TLS connection info
+pub const TLV_SSL: u8 = 0x20;
+/// TLS version, sub-type of `TLV_SSL`
+pub const TLV_SSL_VERSION: u8 = 0x21;
+/// Client certificate common name, sub-type of `TLV_SSL`
+pub const TLV_SSL_CN: u8 = 0x22;
+/// Cipher name, sub-type of `TLV_SSL`
+pub const TLV_SSL_CIPHER: u8 = 0x23;
+/// Certificate signature algorithm, sub-type of `TLV_SSL`
+pub const TLV_SSL_SIG_ALG: u8 = 0x24;
+/// Certificate key algorithm, sub-type of `TLV_SSL`
+pub const TLV_SSL_KEY_ALG: u8 = 0x25;
+/// Network namespace
+pub const TLV_NETNS: u8 = 0x30;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_SIZE: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_SIZE: usize = 16;
+
+/// PROXY protocol header
+#[derive(Clone, PartialEq, Eq)]
+pub struct ProxyHeader {
+    version: u8,
+    local: bool,
+    source: Option<SocketAddr>,
+    destination: Option<SocketAddr>,
+    tlvs: Vec<(u8, Bytes)>,
+}
+
+impl ProxyHeader {
+    /// Protocol version, `1` for text header and `2` for binary header
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Returns `true` if connection is not proxied
+    ///
+    /// Connection is established by the proxy itself (v2 `LOCAL` command or
+    /// v1 `UNKNOWN` protocol), addresses are not available.
+    pub fn is_local(&self) -> bool {
+        self.local
+    }
+
+    /// Original client address
+    pub fn source(&self) -> Option<SocketAddr> {
+        self.source
+    }
+
+    /// Original destination address
+    pub fn destination(&self) -> Option<SocketAddr> {
+        self.destination
+    }
+
+    /// Type-length-value fields of v2 header
+    pub fn tlvs(&self) -> &[(u8, Bytes)] {
+        &self.tlvs
+    }
+
+    /// Value of TLV with specified type
+    pub fn tlv(&self, tp: u8) -> Option<&Bytes> {
+        find(&self.tlvs, tp)
+    }
+
+    /// Negotiated application protocol
+    pub fn alpn(&self) -> Option<&Bytes> {
+        self.tlv(TLV_ALPN)
+    }
+
+    /// Host name requested by the client (TLS SNI)
+    pub fn authority(&self) -> Option<&str> {
+        self.tlv(TLV_AUTHORITY).and_then(|v| str::from_utf8(v).ok())
+    }
+
+    /// Connection identifier assigned by the proxy
+    pub fn unique_id(&self) -> Option<&Bytes> {
+        self.tlv(TLV_UNIQUE_ID)
+    }
+
+    /// TLS connection info, if client is connected to the proxy over TLS
+    pub fn ssl(&self) -> Option<ProxySsl> {
+        self.tlv(TLV_SSL).and_then(|v| ProxySsl::parse(v.clone()))
+    }
+}
+
+impl fmt::Debug for ProxyHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProxyHeader")
+            .field("version", &self.version)
+            .field("local", &self.local)
+            .field("source", &self.source)
+            .field("destination", &self.destination)
+            .field("tlvs", &self.tlvs.iter().map(|(tp, _)| tp).collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// TLS connection info of PROXY protocol v2 header
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProxySsl {
+    client: u8,
+    verify: u32,
+    tlvs: Vec<(u8, Bytes)>,
+}
+
+impl ProxySsl {
+    fn parse(mut src: Bytes) -> Option<Self> {
+        if src.len() < 5 {
+            return None;
+        }
+        let client = src.get_u8();
+        let verify = src.get_u32();
+        let tlvs = parse_tlvs(src).ok()?;
+        Some(ProxySsl { client, verify, tlvs })
+    }
+
+    /// Client is connected over TLS
+    pub fn is_ssl(&self) -> bool {
+        self.client & 0x01 != 0
+    }
+
+    /// Client provided certificate over current connection
+    pub fn has_client_cert(&self) -> bool {
+        self.client & 0x02 != 0
+    }
+
+    /// Client provided certificate at least once over the TLS session
+    pub fn has_session_cert(&self) -> bool {
+        self.client & 0x04 != 0
+    }
+
+    /// Client certificate is verified by the proxy
+    pub fn is_verified(&self) -> bool {
+        self.verify == 0
+    }
+
+    /// TLS version, i.e. `TLSv1.3`
+    pub fn version(&self) -> Option<&str> {
+        self.sub_str(TLV_SSL_VERSION)
+    }
+
+    /// Common name of client certificate subject
+    pub fn common_name(&self) -> Option<&str> {
+        self.sub_str(TLV_SSL_CN)
+    }
+
+    /// Cipher name, i.e. `ECDHE-RSA-AES128-GCM-SHA256`
+    pub fn cipher(&self) -> Option<&str> {
+        self.sub_str(TLV_SSL_CIPHER)
+    }
+
+    /// Value of sub-TLV with specified type
+    pub fn tlv(&self, tp: u8) -> Option<&Bytes> {
+        find(&self.tlvs, tp)
+    }
+
+    fn sub_str(&self, tp: u8) -> Option<&str> {
+        self.tlv(tp).and_then(|v| str::from_utf8(v).ok())
+    }
+}
+
+fn find(tlvs: &[(u8, Bytes)], tp: u8) -> Option<&Bytes> {
+    tlvs.iter().find(|(t, _)| *t == tp).map(|(_, v)| v)
+}
+
+fn parse_tlvs(mut src: Bytes) -> Result<Vec<(u8, Bytes)>, DecodeError> {
+    let mut tlvs = Vec::new();
+    while src.has_remaining() {
+        ensure!(src.len() >= 3, DecodeError::InvalidProxyHeader);
+        let tp = src.get_u8();
+        let len = src.get_u16() as usize;
+        ensure!(src.len() >= len, DecodeError::InvalidProxyHeader);
+        tlvs.push((tp, src.split_to(len)));
+    }
+    Ok(tlvs)
+}
+
+/// PROXY protocol header decoder
+#[derive(Debug)]
+pub(crate) struct ProxyCodec;
+
+impl Decoder for ProxyCodec {
+    type Item = ProxyHeader;
+    type Error = DecodeError;
+
+    fn decode(&self, src: &mut BytesMut) -> Result<Option<Self::Item>, DecodeError> {
+        if src.starts_with(V2_SIGNATURE) {
+            decode_v2(src)
+        } else if src.starts_with(V1_PREFIX) {
+            decode_v1(src)
+        } else if V2_SIGNATURE.starts_with(&src[..]) || V1_PREFIX.starts_with(&src[..]) {
+            Ok(None)
+        } else {
+            Err(DecodeError::InvalidProxyHeader)
+        }
+    }
+}
+
+fn decode_v1(src: &mut BytesMut) -> Result<Option<ProxyHeader>, DecodeError> {
+    let end = match src.windows(2).take(V1_MAX_SIZE - 1).position(|w| w == b"\r\n") {
+        Some(end) => end,
+        None if src.len() >= V1_MAX_SIZE => return Err(DecodeError::InvalidProxyHeader),
+        None => return Ok(None),
+    };
+    let line = src.split_to(end + 2);
+    let line = str::from_utf8(&line[V1_PREFIX.len()..end])
+        .map_err(|_| DecodeError::InvalidProxyHeader)?;
+
+    let mut parts = line.split(' ');
+    let (source, destination) = match parts.next() {
+        // remaining part of the line must be ignored
+        Some("UNKNOWN") => (None, None),
+        Some(proto @ "TCP4") | Some(proto @ "TCP6") => {
+            let mut next = || parts.next().ok_or(DecodeError::InvalidProxyHeader);
+            let src_ip = parse_ip(next()?, proto)?;
+            let dst_ip = parse_ip(next()?, proto)?;
+            let src_port = parse_port(next()?)?;
+            let dst_port = parse_port(next()?)?;
+            ensure!(parts.next().is_none(), DecodeError::InvalidProxyHeader);
+            (Some(SocketAddr::new(src_ip, src_port)), Some(SocketAddr::new(dst_ip, dst_port)))
+        }
+        _ => return Err(DecodeError::InvalidProxyHeader),
+    };
+
+    Ok(Some(ProxyHeader {
+        version: 1,
+        local: source.is_none(),
+        source,
+        destination,
+        tlvs: Vec::new(),
+    }))
+}
+
+fn parse_ip(s: &str, proto: &str) -> Result<IpAddr, DecodeError> {
+    let ip = if proto == "TCP4" {
+        s.parse::<Ipv4Addr>().map(IpAddr::V4)
+    } else {
+        s.parse::<Ipv6Addr>().map(IpAddr::V6)
+    };
+    ip.map_err(|_| DecodeError::InvalidProxyHeader)
+}
+
+fn parse_port(s: &str) -> Result<u16, DecodeError> {
+    // leading zeros are not allowed
+    ensure!(s == "0" || !s.starts_with('0'), DecodeError::InvalidProxyHeader);
+    s.parse().map_err(|_| DecodeError::InvalidProxyHeader)
+}
+
+fn decode_v2(src: &mut BytesMut) -> Result<Option<ProxyHeader>, DecodeError> {
+    if src.len() < V2_HEADER_SIZE {
+        return Ok(None);
+    }
+    let ver_cmd = src[12];
+    let family = src[13];
+    let len = u16::from_be_bytes(src[14..16].try_into().unwrap()) as usize;
+    ensure!(ver_cmd >> 4 == 2, DecodeError::InvalidProxyHeader);
+    if src.len() < V2_HEADER_SIZE + len {
+        return Ok(None);
+    }
+
+    let local = match ver_cmd & 0x0f {
+        0x00 => true,
+        0x01 => false,
+        _ => return Err(DecodeError::InvalidProxyHeader),
+    };
+    src.advance(V2_HEADER_SIZE);
+    let mut payload = src.split_to(len).freeze();
+
+    let addr_len = match family >> 4 {
+        0x00 => 0,
+        0x01 => 12,
+        0x02 => 36,
+        0x03 => 216,
+        _ => return Err(DecodeError::InvalidProxyHeader),
+    };
+    ensure!(payload.len() >= addr_len, DecodeError::InvalidProxyHeader);
+    let mut addrs = payload.split_to(addr_len);
+
+    // addresses of LOCAL command must be ignored, unix addresses are not supported
+    let (source, destination) = match family >> 4 {
+        0x01 if !local => {
+            let src_ip = Ipv4Addr::from(addrs.get_u32());
+            let dst_ip = Ipv4Addr::from(addrs.get_u32());
+            (
+                Some(SocketAddr::new(src_ip.into(), addrs.get_u16())),
+                Some(SocketAddr::new(dst_ip.into(), addrs.get_u16())),
+            )
+        }
+        0x02 if !local => {
+            let src_ip = Ipv6Addr::from(addrs.get_u128());
+            let dst_ip = Ipv6Addr::from(addrs.get_u128());
+            (
+                Some(SocketAddr::new(src_ip.into(), addrs.get_u16())),
+                Some(SocketAddr::new(dst_ip.into(), addrs.get_u16())),
+            )
+        }
+        _ => (None, None),
+    };
+
+    Ok(Some(ProxyHeader { version: 2, local, source, destination, tlvs: parse_tlvs(payload)? }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_v1() {
+        let mut buf =
+            BytesMut::from(&b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 1883\r\n\x10\x0c"[..]);
+        let hdr = ProxyCodec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(hdr.version(), 1);
+        assert!(!hdr.is_local());
+        assert_eq!(hdr.source(), Some("192.168.0.1:56324".parse().unwrap()));
+        assert_eq!(hdr.destination(), Some("192.168.0.11:1883".parse().unwrap()));
+        assert_eq!(&buf[..], b"\x10\x0c");
+
+        let mut buf = BytesMut::from(&b"PROXY TCP6 ::1 ::2 1000 1883\r\n"[..]);
+        let hdr = ProxyCodec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(hdr.source(), Some("[::1]:1000".parse().unwrap()));
+
+        let mut buf = BytesMut::from(&b"PROXY UNKNOWN ffff::1 ffff::2\r\n"[..]);
+        let hdr = ProxyCodec.decode(&mut buf).unwrap().unwrap();
+        assert!(hdr.is_local());
+        assert_eq!(hdr.source(), None);
+
+        let mut buf = BytesMut::from(&b"PROXY TCP4 192.168.0.1 192.1"[..]);
+        assert_eq!(ProxyCodec.decode(&mut buf), Ok(None));
+        let mut buf = BytesMut::from(&b"PRO"[..]);
+        assert_eq!(ProxyCodec.decode(&mut buf), Ok(None));
+
+        let mut buf = BytesMut::from(&b"PROXY TCP4 ::1 ::2 1000 1883\r\n"[..]);
+        assert_eq!(ProxyCodec.decode(&mut buf), Err(DecodeError::InvalidProxyHeader));
+        let mut buf = BytesMut::from(&b"PROXY TCP4 1.1.1.1 2.2.2.2 01 1883\r\n"[..]);
+        assert_eq!(ProxyCodec.decode(&mut buf), Err(DecodeError::InvalidProxyHeader));
+        let mut buf = BytesMut::from(&b"\x10\x0c\x00\x04MQTT"[..]);
+        assert_eq!(ProxyCodec.decode(&mut buf), Err(DecodeError::InvalidProxyHeader));
+        let mut buf = BytesMut::from(&b"PROXY "[..]);
+        buf.extend_from_slice(&[b'A'; 110]);
+        assert_eq!(ProxyCodec.decode(&mut buf), Err(DecodeError::InvalidProxyHeader));
+    }
+
+    #[test]
+    fn test_decode_v2() {
+        let mut buf = BytesMut::from(V2_SIGNATURE);
+        buf.extend_from_slice(b"\x21\x11\x00\x2c");
+        buf.extend_from_slice(b"\xc0\xa8\x00\x01\xc0\xa8\x00\x0b\xdc\x04\x07\x5b");
+        buf.extend_from_slice(b"\x02\x00\x0bexample.com");
+        buf.extend_from_slice(b"\x20\x00\x0f\x07\x00\x00\x00\x00\x21\x00\x07TLSv1.3");
+        buf.extend_from_slice(b"\x10\x0c");
+
+        let mut part = BytesMut::from(&buf[..20]);
+        assert_eq!(ProxyCodec.decode(&mut part), Ok(None));
+
+        let hdr = ProxyCodec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(hdr.version(), 2);
+        assert!(!hdr.is_local());
+        assert_eq!(hdr.source(), Some("192.168.0.1:56324".parse().unwrap()));
+        assert_eq!(hdr.destination(), Some("192.168.0.11:1883".parse().unwrap()));
+        assert_eq!(hdr.authority(), Some("example.com"));
+        let ssl = hdr.ssl().unwrap();
+        assert!(ssl.is_ssl());
+        assert!(ssl.has_client_cert());
+        assert!(ssl.is_verified());
+        assert_eq!(ssl.version(), Some("TLSv1.3"));
+        assert_eq!(ssl.common_name(), None);
+        assert_eq!(&buf[..], b"\x10\x0c");
+
+        // local command
+        let mut buf = BytesMut::from(V2_SIGNATURE);
+        buf.extend_from_slice(b"\x20\x00\x00\x00");
+        let hdr = ProxyCodec.decode(&mut buf).unwrap().unwrap();
+        assert!(hdr.is_local());
+        assert_eq!(hdr.source(), None);
+        assert!(buf.is_empty());
+
+        // unsupported version
+        let mut buf = BytesMut::from(V2_SIGNATURE);
+        buf.extend_from_slice(b"\x11\x00\x00\x00");
+        assert_eq!(ProxyCodec.decode(&mut buf), Err(DecodeError::InvalidProxyHeader));
+
+        // truncated tlv
+        let mut buf = BytesMut::from(V2_SIGNATURE);
+        buf.extend_from_slice(b"\x21\x00\x00\x02\x01\x00");
+        assert_eq!(ProxyCodec.decode(&mut buf), Err(DecodeError::InvalidProxyHeader));
+    }
+}
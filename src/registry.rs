@@ -0,0 +1,100 @@
+//! Connected clients registry
+use std::{cell::RefCell, collections::HashMap, fmt, rc::Rc};
+
+use ntex::util::ByteString;
+
+use crate::{v3, v5};
+
+enum Client {
+    V3(v3::MqttSink),
+    V5(v5::MqttSink),
+}
+
+impl Client {
+    fn takeover(&self) {
+        match self {
+            Client::V3(ref sink) => sink.drop_sink(),
+            Client::V5(ref sink) => sink.takeover(),
+        }
+    }
+}
+
+/// Connected clients registry
+///
+/// Registry tracks open connections by client id. If client connects while
+/// previous connection with the same client id is still open, previous
+/// connection get closed before new connection is acknowledged, v5 client
+/// receives DISCONNECT packet with `SessionTakenOver` reason code.
+///
+/// In-flight state of the previous connection is kept if session expiry
+/// is set, so `SessionManager::resume()` could transfer it to the new
+/// connection. Same registry could be shared by v3 and v5 servers.
+#[derive(Clone, Default)]
+pub struct ClientRegistry(Rc<RefCell<HashMap<ByteString, Client>>>);
+
+impl ClientRegistry {
+    /// Create empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check if client is connected
+    pub fn contains(&self, client_id: &str) -> bool {
+        self.0.borrow().contains_key(client_id)
+    }
+
+    /// Number of connected clients
+    pub fn len(&self) -> usize {
+        self.0.borrow().len()
+    }
+
+    /// Returns `true` if there are no connected clients
+    pub fn is_empty(&self) -> bool {
+        self.0.borrow().is_empty()
+    }
+
+    pub(crate) fn register_v3(&self, client_id: ByteString, sink: v3::MqttSink) {
+        self.register(client_id, Client::V3(sink))
+    }
+
+    pub(crate) fn register_v5(&self, client_id: ByteString, sink: v5::MqttSink) {
+        self.register(client_id, Client::V5(sink))
+    }
+
+    pub(crate) fn unregister_v3(&self, client_id: &str, sink: &v3::MqttSink) {
+        let mut clients = self.0.borrow_mut();
+        if let Some(Client::V3(ref s)) = clients.get(client_id) {
+            if s.ptr_eq(sink) {
+                clients.remove(client_id);
+            }
+        }
+    }
+
+    pub(crate) fn unregister_v5(&self, client_id: &str, sink: &v5::MqttSink) {
+        let mut clients = self.0.borrow_mut();
+        if let Some(Client::V5(ref s)) = clients.get(client_id) {
+            if s.ptr_eq(sink) {
+                clients.remove(client_id);
+            }
+        }
+    }
+
+    fn register(&self, client_id: ByteString, client: Client) {
+        // empty client id is not unique
+        if client_id.is_empty() {
+            return;
+        }
+
+        let prev = self.0.borrow_mut().insert(client_id.clone(), client);
+        if let Some(prev) = prev {
+            log::trace!("Session is taken over: {:?}", client_id);
+            prev.takeover();
+        }
+    }
+}
+
+impl fmt::Debug for ClientRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientRegistry").field("clients", &self.0.borrow().len()).finish()
+    }
+}
@@ -0,0 +1,220 @@
+//! Retained messages
+use std::{cell::RefCell, collections::HashMap, convert::TryFrom, fmt, future::Future, rc::Rc};
+
+use ntex::util::{ByteString, Bytes};
+
+use crate::error::SendPacketError;
+use crate::sessions::Message;
+use crate::subs::Subscription;
+use crate::topic::Topic;
+use crate::types::QoS;
+use crate::v5::codec::{PublishProperties, RetainHandling};
+use crate::{v3, v5};
+
+/// Storage for retained messages
+///
+/// Store keeps last retained message for each topic.
+pub trait RetainStore {
+    /// Store retained message, existing message for the topic is replaced
+    fn set(&self, msg: Message);
+
+    /// Remove retained message for the topic
+    fn remove(&self, topic: &str);
+
+    /// Retained messages matching topic filter
+    fn matches(&self, filter: &Topic) -> Vec<Message>;
+
+    /// Store retained message or clear topic if payload is empty
+    fn update(&self, msg: Message) {
+        if msg.payload.is_empty() {
+            self.remove(&msg.topic)
+        } else {
+            self.set(msg)
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+/// In-memory retained messages store
+///
+/// Clones share same storage.
+pub struct MemoryRetainStore(Rc<RefCell<HashMap<ByteString, Message>>>);
+
+impl MemoryRetainStore {
+    /// Create new in-memory store
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of retained messages
+    pub fn len(&self) -> usize {
+        self.0.borrow().len()
+    }
+
+    /// Returns `true` if store is empty
+    pub fn is_empty(&self) -> bool {
+        self.0.borrow().is_empty()
+    }
+}
+
+impl RetainStore for MemoryRetainStore {
+    fn set(&self, msg: Message) {
+        self.0.borrow_mut().insert(msg.topic.clone(), msg);
+    }
+
+    fn remove(&self, topic: &str) {
+        self.0.borrow_mut().remove(topic);
+    }
+
+    fn matches(&self, filter: &Topic) -> Vec<Message> {
+        self.0.borrow().values().filter(|msg| filter.matches_str(&msg.topic)).cloned().collect()
+    }
+}
+
+impl fmt::Debug for MemoryRetainStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemoryRetainStore").field("messages", &self.0.borrow().len()).finish()
+    }
+}
+
+pub(crate) fn min_qos(qos1: QoS, qos2: QoS) -> QoS {
+    if u8::from(qos1) < u8::from(qos2) {
+        qos1
+    } else {
+        qos2
+    }
+}
+
+/// Retained messages to deliver for confirmed subscription
+///
+/// `is_new` indicates that subscription did not exist before,
+/// see `Subscriptions::subscribe()`.
+fn messages<S: RetainStore + ?Sized>(
+    store: &S,
+    filter: &Topic,
+    sub: &Subscription,
+    is_new: bool,
+) -> Vec<Message> {
+    match sub.options.retain_handling {
+        RetainHandling::NoAtSubscribe => Vec::new(),
+        RetainHandling::AtSubscribeNew if !is_new => Vec::new(),
+        _ => store.matches(filter),
+    }
+}
+
+/// Deliver retained messages to v3 session
+///
+/// Must be called once subscription is confirmed, returned future completes
+/// when all messages are acknowledged. Messages are sent with retain flag set
+/// and qos downgraded to granted qos.
+pub fn deliver_v3<S: RetainStore + ?Sized>(
+    store: &S,
+    sink: &v3::MqttSink,
+    filter: &Topic,
+    qos: QoS,
+) -> impl Future<Output = Result<usize, SendPacketError>> {
+    let msgs = store.matches(filter);
+    let sink = sink.clone();
+
+    async move {
+        for msg in &msgs {
+            let builder = sink.publish(msg.topic.clone(), msg.payload.clone()).retain();
+            match min_qos(msg.qos, qos) {
+                QoS::AtMostOnce => builder.send_at_most_once()?,
+                QoS::AtLeastOnce => builder.send_at_least_once().await?,
+                QoS::ExactlyOnce => builder.send_exactly_once().await?,
+            }
+        }
+        Ok(msgs.len())
+    }
+}
+
+/// Deliver retained messages to v5 session
+///
+/// Must be called once subscription is confirmed, returned future completes
+/// when all messages are acknowledged. Messages are delivered according to
+/// subscription's `RetainHandling` option, sent with retain flag set and
+/// qos downgraded to granted qos. Negative acks from the client are ignored.
+pub fn deliver_v5<S: RetainStore + ?Sized>(
+    store: &S,
+    sink: &v5::MqttSink,
+    filter: &Topic,
+    sub: &Subscription,
+    is_new: bool,
+) -> impl Future<Output = Result<usize, SendPacketError>> {
+    let msgs = messages(store, filter, sub, is_new);
+    let sink = sink.clone();
+    let qos = sub.qos();
+    let id = sub.id;
+
+    async move {
+        for msg in &msgs {
+            let mut properties = msg.properties.clone();
+            properties.subscription_ids = id.map(|id| vec![id]);
+            let builder = sink
+                .publish(msg.topic.clone(), msg.payload.clone())
+                .retain()
+                .properties(|props| *props = properties);
+
+            match min_qos(msg.qos, qos) {
+                QoS::AtMostOnce => builder.send_at_most_once()?,
+                QoS::AtLeastOnce => {
+                    if let Err(err) = builder.send_at_least_once().await {
+                        match SendPacketError::try_from(err) {
+                            Ok(err) => return Err(err),
+                            Err(err) => log::trace!("Retained message is rejected: {:?}", err),
+                        }
+                    }
+                }
+                QoS::ExactlyOnce => {
+                    if let Err(err) = builder.send_exactly_once().await {
+                        match SendPacketError::try_from(err) {
+                            Ok(err) => return Err(err),
+                            Err(err) => log::trace!("Retained message is rejected: {:?}", err),
+                        }
+                    }
+                }
+            }
+        }
+        Ok(msgs.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_store() {
+        let store = MemoryRetainStore::new();
+        store.update(Message::new("a/b".into(), QoS::AtLeastOnce, Bytes::from_static(b"1")));
+        store.update(Message::new("a/c".into(), QoS::AtMostOnce, Bytes::from_static(b"2")));
+        store.update(Message::new("$SYS/a".into(), QoS::AtMostOnce, Bytes::from_static(b"3")));
+        assert_eq!(store.len(), 3);
+
+        assert_eq!(store.matches(&topic!("a/b")).len(), 1);
+        assert_eq!(store.matches(&topic!("a/+")).len(), 2);
+        assert_eq!(store.matches(&topic!("#")).len(), 2);
+        assert_eq!(store.matches(&topic!("$SYS/#")).len(), 1);
+
+        store.update(Message::new("a/b".into(), QoS::AtMostOnce, Bytes::new()));
+        assert_eq!(store.matches(&topic!("a/+")).len(), 1);
+        assert_eq!(store.clone().len(), 2);
+    }
+
+    #[test]
+    fn test_retain_handling() {
+        let store = MemoryRetainStore::new();
+        store.set(Message::new("a".into(), QoS::AtMostOnce, Bytes::from_static(b"1")));
+
+        let mut sub = Subscription::new(QoS::AtLeastOnce);
+        assert_eq!(messages(&store, &topic!("a"), &sub, false).len(), 1);
+
+        sub.options.retain_handling = RetainHandling::AtSubscribeNew;
+        assert_eq!(messages(&store, &topic!("a"), &sub, true).len(), 1);
+        assert!(messages(&store, &topic!("a"), &sub, false).is_empty());
+
+        sub.options.retain_handling = RetainHandling::NoAtSubscribe;
+        assert!(messages(&store, &topic!("a"), &sub, true).is_empty());
+    }
+}
@@ -8,6 +8,7 @@ use ntex::util::{join, Ready};
 
 use crate::error::{MqttError, ProtocolError};
 use crate::io::State;
+use crate::proxy::{ProxyCodec, ProxyHeader};
 use crate::version::{ProtocolVersion, VersionCodec};
 use crate::{v3, v5};
 
@@ -16,6 +17,7 @@ pub struct MqttServer<Io, V3, V5, Err, InitErr> {
     v3: V3,
     v5: V5,
     handshake_timeout: Seconds,
+    proxy: bool,
     _t: marker::PhantomData<(Io, Err, InitErr)>,
 }
 
@@ -34,6 +36,7 @@ impl<Io, Err, InitErr>
             v3: DefaultProtocolServer::new(ProtocolVersion::MQTT3),
             v5: DefaultProtocolServer::new(ProtocolVersion::MQTT5),
             handshake_timeout: Seconds::ZERO,
+            proxy: false,
             _t: marker::PhantomData,
         }
     }
@@ -62,6 +65,18 @@ impl<Io, V3, V5, Err, InitErr> MqttServer<Io, V3, V5, Err, InitErr> {
         self.handshake_timeout = timeout;
         self
     }
+
+    /// Enable PROXY protocol
+    ///
+    /// Server expects PROXY protocol v1 or v2 header before mqtt
+    /// `connect` packet, connections without header are rejected.
+    /// Parsed header is available from `v3::Handshake::proxy_header()`
+    /// and `v5::Handshake::proxy_header()`. By default PROXY protocol
+    /// is disabled.
+    pub fn proxy_protocol(mut self) -> Self {
+        self.proxy = true;
+        self
+    }
 }
 
 impl<Io, V3, V5, Err, InitErr> MqttServer<Io, V3, V5, Err, InitErr>
@@ -69,14 +84,14 @@ where
     Io: AsyncRead + AsyncWrite + Unpin + 'static,
     V3: ServiceFactory<
         Config = (),
-        Request = (Io, State, Option<Sleep>),
+        Request = (Io, State, Option<Sleep>, Option<ProxyHeader>),
         Response = (),
         Error = MqttError<Err>,
         InitError = InitErr,
     >,
     V5: ServiceFactory<
         Config = (),
-        Request = (Io, State, Option<Sleep>),
+        Request = (Io, State, Option<Sleep>, Option<ProxyHeader>),
         Response = (),
         Error = MqttError<Err>,
         InitError = InitErr,
@@ -90,7 +105,7 @@ where
         Io,
         impl ServiceFactory<
             Config = (),
-            Request = (Io, State, Option<Sleep>),
+            Request = (Io, State, Option<Sleep>, Option<ProxyHeader>),
             Response = (),
             Error = MqttError<Err>,
             InitError = InitErr,
@@ -125,6 +140,7 @@ where
             v3: service.inner_finish(),
             v5: self.v5,
             handshake_timeout: self.handshake_timeout,
+            proxy: self.proxy,
             _t: marker::PhantomData,
         }
     }
@@ -137,7 +153,7 @@ where
         Io,
         impl ServiceFactory<
             Config = (),
-            Request = (Io, State, Option<Sleep>),
+            Request = (Io, State, Option<Sleep>, Option<ProxyHeader>),
             Response = (),
             Error = MqttError<Err>,
             InitError = InitErr,
@@ -154,6 +170,7 @@ where
             v3: service.finish_server(),
             v5: self.v5,
             handshake_timeout: self.handshake_timeout,
+            proxy: self.proxy,
             _t: marker::PhantomData,
         }
     }
@@ -167,7 +184,7 @@ where
         V3,
         impl ServiceFactory<
             Config = (),
-            Request = (Io, State, Option<Sleep>),
+            Request = (Io, State, Option<Sleep>, Option<ProxyHeader>),
             Response = (),
             Error = MqttError<Err>,
             InitError = InitErr,
@@ -206,6 +223,7 @@ where
             v3: self.v3,
             v5: service.inner_finish(),
             handshake_timeout: self.handshake_timeout,
+            proxy: self.proxy,
             _t: marker::PhantomData,
         }
     }
@@ -219,7 +237,7 @@ where
         V3,
         impl ServiceFactory<
             Config = (),
-            Request = (Io, State, Option<Sleep>),
+            Request = (Io, State, Option<Sleep>, Option<ProxyHeader>),
             Response = (),
             Error = MqttError<Err>,
             InitError = InitErr,
@@ -235,6 +253,7 @@ where
             v3: self.v3,
             v5: service.finish_server(),
             handshake_timeout: self.handshake_timeout,
+            proxy: self.proxy,
             _t: marker::PhantomData,
         }
     }
@@ -245,14 +264,14 @@ where
     Io: AsyncRead + AsyncWrite + Unpin + 'static,
     V3: ServiceFactory<
         Config = (),
-        Request = (Io, State, Option<Sleep>),
+        Request = (Io, State, Option<Sleep>, Option<ProxyHeader>),
         Response = (),
         Error = MqttError<Err>,
         InitError = InitErr,
     >,
     V5: ServiceFactory<
         Config = (),
-        Request = (Io, State, Option<Sleep>),
+        Request = (Io, State, Option<Sleep>, Option<ProxyHeader>),
         Response = (),
         Error = MqttError<Err>,
         InitError = InitErr,
@@ -276,6 +295,7 @@ where
 
     fn new_service(&self, _: ()) -> Self::Future {
         let handshake_timeout = self.handshake_timeout;
+        let proxy = self.proxy;
         let fut = join(self.v3.new_service(()), self.v5.new_service(()));
         Box::pin(async move {
             let (v3, v5) = fut.await;
@@ -284,6 +304,7 @@ where
             Ok(MqttServerImpl {
                 handlers: Rc::new((v3, v5)),
                 handshake_timeout,
+                proxy,
                 _t: marker::PhantomData,
             })
         })
@@ -294,14 +315,23 @@ where
 pub struct MqttServerImpl<Io, V3, V5, Err> {
     handlers: Rc<(V3, V5)>,
     handshake_timeout: Seconds,
+    proxy: bool,
     _t: marker::PhantomData<(Io, Err)>,
 }
 
 impl<Io, V3, V5, Err> Service for MqttServerImpl<Io, V3, V5, Err>
 where
     Io: AsyncRead + AsyncWrite + Unpin + 'static,
-    V3: Service<Request = (Io, State, Option<Sleep>), Response = (), Error = MqttError<Err>>,
-    V5: Service<Request = (Io, State, Option<Sleep>), Response = (), Error = MqttError<Err>>,
+    V3: Service<
+        Request = (Io, State, Option<Sleep>, Option<ProxyHeader>),
+        Response = (),
+        Error = MqttError<Err>,
+    >,
+    V5: Service<
+        Request = (Io, State, Option<Sleep>, Option<ProxyHeader>),
+        Response = (),
+        Error = MqttError<Err>,
+    >,
 {
     type Request = Io;
     type Response = ();
@@ -335,7 +365,15 @@ where
 
         MqttServerImplResponse {
             state: MqttServerImplState::Version {
-                item: Some((req, State::new(), VersionCodec, self.handlers.clone(), delay)),
+                item: Some((
+                    req,
+                    State::new(),
+                    VersionCodec,
+                    self.handlers.clone(),
+                    delay,
+                    self.proxy,
+                    None,
+                )),
             },
         }
     }
@@ -345,12 +383,12 @@ pin_project_lite::pin_project! {
     pub struct MqttServerImplResponse<Io, V3, V5, Err>
     where
         V3: Service<
-            Request = (Io, State, Option<Sleep>),
+            Request = (Io, State, Option<Sleep>, Option<ProxyHeader>),
             Response = (),
             Error = MqttError<Err>,
         >,
         V5: Service<
-            Request = (Io, State, Option<Sleep>),
+            Request = (Io, State, Option<Sleep>, Option<ProxyHeader>),
             Response = (),
             Error = MqttError<Err>,
         >,
@@ -365,15 +403,33 @@ pin_project_lite::pin_project! {
     pub(crate) enum MqttServerImplState<Io, V3: Service, V5: Service> {
         V3 { #[pin] fut: V3::Future },
         V5 { #[pin] fut: V5::Future },
-        Version { item: Option<(Io, State, VersionCodec, Rc<(V3, V5)>, Option<Sleep>)> },
+        Version {
+            item: Option<(
+                Io,
+                State,
+                VersionCodec,
+                Rc<(V3, V5)>,
+                Option<Sleep>,
+                bool,
+                Option<ProxyHeader>,
+            )>,
+        },
     }
 }
 
 impl<Io, V3, V5, Err> Future for MqttServerImplResponse<Io, V3, V5, Err>
 where
     Io: AsyncRead + AsyncWrite + Unpin + 'static,
-    V3: Service<Request = (Io, State, Option<Sleep>), Response = (), Error = MqttError<Err>>,
-    V5: Service<Request = (Io, State, Option<Sleep>), Response = (), Error = MqttError<Err>>,
+    V3: Service<
+        Request = (Io, State, Option<Sleep>, Option<ProxyHeader>),
+        Response = (),
+        Error = MqttError<Err>,
+    >,
+    V5: Service<
+        Request = (Io, State, Option<Sleep>, Option<ProxyHeader>),
+        Response = (),
+        Error = MqttError<Err>,
+    >,
 {
     type Output = Result<(), MqttError<Err>>;
 
@@ -396,19 +452,39 @@ where
 
                     let st = item.as_mut().unwrap();
 
+                    // read PROXY protocol header first
+                    if st.5 && st.6.is_none() {
+                        match st.1.poll_next(&mut st.0, &ProxyCodec, cx) {
+                            Poll::Ready(Ok(Some(header))) => {
+                                log::trace!("PROXY protocol header is received: {:?}", header);
+                                st.6 = Some(header);
+                                this = self.as_mut().project();
+                                continue;
+                            }
+                            Poll::Ready(Ok(None)) => {
+                                return Poll::Ready(Err(MqttError::Disconnected))
+                            }
+                            Poll::Ready(Err(err)) => {
+                                return Poll::Ready(Err(MqttError::from(err)))
+                            }
+                            Poll::Pending => return Poll::Pending,
+                        }
+                    }
+
                     match st.1.poll_next(&mut st.0, &st.2, cx) {
                         Poll::Ready(Ok(Some(ver))) => {
-                            let (io, state, _, handlers, delay) = item.take().unwrap();
+                            let (io, state, _, handlers, delay, _, proxy) =
+                                item.take().unwrap();
                             this = self.as_mut().project();
                             match ver {
                                 ProtocolVersion::MQTT3 => {
                                     this.state.set(MqttServerImplState::V3 {
-                                        fut: handlers.0.call((io, state, delay)),
+                                        fut: handlers.0.call((io, state, delay, proxy)),
                                     })
                                 }
                                 ProtocolVersion::MQTT5 => {
                                     this.state.set(MqttServerImplState::V5 {
-                                        fut: handlers.1.call((io, state, delay)),
+                                        fut: handlers.1.call((io, state, delay, proxy)),
                                     })
                                 }
                             }
@@ -439,7 +515,7 @@ impl<Io, Err, InitErr> DefaultProtocolServer<Io, Err, InitErr> {
 
 impl<Io, Err, InitErr> ServiceFactory for DefaultProtocolServer<Io, Err, InitErr> {
     type Config = ();
-    type Request = (Io, State, Option<Sleep>);
+    type Request = (Io, State, Option<Sleep>, Option<ProxyHeader>);
     type Response = ();
     type Error = MqttError<Err>;
     type Service = DefaultProtocolServer<Io, Err, InitErr>;
@@ -452,7 +528,7 @@ impl<Io, Err, InitErr> ServiceFactory for DefaultProtocolServer<Io, Err, InitErr
 }
 
 impl<Io, Err, InitErr> Service for DefaultProtocolServer<Io, Err, InitErr> {
-    type Request = (Io, State, Option<Sleep>);
+    type Request = (Io, State, Option<Sleep>, Option<ProxyHeader>);
     type Response = ();
     type Error = MqttError<Err>;
     type Future = Ready<Self::Response, Self::Error>;
@@ -7,6 +7,7 @@ use ntex::time::{Millis, Seconds, Sleep};
 use ntex::util::{select, Either};
 
 use super::io::{DispatchItem, Dispatcher, State, Timer};
+use super::proxy::ProxyHeader;
 
 type ResponseItem<U> = Option<<U as Encoder>::Item>;
 
@@ -169,7 +170,7 @@ where
     Io: AsyncRead + AsyncWrite + Unpin + 'static,
     C: ServiceFactory<
         Config = (),
-        Request = (Io, State),
+        Request = (Io, State, Option<ProxyHeader>),
         Response = (Io, State, Codec, St, Seconds),
     >,
     C::Error: fmt::Debug,
@@ -188,7 +189,7 @@ where
     <Codec as Encoder>::Item: 'static,
 {
     type Config = ();
-    type Request = (Io, State, Option<Sleep>);
+    type Request = (Io, State, Option<Sleep>, Option<ProxyHeader>);
     type Response = ();
     type Error = C::Error;
     type InitError = C::InitError;
@@ -225,7 +226,10 @@ pub(crate) struct FramedServiceImpl2<St, C, T, Io, Codec> {
 impl<St, C, T, Io, Codec> Service for FramedServiceImpl2<St, C, T, Io, Codec>
 where
     Io: AsyncRead + AsyncWrite + Unpin + 'static,
-    C: Service<Request = (Io, State), Response = (Io, State, Codec, St, Seconds)>,
+    C: Service<
+        Request = (Io, State, Option<ProxyHeader>),
+        Response = (Io, State, Codec, St, Seconds),
+    >,
     C::Error: fmt::Debug,
     C::Future: 'static,
     T: ServiceFactory<
@@ -240,7 +244,7 @@ where
     Codec: Decoder + Encoder + Clone + 'static,
     <Codec as Encoder>::Item: 'static,
 {
-    type Request = (Io, State, Option<Sleep>);
+    type Request = (Io, State, Option<Sleep>, Option<ProxyHeader>);
     type Response = ();
     type Error = C::Error;
     type Future = Pin<Box<dyn Future<Output = Result<(), Self::Error>>>>;
@@ -256,12 +260,15 @@ where
     }
 
     #[inline]
-    fn call(&self, (req, state, delay): (Io, State, Option<Sleep>)) -> Self::Future {
+    fn call(
+        &self,
+        (req, state, delay, proxy): (Io, State, Option<Sleep>, Option<ProxyHeader>),
+    ) -> Self::Future {
         log::trace!("Start connection handshake");
 
         let handler = self.handler.clone();
         let timeout = self.disconnect_timeout;
-        let handshake = self.connect.call((req, state));
+        let handshake = self.connect.call((req, state, proxy));
         let time = self.time.clone();
 
         Box::pin(async move {
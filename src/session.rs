@@ -1,6 +1,8 @@
 use std::ops::Deref;
 use std::rc::Rc;
 
+use crate::info::ConnectionInfo;
+
 /// Mqtt connection session
 pub struct Session<T, St>(Rc<SessionInner<T, St>>);
 
@@ -9,6 +11,7 @@ struct SessionInner<T, St> {
     sink: T,
     max_receive: u16,
     max_topic_alias: u16,
+    info: Option<Rc<ConnectionInfo>>,
 }
 
 impl<T, St> Clone for Session<T, St> {
@@ -19,12 +22,18 @@ impl<T, St> Clone for Session<T, St> {
 }
 
 impl<T, St> Session<T, St> {
-    pub(crate) fn new(st: St, sink: T) -> Self {
-        Session(Rc::new(SessionInner { st, sink, max_receive: 0, max_topic_alias: 0 }))
+    pub(crate) fn new(st: St, sink: T, info: Option<Rc<ConnectionInfo>>) -> Self {
+        Session(Rc::new(SessionInner { st, sink, info, max_receive: 0, max_topic_alias: 0 }))
     }
 
-    pub(crate) fn new_v5(st: St, sink: T, max_receive: u16, max_topic_alias: u16) -> Self {
-        Session(Rc::new(SessionInner { st, sink, max_receive, max_topic_alias }))
+    pub(crate) fn new_v5(
+        st: St,
+        sink: T,
+        max_receive: u16,
+        max_topic_alias: u16,
+        info: Option<Rc<ConnectionInfo>>,
+    ) -> Self {
+        Session(Rc::new(SessionInner { st, sink, info, max_receive, max_topic_alias }))
     }
 
     #[inline]
@@ -37,6 +46,12 @@ impl<T, St> Session<T, St> {
         &self.0.st
     }
 
+    #[inline]
+    /// Peer connection info
+    pub fn connection_info(&self) -> Option<&ConnectionInfo> {
+        self.0.info.as_deref()
+    }
+
     pub(crate) fn params(&self) -> (u16, u16) {
         (self.0.max_receive, self.0.max_topic_alias)
     }
@@ -0,0 +1,559 @@
+//! Persistent sessions
+use std::cell::{Cell, RefCell};
+use std::{collections::HashMap, collections::VecDeque, convert::TryFrom, fmt};
+use std::{future::Future, pin::Pin};
+use std::{rc::Rc, time::Duration, time::Instant};
+
+use ntex::time::{sleep, Millis};
+use ntex::util::{ByteString, Bytes};
+
+use crate::error::SendPacketError;
+use crate::subs::Subscription;
+use crate::topic::Topic;
+use crate::types::QoS;
+use crate::v5::codec::PublishProperties;
+use crate::{v3, v5};
+
+/// Session never expires
+pub const NEVER_EXPIRE: u32 = u32::MAX;
+
+/// Outbound message
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    /// Publish topic
+    pub topic: ByteString,
+    /// Publish qos
+    pub qos: QoS,
+    /// Retain flag
+    pub retain: bool,
+    /// Message payload
+    pub payload: Bytes,
+    /// v5 publish properties
+    pub properties: PublishProperties,
+}
+
+impl Message {
+    /// Create new message
+    pub fn new(topic: ByteString, qos: QoS, payload: Bytes) -> Self {
+        Message { topic, qos, payload, retain: false, properties: PublishProperties::default() }
+    }
+}
+
+impl<'a> From<&'a v3::codec::Publish> for Message {
+    fn from(pkt: &'a v3::codec::Publish) -> Self {
+        Message {
+            topic: pkt.topic.clone(),
+            qos: pkt.qos,
+            retain: pkt.retain,
+            payload: pkt.payload.clone(),
+            properties: PublishProperties::default(),
+        }
+    }
+}
+
+impl<'a> From<&'a v5::codec::Publish> for Message {
+    fn from(pkt: &'a v5::codec::Publish) -> Self {
+        let mut properties = pkt.properties.clone();
+        properties.topic_alias = None;
+        properties.subscription_ids = None;
+
+        Message {
+            properties,
+            topic: pkt.topic.clone(),
+            qos: pkt.qos,
+            retain: pkt.retain,
+            payload: pkt.payload.clone(),
+        }
+    }
+}
+
+impl<'a> From<&'a v3::Publish> for Message {
+    fn from(publish: &'a v3::Publish) -> Self {
+        Message::from(publish.packet())
+    }
+}
+
+impl<'a> From<&'a v5::Publish> for Message {
+    fn from(publish: &'a v5::Publish) -> Self {
+        Message::from(publish.packet())
+    }
+}
+
+/// Connection sink that could be used with session manager
+pub trait SessionSink: Clone + 'static {
+    /// Keep in-flight state after connection get closed
+    fn keep_inflight(&self);
+
+    /// Move in-flight state to new connection
+    fn transfer_inflight(&self, sink: &Self);
+
+    /// Check if both sinks belong to the same connection
+    fn is_same(&self, other: &Self) -> bool;
+
+    /// Send message to the peer
+    ///
+    /// Returned future completes when message is acknowledged.
+    fn send(&self, msg: Message) -> Pin<Box<dyn Future<Output = Result<(), SendPacketError>>>>;
+}
+
+impl SessionSink for v3::MqttSink {
+    fn keep_inflight(&self) {
+        v3::MqttSink::keep_inflight(self)
+    }
+
+    fn transfer_inflight(&self, sink: &Self) {
+        v3::MqttSink::transfer_inflight(self, sink)
+    }
+
+    fn is_same(&self, other: &Self) -> bool {
+        self.ptr_eq(other)
+    }
+
+    fn send(&self, msg: Message) -> Pin<Box<dyn Future<Output = Result<(), SendPacketError>>>> {
+        let mut builder = self.publish(msg.topic, msg.payload);
+        if msg.retain {
+            builder = builder.retain();
+        }
+        match msg.qos {
+            QoS::AtMostOnce => Box::pin(std::future::ready(builder.send_at_most_once())),
+            QoS::AtLeastOnce => Box::pin(builder.send_at_least_once()),
+            QoS::ExactlyOnce => Box::pin(builder.send_exactly_once()),
+        }
+    }
+}
+
+impl SessionSink for v5::MqttSink {
+    fn keep_inflight(&self) {
+        v5::MqttSink::keep_inflight(self)
+    }
+
+    fn transfer_inflight(&self, sink: &Self) {
+        v5::MqttSink::transfer_inflight(self, sink)
+    }
+
+    fn is_same(&self, other: &Self) -> bool {
+        self.ptr_eq(other)
+    }
+
+    fn send(&self, msg: Message) -> Pin<Box<dyn Future<Output = Result<(), SendPacketError>>>> {
+        let properties = msg.properties;
+        let mut builder =
+            self.publish(msg.topic, msg.payload).properties(|props| *props = properties);
+        if msg.retain {
+            builder = builder.retain();
+        }
+        match msg.qos {
+            QoS::AtMostOnce => Box::pin(std::future::ready(builder.send_at_most_once())),
+            QoS::AtLeastOnce => {
+                let fut = builder.send_at_least_once();
+                Box::pin(async move {
+                    if let Err(err) = fut.await {
+                        match SendPacketError::try_from(err) {
+                            Ok(err) => return Err(err),
+                            Err(err) => log::trace!("Publish is rejected by peer: {:?}", err),
+                        }
+                    }
+                    Ok(())
+                })
+            }
+            QoS::ExactlyOnce => {
+                let fut = builder.send_exactly_once();
+                Box::pin(async move {
+                    if let Err(err) = fut.await {
+                        match SendPacketError::try_from(err) {
+                            Ok(err) => return Err(err),
+                            Err(err) => log::trace!("Publish is rejected by peer: {:?}", err),
+                        }
+                    }
+                    Ok(())
+                })
+            }
+        }
+    }
+}
+
+struct Entry<S> {
+    sink: S,
+    connected: bool,
+    expiry: u32,
+    deadline: Option<Instant>,
+    subscriptions: Vec<(Topic, Subscription)>,
+    pending: VecDeque<Message>,
+    resume: Option<(S, VecDeque<Message>)>,
+}
+
+impl<S> Entry<S> {
+    fn is_expired(&self, now: Instant) -> bool {
+        !self.connected && self.deadline.map(|d| d <= now).unwrap_or(false)
+    }
+}
+
+struct Inner<S> {
+    sessions: RefCell<HashMap<ByteString, Entry<S>>>,
+    max_pending: Cell<usize>,
+    on_remove: RefCell<Option<Box<dyn Fn(&ByteString, &[(Topic, Subscription)])>>>,
+    timer: Cell<bool>,
+}
+
+impl<S> Inner<S> {
+    fn removed(&self, client_id: &ByteString, entry: Entry<S>) {
+        log::trace!("Session is removed: {:?}", client_id);
+        if let Some(ref f) = *self.on_remove.borrow() {
+            (*f)(client_id, &entry.subscriptions);
+        }
+    }
+
+    /// Remove expired sessions, returns `true` if there are sessions to expire
+    fn expire(&self, now: Instant) -> bool {
+        let (expired, pending) = {
+            let mut sessions = self.sessions.borrow_mut();
+            let ids: Vec<_> = sessions
+                .iter()
+                .filter(|(_, entry)| entry.is_expired(now))
+                .map(|(id, _)| id.clone())
+                .collect();
+            let expired: Vec<_> = ids
+                .into_iter()
+                .filter_map(|id| sessions.remove(&id).map(|e| (id, e)))
+                .collect();
+            let pending =
+                sessions.values().any(|entry| !entry.connected && entry.deadline.is_some());
+            (expired, pending)
+        };
+        expired.into_iter().for_each(|(id, entry)| self.removed(&id, entry));
+        pending
+    }
+}
+
+/// Session manager
+///
+/// Manager keeps state of sessions for clients that connect without clean
+/// start: subscriptions, in-flight packets and messages published while
+/// client is offline. Session is removed once its expiry interval elapses
+/// after disconnect.
+pub struct SessionManager<S> {
+    inner: Rc<Inner<S>>,
+}
+
+impl<S> Clone for SessionManager<S> {
+    fn clone(&self) -> Self {
+        SessionManager { inner: self.inner.clone() }
+    }
+}
+
+impl<S> Default for SessionManager<S> {
+    fn default() -> Self {
+        SessionManager {
+            inner: Rc::new(Inner {
+                sessions: RefCell::new(HashMap::new()),
+                max_pending: Cell::new(1024),
+                on_remove: RefCell::new(None),
+                timer: Cell::new(false),
+            }),
+        }
+    }
+}
+
+impl<S> fmt::Debug for SessionManager<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionManager")
+            .field("sessions", &self.inner.sessions.borrow().len())
+            .field("max_pending", &self.inner.max_pending.get())
+            .finish()
+    }
+}
+
+impl<S: SessionSink> SessionManager<S> {
+    /// Create new session manager
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set max number of messages queued for offline session
+    ///
+    /// By default max pending is set to 1024 messages.
+    pub fn max_pending(self, max: usize) -> Self {
+        self.inner.max_pending.set(max);
+        self
+    }
+
+    /// Set callback for removed sessions
+    ///
+    /// Callback is called with client id and session's subscriptions when
+    /// session expires or gets discarded by clean start.
+    pub fn on_remove<F>(self, f: F) -> Self
+    where
+        F: Fn(&ByteString, &[(Topic, Subscription)]) + 'static,
+    {
+        *self.inner.on_remove.borrow_mut() = Some(Box::new(f));
+        self
+    }
+
+    /// Register client connection, returns `session_present` flag
+    ///
+    /// `expiry` is session expiry interval in seconds, 0 means session ends
+    /// when connection get closed, `NEVER_EXPIRE` means session does not expire.
+    /// If session is resumed, in-flight packets and pending messages are kept
+    /// until `resume()` is called for acknowledged connection.
+    pub fn connect(
+        &self,
+        client_id: ByteString,
+        clean_start: bool,
+        expiry: u32,
+        sink: S,
+    ) -> bool {
+        let mut entry = Entry {
+            expiry,
+            sink: sink.clone(),
+            connected: true,
+            deadline: None,
+            subscriptions: Vec::new(),
+            pending: VecDeque::new(),
+            resume: None,
+        };
+        if expiry != 0 {
+            sink.keep_inflight();
+        }
+
+        let prev = self.inner.sessions.borrow_mut().remove(&client_id);
+        let present = match prev {
+            Some(prev) if clean_start || prev.is_expired(Instant::now()) => {
+                self.inner.removed(&client_id, prev);
+                false
+            }
+            Some(prev) => {
+                let mut pending = prev.pending;
+                // previous connection has not been resumed yet
+                if let Some((sink, queued)) = prev.resume {
+                    sink.transfer_inflight(&prev.sink);
+                    pending = queued.into_iter().chain(pending).collect();
+                }
+                entry.subscriptions = prev.subscriptions;
+                entry.resume = Some((prev.sink, pending));
+                true
+            }
+            None => false,
+        };
+        self.inner.sessions.borrow_mut().insert(client_id, entry);
+        present
+    }
+
+    /// Re-send in-flight packets and pending messages of resumed session
+    ///
+    /// Connect ack must be sent before resume, call it from publish or
+    /// control service factory. Does nothing if session is not resumed.
+    pub fn resume(&self, client_id: &ByteString) {
+        let resumed = self
+            .inner
+            .sessions
+            .borrow_mut()
+            .get_mut(client_id)
+            .and_then(|entry| entry.resume.take().map(|item| (entry.sink.clone(), item)));
+
+        if let Some((sink, (prev, pending))) = resumed {
+            log::trace!("Session is resumed: {:?}", client_id);
+            prev.transfer_inflight(&sink);
+            let futs: Vec<_> = pending.into_iter().map(|msg| sink.send(msg)).collect();
+            ntex::rt::spawn(async move {
+                for fut in futs {
+                    if let Err(err) = fut.await {
+                        log::trace!("Cannot send pending message: {:?}", err);
+                        break;
+                    }
+                }
+            });
+        }
+    }
+
+    /// Client connection is closed
+    ///
+    /// Session without expiry interval is removed, otherwise session expiry
+    /// timer starts.
+    pub fn disconnect(&self, client_id: &ByteString, sink: &S) {
+        let mut sessions = self.inner.sessions.borrow_mut();
+        let expiry = match sessions.get_mut(client_id) {
+            Some(entry) if entry.sink.is_same(sink) => {
+                entry.connected = false;
+                entry.expiry
+            }
+            _ => return,
+        };
+
+        if expiry == 0 {
+            let entry = sessions.remove(client_id).unwrap();
+            drop(sessions);
+            self.inner.removed(client_id, entry);
+        } else if expiry != NEVER_EXPIRE {
+            let entry = sessions.get_mut(client_id).unwrap();
+            entry.deadline = Some(Instant::now() + Duration::from_secs(expiry as u64));
+            drop(sessions);
+            self.start_timer();
+        }
+    }
+
+    /// Update session expiry interval
+    ///
+    /// v5 client could change expiry interval with DISCONNECT packet.
+    pub fn set_expiry(&self, client_id: &ByteString, expiry: u32) {
+        if let Some(entry) = self.inner.sessions.borrow_mut().get_mut(client_id) {
+            entry.expiry = expiry;
+        }
+    }
+
+    /// Remove session
+    pub fn remove(&self, client_id: &ByteString) {
+        let entry = self.inner.sessions.borrow_mut().remove(client_id);
+        if let Some(entry) = entry {
+            self.inner.removed(client_id, entry);
+        }
+    }
+
+    /// Record session's subscription
+    pub fn subscribe(&self, client_id: &ByteString, filter: Topic, sub: Subscription) {
+        if let Some(entry) = self.inner.sessions.borrow_mut().get_mut(client_id) {
+            if let Some(item) =
+                entry.subscriptions.iter_mut().find(|(f, _)| f.levels() == filter.levels())
+            {
+                item.1 = sub;
+            } else {
+                entry.subscriptions.push((filter, sub));
+            }
+        }
+    }
+
+    /// Remove session's subscription
+    pub fn unsubscribe(&self, client_id: &ByteString, filter: &Topic) {
+        if let Some(entry) = self.inner.sessions.borrow_mut().get_mut(client_id) {
+            entry.subscriptions.retain(|(f, _)| f.levels() != filter.levels());
+        }
+    }
+
+    /// Session's subscriptions
+    pub fn subscriptions(&self, client_id: &ByteString) -> Vec<(Topic, Subscription)> {
+        self.inner
+            .sessions
+            .borrow()
+            .get(client_id)
+            .map(|entry| entry.subscriptions.clone())
+            .unwrap_or_default()
+    }
+
+    /// Publish message to a session
+    ///
+    /// Message is sent if client is connected, qos1 and qos2 messages are queued
+    /// for offline session. Returns `false` if session does not exist or pending
+    /// queue is full.
+    pub fn publish(&self, client_id: &ByteString, msg: Message) -> bool {
+        let mut sessions = self.inner.sessions.borrow_mut();
+        match sessions.get_mut(client_id) {
+            Some(Entry { connected: true, resume: Some((_, ref mut pending)), .. }) => {
+                // keep order of messages until session is resumed
+                pending.push_back(msg);
+                true
+            }
+            Some(entry) if entry.connected => {
+                let fut = entry.sink.send(msg);
+                ntex::rt::spawn(async move {
+                    if let Err(err) = fut.await {
+                        log::trace!("Cannot send message: {:?}", err);
+                    }
+                });
+                true
+            }
+            Some(entry) => {
+                if msg.qos == QoS::AtMostOnce
+                    || entry.pending.len() >= self.inner.max_pending.get()
+                {
+                    false
+                } else {
+                    entry.pending.push_back(msg);
+                    true
+                }
+            }
+            None => false,
+        }
+    }
+
+    /// Sink of connected session
+    pub fn sink(&self, client_id: &ByteString) -> Option<S> {
+        self.inner.sessions.borrow().get(client_id).and_then(|entry| {
+            if entry.connected {
+                Some(entry.sink.clone())
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Check if session exists
+    pub fn contains(&self, client_id: &ByteString) -> bool {
+        self.inner.sessions.borrow().contains_key(client_id)
+    }
+
+    /// Check if session's client is connected
+    pub fn is_connected(&self, client_id: &ByteString) -> bool {
+        self.inner
+            .sessions
+            .borrow()
+            .get(client_id)
+            .map(|entry| entry.connected)
+            .unwrap_or(false)
+    }
+
+    /// Number of sessions
+    pub fn len(&self) -> usize {
+        self.inner.sessions.borrow().len()
+    }
+
+    /// Returns `true` if there are no sessions
+    pub fn is_empty(&self) -> bool {
+        self.inner.sessions.borrow().is_empty()
+    }
+
+    fn start_timer(&self) {
+        if self.inner.timer.get() {
+            return;
+        }
+        self.inner.timer.set(true);
+
+        let inner = Rc::downgrade(&self.inner);
+        ntex::rt::spawn(async move {
+            loop {
+                sleep(Millis::ONE_SEC).await;
+                match inner.upgrade() {
+                    Some(inner) => {
+                        if !inner.expire(Instant::now()) {
+                            inner.timer.set(false);
+                            break;
+                        }
+                    }
+                    None => break,
+                }
+            }
+        });
+    }
+}
+
+impl SessionManager<v3::MqttSink> {
+    /// Register v3 client connection, returns `session_present` flag
+    ///
+    /// Session of client without clean session flag does not expire.
+    pub fn connect_v3<Io>(&self, handshake: &v3::Handshake<Io>) -> bool {
+        let pkt = handshake.packet();
+        let expiry = if pkt.clean_session { 0 } else { NEVER_EXPIRE };
+        self.connect(pkt.client_id.clone(), pkt.clean_session, expiry, handshake.sink())
+    }
+}
+
+impl SessionManager<v5::MqttSink> {
+    /// Register v5 client connection, returns `session_present` flag
+    pub fn connect_v5<Io>(&self, handshake: &v5::Handshake<Io>) -> bool {
+        let pkt = handshake.packet();
+        self.connect(
+            pkt.client_id.clone(),
+            pkt.clean_start,
+            pkt.session_expiry_interval_secs.unwrap_or(0),
+            handshake.sink(),
+        )
+    }
+}
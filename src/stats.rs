@@ -0,0 +1,274 @@
+//! Server statistics
+use std::{cell::Cell, fmt, rc::Rc, time::Duration, time::Instant};
+
+use ntex::time::{sleep, Seconds};
+use ntex::util::{ByteString, Bytes};
+
+use crate::sessions::Message;
+use crate::types::{packet_type, QoS};
+
+#[derive(Default)]
+struct Counters {
+    packets: [Cell<u64>; 16],
+    bytes: Cell<u64>,
+}
+
+impl Counters {
+    fn add(&self, first_byte: u8, size: usize) {
+        let cnt = &self.packets[(first_byte >> 4) as usize];
+        cnt.set(cnt.get() + 1);
+        self.bytes.set(self.bytes.get() + size as u64);
+    }
+
+    fn total(&self) -> u64 {
+        self.packets.iter().map(|c| c.get()).sum()
+    }
+
+    fn get(&self, packet_type: u8) -> u64 {
+        self.packets[(packet_type >> 4) as usize].get()
+    }
+}
+
+struct Inner {
+    started: Instant,
+    connected: Cell<usize>,
+    max_connected: Cell<usize>,
+    connections: Cell<u64>,
+    received: Counters,
+    sent: Counters,
+}
+
+/// Server statistics
+///
+/// Collects number of connections, packets and bytes received and sent
+/// by servers that are configured with `MqttServer::stats()`. Clones
+/// share same counters, same instance could be used by v3 and v5 servers.
+#[derive(Clone)]
+pub struct Stats(Rc<Inner>);
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats(Rc::new(Inner {
+            started: Instant::now(),
+            connected: Cell::new(0),
+            max_connected: Cell::new(0),
+            connections: Cell::new(0),
+            received: Counters::default(),
+            sent: Counters::default(),
+        }))
+    }
+}
+
+impl Stats {
+    /// Create new statistics collector
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Time since collector is created
+    pub fn uptime(&self) -> Duration {
+        self.0.started.elapsed()
+    }
+
+    /// Number of connected clients
+    pub fn connected(&self) -> usize {
+        self.0.connected.get()
+    }
+
+    /// Max number of simultaneously connected clients
+    pub fn max_connected(&self) -> usize {
+        self.0.max_connected.get()
+    }
+
+    /// Total number of accepted connections
+    pub fn connections(&self) -> u64 {
+        self.0.connections.get()
+    }
+
+    /// Number of received packets of all types
+    pub fn messages_received(&self) -> u64 {
+        self.0.received.total()
+    }
+
+    /// Number of sent packets of all types
+    pub fn messages_sent(&self) -> u64 {
+        self.0.sent.total()
+    }
+
+    /// Number of received publish packets
+    pub fn publish_received(&self) -> u64 {
+        self.0.received.get(packet_type::PUBLISH_START)
+    }
+
+    /// Number of sent publish packets
+    pub fn publish_sent(&self) -> u64 {
+        self.0.sent.get(packet_type::PUBLISH_START)
+    }
+
+    /// Number of received bytes
+    pub fn bytes_received(&self) -> u64 {
+        self.0.received.bytes.get()
+    }
+
+    /// Number of sent bytes
+    pub fn bytes_sent(&self) -> u64 {
+        self.0.sent.bytes.get()
+    }
+
+    /// Current statistics as retained `$SYS/broker/...` messages
+    pub fn messages(&self) -> Vec<Message> {
+        let msg = |topic: &str, value: u64| {
+            let mut msg = Message::new(
+                ByteString::from(format!("$SYS/broker/{}", topic)),
+                QoS::AtMostOnce,
+                Bytes::from(value.to_string()),
+            );
+            msg.retain = true;
+            msg
+        };
+
+        let mut msgs = vec![
+            msg("uptime", self.uptime().as_secs()),
+            msg("clients/connected", self.connected() as u64),
+            msg("clients/maximum", self.max_connected() as u64),
+            msg("clients/total", self.connections()),
+            msg("messages/received", self.messages_received()),
+            msg("messages/sent", self.messages_sent()),
+            msg("publish/messages/received", self.publish_received()),
+            msg("publish/messages/sent", self.publish_sent()),
+            msg("bytes/received", self.bytes_received()),
+            msg("bytes/sent", self.bytes_sent()),
+        ];
+        for (name, tp) in PACKETS {
+            msgs.push(msg(&format!("packets/received/{}", name), self.0.received.get(*tp)));
+            msgs.push(msg(&format!("packets/sent/{}", name), self.0.sent.get(*tp)));
+        }
+        msgs
+    }
+
+    /// Start periodic publisher
+    ///
+    /// Statistics messages are passed to the `sink` every `interval`.
+    /// Publisher stops when all clones of the collector get dropped.
+    pub fn start_publisher<F>(&self, interval: Seconds, sink: F)
+    where
+        F: Fn(Message) + 'static,
+    {
+        let inner = Rc::downgrade(&self.0);
+        ntex::rt::spawn(async move {
+            loop {
+                if let Some(inner) = inner.upgrade() {
+                    Stats(inner).messages().into_iter().for_each(&sink);
+                } else {
+                    return;
+                }
+                sleep(interval).await;
+            }
+        });
+    }
+
+    /// Connection is accepted
+    pub(crate) fn connection_opened(&self) {
+        let connected = self.0.connected.get() + 1;
+        self.0.connected.set(connected);
+        self.0.connections.set(self.0.connections.get() + 1);
+        if connected > self.0.max_connected.get() {
+            self.0.max_connected.set(connected);
+        }
+    }
+
+    /// Connection is closed
+    pub(crate) fn connection_closed(&self) {
+        self.0.connected.set(self.0.connected.get().saturating_sub(1));
+    }
+
+    /// Packet is decoded, size includes fixed header
+    pub(crate) fn received(&self, first_byte: u8, size: usize) {
+        self.0.received.add(first_byte, size)
+    }
+
+    /// Packet is encoded, size includes fixed header
+    pub(crate) fn sent(&self, first_byte: u8, size: usize) {
+        self.0.sent.add(first_byte, size)
+    }
+}
+
+impl fmt::Debug for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Stats")
+            .field("connected", &self.connected())
+            .field("messages_received", &self.messages_received())
+            .field("messages_sent", &self.messages_sent())
+            .finish()
+    }
+}
+
+const PACKETS: &[(&str, u8)] = &[
+    ("connect", packet_type::CONNECT),
+    ("connack", packet_type::CONNACK),
+    ("publish", packet_type::PUBLISH_START),
+    ("puback", packet_type::PUBACK),
+    ("pubrec", packet_type::PUBREC),
+    ("pubrel", packet_type::PUBREL),
+    ("pubcomp", packet_type::PUBCOMP),
+    ("subscribe", packet_type::SUBSCRIBE),
+    ("suback", packet_type::SUBACK),
+    ("unsubscribe", packet_type::UNSUBSCRIBE),
+    ("unsuback", packet_type::UNSUBACK),
+    ("pingreq", packet_type::PINGREQ),
+    ("pingresp", packet_type::PINGRESP),
+    ("disconnect", packet_type::DISCONNECT),
+    ("auth", packet_type::AUTH),
+];
+
+/// Size of a packet with fixed header
+pub(crate) fn frame_size(remaining_length: u32) -> usize {
+    let len_size = match remaining_length {
+        0..=127 => 1,
+        128..=16_383 => 2,
+        16_384..=2_097_151 => 3,
+        _ => 4,
+    };
+    1 + len_size + remaining_length as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counters() {
+        let stats = Stats::new();
+        stats.connection_opened();
+        stats.connection_opened();
+        stats.connection_closed();
+        assert_eq!(stats.connected(), 1);
+        assert_eq!(stats.max_connected(), 2);
+        assert_eq!(stats.connections(), 2);
+
+        stats.received(0b0011_0010, frame_size(10));
+        stats.received(packet_type::PINGREQ, frame_size(0));
+        stats.sent(packet_type::PINGRESP, frame_size(0));
+        assert_eq!(stats.messages_received(), 2);
+        assert_eq!(stats.publish_received(), 1);
+        assert_eq!(stats.bytes_received(), 14);
+        assert_eq!(stats.messages_sent(), 1);
+        assert_eq!(stats.bytes_sent(), 2);
+
+        let msgs = stats.messages();
+        let msg = msgs.iter().find(|m| m.topic == "$SYS/broker/clients/connected").unwrap();
+        assert!(msg.retain);
+        assert_eq!(msg.payload, Bytes::from_static(b"1"));
+        let msg =
+            msgs.iter().find(|m| m.topic == "$SYS/broker/packets/received/publish").unwrap();
+        assert_eq!(msg.payload, Bytes::from_static(b"1"));
+    }
+
+    #[test]
+    fn test_frame_size() {
+        assert_eq!(frame_size(0), 2);
+        assert_eq!(frame_size(127), 129);
+        assert_eq!(frame_size(128), 131);
+        assert_eq!(frame_size(16_384), 16_388);
+    }
+}
@@ -0,0 +1,543 @@
+//! Subscriptions registry
+use std::collections::{hash_map::RandomState, HashMap};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::{cell::Cell, fmt, num::NonZeroU32};
+
+use crate::topic::{Level, SharedTopic, Topic};
+use crate::types::QoS;
+use crate::v5::codec::{RetainHandling, SubscriptionOptions};
+
+/// Subscription granted to a session
+#[derive(Debug, Clone, PartialEq)]
+pub struct Subscription {
+    /// Subscription options, qos is granted qos
+    pub options: SubscriptionOptions,
+    /// v5 subscription identifier
+    pub id: Option<NonZeroU32>,
+}
+
+impl Subscription {
+    /// Create subscription with granted qos and default options
+    pub fn new(qos: QoS) -> Self {
+        Subscription {
+            options: SubscriptionOptions {
+                qos,
+                no_local: false,
+                retain_as_published: false,
+                retain_handling: RetainHandling::AtSubscribe,
+            },
+            id: None,
+        }
+    }
+
+    /// Granted qos
+    pub fn qos(&self) -> QoS {
+        self.options.qos
+    }
+
+    /// Retain flag for forwarded publish
+    ///
+    /// Flag is kept only if subscription has `retain_as_published` option.
+    pub fn retain(&self, retain: bool) -> bool {
+        retain && self.options.retain_as_published
+    }
+}
+
+impl From<QoS> for Subscription {
+    fn from(qos: QoS) -> Self {
+        Subscription::new(qos)
+    }
+}
+
+impl From<SubscriptionOptions> for Subscription {
+    fn from(options: SubscriptionOptions) -> Self {
+        Subscription { options, id: None }
+    }
+}
+
+/// Member selection strategy for shared subscriptions
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ShareStrategy {
+    /// Members receive messages in turn
+    RoundRobin,
+    /// Random member receives message
+    Random,
+    /// Same member receives messages until it leaves the group
+    Sticky,
+}
+
+struct Group<K> {
+    members: Vec<(K, Subscription)>,
+    next: Cell<usize>,
+}
+
+impl<K: Eq> Group<K> {
+    fn insert(&mut self, key: K, sub: Subscription) -> bool {
+        if let Some(item) = self.members.iter_mut().find(|(k, _)| *k == key) {
+            item.1 = sub;
+            false
+        } else {
+            self.members.push((key, sub));
+            true
+        }
+    }
+
+    fn remove(&mut self, key: &K) -> bool {
+        if let Some(pos) = self.members.iter().position(|(k, _)| k == key) {
+            self.members.remove(pos);
+            // sticky member keeps its position
+            if pos < self.next.get() {
+                self.next.set(self.next.get() - 1);
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    fn pick(&self, strategy: ShareStrategy) -> Option<&(K, Subscription)> {
+        if self.members.is_empty() {
+            return None;
+        }
+        let idx = match strategy {
+            ShareStrategy::RoundRobin => {
+                let idx = self.next.get() % self.members.len();
+                self.next.set(idx + 1);
+                idx
+            }
+            ShareStrategy::Random => {
+                let mut hasher = RandomState::new().build_hasher();
+                hasher.write_usize(self.members.len());
+                hasher.finish() as usize % self.members.len()
+            }
+            ShareStrategy::Sticky => {
+                let idx = self.next.get() % self.members.len();
+                self.next.set(idx);
+                idx
+            }
+        };
+        self.members.get(idx)
+    }
+}
+
+struct Entries<K> {
+    subs: HashMap<K, Subscription>,
+    shared: HashMap<String, Group<K>>,
+}
+
+impl<K> Default for Entries<K> {
+    fn default() -> Self {
+        Entries { subs: HashMap::new(), shared: HashMap::new() }
+    }
+}
+
+impl<K: Eq + Hash> Entries<K> {
+    fn is_empty(&self) -> bool {
+        self.subs.is_empty() && self.shared.is_empty()
+    }
+
+    fn insert_shared(&mut self, group: &str, key: K, sub: Subscription) -> bool {
+        self.shared
+            .entry(group.to_string())
+            .or_insert_with(|| Group { members: Vec::new(), next: Cell::new(0) })
+            .insert(key, sub)
+    }
+
+    fn remove_shared(&mut self, group: &str, key: &K) -> bool {
+        if let Some(members) = self.shared.get_mut(group) {
+            let removed = members.remove(key);
+            if members.members.is_empty() {
+                self.shared.remove(group);
+            }
+            removed
+        } else {
+            false
+        }
+    }
+
+    fn matches<'a>(
+        &'a self,
+        strategy: ShareStrategy,
+        out: &mut Vec<(&'a K, &'a Subscription)>,
+    ) {
+        out.extend(self.subs.iter());
+        // one member of each group
+        out.extend(self.shared.values().filter_map(|g| g.pick(strategy)).map(|(k, s)| (k, s)));
+    }
+}
+
+struct Node<K> {
+    levels: HashMap<String, Node<K>>,
+    single: Option<Box<Node<K>>>,
+    multi: Entries<K>,
+    subs: Entries<K>,
+}
+
+impl<K> Default for Node<K> {
+    fn default() -> Self {
+        Node {
+            levels: HashMap::new(),
+            single: None,
+            multi: Entries::default(),
+            subs: Entries::default(),
+        }
+    }
+}
+
+impl<K: Eq + Hash> Node<K> {
+    fn is_empty(&self) -> bool {
+        self.levels.is_empty()
+            && self.single.is_none()
+            && self.multi.is_empty()
+            && self.subs.is_empty()
+    }
+
+    fn entries(&mut self, levels: &[Level]) -> &mut Entries<K> {
+        match levels.split_first() {
+            None => &mut self.subs,
+            Some((Level::MultiWildcard, _)) => &mut self.multi,
+            Some((Level::SingleWildcard, rest)) => {
+                self.single.get_or_insert_with(Default::default).entries(rest)
+            }
+            Some((level, rest)) => self
+                .levels
+                .entry(level.value().unwrap_or_default().to_string())
+                .or_default()
+                .entries(rest),
+        }
+    }
+
+    fn remove<F>(&mut self, levels: &[Level], f: &F) -> bool
+    where
+        F: Fn(&mut Entries<K>) -> bool,
+    {
+        match levels.split_first() {
+            None => f(&mut self.subs),
+            Some((Level::MultiWildcard, _)) => f(&mut self.multi),
+            Some((Level::SingleWildcard, rest)) => {
+                if let Some(ref mut node) = self.single {
+                    let removed = node.remove(rest, f);
+                    if node.is_empty() {
+                        self.single = None;
+                    }
+                    removed
+                } else {
+                    false
+                }
+            }
+            Some((level, rest)) => {
+                let name = level.value().unwrap_or_default();
+                if let Some(node) = self.levels.get_mut(name) {
+                    let removed = node.remove(rest, f);
+                    if node.is_empty() {
+                        self.levels.remove(name);
+                    }
+                    removed
+                } else {
+                    false
+                }
+            }
+        }
+    }
+
+    fn matches<'a>(
+        &'a self,
+        topic: &[&str],
+        first: bool,
+        strategy: ShareStrategy,
+        out: &mut Vec<(&'a K, &'a Subscription)>,
+    ) {
+        match topic.split_first() {
+            None => {
+                // `a/#` matches `a` as well
+                self.subs.matches(strategy, out);
+                self.multi.matches(strategy, out);
+            }
+            Some((level, rest)) => {
+                // wildcards at first level do not match `$` topics
+                if !(first && level.starts_with('$')) {
+                    self.multi.matches(strategy, out);
+                    if let Some(ref node) = self.single {
+                        node.matches(rest, false, strategy, out);
+                    }
+                }
+                if let Some(node) = self.levels.get(*level) {
+                    node.matches(rest, false, strategy, out);
+                }
+            }
+        }
+    }
+}
+
+/// Subscriptions registry
+///
+/// Registry maps topic filters to subscribed sessions. Filters are stored in
+/// a level trie, so matching a publish topic does not depend on number of
+/// registered filters. Each shared subscription group receives a publish
+/// once, group member is selected according to `ShareStrategy`.
+pub struct Subscriptions<K> {
+    root: Node<K>,
+    sessions: HashMap<K, Vec<Topic>>,
+    shared: HashMap<K, Vec<SharedTopic>>,
+    strategy: ShareStrategy,
+}
+
+impl<K> Default for Subscriptions<K> {
+    fn default() -> Self {
+        Subscriptions {
+            root: Node::default(),
+            sessions: HashMap::new(),
+            shared: HashMap::new(),
+            strategy: ShareStrategy::RoundRobin,
+        }
+    }
+}
+
+impl<K> fmt::Debug for Subscriptions<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Subscriptions")
+            .field("sessions", &self.sessions.len())
+            .field("shared", &self.shared.len())
+            .field("strategy", &self.strategy)
+            .finish()
+    }
+}
+
+impl<K: Eq + Hash + Clone> Subscriptions<K> {
+    /// Create empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set member selection strategy for shared subscriptions
+    ///
+    /// By default `ShareStrategy::RoundRobin` is used.
+    pub fn strategy(mut self, strategy: ShareStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Add subscription for a session
+    ///
+    /// Existing subscription for the same filter is replaced. Returns `true`
+    /// if subscription did not exist.
+    pub fn subscribe<S>(&mut self, key: K, filter: &Topic, sub: S) -> bool
+    where
+        S: Into<Subscription>,
+    {
+        let is_new =
+            self.root.entries(filter.levels()).subs.insert(key.clone(), sub.into()).is_none();
+        if is_new {
+            self.sessions.entry(key).or_default().push(filter.clone());
+        }
+        is_new
+    }
+
+    /// Remove session's subscription, returns `true` if subscription existed
+    pub fn unsubscribe(&mut self, key: &K, filter: &Topic) -> bool {
+        if !self.root.remove(filter.levels(), &|e| e.subs.remove(key).is_some()) {
+            return false;
+        }
+        if let Some(filters) = self.sessions.get_mut(key) {
+            filters.retain(|f| f.levels() != filter.levels());
+            if filters.is_empty() {
+                self.sessions.remove(key);
+            }
+        }
+        true
+    }
+
+    /// Add session to shared subscription group
+    ///
+    /// Existing subscription for the same group and filter is replaced.
+    /// Returns `true` if subscription did not exist.
+    pub fn subscribe_shared<S>(&mut self, key: K, filter: &SharedTopic, sub: S) -> bool
+    where
+        S: Into<Subscription>,
+    {
+        let is_new = self.root.entries(filter.filter().levels()).insert_shared(
+            filter.group(),
+            key.clone(),
+            sub.into(),
+        );
+        if is_new {
+            self.shared.entry(key).or_default().push(filter.clone());
+        }
+        is_new
+    }
+
+    /// Remove session from shared subscription group, returns `true`
+    /// if subscription existed
+    pub fn unsubscribe_shared(&mut self, key: &K, filter: &SharedTopic) -> bool {
+        let group = filter.group();
+        if !self.root.remove(filter.filter().levels(), &|e| e.remove_shared(group, key)) {
+            return false;
+        }
+        if let Some(filters) = self.shared.get_mut(key) {
+            filters.retain(|f| {
+                f.group() != group || f.filter().levels() != filter.filter().levels()
+            });
+            if filters.is_empty() {
+                self.shared.remove(key);
+            }
+        }
+        true
+    }
+
+    /// Remove all subscriptions of a session
+    pub fn remove(&mut self, key: &K) {
+        if let Some(filters) = self.sessions.remove(key) {
+            for filter in filters {
+                self.root.remove(filter.levels(), &|e| e.subs.remove(key).is_some());
+            }
+        }
+        if let Some(filters) = self.shared.remove(key) {
+            for filter in filters {
+                let group = filter.group();
+                self.root.remove(filter.filter().levels(), &|e| e.remove_shared(group, key));
+            }
+        }
+    }
+
+    /// Session's topic filters
+    pub fn filters(&self, key: &K) -> &[Topic] {
+        self.sessions.get(key).map(|f| f.as_slice()).unwrap_or(&[])
+    }
+
+    /// Session's shared subscription filters
+    pub fn shared_filters(&self, key: &K) -> &[SharedTopic] {
+        self.shared.get(key).map(|f| f.as_slice()).unwrap_or(&[])
+    }
+
+    /// Subscriptions matching publish topic
+    ///
+    /// Session with overlapping filters is returned once for each matched filter,
+    /// one member is returned for each matched shared subscription group.
+    pub fn matches(&self, topic: &str) -> Vec<(&K, &Subscription)> {
+        let levels: Vec<_> = topic.split('/').collect();
+        let mut out = Vec::new();
+        self.root.matches(&levels, true, self.strategy, &mut out);
+        out
+    }
+
+    /// Number of sessions with subscriptions
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+            + self.shared.keys().filter(|key| !self.sessions.contains_key(key)).count()
+    }
+
+    /// Returns `true` if registry has no subscriptions
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty() && self.shared.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(subs: &Subscriptions<&'static str>, topic: &str) -> Vec<&'static str> {
+        let mut keys: Vec<_> = subs.matches(topic).into_iter().map(|(k, _)| *k).collect();
+        keys.sort_unstable();
+        keys
+    }
+
+    #[test]
+    fn test_matches() {
+        let mut subs = Subscriptions::new();
+        assert!(subs.subscribe("s1", &topic!("a/b/c"), QoS::AtMostOnce));
+        assert!(subs.subscribe("s2", &topic!("a/+/c"), QoS::AtLeastOnce));
+        assert!(subs.subscribe("s3", &topic!("a/#"), QoS::ExactlyOnce));
+        assert!(subs.subscribe("s4", &topic!("#"), QoS::AtMostOnce));
+        assert!(subs.subscribe("s5", &topic!("+/b/+"), QoS::AtMostOnce));
+        assert!(subs.subscribe("s6", &topic!("$SYS/#"), QoS::AtMostOnce));
+        assert!(subs.subscribe("s7", &topic!("/a"), QoS::AtMostOnce));
+        assert!(!subs.subscribe("s1", &topic!("a/b/c"), QoS::AtLeastOnce));
+
+        assert_eq!(matches(&subs, "a/b/c"), vec!["s1", "s2", "s3", "s4", "s5"]);
+        assert_eq!(matches(&subs, "a/x/c"), vec!["s2", "s3", "s4"]);
+        assert_eq!(matches(&subs, "a"), vec!["s3", "s4"]);
+        assert_eq!(matches(&subs, "a/b/c/d"), vec!["s3", "s4"]);
+        assert_eq!(matches(&subs, "/a"), vec!["s4", "s7"]);
+        assert_eq!(matches(&subs, "$SYS/load"), vec!["s6"]);
+        assert_eq!(matches(&subs, "$SYS"), vec!["s6"]);
+        assert!(matches(&subs, "$other/b/c").is_empty());
+
+        let (_, sub) = subs.matches("a/b/c").into_iter().find(|(k, _)| **k == "s1").unwrap();
+        assert_eq!(sub.qos(), QoS::AtLeastOnce);
+    }
+
+    #[test]
+    fn test_unsubscribe() {
+        let mut subs = Subscriptions::new();
+        subs.subscribe("s1", &topic!("a/+/c"), QoS::AtMostOnce);
+        subs.subscribe("s1", &topic!("a/#"), QoS::AtMostOnce);
+        subs.subscribe("s2", &topic!("a/+/c"), QoS::AtMostOnce);
+        assert_eq!(subs.len(), 2);
+        assert_eq!(subs.filters(&"s1").len(), 2);
+
+        assert!(subs.unsubscribe(&"s1", &topic!("a/+/c")));
+        assert!(!subs.unsubscribe(&"s1", &topic!("a/+/c")));
+        assert_eq!(matches(&subs, "a/b/c"), vec!["s1", "s2"]);
+
+        subs.remove(&"s1");
+        assert_eq!(matches(&subs, "a/b/c"), vec!["s2"]);
+        subs.remove(&"s2");
+        assert!(subs.is_empty());
+        assert!(subs.root.is_empty());
+    }
+
+    #[test]
+    fn test_shared() {
+        let g1: SharedTopic = "$share/g1/a/+".parse().unwrap();
+        let g2: SharedTopic = "$share/g2/a/#".parse().unwrap();
+
+        let mut subs = Subscriptions::new();
+        assert!(subs.subscribe_shared("s1", &g1, QoS::AtMostOnce));
+        assert!(subs.subscribe_shared("s2", &g1, QoS::AtMostOnce));
+        assert!(subs.subscribe_shared("s3", &g2, QoS::AtMostOnce));
+        assert!(!subs.subscribe_shared("s3", &g2, QoS::AtLeastOnce));
+        subs.subscribe("s4", &topic!("a/b"), QoS::AtMostOnce);
+        assert_eq!(subs.len(), 4);
+
+        // round robin
+        assert_eq!(matches(&subs, "a/b"), vec!["s1", "s3", "s4"]);
+        assert_eq!(matches(&subs, "a/b"), vec!["s2", "s3", "s4"]);
+        assert_eq!(matches(&subs, "a/b"), vec!["s1", "s3", "s4"]);
+        assert_eq!(matches(&subs, "a/b/c"), vec!["s3"]);
+
+        assert!(subs.unsubscribe_shared(&"s1", &g1));
+        assert!(!subs.unsubscribe_shared(&"s1", &g1));
+        assert_eq!(matches(&subs, "a/b"), vec!["s2", "s3", "s4"]);
+        assert_eq!(matches(&subs, "a/b"), vec!["s2", "s3", "s4"]);
+
+        subs.remove(&"s2");
+        subs.remove(&"s3");
+        subs.remove(&"s4");
+        assert!(subs.is_empty());
+        assert!(subs.root.is_empty());
+    }
+
+    #[test]
+    fn test_shared_strategy() {
+        let g: SharedTopic = "$share/g/a".parse().unwrap();
+
+        let mut subs = Subscriptions::new().strategy(ShareStrategy::Sticky);
+        for key in &["s1", "s2", "s3"] {
+            subs.subscribe_shared(*key, &g, QoS::AtMostOnce);
+        }
+        assert_eq!(matches(&subs, "a"), vec!["s1"]);
+        assert_eq!(matches(&subs, "a"), vec!["s1"]);
+        subs.unsubscribe_shared(&"s1", &g);
+        assert_eq!(matches(&subs, "a"), vec!["s2"]);
+        assert_eq!(matches(&subs, "a"), vec!["s2"]);
+
+        let mut subs = Subscriptions::new().strategy(ShareStrategy::Random);
+        for key in &["s1", "s2", "s3"] {
+            subs.subscribe_shared(*key, &g, QoS::AtMostOnce);
+        }
+        for _ in 0..10 {
+            assert_eq!(matches(&subs, "a").len(), 1);
+        }
+    }
+}
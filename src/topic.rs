@@ -265,6 +265,59 @@ impl FromStr for Topic {
     }
 }
 
+/// Shared subscription topic filter, `$share/{group}/{filter}`
+#[derive(Debug, Clone)]
+pub struct SharedTopic {
+    group: String,
+    filter: Topic,
+}
+
+impl SharedTopic {
+    /// Shared subscription prefix
+    pub const PREFIX: &'static str = "$share/";
+
+    /// Check if topic filter is a shared subscription filter
+    pub fn is_shared<T: AsRef<str>>(s: T) -> bool {
+        s.as_ref().starts_with(Self::PREFIX)
+    }
+
+    #[inline]
+    /// Share name
+    pub fn group(&self) -> &str {
+        &self.group
+    }
+
+    #[inline]
+    /// Topic filter of the shared subscription
+    pub fn filter(&self) -> &Topic {
+        &self.filter
+    }
+}
+
+impl FromStr for SharedTopic {
+    type Err = TopicError;
+
+    fn from_str(s: &str) -> Result<Self, TopicError> {
+        let rest = s.strip_prefix(Self::PREFIX).ok_or(TopicError::InvalidTopic)?;
+        let (group, filter) = rest.split_once('/').ok_or(TopicError::InvalidTopic)?;
+
+        if group.is_empty() || group.contains(&['+', '#'][..]) {
+            return Err(TopicError::InvalidLevel);
+        }
+        if filter.is_empty() {
+            return Err(TopicError::InvalidTopic);
+        }
+
+        Ok(SharedTopic { group: String::from(group), filter: Topic::from_str(filter)? })
+    }
+}
+
+impl fmt::Display for SharedTopic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}/{}", Self::PREFIX, self.group, self.filter)
+    }
+}
+
 impl fmt::Display for Level {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match *self {
@@ -488,4 +541,29 @@ mod tests {
         assert!(Topic::from_str(&"$SYS/#").unwrap().matches_str("$SYS/"));
         assert!(Topic::from_str("$SYS/monitor/+").unwrap().matches_str("$SYS/monitor/Clients"));
     }
+
+    #[test]
+    fn test_shared_topic() {
+        let t: SharedTopic = "$share/group/sport/+".parse().unwrap();
+        assert_eq!(t.group(), "group");
+        assert_eq!(t.filter().levels(), &vec![Level::normal("sport"), Level::SingleWildcard]);
+        assert!(t.filter().matches_str("sport/tennis"));
+        assert_eq!(t.to_string(), "$share/group/sport/+");
+
+        assert!(SharedTopic::is_shared("$share/group/#"));
+        assert!(!SharedTopic::is_shared("$SYS/#"));
+
+        assert_eq!(SharedTopic::from_str("sport/+").err(), Some(TopicError::InvalidTopic));
+        assert_eq!(SharedTopic::from_str("$share/group").err(), Some(TopicError::InvalidTopic));
+        assert_eq!(
+            SharedTopic::from_str("$share/group/").err(),
+            Some(TopicError::InvalidTopic)
+        );
+        assert_eq!(SharedTopic::from_str("$share//a").err(), Some(TopicError::InvalidLevel));
+        assert_eq!(SharedTopic::from_str("$share/+/a").err(), Some(TopicError::InvalidLevel));
+        assert_eq!(
+            SharedTopic::from_str("$share/g/a/#/b").err(),
+            Some(TopicError::InvalidTopic)
+        );
+    }
 }
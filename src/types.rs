@@ -1,4 +1,6 @@
 pub const MQTT: &[u8] = b"MQTT";
+pub const MQISDP: &[u8] = b"MQIsdp";
+pub const MQTT_LEVEL_31: u8 = 3;
 pub const MQTT_LEVEL_3: u8 = 4;
 pub const MQTT_LEVEL_5: u8 = 5;
 pub const WILL_QOS_SHIFT: u8 = 3;
@@ -6,6 +8,19 @@ pub const WILL_QOS_SHIFT: u8 = 3;
 /// Max possible packet size
 pub const MAX_PACKET_SIZE: u32 = 0xF_FF_FF_FF;
 
+/// Managed client connection state
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Client is connecting to the server
+    Connecting,
+    /// Connection is established
+    Connected,
+    /// Connection is lost, client waits for reconnect
+    Disconnected,
+    /// Client is stopped
+    Stopped,
+}
+
 prim_enum! {
     /// Quality of Service
     pub enum QoS {
@@ -15,6 +15,7 @@ use ntex::connect::rustls::{ClientConfig, RustlsConnector};
 use super::{codec, connection::Client, error::ClientError, error::ProtocolError};
 use crate::io::State;
 use crate::v3::shared::{MqttShared, MqttSinkPool};
+use crate::ws::WsConnector;
 
 /// Mqtt client connector
 pub struct MqttConnector<A, T> {
@@ -188,6 +189,27 @@ where
         }
     }
 
+    /// Use websocket transport
+    ///
+    /// Wraps current connector, tls connector must be configured before websocket.
+    pub fn websocket<U>(self, path: U) -> MqttConnector<A, WsConnector<A, T>>
+    where
+        ByteString: From<U>,
+        T::Future: 'static,
+    {
+        MqttConnector {
+            connector: WsConnector::with_connector(path, self.connector),
+            pkt: self.pkt,
+            address: self.address,
+            max_send: self.max_send,
+            max_receive: self.max_receive,
+            max_packet_size: self.max_packet_size,
+            handshake_timeout: self.handshake_timeout,
+            disconnect_timeout: self.disconnect_timeout,
+            pool: self.pool,
+        }
+    }
+
     #[cfg(feature = "openssl")]
     /// Use openssl connector
     pub fn openssl(self, connector: SslConnector) -> MqttConnector<A, OpensslConnector<A>> {
@@ -24,7 +24,7 @@ impl<E> ControlMessage<E> {
     }
 
     pub(super) fn closed(is_error: bool) -> Self {
-        ControlMessage::Closed(Closed::new(is_error))
+        ControlMessage::Closed(Closed::new(is_error, None))
     }
 
     pub(super) fn error(err: E) -> Self {
@@ -53,9 +53,16 @@ impl Publish {
         &mut self.0
     }
 
+    /// Ack publish packet
+    ///
+    /// Sends PUBREC for qos2 publish packet
     pub fn ack(self) -> ControlResult {
         if let Some(id) = self.0.packet_id {
-            ControlResult { result: ControlResultKind::PublishAck(id) }
+            if self.0.qos == codec::QoS::ExactlyOnce {
+                ControlResult { result: ControlResultKind::PublishReceived(id) }
+            } else {
+                ControlResult { result: ControlResultKind::PublishAck(id) }
+            }
         } else {
             ControlResult { result: ControlResultKind::Nothing }
         }
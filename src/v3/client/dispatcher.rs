@@ -7,7 +7,8 @@ use ntex::util::{inflight::InFlightService, Either, HashSet, Ready};
 
 use crate::v3::shared::{Ack, MqttShared};
 use crate::v3::{codec, control::ControlResultKind, publish::Publish, sink::MqttSink};
-use crate::{error::MqttError, error::ProtocolError, io::DispatchItem, types::packet_type};
+use crate::{error::MqttError, error::ProtocolError, io::DispatchItem};
+use crate::{types::packet_type, types::QoS};
 
 use super::control::{ControlMessage, ControlResult};
 
@@ -45,6 +46,8 @@ struct Inner<C> {
     control: C,
     sink: MqttSink,
     inflight: RefCell<HashSet<NonZeroU16>>,
+    // qos2 publishes acked with PUBREC, waiting for PUBREL
+    await_release: RefCell<HashSet<NonZeroU16>>,
 }
 
 impl<T, C, E> Dispatcher<T, C, E>
@@ -57,7 +60,12 @@ where
             publish,
             sink: sink.clone(),
             shutdown: Cell::new(false),
-            inner: Rc::new(Inner { sink, control, inflight: RefCell::new(HashSet::default()) }),
+            inner: Rc::new(Inner {
+                sink,
+                control,
+                inflight: RefCell::new(HashSet::default()),
+                await_release: RefCell::new(HashSet::default()),
+            }),
             _t: PhantomData,
         }
     }
@@ -91,7 +99,7 @@ where
 
     fn poll_shutdown(&self, _: &mut Context<'_>, is_error: bool) -> Poll<()> {
         if !self.shutdown.get() {
-            self.inner.sink.close();
+            self.inner.sink.drop_sink();
             self.shutdown.set(true);
             let fut = self.inner.control.call(ControlMessage::closed(is_error));
             ntex::rt::spawn(async move {
@@ -107,9 +115,18 @@ where
             DispatchItem::Item(codec::Packet::Publish(publish)) => {
                 let inner = self.inner.clone();
                 let packet_id = publish.packet_id;
+                let qos = publish.qos;
 
-                // check for duplicated packet id
                 if let Some(pid) = packet_id {
+                    // qos2 publish is already received, peer did not get PUBREC
+                    if qos == QoS::ExactlyOnce && inner.await_release.borrow().contains(&pid) {
+                        log::trace!("Re-delivered qos2 publish packet: {:?}", pid);
+                        return Either::Right(Either::Left(Ready::Ok(Some(
+                            codec::Packet::PublishReceived { packet_id: pid },
+                        ))));
+                    }
+
+                    // check for duplicated packet id
                     if !inner.inflight.borrow_mut().insert(pid) {
                         log::trace!("Duplicated packet id for publish packet: {:?}", pid);
                         return Either::Right(Either::Left(Ready::Err(
@@ -118,6 +135,7 @@ where
                     }
                 }
                 Either::Left(PublishResponse {
+                    qos,
                     packet_id,
                     inner,
                     fut: self.publish.call(Publish::new(publish)),
@@ -132,6 +150,34 @@ where
                     Either::Right(Either::Left(Ready::Ok(None)))
                 }
             }
+            DispatchItem::Item(codec::Packet::PublishReceived { packet_id }) => {
+                if let Err(e) = self.sink.pkt_ack(Ack::Receive(packet_id)) {
+                    Either::Right(Either::Left(Ready::Err(MqttError::Protocol(e))))
+                } else {
+                    Either::Right(Either::Left(Ready::Ok(None)))
+                }
+            }
+            DispatchItem::Item(codec::Packet::PublishComplete { packet_id }) => {
+                if let Err(e) = self.sink.pkt_ack(Ack::Complete(packet_id)) {
+                    Either::Right(Either::Left(Ready::Err(MqttError::Protocol(e))))
+                } else {
+                    Either::Right(Either::Left(Ready::Ok(None)))
+                }
+            }
+            DispatchItem::Item(codec::Packet::PublishRelease { packet_id }) => {
+                if self.inner.await_release.borrow_mut().remove(&packet_id) {
+                    self.inner.inflight.borrow_mut().remove(&packet_id);
+                    Either::Right(Either::Left(Ready::Ok(Some(
+                        codec::Packet::PublishComplete { packet_id },
+                    ))))
+                } else {
+                    log::trace!("Unknown packet id for PUBREL: {:?}", packet_id);
+                    Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(ProtocolError::PacketIdMismatch),
+                        &self.inner,
+                    )))
+                }
+            }
             DispatchItem::Item(codec::Packet::PingRequest) => {
                 Either::Right(Either::Left(Ready::Ok(Some(codec::Packet::PingResponse))))
             }
@@ -211,6 +257,7 @@ pin_project_lite::pin_project! {
         #[pin]
         fut_c: Option<ControlResponse<C, E>>,
         packet_id: Option<NonZeroU16>,
+        qos: QoS,
         inner: Rc<Inner<C>>,
         _t: PhantomData<E>,
     }
@@ -244,8 +291,17 @@ where
                 log::trace!("Publish result for packet {:?} is ready", this.packet_id);
 
                 if let Some(packet_id) = this.packet_id {
-                    this.inner.inflight.borrow_mut().remove(packet_id);
-                    Poll::Ready(Ok(Some(codec::Packet::PublishAck { packet_id: *packet_id })))
+                    if *this.qos == QoS::ExactlyOnce {
+                        this.inner.await_release.borrow_mut().insert(*packet_id);
+                        Poll::Ready(Ok(Some(codec::Packet::PublishReceived {
+                            packet_id: *packet_id,
+                        })))
+                    } else {
+                        this.inner.inflight.borrow_mut().remove(packet_id);
+                        Poll::Ready(Ok(Some(codec::Packet::PublishAck {
+                            packet_id: *packet_id,
+                        })))
+                    }
                 } else {
                     Poll::Ready(Ok(None))
                 }
@@ -296,6 +352,10 @@ where
                     this.inner.inflight.borrow_mut().remove(&id);
                     Some(codec::Packet::PublishAck { packet_id: id })
                 }
+                ControlResultKind::PublishReceived(id) => {
+                    this.inner.await_release.borrow_mut().insert(id);
+                    Some(codec::Packet::PublishReceived { packet_id: id })
+                }
                 ControlResultKind::Subscribe(_) => unreachable!(),
                 ControlResultKind::Unsubscribe(_) => unreachable!(),
                 ControlResultKind::Disconnect => {
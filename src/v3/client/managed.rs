@@ -0,0 +1,465 @@
+use std::{cell::Cell, cell::RefCell, collections::VecDeque, fmt, future::Future, rc::Rc};
+
+use ntex::channel::{mpsc, oneshot};
+use ntex::codec::{AsyncRead, AsyncWrite};
+use ntex::connect::{self, Address, Connect};
+use ntex::service::Service;
+use ntex::time::sleep;
+use ntex::util::{ByteString, Bytes, Either, Ready};
+
+use super::{connection::Client, connector::MqttConnector};
+use crate::backoff::Backoff;
+use crate::io::State;
+use crate::types::ConnectionState;
+use crate::v3::shared::{MqttShared, MqttSinkPool};
+use crate::v3::{codec, error::SendPacketError, sink::MqttSink};
+
+const DEFAULT_BUFFER_SIZE: usize = 128;
+
+/// Mqtt client that reconnects to the server
+///
+/// Subscriptions made through `ManagedSink` get re-issued after reconnect
+/// if server does not have stored session state. QoS 0 and QoS 1 publishes
+/// are buffered while client is offline.
+pub struct ManagedClient<A, T> {
+    connector: MqttConnector<A, T>,
+    backoff: Backoff,
+    inner: Rc<Inner>,
+}
+
+struct Inner {
+    sink: RefCell<MqttSink>,
+    state: Cell<ConnectionState>,
+    stopped: Cell<bool>,
+    max_buffer: Cell<usize>,
+    buffer: RefCell<VecDeque<Buffered>>,
+    subscriptions: RefCell<Vec<(ByteString, codec::QoS)>>,
+    waiters: RefCell<VecDeque<oneshot::Sender<()>>>,
+    streams: RefCell<Vec<mpsc::Sender<ConnectionState>>>,
+    callbacks: RefCell<Vec<Box<dyn Fn(ConnectionState)>>>,
+}
+
+struct Buffered {
+    topic: ByteString,
+    payload: Bytes,
+    retain: bool,
+    tx: Option<oneshot::Sender<Result<(), SendPacketError>>>,
+}
+
+impl<A, T> fmt::Debug for ManagedClient<A, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("v3::ManagedClient")
+            .field("backoff", &self.backoff)
+            .field("max_buffer", &self.inner.max_buffer.get())
+            .field("state", &self.inner.state.get())
+            .finish()
+    }
+}
+
+impl<A, T> ManagedClient<A, T>
+where
+    A: Address + Clone,
+    T: Service<Request = Connect<A>, Error = connect::ConnectError>,
+    T::Response: AsyncRead + AsyncWrite + Unpin + 'static,
+{
+    /// Create new managed client
+    pub fn new(connector: MqttConnector<A, T>) -> Self {
+        // sink is disconnected until first connection get established
+        let state = State::new();
+        state.close();
+        let shared =
+            MqttShared::new(state, codec::Codec::new(), 0, Rc::new(MqttSinkPool::default()));
+
+        ManagedClient {
+            connector,
+            backoff: Backoff::default(),
+            inner: Rc::new(Inner {
+                sink: RefCell::new(MqttSink::new(Rc::new(shared))),
+                state: Cell::new(ConnectionState::Disconnected),
+                stopped: Cell::new(false),
+                max_buffer: Cell::new(DEFAULT_BUFFER_SIZE),
+                buffer: RefCell::new(VecDeque::new()),
+                subscriptions: RefCell::new(Vec::new()),
+                waiters: RefCell::new(VecDeque::new()),
+                streams: RefCell::new(Vec::new()),
+                callbacks: RefCell::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Set reconnect backoff policy
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Set max number of publishes buffered while client is offline
+    ///
+    /// By default max buffer size is 128 packets
+    pub fn max_buffer(self, size: usize) -> Self {
+        self.inner.max_buffer.set(size);
+        self
+    }
+
+    /// Register connection state callback
+    pub fn on_state_change<F>(self, f: F) -> Self
+    where
+        F: Fn(ConnectionState) + 'static,
+    {
+        self.inner.callbacks.borrow_mut().push(Box::new(f));
+        self
+    }
+
+    /// Get stream of connection state changes
+    pub fn state_changes(&self) -> mpsc::Receiver<ConnectionState> {
+        let (tx, rx) = mpsc::channel();
+        self.inner.streams.borrow_mut().push(tx);
+        rx
+    }
+
+    #[inline]
+    /// Get client sink
+    pub fn sink(&self) -> ManagedSink {
+        ManagedSink(self.inner.clone())
+    }
+
+    /// Run client with default control messages handler
+    pub async fn start_default(self) {
+        self.start(|client| client.start_default()).await
+    }
+
+    /// Run client, provided factory is used for handling of each new connection
+    ///
+    /// Connection is handled until factory's future resolves.
+    pub async fn start<F, R>(self, factory: F)
+    where
+        F: Fn(Client<T::Response>) -> R,
+        R: Future,
+    {
+        let inner = self.inner;
+        let mut attempt = 0;
+
+        while !inner.stopped.get() {
+            inner.set_state(ConnectionState::Connecting);
+
+            match self.connector.connect().await {
+                Ok(client) => {
+                    attempt = 0;
+                    if inner.stopped.get() {
+                        client.sink().close();
+                        break;
+                    }
+                    let sink = client.sink();
+                    sink.keep_inflight();
+
+                    // unacknowledged packets are re-sent if server keeps session state
+                    let prev = inner.sink.replace(sink.clone());
+                    if client.session_present() {
+                        prev.transfer_inflight(&sink);
+                    } else {
+                        prev.close();
+                        resubscribe(&inner, &sink);
+                    }
+                    inner.flush(&sink);
+                    inner.set_state(ConnectionState::Connected);
+
+                    // wake up waiters
+                    for tx in inner.waiters.borrow_mut().drain(..) {
+                        let _ = tx.send(());
+                    }
+
+                    let _ = factory(client).await;
+                    log::trace!("Mqtt connection is closed");
+                }
+                Err(err) => log::trace!("Cannot connect to mqtt server: {:?}", err),
+            }
+            inner.set_state(ConnectionState::Disconnected);
+
+            if !inner.stopped.get() {
+                let delay = self.backoff.delay(attempt);
+                attempt = attempt.saturating_add(1);
+                log::trace!("Reconnecting in {:?}", delay);
+                sleep(delay).await;
+            }
+        }
+        inner.waiters.borrow_mut().clear();
+        inner.buffer.borrow_mut().clear();
+        inner.sink.borrow().close();
+        inner.set_state(ConnectionState::Stopped);
+    }
+}
+
+impl Inner {
+    fn set_state(&self, state: ConnectionState) {
+        if self.state.get() != state {
+            self.state.set(state);
+            for f in self.callbacks.borrow().iter() {
+                (*f)(state)
+            }
+            self.streams.borrow_mut().retain(|tx| tx.send(state).is_ok());
+        }
+    }
+
+    fn is_online(&self) -> bool {
+        self.state.get() == ConnectionState::Connected && self.sink.borrow().is_open()
+    }
+
+    /// Send buffered publishes to new connection
+    fn flush(&self, sink: &MqttSink) {
+        let buffer: Vec<_> = self.buffer.borrow_mut().drain(..).collect();
+        if !buffer.is_empty() {
+            log::trace!("Sending {} buffered publishes", buffer.len());
+        }
+
+        for item in buffer {
+            let mut builder = sink.publish(item.topic, item.payload);
+            if item.retain {
+                builder = builder.retain();
+            }
+            if let Some(tx) = item.tx {
+                let fut = builder.send_at_least_once();
+                ntex::rt::spawn(async move {
+                    let _ = tx.send(fut.await);
+                });
+            } else if let Err(err) = builder.send_at_most_once() {
+                log::error!("Cannot send buffered publish: {:?}", err);
+            }
+        }
+    }
+}
+
+fn resubscribe(inner: &Inner, sink: &MqttSink) {
+    let mut builder = sink.subscribe();
+    for (filter, qos) in inner.subscriptions.borrow().iter() {
+        builder = builder.topic_filter(filter.clone(), *qos);
+    }
+
+    if !inner.subscriptions.borrow().is_empty() {
+        ntex::rt::spawn(async move {
+            match builder.send().await {
+                Ok(codes) => log::trace!("Re-subscribed: {:?}", codes),
+                Err(err) => log::error!("Cannot re-subscribe: {:?}", err),
+            }
+        });
+    }
+}
+
+/// Mqtt sink that stays valid across reconnects
+#[derive(Clone)]
+pub struct ManagedSink(Rc<Inner>);
+
+impl ManagedSink {
+    #[inline]
+    /// Get sink of current connection
+    pub fn sink(&self) -> MqttSink {
+        self.0.sink.borrow().clone()
+    }
+
+    #[inline]
+    /// Get connection state
+    pub fn state(&self) -> ConnectionState {
+        self.0.state.get()
+    }
+
+    #[inline]
+    /// Check connection status
+    pub fn is_open(&self) -> bool {
+        self.0.is_online()
+    }
+
+    /// Number of publishes buffered while client is offline
+    pub fn buffered(&self) -> usize {
+        self.0.buffer.borrow().len()
+    }
+
+    /// Wait for established connection.
+    ///
+    /// Returns `false` if client is stopped
+    pub async fn connected(&self) -> bool {
+        if self.0.stopped.get() {
+            return false;
+        }
+        if self.is_open() {
+            return true;
+        }
+        let (tx, rx) = oneshot::channel();
+        self.0.waiters.borrow_mut().push_back(tx);
+        rx.await.is_ok()
+    }
+
+    /// Get notification when packet could be send to the peer.
+    ///
+    /// Waits for reconnect, result indicates if client is alive
+    pub async fn ready(&self) -> bool {
+        loop {
+            if !self.connected().await {
+                return false;
+            }
+            if self.sink().ready().await {
+                return true;
+            }
+        }
+    }
+
+    /// Stop reconnecting and close mqtt connection
+    pub fn close(&self) {
+        self.0.stopped.set(true);
+        self.0.waiters.borrow_mut().clear();
+        self.0.buffer.borrow_mut().clear();
+        self.sink().close();
+    }
+
+    /// Create publish packet builder
+    pub fn publish(&self, topic: ByteString, payload: Bytes) -> ManagedPublishBuilder {
+        ManagedPublishBuilder { inner: self.0.clone(), topic, payload, retain: false }
+    }
+
+    /// Create subscribe packet builder
+    ///
+    /// Granted subscriptions get re-issued after reconnect
+    pub fn subscribe(&self) -> ManagedSubscribeBuilder {
+        ManagedSubscribeBuilder { inner: self.0.clone(), topic_filters: Vec::new() }
+    }
+
+    /// Create unsubscribe packet builder
+    pub fn unsubscribe(&self) -> ManagedUnsubscribeBuilder {
+        ManagedUnsubscribeBuilder { inner: self.0.clone(), topic_filters: Vec::new() }
+    }
+}
+
+impl fmt::Debug for ManagedSink {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("ManagedSink").field("state", &self.0.state.get()).finish()
+    }
+}
+
+/// Publish packet builder of managed sink
+pub struct ManagedPublishBuilder {
+    inner: Rc<Inner>,
+    topic: ByteString,
+    payload: Bytes,
+    retain: bool,
+}
+
+impl ManagedPublishBuilder {
+    /// Set retain flag
+    pub fn retain(mut self) -> Self {
+        self.retain = true;
+        self
+    }
+
+    fn buffer(
+        self,
+        tx: Option<oneshot::Sender<Result<(), SendPacketError>>>,
+    ) -> Result<(), SendPacketError> {
+        let mut buffer = self.inner.buffer.borrow_mut();
+        if self.inner.stopped.get() || buffer.len() >= self.inner.max_buffer.get() {
+            log::error!("Mqtt sink is disconnected");
+            Err(SendPacketError::Disconnected)
+        } else {
+            log::trace!("Buffer publish to {:?}", self.topic);
+            buffer.push_back(Buffered {
+                tx,
+                topic: self.topic,
+                payload: self.payload,
+                retain: self.retain,
+            });
+            Ok(())
+        }
+    }
+
+    /// Send publish packet with QoS 0
+    ///
+    /// Packet is buffered if client is offline
+    pub fn send_at_most_once(self) -> Result<(), SendPacketError> {
+        if self.inner.is_online() {
+            let mut builder = self.inner.sink.borrow().publish(self.topic, self.payload);
+            if self.retain {
+                builder = builder.retain();
+            }
+            builder.send_at_most_once()
+        } else {
+            self.buffer(None)
+        }
+    }
+
+    /// Send publish packet with QoS 1
+    ///
+    /// Packet is buffered if client is offline
+    pub fn send_at_least_once(self) -> impl Future<Output = Result<(), SendPacketError>> {
+        if self.inner.is_online() {
+            let mut builder = self.inner.sink.borrow().publish(self.topic, self.payload);
+            if self.retain {
+                builder = builder.retain();
+            }
+            Either::Left(builder.send_at_least_once())
+        } else {
+            let (tx, rx) = oneshot::channel();
+            match self.buffer(Some(tx)) {
+                Ok(_) => Either::Right(Either::Left(async move {
+                    rx.await.unwrap_or(Err(SendPacketError::Disconnected))
+                })),
+                Err(err) => Either::Right(Either::Right(Ready::Err(err))),
+            }
+        }
+    }
+}
+
+/// Subscribe packet builder of managed sink
+pub struct ManagedSubscribeBuilder {
+    inner: Rc<Inner>,
+    topic_filters: Vec<(ByteString, codec::QoS)>,
+}
+
+impl ManagedSubscribeBuilder {
+    /// Add topic filter
+    pub fn topic_filter(mut self, filter: ByteString, qos: codec::QoS) -> Self {
+        self.topic_filters.push((filter, qos));
+        self
+    }
+
+    /// Send subscribe packet
+    pub async fn send(self) -> Result<Vec<codec::SubscribeReturnCode>, SendPacketError> {
+        let mut builder = self.inner.sink.borrow().subscribe();
+        for (filter, qos) in &self.topic_filters {
+            builder = builder.topic_filter(filter.clone(), *qos);
+        }
+        let codes = builder.send().await?;
+
+        // store granted subscriptions
+        let mut subs = self.inner.subscriptions.borrow_mut();
+        for ((filter, qos), code) in self.topic_filters.into_iter().zip(codes.iter()) {
+            if let codec::SubscribeReturnCode::Success(_) = code {
+                subs.retain(|(f, _)| *f != filter);
+                subs.push((filter, qos));
+            }
+        }
+        Ok(codes)
+    }
+}
+
+/// Unsubscribe packet builder of managed sink
+pub struct ManagedUnsubscribeBuilder {
+    inner: Rc<Inner>,
+    topic_filters: Vec<ByteString>,
+}
+
+impl ManagedUnsubscribeBuilder {
+    /// Add topic filter
+    pub fn topic_filter(mut self, filter: ByteString) -> Self {
+        self.topic_filters.push(filter);
+        self
+    }
+
+    /// Send unsubscribe packet
+    pub async fn send(self) -> Result<(), SendPacketError> {
+        // subscriptions are not re-issued even if unsubscribe fails
+        self.inner.subscriptions.borrow_mut().retain(|(f, _)| !self.topic_filters.contains(f));
+
+        let mut builder = self.inner.sink.borrow().unsubscribe();
+        for filter in self.topic_filters {
+            builder = builder.topic_filter(filter);
+        }
+        builder.send().await
+    }
+}
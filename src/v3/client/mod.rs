@@ -3,11 +3,18 @@ mod connection;
 mod connector;
 pub mod control;
 mod dispatcher;
+mod managed;
 
 pub use self::connection::{Client, ClientRouter};
 pub use self::connector::MqttConnector;
 pub use self::control::{ControlMessage, ControlResult};
+pub use self::managed::{
+    ManagedClient, ManagedPublishBuilder, ManagedSink, ManagedSubscribeBuilder,
+    ManagedUnsubscribeBuilder,
+};
+
+pub use crate::backoff::Backoff;
 
 pub use crate::topic::Topic;
-pub use crate::types::QoS;
+pub use crate::types::{ConnectionState, QoS};
 pub use crate::v3::{codec, error, error::ClientError, sink::MqttSink};
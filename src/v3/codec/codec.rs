@@ -5,6 +5,7 @@ use ntex::util::{Buf, BytesMut};
 
 use super::{decode, encode, Packet, Publish};
 use crate::error::{DecodeError, EncodeError};
+use crate::stats::{frame_size, Stats};
 use crate::types::{FixedHeader, QoS};
 use crate::utils::decode_variable_length;
 
@@ -13,6 +14,7 @@ use crate::utils::decode_variable_length;
 pub struct Codec {
     state: Cell<DecodeState>,
     max_size: Cell<u32>,
+    stats: Option<Stats>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -24,7 +26,11 @@ enum DecodeState {
 impl Codec {
     /// Create `Codec` instance
     pub fn new() -> Self {
-        Codec { state: Cell::new(DecodeState::FrameHeader), max_size: Cell::new(0) }
+        Codec {
+            state: Cell::new(DecodeState::FrameHeader),
+            max_size: Cell::new(0),
+            stats: None,
+        }
     }
 
     /// Set max inbound frame size.
@@ -43,6 +49,12 @@ impl Codec {
     pub fn set_max_size(&self, size: u32) {
         self.max_size.set(size);
     }
+
+    /// Count decoded and encoded packets
+    pub(crate) fn with_stats(mut self, stats: Option<Stats>) -> Self {
+        self.stats = stats;
+        self
+    }
 }
 
 impl Default for Codec {
@@ -96,6 +108,9 @@ impl Decoder for Codec {
                     let packet_buf = src.split_to(fixed.remaining_length as usize);
                     let packet = decode::decode_packet(packet_buf.freeze(), fixed.first_byte)?;
                     self.state.set(DecodeState::FrameHeader);
+                    if let Some(ref stats) = self.stats {
+                        stats.received(fixed.first_byte, frame_size(fixed.remaining_length));
+                    }
                     src.reserve(2);
                     return Ok(Some(packet));
                 }
@@ -117,6 +132,9 @@ impl Encoder for Codec {
         let content_size = encode::get_encoded_size(&item);
         dst.reserve(content_size + 5);
         encode::encode(&item, dst, content_size as u32)?;
+        if let Some(ref stats) = self.stats {
+            stats.sent(item.packet_type(), frame_size(content_size as u32));
+        }
         Ok(())
     }
 }
@@ -3,10 +3,12 @@ use std::{convert::TryFrom, convert::TryInto, num::NonZeroU16};
 use ntex::util::{Buf, ByteString, Bytes};
 
 use crate::error::DecodeError;
-use crate::types::{packet_type, QoS, MQTT, MQTT_LEVEL_3, WILL_QOS_SHIFT};
+use crate::types::{
+    packet_type, QoS, MQISDP, MQTT, MQTT_LEVEL_3, MQTT_LEVEL_31, WILL_QOS_SHIFT,
+};
 use crate::utils::Decode;
 
-use super::packet::{Connect, LastWill, Packet, Publish, SubscribeReturnCode};
+use super::packet::{Connect, LastWill, Packet, ProtocolLevel, Publish, SubscribeReturnCode};
 use super::{ConnectAckFlags, ConnectFlags};
 
 pub(crate) fn decode_packet(mut src: Bytes, first_byte: u8) -> Result<Packet, DecodeError> {
@@ -48,13 +50,27 @@ fn decode_ack(mut src: Bytes, f: impl Fn(NonZeroU16) -> Packet) -> Result<Packet
 
 fn decode_connect_packet(src: &mut Bytes) -> Result<Packet, DecodeError> {
     ensure!(src.remaining() >= 10, DecodeError::InvalidLength);
-    let len = src.get_u16();
+    let len = src.get_u16() as usize;
 
-    ensure!(len == 4 && &src.as_ref()[0..4] == MQTT, DecodeError::InvalidProtocol);
-    src.advance(4);
+    let protocol = if len == 4 && &src.as_ref()[0..4] == MQTT {
+        ProtocolLevel::MQTT311
+    } else if len == 6 && &src.as_ref()[0..6] == MQISDP {
+        ProtocolLevel::MQTT31
+    } else {
+        return Err(DecodeError::InvalidProtocol);
+    };
+    src.advance(len);
+    ensure!(src.remaining() >= 4, DecodeError::InvalidLength);
 
     let level = src.get_u8();
-    ensure!(level == MQTT_LEVEL_3, DecodeError::UnsupportedProtocolLevel);
+    match protocol {
+        ProtocolLevel::MQTT311 => {
+            ensure!(level == MQTT_LEVEL_3, DecodeError::UnsupportedProtocolLevel)
+        }
+        ProtocolLevel::MQTT31 => {
+            ensure!(level == MQTT_LEVEL_31, DecodeError::UnsupportedProtocolLevel)
+        }
+    }
 
     let flags =
         ConnectFlags::from_bits(src.get_u8()).ok_or(DecodeError::ConnectReservedFlagSet)?;
@@ -62,8 +78,11 @@ fn decode_connect_packet(src: &mut Bytes) -> Result<Packet, DecodeError> {
     let keep_alive = u16::decode(src)?;
     let client_id = ByteString::decode(src)?;
 
+    // mqtt 3.1 client id is checked by server handshake
     ensure!(
-        !client_id.is_empty() || flags.contains(ConnectFlags::CLEAN_START),
+        protocol == ProtocolLevel::MQTT31
+            || !client_id.is_empty()
+            || flags.contains(ConnectFlags::CLEAN_START),
         DecodeError::InvalidClientId
     );
 
@@ -87,6 +106,7 @@ fn decode_connect_packet(src: &mut Bytes) -> Result<Packet, DecodeError> {
     let password =
         if flags.contains(ConnectFlags::PASSWORD) { Some(Bytes::decode(src)?) } else { None };
     Ok(Connect {
+        protocol,
         clean_session: flags.contains(ConnectFlags::CLEAN_START),
         keep_alive,
         client_id,
@@ -189,6 +209,7 @@ mod tests {
                 b"\x00\x04MQTT\x04\xC0\x00\x3C\x00\x0512345\x00\x04user\x00\x04pass"
             )),
             Ok(Packet::Connect(Box::new(Connect {
+                protocol: ProtocolLevel::MQTT311,
                 clean_session: false,
                 keep_alive: 60,
                 client_id: ByteString::try_from(Bytes::from_static(b"12345")).unwrap(),
@@ -203,6 +224,7 @@ mod tests {
                 b"\x00\x04MQTT\x04\x14\x00\x3C\x00\x0512345\x00\x05topic\x00\x07message"
             )),
             Ok(Packet::Connect(Box::new(Connect {
+                protocol: ProtocolLevel::MQTT311,
                 clean_session: false,
                 keep_alive: 60,
                 client_id: ByteString::try_from(Bytes::from_static(b"12345")).unwrap(),
@@ -217,6 +239,40 @@ mod tests {
             })))
         );
 
+        assert_eq!(
+            decode_connect_packet(&mut Bytes::from_static(
+                b"\x00\x06MQIsdp\x03\x02\x00\x3C\x00\x0512345"
+            )),
+            Ok(Packet::Connect(Box::new(Connect {
+                protocol: ProtocolLevel::MQTT31,
+                clean_session: true,
+                keep_alive: 60,
+                client_id: ByteString::from_static("12345"),
+                last_will: None,
+                username: None,
+                password: None,
+            })))
+        );
+        assert_eq!(
+            decode_connect_packet(&mut Bytes::from_static(
+                b"\x00\x06MQIsdp\x04\x02\x00\x3C\x00\x0512345"
+            )),
+            Err(DecodeError::UnsupportedProtocolLevel),
+        );
+        // mqtt 3.1 client id is checked by server handshake
+        assert!(std::matches!(
+            decode_connect_packet(&mut Bytes::from_static(
+                b"\x00\x06MQIsdp\x03\x00\x00\x3C\x00\x00"
+            )),
+            Ok(Packet::Connect(ref pkt)) if pkt.client_id.is_empty()
+        ));
+        assert!(std::matches!(
+            decode_connect_packet(&mut Bytes::from_static(
+                b"\x00\x06MQIsdp\x03\x02\x00\x3C\x00\x18012345678901234567890123"
+            )),
+            Ok(Packet::Connect(ref pkt)) if pkt.client_id.len() == 24
+        ));
+
         assert_eq!(
             decode_connect_packet(&mut Bytes::from_static(b"\x00\x02MQ00000000000000000000")),
             Err(DecodeError::InvalidProtocol),
@@ -1,7 +1,9 @@
 use ntex::util::{BufMut, BytesMut};
 
 use crate::error::EncodeError;
-use crate::types::{packet_type, ConnectFlags, QoS, MQTT, MQTT_LEVEL_3, WILL_QOS_SHIFT};
+use crate::types::{
+    packet_type, ConnectFlags, QoS, MQISDP, MQTT, MQTT_LEVEL_3, MQTT_LEVEL_31, WILL_QOS_SHIFT,
+};
 use crate::utils::{write_variable_length, Encode};
 
 use super::packet::*;
@@ -9,10 +11,13 @@ use super::packet::*;
 pub(crate) fn get_encoded_size(packet: &Packet) -> usize {
     match *packet {
         Packet::Connect ( ref connect ) => {
-            let Connect {ref last_will, ref client_id, ref username, ref password, ..} = **connect;
+            let Connect {protocol, ref last_will, ref client_id, ref username, ref password, ..} = **connect;
 
             // Protocol Name + Protocol Level + Connect Flags + Keep Alive
             let mut n = 2 + 4 + 1 + 1 + 2;
+            if protocol == ProtocolLevel::MQTT31 {
+                n += 2;
+            }
 
             // Client Id
             n += 2 + client_id.len();
@@ -164,6 +169,7 @@ pub(crate) fn encode(
 
 fn encode_connect(connect: &Connect, dst: &mut BytesMut) -> Result<(), EncodeError> {
     let Connect {
+        protocol,
         clean_session,
         keep_alive,
         ref last_will,
@@ -172,7 +178,11 @@ fn encode_connect(connect: &Connect, dst: &mut BytesMut) -> Result<(), EncodeErr
         ref password,
     } = *connect;
 
-    MQTT.as_ref().encode(dst)?;
+    let (name, level) = match protocol {
+        ProtocolLevel::MQTT311 => (MQTT, MQTT_LEVEL_3),
+        ProtocolLevel::MQTT31 => (MQISDP, MQTT_LEVEL_31),
+    };
+    name.encode(dst)?;
 
     let mut flags = ConnectFlags::empty();
 
@@ -199,7 +209,7 @@ fn encode_connect(connect: &Connect, dst: &mut BytesMut) -> Result<(), EncodeErr
         flags |= ConnectFlags::CLEAN_START;
     }
 
-    dst.put_slice(&[MQTT_LEVEL_3, flags.bits()]);
+    dst.put_slice(&[level, flags.bits()]);
     dst.put_u16(keep_alive);
     client_id.encode(dst)?;
 
@@ -265,6 +275,7 @@ mod tests {
     fn test_encode_connect_packets() {
         assert_encode_packet(
             &Packet::Connect(Box::new(Connect {
+                protocol: ProtocolLevel::MQTT311,
                 clean_session: false,
                 keep_alive: 60,
                 client_id: ByteString::from_static("12345"),
@@ -278,6 +289,7 @@ mod tests {
 
         assert_encode_packet(
             &Packet::Connect(Box::new(Connect {
+                protocol: ProtocolLevel::MQTT311,
                 clean_session: false,
                 keep_alive: 60,
                 client_id: ByteString::from_static("12345"),
@@ -294,6 +306,19 @@ mod tests {
 \x0512345\x00\x05topic\x00\x07message"[..],
         );
 
+        assert_encode_packet(
+            &Packet::Connect(Box::new(Connect {
+                protocol: ProtocolLevel::MQTT31,
+                clean_session: true,
+                keep_alive: 60,
+                client_id: ByteString::from_static("12345"),
+                last_will: None,
+                username: None,
+                password: None,
+            })),
+            &b"\x10\x13\x00\x06MQIsdp\x03\x02\x00\x3C\x00\x0512345"[..],
+        );
+
         assert_encode_packet(&Packet::Disconnect, b"\xe0\x00");
     }
 
@@ -1,4 +1,4 @@
-//! MQTT v3.1.1 Protocol codec
+//! MQTT v3.1 and v3.1.1 Protocol codec
 
 #[allow(clippy::module_inception)]
 mod codec;
@@ -8,7 +8,7 @@ mod packet;
 
 pub use self::codec::Codec;
 pub use self::packet::{
-    Connect, ConnectAckReason, LastWill, Packet, Publish, SubscribeReturnCode,
+    Connect, ConnectAckReason, LastWill, Packet, ProtocolLevel, Publish, SubscribeReturnCode,
 };
 pub use crate::topic::{Level, Topic, TopicError};
 pub use crate::types::{ConnectAckFlags, ConnectFlags, QoS};
@@ -55,9 +55,28 @@ pub struct LastWill {
     pub message: Bytes,
 }
 
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+/// Protocol name and level
+pub enum ProtocolLevel {
+    /// MQTT 3.1, protocol name `MQIsdp`, level 3
+    MQTT31,
+    /// MQTT 3.1.1, protocol name `MQTT`, level 4
+    MQTT311,
+}
+
+// `#[default]` enum variant attribute requires rust 1.62
+#[allow(clippy::derivable_impls)]
+impl Default for ProtocolLevel {
+    fn default() -> Self {
+        ProtocolLevel::MQTT311
+    }
+}
+
 #[derive(Default, Debug, PartialEq, Clone)]
 /// Connect packet content
 pub struct Connect {
+    /// protocol name and level
+    pub protocol: ProtocolLevel,
     /// the handling of the Session state.
     pub clean_session: bool,
     /// a time interval measured in seconds.
@@ -1,8 +1,8 @@
 use ntex::util::ByteString;
-use std::{marker::PhantomData, num::NonZeroU16};
+use std::{marker::PhantomData, num::NonZeroU16, rc::Rc};
 
 use super::codec;
-use crate::{error, types::QoS};
+use crate::{error, info::ConnectionInfo, types::QoS};
 
 #[derive(Debug)]
 pub enum ControlMessage<E> {
@@ -31,6 +31,7 @@ pub struct ControlResult {
 pub(crate) enum ControlResultKind {
     Nothing,
     PublishAck(NonZeroU16),
+    PublishReceived(NonZeroU16),
     Ping,
     Disconnect,
     Subscribe(SubscribeResult),
@@ -47,8 +48,8 @@ impl<E> ControlMessage<E> {
         ControlMessage::Disconnect(Disconnect)
     }
 
-    pub(crate) fn closed(is_error: bool) -> Self {
-        ControlMessage::Closed(Closed::new(is_error))
+    pub(crate) fn closed(is_error: bool, info: Option<Rc<ConnectionInfo>>) -> Self {
+        ControlMessage::Closed(Closed::new(is_error, info))
     }
 
     pub(super) fn error(err: E) -> Self {
@@ -305,11 +306,12 @@ impl Unsubscribe {
 #[derive(Debug)]
 pub struct Closed {
     is_error: bool,
+    info: Option<Rc<ConnectionInfo>>,
 }
 
 impl Closed {
-    pub(crate) fn new(is_error: bool) -> Self {
-        Self { is_error }
+    pub(crate) fn new(is_error: bool, info: Option<Rc<ConnectionInfo>>) -> Self {
+        Self { is_error, info }
     }
 
     /// Returns error state on connection close
@@ -317,6 +319,11 @@ impl Closed {
         self.is_error
     }
 
+    /// Returns peer connection info, available for server connections
+    pub fn connection_info(&self) -> Option<&ConnectionInfo> {
+        self.info.as_deref()
+    }
+
     #[inline]
     /// convert packet to a result
     pub fn ack(self) -> ControlResult {
@@ -3,10 +3,13 @@ use std::task::{Context, Poll};
 use std::{future::Future, marker::PhantomData, num::NonZeroU16, pin::Pin, rc::Rc};
 
 use ntex::service::{fn_factory_with_config, Service, ServiceFactory};
-use ntex::util::{inflight::InFlightService, join, Either, HashSet, Ready};
+use ntex::util::{inflight::InFlightService, join, ByteString, Either, HashSet, Ready};
 
 use crate::error::{MqttError, ProtocolError};
-use crate::io::DispatchItem;
+use crate::registry::ClientRegistry;
+use crate::stats::Stats;
+use crate::will::{ConnectWill, WillManager};
+use crate::{io::DispatchItem, types::QoS};
 
 use super::control::{
     ControlMessage, ControlResult, ControlResultKind, Subscribe, Unsubscribe,
@@ -19,6 +22,9 @@ pub(super) fn factory<St, T, C, E>(
     publish: T,
     control: C,
     inflight: usize,
+    will: Option<WillManager>,
+    registry: Option<ClientRegistry>,
+    stats: Option<Stats>,
 ) -> impl ServiceFactory<
     Config = Session<St>,
     Request = DispatchItem<Rc<MqttShared>>,
@@ -48,14 +54,31 @@ where
         // create services
         let fut = join(publish.new_service(cfg.clone()), control.new_service(cfg.clone()));
 
+        // connection's last will
+        let connect = cfg.sink().take_will();
+        let registry = registry
+            .clone()
+            .and_then(|registry| connect.as_ref().map(|c| (registry, c.client_id.clone())));
+        let will = connect.and_then(|mut connect| {
+            will.clone().map(|manager| {
+                manager.connected(&mut connect);
+                (manager, connect)
+            })
+        });
+        let stats = stats.clone();
+
         async move {
             let (publish, control) = fut.await;
+            let (publish, control) = (publish?, control?);
+            if let Some(ref stats) = stats {
+                stats.connection_opened();
+            }
 
             Ok(
                 // limit number of in-flight messages
                 InFlightService::new(
                     inflight,
-                    Dispatcher::<_, _, _, E>::new(cfg, publish?, control?),
+                    Dispatcher::<_, _, _, E>::new(cfg, publish, control, will, registry, stats),
                 ),
             )
         }
@@ -67,6 +90,9 @@ pub(crate) struct Dispatcher<St, T, C, E> {
     session: Session<St>,
     publish: T,
     shutdown: Cell<bool>,
+    will: RefCell<Option<(WillManager, ConnectWill)>>,
+    registry: Option<(ClientRegistry, ByteString)>,
+    stats: Option<Stats>,
     inner: Rc<Inner<C>>,
     _t: PhantomData<(E,)>,
 }
@@ -75,6 +101,8 @@ struct Inner<C> {
     control: C,
     sink: MqttSink,
     inflight: RefCell<HashSet<NonZeroU16>>,
+    // qos2 publishes acked with PUBREC, waiting for PUBREL
+    await_release: RefCell<HashSet<NonZeroU16>>,
 }
 
 impl<St, T, C, E> Dispatcher<St, T, C, E>
@@ -82,14 +110,29 @@ where
     T: Service<Request = Publish, Response = (), Error = E>,
     C: Service<Request = ControlMessage<E>, Response = ControlResult, Error = E>,
 {
-    pub(crate) fn new(session: Session<St>, publish: T, control: C) -> Self {
+    pub(crate) fn new(
+        session: Session<St>,
+        publish: T,
+        control: C,
+        will: Option<(WillManager, ConnectWill)>,
+        registry: Option<(ClientRegistry, ByteString)>,
+        stats: Option<Stats>,
+    ) -> Self {
         let sink = session.sink().clone();
 
         Self {
             session,
             publish,
             shutdown: Cell::new(false),
-            inner: Rc::new(Inner { sink, control, inflight: RefCell::new(HashSet::default()) }),
+            will: RefCell::new(will),
+            registry,
+            stats,
+            inner: Rc::new(Inner {
+                sink,
+                control,
+                inflight: RefCell::new(HashSet::default()),
+                await_release: RefCell::new(HashSet::default()),
+            }),
             _t: PhantomData,
         }
     }
@@ -123,9 +166,24 @@ where
 
     fn poll_shutdown(&self, _: &mut Context<'_>, is_error: bool) -> Poll<()> {
         if !self.shutdown.get() {
-            self.inner.sink.close();
+            self.inner.sink.drop_sink();
             self.shutdown.set(true);
-            let fut = self.inner.control.call(ControlMessage::closed(is_error));
+
+            // will message is discarded by DISCONNECT packet
+            if let Some((manager, will)) = self.will.borrow_mut().take() {
+                manager.closed(will);
+            }
+            if let Some((ref registry, ref client_id)) = self.registry {
+                registry.unregister_v3(client_id, &self.inner.sink);
+            }
+            if let Some(ref stats) = self.stats {
+                stats.connection_closed();
+            }
+
+            let fut = self
+                .inner
+                .control
+                .call(ControlMessage::closed(is_error, self.inner.sink.connection_info()));
             ntex::rt::spawn(async move {
                 let _ = fut.await;
             });
@@ -140,9 +198,18 @@ where
             DispatchItem::Item(codec::Packet::Publish(publish)) => {
                 let inner = self.inner.clone();
                 let packet_id = publish.packet_id;
+                let qos = publish.qos;
 
-                // check for duplicated packet id
                 if let Some(pid) = packet_id {
+                    // qos2 publish is already received, peer did not get PUBREC
+                    if qos == QoS::ExactlyOnce && inner.await_release.borrow().contains(&pid) {
+                        log::trace!("Re-delivered qos2 publish packet: {:?}", pid);
+                        return Either::Right(Either::Left(Ready::Ok(Some(
+                            codec::Packet::PublishReceived { packet_id: pid },
+                        ))));
+                    }
+
+                    // check for duplicated packet id
                     if !inner.inflight.borrow_mut().insert(pid) {
                         log::trace!("Duplicated packet id for publish packet: {:?}", pid);
                         return Either::Right(Either::Right(ControlResponse::new(
@@ -152,6 +219,7 @@ where
                     }
                 }
                 Either::Left(PublishResponse {
+                    qos,
                     packet_id,
                     inner,
                     state: PublishResponseState::Publish {
@@ -169,6 +237,40 @@ where
                     Either::Right(Either::Left(Ready::Ok(None)))
                 }
             }
+            DispatchItem::Item(codec::Packet::PublishReceived { packet_id }) => {
+                if let Err(e) = self.session.sink().pkt_ack(Ack::Receive(packet_id)) {
+                    Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(e),
+                        &self.inner,
+                    )))
+                } else {
+                    Either::Right(Either::Left(Ready::Ok(None)))
+                }
+            }
+            DispatchItem::Item(codec::Packet::PublishComplete { packet_id }) => {
+                if let Err(e) = self.session.sink().pkt_ack(Ack::Complete(packet_id)) {
+                    Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(e),
+                        &self.inner,
+                    )))
+                } else {
+                    Either::Right(Either::Left(Ready::Ok(None)))
+                }
+            }
+            DispatchItem::Item(codec::Packet::PublishRelease { packet_id }) => {
+                if self.inner.await_release.borrow_mut().remove(&packet_id) {
+                    self.inner.inflight.borrow_mut().remove(&packet_id);
+                    Either::Right(Either::Left(Ready::Ok(Some(
+                        codec::Packet::PublishComplete { packet_id },
+                    ))))
+                } else {
+                    log::trace!("Unknown packet id for PUBREL: {:?}", packet_id);
+                    Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(ProtocolError::PacketIdMismatch),
+                        &self.inner,
+                    )))
+                }
+            }
             DispatchItem::Item(codec::Packet::PingRequest) => Either::Right(Either::Right(
                 ControlResponse::new(ControlMessage::ping(), &self.inner),
             )),
@@ -198,9 +300,16 @@ where
                     &self.inner,
                 )))
             }
-            DispatchItem::Item(codec::Packet::Disconnect) => Either::Right(Either::Right(
-                ControlResponse::new(ControlMessage::pkt_disconnect(), &self.inner),
-            )),
+            DispatchItem::Item(codec::Packet::Disconnect) => {
+                // normal disconnect, discard last will
+                if let Some((_, ref mut will)) = *self.will.borrow_mut() {
+                    will.message = None;
+                }
+                Either::Right(Either::Right(ControlResponse::new(
+                    ControlMessage::pkt_disconnect(),
+                    &self.inner,
+                )))
+            }
             DispatchItem::Item(_) => Either::Right(Either::Left(Ready::Ok(None))),
             DispatchItem::EncoderError(err) => {
                 Either::Right(Either::Right(ControlResponse::new(
@@ -237,6 +346,7 @@ pin_project_lite::pin_project! {
         #[pin]
         state: PublishResponseState<T, C, E>,
         packet_id: Option<NonZeroU16>,
+        qos: QoS,
         inner: Rc<Inner<C>>,
     }
 }
@@ -265,10 +375,17 @@ where
                     log::trace!("Publish result for packet {:?} is ready", this.packet_id);
 
                     if let Some(packet_id) = this.packet_id {
-                        this.inner.inflight.borrow_mut().remove(packet_id);
-                        Poll::Ready(Ok(Some(codec::Packet::PublishAck {
-                            packet_id: *packet_id,
-                        })))
+                        if *this.qos == QoS::ExactlyOnce {
+                            this.inner.await_release.borrow_mut().insert(*packet_id);
+                            Poll::Ready(Ok(Some(codec::Packet::PublishReceived {
+                                packet_id: *packet_id,
+                            })))
+                        } else {
+                            this.inner.inflight.borrow_mut().remove(packet_id);
+                            Poll::Ready(Ok(Some(codec::Packet::PublishAck {
+                                packet_id: *packet_id,
+                            })))
+                        }
                     } else {
                         Poll::Ready(Ok(None))
                     }
@@ -340,10 +457,13 @@ where
                     ControlResultKind::Disconnect
                     | ControlResultKind::Closed
                     | ControlResultKind::Nothing => {
-                        this.inner.sink.close();
+                        this.inner.sink.drop_sink();
                         None
                     }
-                    ControlResultKind::PublishAck(_) => unreachable!(),
+                    ControlResultKind::PublishAck(_)
+                    | ControlResultKind::PublishReceived(_) => {
+                        unreachable!()
+                    }
                 };
                 Poll::Ready(Ok(packet))
             }
@@ -5,19 +5,36 @@ use ntex::time::Seconds;
 use super::codec as mqtt;
 use super::shared::MqttShared;
 use super::sink::MqttSink;
+use crate::cert::CertIdentity;
+use crate::info::{ConnectionInfo, Protocol};
+use crate::{proxy::ProxyHeader, will::ConnectWill};
 
 /// Connect message
 pub struct Handshake<Io> {
     io: Io,
     pkt: Box<mqtt::Connect>,
     shared: Rc<MqttShared>,
+    info: Rc<ConnectionInfo>,
 }
 
-impl<Io> Handshake<Io> {
-    pub(crate) fn new(pkt: Box<mqtt::Connect>, io: Io, shared: Rc<MqttShared>) -> Self {
-        Self { io, pkt, shared }
+impl<Io: 'static> Handshake<Io> {
+    pub(crate) fn new(
+        pkt: Box<mqtt::Connect>,
+        io: Io,
+        shared: Rc<MqttShared>,
+        proxy: Option<ProxyHeader>,
+    ) -> Self {
+        let protocol = match pkt.protocol {
+            mqtt::ProtocolLevel::MQTT31 => Protocol::MQTT31,
+            mqtt::ProtocolLevel::MQTT311 => Protocol::MQTT311,
+        };
+        let info = Rc::new(ConnectionInfo::new(&io, protocol, proxy));
+        *shared.info.borrow_mut() = Some(info.clone());
+        Self { io, pkt, shared, info }
     }
+}
 
+impl<Io> Handshake<Io> {
     pub fn packet(&self) -> &mqtt::Connect {
         &self.pkt
     }
@@ -26,6 +43,29 @@ impl<Io> Handshake<Io> {
         &mut self.pkt
     }
 
+    /// Returns PROXY protocol header
+    ///
+    /// Header is available if PROXY protocol is enabled with
+    /// `MqttServer::proxy_protocol()`.
+    pub fn proxy_header(&self) -> Option<&ProxyHeader> {
+        self.info.proxy_header()
+    }
+
+    /// Returns peer connection info
+    pub fn connection_info(&self) -> &ConnectionInfo {
+        &self.info
+    }
+
+    /// Returns identity of the peer certificate, available for tls connections
+    pub fn peer_identity(&self) -> Option<&CertIdentity> {
+        self.info.peer_identity()
+    }
+
+    /// Returns protocol level requested by client
+    pub fn protocol(&self) -> mqtt::ProtocolLevel {
+        self.pkt.protocol
+    }
+
     #[inline]
     pub fn io(&mut self) -> &mut Io {
         &mut self.io
@@ -38,6 +78,8 @@ impl<Io> Handshake<Io> {
 
     /// Ack handshake message and set state
     pub fn ack<St>(self, st: St, session_present: bool) -> HandshakeAck<Io, St> {
+        *self.shared.client_id.borrow_mut() = Some(self.pkt.client_id.clone());
+        *self.shared.will.borrow_mut() = Some(ConnectWill::from_v3(&self.pkt));
         HandshakeAck {
             session_present,
             io: self.io,
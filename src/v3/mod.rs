@@ -1,4 +1,4 @@
-//! MQTT 3.1.1 Client/Server framework
+//! MQTT 3.1 and 3.1.1 Client/Server framework
 
 pub mod client;
 pub mod codec;
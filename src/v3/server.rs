@@ -8,7 +8,11 @@ use ntex::util::{timeout::Timeout, timeout::TimeoutError, Either, Ready};
 
 use crate::error::{MqttError, ProtocolError};
 use crate::io::{DispatchItem, Dispatcher, State, Timer};
+use crate::proxy::ProxyHeader;
+use crate::registry::ClientRegistry;
 use crate::service::{FramedService, FramedService2};
+use crate::stats::Stats;
+use crate::will::WillManager;
 
 use super::control::{ControlMessage, ControlResult};
 use super::default::{DefaultControlService, DefaultPublishService};
@@ -26,6 +30,9 @@ pub struct MqttServer<Io, St, C: ServiceFactory, Cn: ServiceFactory, P: ServiceF
     inflight: usize,
     handshake_timeout: Seconds,
     disconnect_timeout: Seconds,
+    will: Option<WillManager>,
+    registry: Option<ClientRegistry>,
+    pub(super) stats: Option<Stats>,
     pub(super) pool: Rc<MqttSinkPool>,
     _t: PhantomData<(Io, St)>,
 }
@@ -57,6 +64,9 @@ where
             inflight: 16,
             handshake_timeout: Seconds::ZERO,
             disconnect_timeout: Seconds(3),
+            will: None,
+            registry: None,
+            stats: None,
             pool: Default::default(),
             _t: PhantomData,
         }
@@ -121,6 +131,32 @@ where
         self
     }
 
+    /// Set last will manager
+    ///
+    /// Will of connection that is closed without DISCONNECT packet
+    /// is handed to the manager. By default last will is ignored.
+    pub fn will_manager(mut self, manager: WillManager) -> Self {
+        self.will = Some(manager);
+        self
+    }
+
+    /// Set connected clients registry
+    ///
+    /// Connection with the same client id as new connection get closed
+    /// before new connection is acknowledged.
+    pub fn client_registry(mut self, registry: ClientRegistry) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    /// Set statistics collector
+    ///
+    /// Collector counts connections, packets and bytes of the server.
+    pub fn stats(mut self, stats: Stats) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
     /// Service to handle control packets
     ///
     /// All control packets are processed sequentially, max buffered
@@ -143,6 +179,9 @@ where
             inflight: self.inflight,
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
+            will: self.will,
+            registry: self.registry,
+            stats: self.stats,
             pool: self.pool,
             _t: PhantomData,
         }
@@ -163,6 +202,9 @@ where
             inflight: self.inflight,
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
+            will: self.will,
+            registry: self.registry,
+            stats: self.stats,
             pool: self.pool,
             _t: PhantomData,
         }
@@ -187,9 +229,11 @@ where
                 handshake,
                 self.max_size,
                 self.handshake_timeout,
+                self.registry.clone(),
+                self.stats.clone(),
                 self.pool,
             ),
-            factory(publish, control, self.inflight),
+            factory(publish, control, self.inflight, self.will, self.registry, self.stats),
             self.disconnect_timeout,
         )
     }
@@ -199,7 +243,7 @@ where
         self,
     ) -> impl ServiceFactory<
         Config = (),
-        Request = (Io, State, Option<Sleep>),
+        Request = (Io, State, Option<Sleep>, Option<ProxyHeader>),
         Response = (),
         Error = MqttError<C::Error>,
         InitError = C::InitError,
@@ -218,9 +262,11 @@ where
                 handshake,
                 self.max_size,
                 self.handshake_timeout,
+                self.registry.clone(),
+                self.stats.clone(),
                 self.pool,
             ),
-            factory(publish, control, self.inflight),
+            factory(publish, control, self.inflight, self.will, self.registry, self.stats),
             self.disconnect_timeout,
         )
     }
@@ -248,10 +294,18 @@ where
         ServerSelector {
             check: Rc::new(check),
             connect: self.handshake,
-            handler: Rc::new(factory(publish, control, self.inflight)),
+            handler: Rc::new(factory(
+                publish,
+                control,
+                self.inflight,
+                self.will,
+                self.registry.clone(),
+                self.stats,
+            )),
             max_size: self.max_size,
             disconnect_timeout: self.disconnect_timeout,
             time: Timer::new(Millis::ONE_SEC),
+            registry: self.registry,
             _t: PhantomData,
         }
     }
@@ -261,6 +315,8 @@ fn handshake_service_factory<Io, St, C>(
     factory: C,
     max_size: u32,
     handshake_timeout: Seconds,
+    registry: Option<ClientRegistry>,
+    stats: Option<Stats>,
     pool: Rc<MqttSinkPool>,
 ) -> impl ServiceFactory<
     Config = (),
@@ -269,7 +325,7 @@ fn handshake_service_factory<Io, St, C>(
     Error = MqttError<C::Error>,
 >
 where
-    Io: AsyncRead + AsyncWrite + Unpin,
+    Io: AsyncRead + AsyncWrite + Unpin + 'static,
     C: ServiceFactory<Config = (), Request = Handshake<Io>, Response = HandshakeAck<Io, St>>,
     C::Error: fmt::Debug,
 {
@@ -277,6 +333,8 @@ where
         Timeout::new(Millis::from(handshake_timeout)),
         ntex::service::fn_factory(move || {
             let pool = pool.clone();
+            let registry = registry.clone();
+            let stats = stats.clone();
             let fut = factory.new_service(());
             async move {
                 let service = fut.await?;
@@ -285,7 +343,16 @@ where
                 Ok::<_, C::InitError>(ntex::service::apply_fn(
                     service,
                     move |conn: Io, service| {
-                        handshake(conn, None, service.clone(), max_size, pool.clone())
+                        handshake(
+                            conn,
+                            None,
+                            None,
+                            service.clone(),
+                            max_size,
+                            registry.clone(),
+                            stats.clone(),
+                            pool.clone(),
+                        )
                     },
                 ))
             }
@@ -301,16 +368,18 @@ fn handshake_service_factory2<Io, St, C>(
     factory: C,
     max_size: u32,
     handshake_timeout: Seconds,
+    registry: Option<ClientRegistry>,
+    stats: Option<Stats>,
     pool: Rc<MqttSinkPool>,
 ) -> impl ServiceFactory<
     Config = (),
-    Request = (Io, State),
+    Request = (Io, State, Option<ProxyHeader>),
     Response = (Io, State, Rc<MqttShared>, Session<St>, Seconds),
     Error = MqttError<C::Error>,
     InitError = C::InitError,
 >
 where
-    Io: AsyncRead + AsyncWrite + Unpin,
+    Io: AsyncRead + AsyncWrite + Unpin + 'static,
     C: ServiceFactory<Config = (), Request = Handshake<Io>, Response = HandshakeAck<Io, St>>,
     C::Error: fmt::Debug,
 {
@@ -318,13 +387,24 @@ where
         Timeout::new(Millis::from(handshake_timeout)),
         ntex::service::fn_factory(move || {
             let pool = pool.clone();
+            let registry = registry.clone();
+            let stats = stats.clone();
             let fut = factory.new_service(());
             async move {
                 let service = fut.await?;
                 let pool = pool.clone();
                 let service = Rc::new(service.map_err(MqttError::Service));
-                Ok(ntex::service::apply_fn(service, move |(io, state), service| {
-                    handshake(io, Some(state), service.clone(), max_size, pool.clone())
+                Ok(ntex::service::apply_fn(service, move |(io, state, proxy), service| {
+                    handshake(
+                        io,
+                        Some(state),
+                        proxy,
+                        service.clone(),
+                        max_size,
+                        registry.clone(),
+                        stats.clone(),
+                        pool.clone(),
+                    )
                 }))
             }
         }),
@@ -335,15 +415,25 @@ where
     })
 }
 
+/// MQTT 3.1 client id is between 1 and 23 characters long
+fn is_valid_client_id(connect: &mqtt::Connect) -> bool {
+    connect.protocol != mqtt::ProtocolLevel::MQTT31
+        || (!connect.client_id.is_empty() && connect.client_id.chars().count() <= 23)
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn handshake<Io, S, St, E>(
     mut io: Io,
     state: Option<State>,
+    proxy: Option<ProxyHeader>,
     service: S,
     max_size: u32,
+    registry: Option<ClientRegistry>,
+    stats: Option<Stats>,
     pool: Rc<MqttSinkPool>,
 ) -> Result<(Io, State, Rc<MqttShared>, Session<St>, Seconds), S::Error>
 where
-    Io: AsyncRead + AsyncWrite + Unpin,
+    Io: AsyncRead + AsyncWrite + Unpin + 'static,
     S: Service<Request = Handshake<Io>, Response = HandshakeAck<Io, St>, Error = MqttError<E>>,
 {
     log::trace!("Starting mqtt handshake");
@@ -351,7 +441,7 @@ where
     let state = state.unwrap_or_else(State::new);
     let shared = Rc::new(MqttShared::new(
         state.clone(),
-        mqtt::Codec::default().max_size(max_size),
+        mqtt::Codec::default().max_size(max_size).with_stats(stats),
         16,
         pool,
     ));
@@ -373,26 +463,44 @@ where
 
     match packet {
         mqtt::Packet::Connect(connect) => {
-            // authenticate mqtt connection
-            let mut ack = service.call(Handshake::new(connect, io, shared)).await?;
+            // mqtt 3.1 has no session present flag
+            let mqtt31 = connect.protocol == mqtt::ProtocolLevel::MQTT31;
+            let id_valid = is_valid_client_id(&connect);
+
+            let hnd = Handshake::new(connect, io, shared, proxy);
+            let mut ack = if id_valid {
+                // authenticate mqtt connection
+                service.call(hnd).await?
+            } else {
+                log::trace!("MQTT 3.1 client id is rejected: {:?}", hnd.packet().client_id);
+                hnd.identifier_rejected()
+            };
 
             match ack.session {
                 Some(session) => {
                     let pkt = mqtt::Packet::ConnectAck {
-                        session_present: ack.session_present,
+                        session_present: ack.session_present && !mqtt31,
                         return_code: mqtt::ConnectAckReason::ConnectionAccepted,
                     };
 
                     log::trace!("Sending success handshake ack: {:#?}", pkt);
 
+                    // close previous connection of the client
+                    if let Some(ref registry) = registry {
+                        if let Some(client_id) = ack.shared.client_id() {
+                            registry.register_v3(client_id, MqttSink::new(ack.shared.clone()));
+                        }
+                    }
+
                     state.set_buffer_params(ack.read_hw, ack.write_hw, ack.lw);
                     state.send(&mut ack.io, &ack.shared.codec, pkt).await?;
 
+                    let info = ack.shared.info.borrow().clone();
                     Ok((
                         ack.io,
                         ack.shared.state.clone(),
                         ack.shared.clone(),
-                        Session::new(session, MqttSink::new(ack.shared)),
+                        Session::new(session, MqttSink::new(ack.shared), info),
                         ack.keepalive,
                     ))
                 }
@@ -426,6 +534,7 @@ pub(crate) struct ServerSelector<St, C, T, Io, F, R> {
     time: Timer,
     check: Rc<F>,
     max_size: u32,
+    registry: Option<ClientRegistry>,
     _t: PhantomData<(St, Io, R)>,
 }
 
@@ -460,6 +569,7 @@ where
         let time = self.time.clone();
         let check = self.check.clone();
         let max_size = self.max_size;
+        let registry = self.registry.clone();
 
         // create connect service and then create service impl
         Box::pin(async move {
@@ -469,6 +579,7 @@ where
                 time,
                 check,
                 max_size,
+                registry,
                 connect: Rc::new(fut.await?),
                 _t: PhantomData,
             })
@@ -483,6 +594,7 @@ pub(crate) struct ServerSelectorImpl<St, C, T, Io, F, R> {
     disconnect_timeout: Seconds,
     time: Timer,
     max_size: u32,
+    registry: Option<ClientRegistry>,
     _t: PhantomData<(St, Io, R)>,
 }
 
@@ -526,6 +638,7 @@ where
         let timeout = self.disconnect_timeout;
         let time = self.time.clone();
         let max_size = self.max_size;
+        let registry = self.registry.clone();
 
         Box::pin(async move {
             let (hnd, state, mut delay) = req;
@@ -543,8 +656,14 @@ where
             if !result.map_err(MqttError::Service)? {
                 Ok(Either::Left((hnd, state, delay)))
             } else {
+                // mqtt 3.1 has no session present flag
+                let mqtt31 = hnd.packet().protocol == mqtt::ProtocolLevel::MQTT31;
+
                 // authenticate mqtt connection
-                let mut ack = if let Some(ref mut delay) = delay {
+                let mut ack = if !is_valid_client_id(hnd.packet()) {
+                    log::trace!("MQTT 3.1 client id is rejected: {:?}", hnd.packet().client_id);
+                    hnd.identifier_rejected()
+                } else if let Some(ref mut delay) = delay {
                     let fut = connect.call(hnd);
                     match crate::utils::select(fut, delay).await {
                         Either::Left(res) => res.map_err(|e| {
@@ -563,7 +682,7 @@ where
                 match ack.session {
                     Some(session) => {
                         let pkt = mqtt::Packet::ConnectAck {
-                            session_present: ack.session_present,
+                            session_present: ack.session_present && !mqtt31,
                             return_code: mqtt::ConnectAckReason::ConnectionAccepted,
                         };
                         log::trace!(
@@ -571,6 +690,14 @@ where
                             pkt
                         );
 
+                        // close previous connection of the client
+                        if let Some(ref registry) = registry {
+                            if let Some(client_id) = ack.shared.client_id() {
+                                registry
+                                    .register_v3(client_id, MqttSink::new(ack.shared.clone()));
+                            }
+                        }
+
                         ack.shared.codec.set_max_size(max_size);
                         state.set_buffer_params(ack.read_hw, ack.write_hw, ack.lw);
                         state
@@ -578,7 +705,9 @@ where
                             .await
                             .map_err(MqttError::from)?;
 
-                        let session = Session::new(session, MqttSink::new(ack.shared.clone()));
+                        let info = ack.shared.info.borrow().clone();
+                        let session =
+                            Session::new(session, MqttSink::new(ack.shared.clone()), info);
                         let handler = handler.new_service(session).await?;
                         log::trace!("Connection handler is created, starting dispatcher");
 
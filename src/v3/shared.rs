@@ -2,20 +2,26 @@ use std::{cell::Cell, cell::RefCell, collections::VecDeque, num::NonZeroU16, rc:
 
 use ntex::channel::pool;
 use ntex::codec::{Decoder, Encoder};
-use ntex::util::{BytesMut, HashMap};
+use ntex::util::{ByteString, BytesMut, HashMap};
 
 use crate::error::{DecodeError, EncodeError};
-use crate::{io::State, types::packet_type, v3::codec};
+use crate::{
+    info::ConnectionInfo, io::State, types::packet_type, v3::codec, will::ConnectWill,
+};
 
 pub(super) enum Ack {
     Publish(NonZeroU16),
+    Receive(NonZeroU16),
+    Complete(NonZeroU16),
     Subscribe { packet_id: NonZeroU16, status: Vec<codec::SubscribeReturnCode> },
     Unsubscribe(NonZeroU16),
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Eq)]
 pub(super) enum AckType {
     Publish,
+    Receive,
+    Complete,
     Subscribe,
     Unsubscribe,
 }
@@ -35,6 +41,10 @@ pub(crate) struct MqttShared {
     pub(super) cap: Cell<usize>,
     queues: RefCell<MqttSharedQueues>,
     pub(super) inflight_idx: Cell<u16>,
+    pub(super) keep_inflight: Cell<bool>,
+    pub(super) client_id: RefCell<Option<ByteString>>,
+    pub(super) will: RefCell<Option<ConnectWill>>,
+    pub(super) info: RefCell<Option<Rc<ConnectionInfo>>>,
     pub(super) pool: Rc<MqttSinkPool>,
     pub(super) state: State,
     pub(super) codec: codec::Codec,
@@ -43,6 +53,7 @@ pub(crate) struct MqttShared {
 pub(super) struct MqttSharedQueues {
     pub(super) inflight: HashMap<u16, (pool::Sender<Ack>, AckType)>,
     pub(super) inflight_order: VecDeque<u16>,
+    pub(super) inflight_packets: HashMap<u16, codec::Publish>,
     pub(super) waiters: VecDeque<pool::Sender<()>>,
 }
 
@@ -61,19 +72,29 @@ impl MqttShared {
             queues: RefCell::new(MqttSharedQueues {
                 inflight: HashMap::default(),
                 inflight_order: VecDeque::with_capacity(8),
+                inflight_packets: HashMap::default(),
                 waiters: VecDeque::new(),
             }),
             inflight_idx: Cell::new(0),
+            keep_inflight: Cell::new(false),
+            client_id: RefCell::new(None),
+            will: RefCell::new(None),
+            info: RefCell::new(None),
         }
     }
 
+    /// Client id of accepted connection
+    pub(super) fn client_id(&self) -> Option<ByteString> {
+        self.client_id.borrow().clone()
+    }
+
     pub(super) fn with_queues<R>(&self, f: impl FnOnce(&mut MqttSharedQueues) -> R) -> R {
         let mut queues = self.queues.borrow_mut();
         f(&mut queues)
     }
 
     pub(super) fn has_credit(&self) -> bool {
-        self.cap.get() - self.queues.borrow().inflight.len() > 0
+        self.cap.get() > self.queues.borrow().inflight.len()
     }
 
     pub(super) fn next_id(&self) -> u16 {
@@ -111,6 +132,8 @@ impl Ack {
     pub(super) fn packet_type(&self) -> u8 {
         match self {
             Ack::Publish(_) => packet_type::PUBACK,
+            Ack::Receive(_) => packet_type::PUBREC,
+            Ack::Complete(_) => packet_type::PUBCOMP,
             Ack::Subscribe { .. } => packet_type::SUBACK,
             Ack::Unsubscribe(_) => packet_type::UNSUBACK,
         }
@@ -119,6 +142,8 @@ impl Ack {
     pub(super) fn packet_id(&self) -> u16 {
         match self {
             Ack::Publish(id) => id.get(),
+            Ack::Receive(id) => id.get(),
+            Ack::Complete(id) => id.get(),
             Ack::Subscribe { packet_id, .. } => packet_id.get(),
             Ack::Unsubscribe(id) => id.get(),
         }
@@ -135,6 +160,8 @@ impl Ack {
     pub(super) fn is_match(&self, tp: AckType) -> bool {
         match (self, tp) {
             (Ack::Publish(_), AckType::Publish) => true,
+            (Ack::Receive(_), AckType::Receive) => true,
+            (Ack::Complete(_), AckType::Complete) => true,
             (Ack::Subscribe { .. }, AckType::Subscribe) => true,
             (Ack::Unsubscribe(_), AckType::Unsubscribe) => true,
             (_, _) => false,
@@ -146,6 +173,8 @@ impl AckType {
     pub(super) fn name(&self) -> &'static str {
         match self {
             AckType::Publish => "PublishAck",
+            AckType::Receive => "PublishReceived",
+            AckType::Complete => "PublishComplete",
             AckType::Subscribe => "SubscribeAck",
             AckType::Unsubscribe => "UnsubscribeAck",
         }
@@ -1,10 +1,11 @@
 use std::future::{ready, Future};
-use std::{fmt, num::NonZeroU16, rc::Rc};
+use std::{fmt, mem, num::NonZeroU16, rc::Rc};
 
 use ntex::util::{ByteString, Bytes, Either, Ready};
 
 use super::shared::{Ack, AckType, MqttShared};
 use super::{codec, error::ProtocolError, error::SendPacketError};
+use crate::{info::ConnectionInfo, will::ConnectWill};
 
 pub struct MqttSink(Rc<MqttShared>);
 
@@ -19,9 +20,15 @@ impl MqttSink {
         MqttSink(state)
     }
 
+    #[inline]
+    /// Check connection status
+    pub fn is_open(&self) -> bool {
+        self.0.state.is_open()
+    }
+
     /// Get client receive credit
     pub fn credit(&self) -> usize {
-        self.0.cap.get() - self.0.with_queues(|q| q.inflight.len())
+        self.0.cap.get().saturating_sub(self.0.with_queues(|q| q.inflight.len()))
     }
 
     /// Get notification when packet could be send to the peer.
@@ -52,6 +59,7 @@ impl MqttSink {
         }
         self.0.with_queues(|q| {
             q.inflight.clear();
+            q.inflight_packets.clear();
             q.waiters.clear();
         });
     }
@@ -64,10 +72,106 @@ impl MqttSink {
         }
         self.0.with_queues(|q| {
             q.inflight.clear();
+            q.inflight_packets.clear();
             q.waiters.clear();
         });
     }
 
+    /// Close mqtt connection, in-flight state is kept if it could be transferred
+    pub(crate) fn drop_sink(&self) {
+        if self.0.keep_inflight.get() {
+            if self.0.state.is_open() {
+                self.0.state.close();
+            }
+            self.0.with_queues(|q| q.waiters.clear());
+        } else {
+            self.close();
+        }
+    }
+
+    /// Keep in-flight state after connection get closed
+    pub(crate) fn keep_inflight(&self) {
+        self.0.keep_inflight.set(true);
+    }
+
+    /// Check if both sinks belong to the same connection
+    pub(crate) fn ptr_eq(&self, other: &MqttSink) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+
+    /// Take connection's last will
+    pub(super) fn take_will(&self) -> Option<ConnectWill> {
+        self.0.will.borrow_mut().take()
+    }
+
+    /// Peer connection info, available for server connections
+    pub(crate) fn connection_info(&self) -> Option<Rc<ConnectionInfo>> {
+        self.0.info.borrow().clone()
+    }
+
+    /// Move in-flight state to new connection
+    ///
+    /// Unacknowledged publish packets are re-sent with dup flag set,
+    /// PUBREL is re-sent for pending qos2 packets.
+    pub(crate) fn transfer_inflight(&self, sink: &MqttSink) {
+        let (mut inflight, order, mut packets) = self.0.with_queues(|q| {
+            q.waiters.clear();
+            (
+                mem::take(&mut q.inflight),
+                mem::take(&mut q.inflight_order),
+                mem::take(&mut q.inflight_packets),
+            )
+        });
+        sink.0.inflight_idx.set(self.0.inflight_idx.get());
+
+        let keep = sink.0.keep_inflight.get();
+        // released qos2 publishes are not ordered
+        let mut released: Vec<_> = inflight
+            .iter()
+            .filter(|(_, (_, tp))| *tp == AckType::Complete)
+            .map(|(idx, _)| *idx)
+            .collect();
+        released.sort_unstable();
+
+        sink.0.with_queues(|q| {
+            for idx in released.into_iter().chain(order) {
+                let (tx, tp) = if let Some(item) = inflight.remove(&idx) {
+                    item
+                } else {
+                    continue;
+                };
+
+                let pkt = match tp {
+                    AckType::Publish | AckType::Receive => {
+                        if let Some(mut pkt) = packets.remove(&idx) {
+                            log::trace!("Re-send publish packet with id: {}", idx);
+                            pkt.dup = true;
+                            if keep {
+                                q.inflight_packets.insert(idx, pkt.clone());
+                            }
+                            codec::Packet::Publish(pkt)
+                        } else {
+                            continue;
+                        }
+                    }
+                    AckType::Complete => {
+                        log::trace!("Re-send publish release packet with id: {}", idx);
+                        codec::Packet::PublishRelease {
+                            packet_id: NonZeroU16::new(idx).unwrap(),
+                        }
+                    }
+                    // subscribe and unsubscribe requests fail
+                    AckType::Subscribe | AckType::Unsubscribe => continue,
+                };
+                q.inflight.insert(idx, (tx, tp));
+                if tp != AckType::Complete {
+                    q.inflight_order.push_back(idx);
+                }
+                let _ = sink.0.state.write().encode(pkt, &sink.0.codec);
+            }
+        });
+    }
+
     /// Send ping
     pub(super) fn ping(&self) -> bool {
         self.0.state.write().encode(codec::Packet::PingRequest, &self.0.codec).is_ok()
@@ -102,41 +206,60 @@ impl MqttSink {
 
     pub(super) fn pkt_ack(&self, pkt: Ack) -> Result<(), ProtocolError> {
         let result = self.0.with_queues(|queues| {
-            // check ack order
-            if let Some(idx) = queues.inflight_order.pop_front() {
-                if idx != pkt.packet_id() {
-                    log::trace!(
-                    "MQTT protocol error, packet_id order does not match, expected {}, got: {}",
-                    idx,
-                    pkt.packet_id()
-                );
-                    Err(ProtocolError::PacketIdMismatch)
-                } else {
-                    // get publish ack channel
-                    log::trace!("Ack packet with id: {}", pkt.packet_id());
-                    let idx = pkt.packet_id();
-                    if let Some((tx, tp)) = queues.inflight.remove(&idx) {
-                        if pkt.is_match(tp) {
-                            let _ = tx.send(pkt);
-
-                            // wake up queued request (receive max limit)
-                            while let Some(tx) = queues.waiters.pop_front() {
-                                if tx.send(()).is_ok() {
-                                    break;
-                                }
-                            }
-                            Ok(())
-                        } else {
-                            log::trace!("MQTT protocol error, unexpected packet");
-                            Err(ProtocolError::Unexpected(pkt.packet_type(), tp.name()))
+            let idx = pkt.packet_id();
+
+            // check ack order, released qos2 publish is completed in any order
+            if !std::matches!(pkt, Ack::Complete(_)) {
+                match queues.inflight_order.pop_front() {
+                    Some(id) if id == idx => (),
+                    Some(id) => {
+                        log::trace!(
+                            "MQTT protocol error, packet_id order does not match, expected {}, got: {}",
+                            id,
+                            idx
+                        );
+                        return Err(ProtocolError::PacketIdMismatch);
+                    }
+                    None => {
+                        log::trace!("Unexpected PublishAck packet: {:?}", idx);
+                        return Err(ProtocolError::PacketIdMismatch);
+                    }
+                }
+            }
+
+            // get publish ack channel
+            log::trace!("Ack packet with id: {}", idx);
+            if let Some((tx, tp)) = queues.inflight.remove(&idx) {
+                queues.inflight_packets.remove(&idx);
+                if pkt.is_match(tp) {
+                    // qos2 publish keeps in-flight slot until PUBCOMP
+                    if let Ack::Receive(packet_id) = pkt {
+                        queues.inflight.insert(idx, (tx, AckType::Complete));
+                        let _ = self
+                            .0
+                            .state
+                            .write()
+                            .encode(codec::Packet::PublishRelease { packet_id }, &self.0.codec);
+                        return Ok(());
+                    }
+                    let _ = tx.send(pkt);
+
+                    // wake up queued request (receive max limit)
+                    while let Some(tx) = queues.waiters.pop_front() {
+                        if tx.send(()).is_ok() {
+                            break;
                         }
-                    } else {
-                        log::error!("In-flight state inconsistency");
-                        Err(ProtocolError::PacketIdMismatch)
                     }
+                    Ok(())
+                } else {
+                    log::trace!("MQTT protocol error, unexpected packet");
+                    Err(ProtocolError::Unexpected(pkt.packet_type(), tp.name()))
                 }
+            } else if let Ack::Complete(_) = pkt {
+                log::trace!("Unexpected PublishComplete packet: {:?}", idx);
+                Err(ProtocolError::PacketIdMismatch)
             } else {
-                log::trace!("Unexpected PublishAck packet: {:?}", pkt.packet_id());
+                log::error!("In-flight state inconsistency");
                 Err(ProtocolError::PacketIdMismatch)
             }
         });
@@ -246,6 +369,9 @@ impl PublishBuilder {
             }
             queues.inflight.insert(idx, (tx, AckType::Publish));
             queues.inflight_order.push_back(idx);
+            if shared.keep_inflight.get() {
+                queues.inflight_packets.insert(idx, packet.clone());
+            }
             Ok(rx)
         });
 
@@ -263,6 +389,71 @@ impl PublishBuilder {
             Err(err) => Either::Left(Ready::Err(SendPacketError::Encode(err))),
         }
     }
+
+    /// Send publish packet with QoS 2
+    pub fn send_exactly_once(self) -> impl Future<Output = Result<(), SendPacketError>> {
+        let shared = self.shared;
+        let mut packet = self.packet;
+        packet.qos = codec::QoS::ExactlyOnce;
+
+        if shared.state.is_open() {
+            // handle client receive maximum
+            if !shared.has_credit() {
+                let (tx, rx) = shared.pool.waiters.channel();
+                shared.with_queues(|q| q.waiters.push_back(tx));
+
+                return Either::Left(Either::Right(async move {
+                    if rx.await.is_err() {
+                        return Err(SendPacketError::Disconnected);
+                    }
+                    Self::send_exactly_once_inner(packet, shared).await
+                }));
+            }
+            Either::Right(Self::send_exactly_once_inner(packet, shared))
+        } else {
+            Either::Left(Either::Left(Ready::Err(SendPacketError::Disconnected)))
+        }
+    }
+
+    fn send_exactly_once_inner(
+        mut packet: codec::Publish,
+        shared: Rc<MqttShared>,
+    ) -> impl Future<Output = Result<(), SendPacketError>> {
+        let rx = shared.with_queues(|queues| {
+            // publish complete channel
+            let (tx, rx) = shared.pool.queue.channel();
+
+            // packet id
+            let mut idx = packet.packet_id.map(|i| i.get()).unwrap_or(0);
+            if idx == 0 {
+                idx = shared.next_id();
+                packet.packet_id = NonZeroU16::new(idx);
+            }
+            if queues.inflight.contains_key(&idx) {
+                return Err(SendPacketError::PacketIdInUse(idx));
+            }
+            queues.inflight.insert(idx, (tx, AckType::Receive));
+            queues.inflight_order.push_back(idx);
+            if shared.keep_inflight.get() {
+                queues.inflight_packets.insert(idx, packet.clone());
+            }
+            Ok(rx)
+        });
+
+        let rx = match rx {
+            Ok(rx) => rx,
+            Err(e) => return Either::Left(Ready::Err(e)),
+        };
+
+        log::trace!("Publish (QoS2) to {:#?}", packet);
+
+        match shared.state.write().encode(codec::Packet::Publish(packet), &shared.codec) {
+            Ok(_) => Either::Right(async move {
+                rx.await.map(|_| ()).map_err(|_| SendPacketError::Disconnected)
+            }),
+            Err(err) => Either::Left(Ready::Err(SendPacketError::Encode(err))),
+        }
+    }
 }
 
 /// Subscribe packet builder
@@ -1,4 +1,4 @@
-use std::{future::Future, num::NonZeroU16, num::NonZeroU32, rc::Rc, time::Duration};
+use std::{future::Future, num::NonZeroU16, num::NonZeroU32, pin::Pin, rc::Rc, time::Duration};
 
 use ntex::codec::{AsyncRead, AsyncWrite};
 use ntex::connect::{self, Address, Connect, Connector};
@@ -12,9 +12,17 @@ use ntex::connect::openssl::{OpensslConnector, SslConnector};
 #[cfg(feature = "rustls")]
 use ntex::connect::rustls::{ClientConfig, RustlsConnector};
 
-use super::{codec, connection::Client, error::ClientError, error::ProtocolError};
+use super::{codec, connection::Client, error::ClientError, error::ProtocolError, Store};
 use crate::io::State;
 use crate::v5::shared::{MqttShared, MqttSinkPool};
+use crate::v5::sink::MqttSink;
+use crate::ws::WsConnector;
+
+type Authenticator = Rc<
+    dyn Fn(
+        codec::Auth,
+    ) -> Pin<Box<dyn Future<Output = Result<codec::Auth, codec::Disconnect>>>>,
+>;
 
 /// Mqtt client connector
 pub struct MqttConnector<A, T> {
@@ -24,6 +32,8 @@ pub struct MqttConnector<A, T> {
     handshake_timeout: Seconds,
     disconnect_timeout: Seconds,
     pool: Rc<MqttSinkPool>,
+    store: Option<Rc<dyn Store>>,
+    authenticator: Option<Authenticator>,
 }
 
 impl<A> MqttConnector<A, ()>
@@ -40,6 +50,8 @@ where
             handshake_timeout: Seconds::ZERO,
             disconnect_timeout: Seconds(3),
             pool: Rc::new(MqttSinkPool::default()),
+            store: None,
+            authenticator: None,
         }
     }
 }
@@ -93,6 +105,23 @@ where
         self
     }
 
+    /// Set authenticator for enhanced authentication
+    ///
+    /// Authenticator is called for each server's AUTH challenge received
+    /// before connect ack, returned packet is sent back to the server.
+    /// Reason code of the response is set to `ContinueAuth`, authentication
+    /// method is set to the connect packet's method if it is not specified.
+    /// If authenticator fails, returned DISCONNECT packet is sent to the server
+    /// and connect fails with `ClientError::Auth` error.
+    pub fn authenticator<F, R>(mut self, f: F) -> Self
+    where
+        F: Fn(codec::Auth) -> R + 'static,
+        R: Future<Output = Result<codec::Auth, codec::Disconnect>> + 'static,
+    {
+        self.authenticator = Some(Rc::new(move |pkt| Box::pin(f(pkt))));
+        self
+    }
+
     #[inline]
     /// Username can be used by the Server for authentication and authorization.
     pub fn username(mut self, val: ByteString) -> Self {
@@ -176,6 +205,17 @@ where
         self
     }
 
+    /// Set store for outgoing in-flight packets
+    ///
+    /// Unacknowledged packets from the store are re-sent on connect.
+    pub fn store<S>(mut self, store: S) -> Self
+    where
+        S: Store + 'static,
+    {
+        self.store = Some(Rc::new(store));
+        self
+    }
+
     /// Use custom connector
     pub fn connector<U>(self, connector: U) -> MqttConnector<A, U>
     where
@@ -189,6 +229,28 @@ where
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
             pool: self.pool,
+            store: self.store,
+            authenticator: self.authenticator,
+        }
+    }
+
+    /// Use websocket transport
+    ///
+    /// Wraps current connector, tls connector must be configured before websocket.
+    pub fn websocket<U>(self, path: U) -> MqttConnector<A, WsConnector<A, T>>
+    where
+        ByteString: From<U>,
+        T::Future: 'static,
+    {
+        MqttConnector {
+            connector: WsConnector::with_connector(path, self.connector),
+            pkt: self.pkt,
+            address: self.address,
+            handshake_timeout: self.handshake_timeout,
+            disconnect_timeout: self.disconnect_timeout,
+            pool: self.pool,
+            store: self.store,
+            authenticator: self.authenticator,
         }
     }
 
@@ -202,6 +264,8 @@ where
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
             pool: self.pool,
+            store: self.store,
+            authenticator: self.authenticator,
         }
     }
 
@@ -217,6 +281,8 @@ where
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
             pool: self.pool,
+            store: self.store,
+            authenticator: self.authenticator,
         }
     }
 
@@ -243,25 +309,61 @@ where
         let max_receive = pkt.receive_max.map(|v| v.get()).unwrap_or(0);
         let disconnect_timeout = self.disconnect_timeout;
         let pool = self.pool.clone();
+        let store = self.store.clone();
+        let authenticator = self.authenticator.clone();
 
         async move {
             let mut io = fut.await?;
             let state = State::new();
             let codec = codec::Codec::new().max_inbound_size(max_packet_size);
+            let auth_method = pkt.auth_method.clone();
 
             state.send(&mut io, &codec, codec::Packet::Connect(Box::new(pkt))).await?;
 
-            let packet = state
-                .next(&mut io, &codec)
-                .await
-                .map_err(|e| ClientError::from(ProtocolError::from(e)))
-                .and_then(|res| {
-                    res.ok_or_else(|| {
-                        log::trace!("Mqtt server is disconnected during handshake");
-                        ClientError::Disconnected
-                    })
-                })?;
-            let shared = Rc::new(MqttShared::new(state.clone(), codec, 0, pool));
+            let packet = loop {
+                let packet = state
+                    .next(&mut io, &codec)
+                    .await
+                    .map_err(|e| ClientError::from(ProtocolError::from(e)))
+                    .and_then(|res| {
+                        res.ok_or_else(|| {
+                            log::trace!("Mqtt server is disconnected during handshake");
+                            ClientError::Disconnected
+                        })
+                    })?;
+
+                // enhanced authentication exchange
+                match (packet, authenticator.as_ref()) {
+                    (codec::Packet::Auth(pkt), Some(auth))
+                        if pkt.reason_code == codec::AuthReasonCode::ContinueAuth =>
+                    {
+                        log::trace!("Auth challenge from server: {:?}", pkt);
+                        match auth(pkt).await {
+                            Ok(mut pkt) => {
+                                pkt.reason_code = codec::AuthReasonCode::ContinueAuth;
+                                if pkt.auth_method.is_none() {
+                                    pkt.auth_method = auth_method.clone();
+                                }
+                                state.send(&mut io, &codec, codec::Packet::Auth(pkt)).await?;
+                            }
+                            Err(pkt) => {
+                                log::trace!("Authentication failed: {:?}", pkt);
+                                state
+                                    .send(
+                                        &mut io,
+                                        &codec,
+                                        codec::Packet::Disconnect(pkt.clone()),
+                                    )
+                                    .await?;
+                                return Err(ClientError::Auth(Box::new(pkt)));
+                            }
+                        }
+                    }
+                    (packet, _) => break packet,
+                }
+            };
+            let shared =
+                Rc::new(MqttShared::new(state.clone(), codec, 0, pool).with_store(store));
 
             match packet {
                 codec::Packet::ConnectAck(pkt) => {
@@ -275,6 +377,10 @@ where
                         let keep_alive = pkt.server_keepalive_sec.unwrap_or(keep_alive);
 
                         shared.cap.set(pkt.receive_max.map(|v| v.get()).unwrap_or(0) as usize);
+                        shared.set_topic_alias_max(pkt.topic_alias_max);
+
+                        // re-send stored packets
+                        MqttSink::new(shared.clone()).replay_store(pkt.session_present);
 
                         Ok(Client::new(
                             io,
@@ -27,7 +27,7 @@ impl<E> ControlMessage<E> {
     }
 
     pub(super) fn closed(is_error: bool) -> Self {
-        ControlMessage::Closed(Closed::new(is_error))
+        ControlMessage::Closed(Closed::new(is_error, None))
     }
 
     pub(super) fn error(err: E) -> Self {
@@ -60,18 +60,11 @@ impl Publish {
         ControlResult { packet: None, disconnect: false }
     }
 
+    /// Ack publish packet
+    ///
+    /// Sends PUBREC with provided reason code for qos2 publish packet
     pub fn ack(self, reason_code: codec::PublishAckReason) -> ControlResult {
-        ControlResult {
-            packet: self.0.packet_id.map(|packet_id| {
-                codec::Packet::PublishAck(codec::PublishAck {
-                    packet_id,
-                    reason_code,
-                    properties: codec::UserProperties::new(),
-                    reason_string: None,
-                })
-            }),
-            disconnect: false,
-        }
+        self.ack_with(reason_code, codec::UserProperties::new(), None)
     }
 
     pub fn ack_with(
@@ -80,14 +73,16 @@ impl Publish {
         properties: codec::UserProperties,
         reason_string: Option<ByteString>,
     ) -> ControlResult {
+        let qos = self.0.qos;
         ControlResult {
             packet: self.0.packet_id.map(|packet_id| {
-                codec::Packet::PublishAck(codec::PublishAck {
-                    packet_id,
-                    reason_code,
-                    properties,
-                    reason_string,
-                })
+                let ack =
+                    codec::PublishAck { packet_id, reason_code, properties, reason_string };
+                if qos == codec::QoS::ExactlyOnce {
+                    codec::Packet::PublishReceived(ack)
+                } else {
+                    codec::Packet::PublishAck(ack)
+                }
             }),
             disconnect: false,
         }
@@ -3,12 +3,12 @@ use std::task::{Context, Poll};
 use std::{future::Future, marker::PhantomData, num::NonZeroU16, pin::Pin, rc::Rc};
 
 use ntex::service::Service;
-use ntex::util::{Either, HashSet, Ready};
+use ntex::util::{ByteString, Either, HashMap, HashSet, Ready};
 
 use crate::error::{MqttError, ProtocolError};
 use crate::v5::shared::{Ack, MqttShared};
 use crate::v5::{codec, publish::Publish, publish::PublishAck, sink::MqttSink};
-use crate::{io::DispatchItem, types::packet_type};
+use crate::{io::DispatchItem, types::packet_type, types::QoS};
 
 use super::control::{ControlMessage, ControlResult};
 
@@ -54,7 +54,9 @@ struct Inner<C> {
 
 struct PublishInfo {
     inflight: HashSet<NonZeroU16>,
-    aliases: HashSet<NonZeroU16>,
+    aliases: HashMap<NonZeroU16, ByteString>,
+    // qos2 publishes acked with PUBREC, waiting for PUBREL
+    await_release: HashSet<NonZeroU16>,
 }
 
 impl<T, C, E> Dispatcher<T, C, E>
@@ -82,8 +84,9 @@ where
                 control,
                 sink,
                 info: RefCell::new(PublishInfo {
-                    aliases: HashSet::default(),
+                    aliases: HashMap::default(),
                     inflight: HashSet::default(),
+                    await_release: HashSet::default(),
                 }),
             }),
             _t: PhantomData,
@@ -136,14 +139,26 @@ where
         log::trace!("Dispatch packet: {:#?}", request);
 
         match request {
-            DispatchItem::Item(codec::Packet::Publish(publish)) => {
+            DispatchItem::Item(codec::Packet::Publish(mut publish)) => {
                 let info = self.inner.clone();
                 let packet_id = publish.packet_id;
+                let qos = publish.qos;
 
                 {
                     let mut inner = info.info.borrow_mut();
 
                     if let Some(pid) = packet_id {
+                        // qos2 publish is already received, peer did not get PUBREC
+                        if qos == QoS::ExactlyOnce && inner.await_release.contains(&pid) {
+                            log::trace!("Re-delivered qos2 publish packet: {:?}", pid);
+                            return Either::Right(Either::Left(Ready::Ok(Some(
+                                codec::Packet::PublishReceived(codec::PublishAck {
+                                    packet_id: pid,
+                                    ..Default::default()
+                                }),
+                            ))));
+                        }
+
                         // check for receive maximum
                         if self.max_receive != 0 && inner.inflight.len() >= self.max_receive {
                             log::trace!(
@@ -161,13 +176,16 @@ where
 
                         // check for duplicated packet id
                         if !inner.inflight.insert(pid) {
-                            self.inner.sink.send(codec::Packet::PublishAck(
-                                codec::PublishAck {
-                                    packet_id: pid,
-                                    reason_code: codec::PublishAckReason::PacketIdentifierInUse,
-                                    ..Default::default()
-                                },
-                            ));
+                            let ack = codec::PublishAck {
+                                packet_id: pid,
+                                reason_code: codec::PublishAckReason::PacketIdentifierInUse,
+                                ..Default::default()
+                            };
+                            if qos == QoS::ExactlyOnce {
+                                self.inner.sink.send(codec::Packet::PublishReceived(ack));
+                            } else {
+                                self.inner.sink.send(codec::Packet::PublishAck(ack));
+                            }
                             return Either::Right(Either::Left(Ready::Ok(None)));
                         }
                     }
@@ -176,7 +194,9 @@ where
                     if let Some(alias) = publish.properties.topic_alias {
                         // check existing topic
                         if publish.topic.is_empty() {
-                            if !inner.aliases.contains(&alias) {
+                            if let Some(topic) = inner.aliases.get(&alias) {
+                                publish.topic = topic.clone();
+                            } else {
                                 return Either::Right(Either::Right(ControlResponse::new(
                                     ControlMessage::proto_error(
                                         ProtocolError::UnknownTopicAlias,
@@ -193,16 +213,17 @@ where
                             }
 
                             // record new alias
-                            inner.aliases.insert(alias);
+                            inner.aliases.insert(alias, publish.topic.clone());
                         }
                     }
                 }
 
                 Either::Left(PublishResponse {
+                    qos,
                     packet_id: packet_id.map(|v| v.get()).unwrap_or(0),
                     inner: info,
                     state: PublishResponseState::Publish {
-                        fut: self.publish.call(Publish::new(publish)),
+                        fut: self.publish.call(Publish::new(publish, None)),
                     },
                     _t: PhantomData,
                 })
@@ -217,6 +238,44 @@ where
                     Either::Right(Either::Left(Ready::Ok(None)))
                 }
             }
+            DispatchItem::Item(codec::Packet::PublishReceived(packet)) => {
+                if let Err(err) = self.inner.sink.pkt_ack(Ack::Receive(packet)) {
+                    Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(err),
+                        &self.inner,
+                    )))
+                } else {
+                    Either::Right(Either::Left(Ready::Ok(None)))
+                }
+            }
+            DispatchItem::Item(codec::Packet::PublishComplete(packet)) => {
+                if let Err(err) = self.inner.sink.pkt_ack(Ack::Complete(packet)) {
+                    Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(err),
+                        &self.inner,
+                    )))
+                } else {
+                    Either::Right(Either::Left(Ready::Ok(None)))
+                }
+            }
+            DispatchItem::Item(codec::Packet::PublishRelease(pkt)) => {
+                let mut info = self.inner.info.borrow_mut();
+                let reason_code = if info.await_release.remove(&pkt.packet_id) {
+                    info.inflight.remove(&pkt.packet_id);
+                    codec::PublishAck2Reason::Success
+                } else {
+                    log::trace!("Unknown packet id for PUBREL: {:?}", pkt.packet_id);
+                    codec::PublishAck2Reason::PacketIdNotFound
+                };
+                Either::Right(Either::Left(Ready::Ok(Some(codec::Packet::PublishComplete(
+                    codec::PublishAck2 {
+                        reason_code,
+                        packet_id: pkt.packet_id,
+                        properties: codec::UserProperties::default(),
+                        reason_string: None,
+                    },
+                )))))
+            }
             DispatchItem::Item(codec::Packet::SubscribeAck(packet)) => {
                 if let Err(err) = self.inner.sink.pkt_ack(Ack::Subscribe(packet)) {
                     Either::Right(Either::Right(ControlResponse::new(
@@ -312,6 +371,7 @@ pin_project_lite::pin_project! {
         #[pin]
         state: PublishResponseState<T, C, E>,
         packet_id: u16,
+        qos: QoS,
         inner: Rc<Inner<C>>,
         _t: PhantomData<E>,
     }
@@ -365,14 +425,25 @@ where
                 };
                 if let Some(id) = NonZeroU16::new(*this.packet_id) {
                     log::trace!("Sending publish ack for {} id", this.packet_id);
-                    this.inner.info.borrow_mut().inflight.remove(&id);
                     let ack = codec::PublishAck {
                         packet_id: id,
                         reason_code: ack.reason_code,
                         reason_string: ack.reason_string,
                         properties: ack.properties,
                     };
-                    Poll::Ready(Ok(Some(codec::Packet::PublishAck(ack))))
+                    let mut info = this.inner.info.borrow_mut();
+                    if *this.qos == QoS::ExactlyOnce {
+                        // failed PUBREC completes qos2 flow
+                        if u8::from(ack.reason_code) < 0x80 {
+                            info.await_release.insert(id);
+                        } else {
+                            info.inflight.remove(&id);
+                        }
+                        Poll::Ready(Ok(Some(codec::Packet::PublishReceived(ack))))
+                    } else {
+                        info.inflight.remove(&id);
+                        Poll::Ready(Ok(Some(codec::Packet::PublishAck(ack))))
+                    }
                 } else {
                     Poll::Ready(Ok(None))
                 }
@@ -433,7 +504,18 @@ where
         let result = match this.fut.poll(cx) {
             Poll::Ready(Ok(result)) => {
                 if let Some(id) = NonZeroU16::new(self.packet_id) {
-                    self.inner.info.borrow_mut().inflight.remove(&id);
+                    let mut info = self.inner.info.borrow_mut();
+                    match result.packet {
+                        // qos2 publish waits for PUBREL
+                        Some(codec::Packet::PublishReceived(ref ack))
+                            if u8::from(ack.reason_code) < 0x80 =>
+                        {
+                            info.await_release.insert(id);
+                        }
+                        _ => {
+                            info.inflight.remove(&id);
+                        }
+                    }
                 }
                 result
             }
@@ -0,0 +1,410 @@
+use std::{cell::Cell, cell::RefCell, collections::VecDeque, fmt, future::Future};
+use std::{num::NonZeroU32, rc::Rc};
+
+use ntex::channel::{mpsc, oneshot};
+use ntex::codec::{AsyncRead, AsyncWrite};
+use ntex::connect::{self, Address, Connect};
+use ntex::service::Service;
+use ntex::time::sleep;
+use ntex::util::{ByteString, Bytes};
+
+use super::{connection::Client, connector::MqttConnector};
+use crate::backoff::Backoff;
+use crate::io::State;
+use crate::types::ConnectionState;
+use crate::v5::shared::{MqttShared, MqttSinkPool};
+use crate::v5::sink::{MqttSink, PublishBuilder};
+use crate::v5::{codec, error::SendPacketError};
+
+/// Mqtt client that reconnects to the server
+///
+/// Subscriptions made through `ManagedSink` get re-issued after reconnect
+/// if server does not have stored session state.
+pub struct ManagedClient<A, T> {
+    connector: MqttConnector<A, T>,
+    backoff: Backoff,
+    inner: Rc<Inner>,
+}
+
+struct Inner {
+    sink: RefCell<MqttSink>,
+    state: Cell<ConnectionState>,
+    stopped: Cell<bool>,
+    subscriptions: RefCell<Vec<Subscription>>,
+    waiters: RefCell<VecDeque<oneshot::Sender<()>>>,
+    streams: RefCell<Vec<mpsc::Sender<ConnectionState>>>,
+    callbacks: RefCell<Vec<Box<dyn Fn(ConnectionState)>>>,
+}
+
+struct Subscription {
+    id: Option<NonZeroU32>,
+    user_properties: codec::UserProperties,
+    topic_filters: Vec<(ByteString, codec::SubscriptionOptions)>,
+}
+
+impl<A, T> fmt::Debug for ManagedClient<A, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("v5::ManagedClient")
+            .field("backoff", &self.backoff)
+            .field("state", &self.inner.state.get())
+            .finish()
+    }
+}
+
+impl<A, T> ManagedClient<A, T>
+where
+    A: Address + Clone,
+    T: Service<Request = Connect<A>, Error = connect::ConnectError>,
+    T::Response: AsyncRead + AsyncWrite + Unpin + 'static,
+{
+    /// Create new managed client
+    pub fn new(connector: MqttConnector<A, T>) -> Self {
+        // sink is disconnected until first connection get established
+        let state = State::new();
+        state.close();
+        let shared =
+            MqttShared::new(state, codec::Codec::new(), 0, Rc::new(MqttSinkPool::default()));
+
+        ManagedClient {
+            connector,
+            backoff: Backoff::default(),
+            inner: Rc::new(Inner {
+                sink: RefCell::new(MqttSink::new(Rc::new(shared))),
+                state: Cell::new(ConnectionState::Disconnected),
+                stopped: Cell::new(false),
+                subscriptions: RefCell::new(Vec::new()),
+                waiters: RefCell::new(VecDeque::new()),
+                streams: RefCell::new(Vec::new()),
+                callbacks: RefCell::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Set reconnect backoff policy
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Register connection state callback
+    pub fn on_state_change<F>(self, f: F) -> Self
+    where
+        F: Fn(ConnectionState) + 'static,
+    {
+        self.inner.callbacks.borrow_mut().push(Box::new(f));
+        self
+    }
+
+    /// Get stream of connection state changes
+    pub fn state_changes(&self) -> mpsc::Receiver<ConnectionState> {
+        let (tx, rx) = mpsc::channel();
+        self.inner.streams.borrow_mut().push(tx);
+        rx
+    }
+
+    #[inline]
+    /// Get client sink
+    pub fn sink(&self) -> ManagedSink {
+        ManagedSink(self.inner.clone())
+    }
+
+    /// Run client with default control messages handler
+    pub async fn start_default(self) {
+        self.start(|client| client.start_default()).await
+    }
+
+    /// Run client, provided factory is used for handling of each new connection
+    ///
+    /// Connection is handled until factory's future resolves.
+    pub async fn start<F, R>(self, factory: F)
+    where
+        F: Fn(Client<T::Response>) -> R,
+        R: Future,
+    {
+        let inner = self.inner;
+        let mut attempt = 0;
+
+        while !inner.stopped.get() {
+            inner.set_state(ConnectionState::Connecting);
+
+            match self.connector.connect().await {
+                Ok(client) => {
+                    attempt = 0;
+                    if inner.stopped.get() {
+                        client.sink().close();
+                        break;
+                    }
+                    let sink = client.sink();
+                    let session_present = client.session_present();
+                    sink.keep_inflight();
+
+                    // unacknowledged packets are re-sent if server keeps session state
+                    let prev = inner.sink.replace(sink.clone());
+                    if session_present {
+                        prev.transfer_inflight(&sink);
+                    } else {
+                        prev.close();
+                    }
+                    inner.set_state(ConnectionState::Connected);
+
+                    // wake up waiters
+                    for tx in inner.waiters.borrow_mut().drain(..) {
+                        let _ = tx.send(());
+                    }
+
+                    if !session_present {
+                        ntex::rt::spawn(resubscribe(inner.clone(), sink));
+                    }
+                    let _ = factory(client).await;
+                    log::trace!("Mqtt connection is closed");
+                }
+                Err(err) => log::trace!("Cannot connect to mqtt server: {:?}", err),
+            }
+            inner.set_state(ConnectionState::Disconnected);
+
+            if !inner.stopped.get() {
+                let delay = self.backoff.delay(attempt);
+                attempt = attempt.saturating_add(1);
+                log::trace!("Reconnecting in {:?}", delay);
+                sleep(delay).await;
+            }
+        }
+        inner.waiters.borrow_mut().clear();
+        inner.sink.borrow().close();
+        inner.set_state(ConnectionState::Stopped);
+    }
+}
+
+impl Inner {
+    fn set_state(&self, state: ConnectionState) {
+        if self.state.get() != state {
+            self.state.set(state);
+            for f in self.callbacks.borrow().iter() {
+                (*f)(state)
+            }
+            self.streams.borrow_mut().retain(|tx| tx.send(state).is_ok());
+        }
+    }
+}
+
+async fn resubscribe(inner: Rc<Inner>, sink: MqttSink) {
+    let subs: Vec<_> = inner
+        .subscriptions
+        .borrow()
+        .iter()
+        .map(|sub| {
+            let mut builder = sink.subscribe(sub.id);
+            for (key, value) in &sub.user_properties {
+                builder = builder.property(key.clone(), value.clone());
+            }
+            for (filter, opts) in &sub.topic_filters {
+                builder = builder.topic_filter(filter.clone(), opts.clone());
+            }
+            builder
+        })
+        .collect();
+
+    for builder in subs {
+        match builder.send().await {
+            Ok(ack) => log::trace!("Re-subscribed: {:?}", ack),
+            Err(err) => {
+                log::error!("Cannot re-subscribe: {:?}", err);
+                break;
+            }
+        }
+    }
+}
+
+/// Mqtt sink that stays valid across reconnects
+#[derive(Clone)]
+pub struct ManagedSink(Rc<Inner>);
+
+impl ManagedSink {
+    #[inline]
+    /// Get sink of current connection
+    pub fn sink(&self) -> MqttSink {
+        self.0.sink.borrow().clone()
+    }
+
+    #[inline]
+    /// Get connection state
+    pub fn state(&self) -> ConnectionState {
+        self.0.state.get()
+    }
+
+    #[inline]
+    /// Check connection status
+    pub fn is_open(&self) -> bool {
+        self.0.state.get() == ConnectionState::Connected && self.sink().is_open()
+    }
+
+    /// Wait for established connection.
+    ///
+    /// Returns `false` if client is stopped
+    pub async fn connected(&self) -> bool {
+        if self.0.stopped.get() {
+            return false;
+        }
+        if self.is_open() {
+            return true;
+        }
+        let (tx, rx) = oneshot::channel();
+        self.0.waiters.borrow_mut().push_back(tx);
+        rx.await.is_ok()
+    }
+
+    /// Get notification when packet could be send to the peer.
+    ///
+    /// Waits for reconnect, result indicates if client is alive
+    pub async fn ready(&self) -> bool {
+        loop {
+            if !self.connected().await {
+                return false;
+            }
+            if self.sink().ready().await {
+                return true;
+            }
+        }
+    }
+
+    /// Stop reconnecting and close mqtt connection
+    pub fn close(&self) {
+        self.0.stopped.set(true);
+        self.0.waiters.borrow_mut().clear();
+        self.sink().close();
+    }
+
+    /// Stop reconnecting and close mqtt connection
+    pub fn close_with_reason(&self, pkt: codec::Disconnect) {
+        self.0.stopped.set(true);
+        self.0.waiters.borrow_mut().clear();
+        self.sink().close_with_reason(pkt);
+    }
+
+    /// Create publish packet builder for current connection
+    pub fn publish<U>(&self, topic: U, payload: Bytes) -> PublishBuilder
+    where
+        ByteString: From<U>,
+    {
+        self.sink().publish(topic, payload)
+    }
+
+    /// Create subscribe packet builder
+    ///
+    /// Granted subscriptions get re-issued after reconnect
+    pub fn subscribe(&self, id: Option<NonZeroU32>) -> ManagedSubscribeBuilder {
+        ManagedSubscribeBuilder {
+            inner: self.0.clone(),
+            sub: Subscription { id, user_properties: Vec::new(), topic_filters: Vec::new() },
+        }
+    }
+
+    /// Create unsubscribe packet builder
+    pub fn unsubscribe(&self) -> ManagedUnsubscribeBuilder {
+        ManagedUnsubscribeBuilder {
+            inner: self.0.clone(),
+            user_properties: Vec::new(),
+            topic_filters: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Debug for ManagedSink {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("ManagedSink").field("state", &self.0.state.get()).finish()
+    }
+}
+
+/// Subscribe packet builder of managed sink
+pub struct ManagedSubscribeBuilder {
+    inner: Rc<Inner>,
+    sub: Subscription,
+}
+
+impl ManagedSubscribeBuilder {
+    /// Add topic filter
+    pub fn topic_filter(
+        mut self,
+        filter: ByteString,
+        opts: codec::SubscriptionOptions,
+    ) -> Self {
+        self.sub.topic_filters.push((filter, opts));
+        self
+    }
+
+    /// Add user property
+    pub fn property(mut self, key: ByteString, value: ByteString) -> Self {
+        self.sub.user_properties.push((key, value));
+        self
+    }
+
+    /// Send subscribe packet
+    pub async fn send(self) -> Result<codec::SubscribeAck, SendPacketError> {
+        let mut builder = self.inner.sink.borrow().subscribe(self.sub.id);
+        for (key, value) in &self.sub.user_properties {
+            builder = builder.property(key.clone(), value.clone());
+        }
+        for (filter, opts) in &self.sub.topic_filters {
+            builder = builder.topic_filter(filter.clone(), opts.clone());
+        }
+        let ack = builder.send().await?;
+
+        // store granted subscriptions
+        let mut sub = self.sub;
+        let mut status = ack.status.iter();
+        sub.topic_filters
+            .retain(|_| status.next().map(|reason| u8::from(*reason) < 0x80).unwrap_or(false));
+        if !sub.topic_filters.is_empty() {
+            let mut subs = self.inner.subscriptions.borrow_mut();
+            for item in subs.iter_mut() {
+                item.topic_filters
+                    .retain(|(f, _)| !sub.topic_filters.iter().any(|(s, _)| s == f));
+            }
+            subs.retain(|item| !item.topic_filters.is_empty());
+            subs.push(sub);
+        }
+        Ok(ack)
+    }
+}
+
+/// Unsubscribe packet builder of managed sink
+pub struct ManagedUnsubscribeBuilder {
+    inner: Rc<Inner>,
+    user_properties: codec::UserProperties,
+    topic_filters: Vec<ByteString>,
+}
+
+impl ManagedUnsubscribeBuilder {
+    /// Add topic filter
+    pub fn topic_filter(mut self, filter: ByteString) -> Self {
+        self.topic_filters.push(filter);
+        self
+    }
+
+    /// Add user property
+    pub fn property(mut self, key: ByteString, value: ByteString) -> Self {
+        self.user_properties.push((key, value));
+        self
+    }
+
+    /// Send unsubscribe packet
+    pub async fn send(self) -> Result<codec::UnsubscribeAck, SendPacketError> {
+        // subscriptions are not re-issued even if unsubscribe fails
+        {
+            let mut subs = self.inner.subscriptions.borrow_mut();
+            for item in subs.iter_mut() {
+                item.topic_filters.retain(|(f, _)| !self.topic_filters.contains(f));
+            }
+            subs.retain(|item| !item.topic_filters.is_empty());
+        }
+
+        let mut builder = self.inner.sink.borrow().unsubscribe();
+        for (key, value) in self.user_properties {
+            builder = builder.property(key, value);
+        }
+        for filter in self.topic_filters {
+            builder = builder.topic_filter(filter);
+        }
+        builder.send().await
+    }
+}
@@ -4,11 +4,18 @@ mod connection;
 mod connector;
 pub mod control;
 mod dispatcher;
+mod managed;
 
 pub use self::connection::{Client, ClientRouter};
 pub use self::connector::MqttConnector;
 pub use self::control::{ControlMessage, ControlResult};
+pub use self::managed::{
+    ManagedClient, ManagedSink, ManagedSubscribeBuilder, ManagedUnsubscribeBuilder,
+};
+pub use super::store::{FileStore, MemoryStore, Store, StoreItem};
+
+pub use crate::backoff::Backoff;
 
 pub use crate::topic::Topic;
-pub use crate::types::QoS;
+pub use crate::types::{ConnectionState, QoS};
 pub use crate::v5::{codec, error, sink::MqttSink};
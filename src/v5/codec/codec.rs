@@ -5,6 +5,7 @@ use ntex::util::{Buf, BytesMut};
 
 use super::{decode::decode_packet, encode::EncodeLtd, Packet};
 use crate::error::{DecodeError, EncodeError};
+use crate::stats::{frame_size, Stats};
 use crate::types::{FixedHeader, MAX_PACKET_SIZE};
 use crate::utils::decode_variable_length;
 
@@ -14,6 +15,7 @@ pub struct Codec {
     max_in_size: Cell<u32>,
     max_out_size: Cell<u32>,
     flags: Cell<CodecFlags>,
+    stats: Option<Stats>,
 }
 
 bitflags::bitflags! {
@@ -36,6 +38,7 @@ impl Codec {
             max_in_size: Cell::new(0),
             max_out_size: Cell::new(0),
             flags: Cell::new(CodecFlags::empty()),
+            stats: None,
         }
     }
 
@@ -76,6 +79,12 @@ impl Codec {
     pub fn set_max_outbound_size(&self, size: u32) {
         self.max_out_size.set(size);
     }
+
+    /// Count decoded and encoded packets
+    pub(crate) fn with_stats(mut self, stats: Option<Stats>) -> Self {
+        self.stats = stats;
+        self
+    }
 }
 
 impl Default for Codec {
@@ -134,6 +143,9 @@ impl Decoder for Codec {
                     let packet_buf = src.split_to(fixed.remaining_length as usize).freeze();
                     let packet = decode_packet(packet_buf, fixed.first_byte)?;
                     self.state.set(DecodeState::FrameHeader);
+                    if let Some(ref stats) = self.stats {
+                        stats.received(fixed.first_byte, frame_size(fixed.remaining_length));
+                    }
                     src.reserve(5); // enough to fix 1 fixed header byte + 4 bytes max variable packet length
 
                     if let Packet::Connect(ref pkt) = packet {
@@ -194,6 +206,9 @@ impl Encoder for Codec {
         }
         dst.reserve(content_size + 5);
         item.encode(dst, content_size as u32)?; // safe: max_size <= u32 max value
+        if let Some(ref stats) = self.stats {
+            stats.sent(item.packet_type(), frame_size(content_size as u32));
+        }
         Ok(())
     }
 }
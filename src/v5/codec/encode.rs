@@ -372,6 +372,40 @@ mod tests {
 \x0512345\x00\x00\x05topic\x00\x07message"[..],
         );
 
+        assert_encode_packet(
+            &Packet::Connect(Box::new(Connect {
+                clean_start: false,
+                keep_alive: 60,
+                client_id: ByteString::from_static("12345"),
+                last_will: Some(LastWill {
+                    qos: QoS::ExactlyOnce,
+                    retain: false,
+                    topic: ByteString::from_static("topic"),
+                    message: Bytes::from_static(b"message"),
+                    will_delay_interval_sec: Some(10),
+                    correlation_data: None,
+                    message_expiry_interval: None,
+                    content_type: None,
+                    user_properties: vec![],
+                    is_utf8_payload: None,
+                    response_topic: None,
+                }),
+                username: None,
+                password: None,
+                session_expiry_interval_secs: None,
+                auth_method: None,
+                auth_data: None,
+                request_problem_info: true,
+                request_response_info: false,
+                receive_max: None,
+                topic_alias_max: 0,
+                user_properties: vec![],
+                max_packet_size: None,
+            })),
+            &b"\x10\x28\x00\x04MQTT\x05\x14\x00\x3C\x00\x00\
+\x0512345\x05\x18\x00\x00\x00\x0A\x00\x05topic\x00\x07message"[..],
+        );
+
         assert_encode_packet(
             &Packet::Disconnect(Disconnect {
                 reason_code: DisconnectReasonCode::NormalDisconnection,
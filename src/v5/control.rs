@@ -1,9 +1,9 @@
-use std::marker::PhantomData;
+use std::{marker::PhantomData, num::NonZeroU32, rc::Rc};
 
 use ntex::util::ByteString;
 
 use super::codec::{self, DisconnectReasonCode, QoS, UserProperties};
-use crate::error;
+use crate::{error, info::ConnectionInfo, subs, topic::SharedTopic};
 
 /// Control plain messages
 #[derive(Debug)]
@@ -38,8 +38,8 @@ impl<E> ControlMessage<E> {
         ControlMessage::Disconnect(Disconnect(pkt))
     }
 
-    pub(super) fn closed(is_error: bool) -> Self {
-        ControlMessage::Closed(Closed::new(is_error))
+    pub(super) fn closed(is_error: bool, info: Option<Rc<ConnectionInfo>>) -> Self {
+        ControlMessage::Closed(Closed::new(is_error, info))
     }
 
     pub(super) fn error(err: E) -> Self {
@@ -187,6 +187,7 @@ impl<'a> SubscribeIter<'a> {
 
         if self.entry < subs.packet.topic_filters.len() {
             let s = Subscription {
+                id: subs.packet.id,
                 topic: &subs.packet.topic_filters[self.entry].0,
                 options: &subs.packet.topic_filters[self.entry].1,
                 status: &mut subs.result.status[self.entry],
@@ -211,6 +212,7 @@ impl<'a> Iterator for SubscribeIter<'a> {
 /// Subscription topic
 #[derive(Debug)]
 pub struct Subscription<'a> {
+    id: Option<NonZeroU32>,
     topic: &'a ByteString,
     options: &'a codec::SubscriptionOptions,
     status: &'a mut codec::SubscribeAckReason,
@@ -229,6 +231,32 @@ impl<'a> Subscription<'a> {
         self.options
     }
 
+    #[inline]
+    /// subscription identifier
+    pub fn id(&self) -> Option<NonZeroU32> {
+        self.id
+    }
+
+    #[inline]
+    /// check if topic is a shared subscription filter
+    pub fn is_shared(&self) -> bool {
+        SharedTopic::is_shared(self.topic)
+    }
+
+    /// parse shared subscription filter
+    ///
+    /// Returns `None` if topic is not a valid `$share/{group}/{filter}` filter.
+    pub fn shared(&self) -> Option<SharedTopic> {
+        self.topic.parse().ok()
+    }
+
+    /// subscription with options and identifier for current topic and granted qos
+    pub fn subscription(&self, qos: QoS) -> subs::Subscription {
+        let mut options = self.options.clone();
+        options.qos = qos;
+        subs::Subscription { options, id: self.id }
+    }
+
     #[inline]
     /// fail to subscribe to the topic
     pub fn fail(&mut self, status: codec::SubscribeAckReason) {
@@ -380,6 +408,14 @@ impl<'a> UnsubscribeItem<'a> {
         self.topic
     }
 
+    #[inline]
+    /// parse shared subscription filter
+    ///
+    /// Returns `None` if topic is not a valid `$share/{group}/{filter}` filter.
+    pub fn shared(&self) -> Option<SharedTopic> {
+        self.topic.parse().ok()
+    }
+
     #[inline]
     /// fail to unsubscribe from the topic
     pub fn fail(&mut self, status: codec::UnsubscribeAckReason) {
@@ -397,11 +433,12 @@ impl<'a> UnsubscribeItem<'a> {
 #[derive(Debug)]
 pub struct Closed {
     is_error: bool,
+    info: Option<Rc<ConnectionInfo>>,
 }
 
 impl Closed {
-    pub(crate) fn new(is_error: bool) -> Self {
-        Self { is_error }
+    pub(crate) fn new(is_error: bool, info: Option<Rc<ConnectionInfo>>) -> Self {
+        Self { is_error, info }
     }
 
     /// Returns error state on connection close
@@ -409,6 +446,11 @@ impl Closed {
         self.is_error
     }
 
+    /// Returns peer connection info, available for server connections
+    pub fn connection_info(&self) -> Option<&ConnectionInfo> {
+        self.info.as_deref()
+    }
+
     #[inline]
     /// convert packet to a result
     pub fn ack(self) -> ControlResult {
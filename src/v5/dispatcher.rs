@@ -3,21 +3,28 @@ use std::task::{Context, Poll};
 use std::{convert::TryFrom, future::Future, marker, num, pin::Pin, rc::Rc};
 
 use ntex::service::{fn_factory_with_config, Service, ServiceFactory};
-use ntex::util::{join, Either, HashSet, Ready};
+use ntex::util::{join, ByteString, Either, HashMap, HashSet, Ready};
 
 use crate::error::{MqttError, ProtocolError};
 use crate::io::DispatchItem;
+use crate::registry::ClientRegistry;
+use crate::stats::Stats;
+use crate::will::{ConnectWill, WillManager};
+use crate::{topic::SharedTopic, types::packet_type};
 
 use super::control::{self, ControlMessage, ControlResult};
 use super::publish::{Publish, PublishAck};
 use super::shared::{Ack, MqttShared};
 use super::sink::MqttSink;
-use super::{codec, Session};
+use super::{codec, QoS, Session};
 
 /// mqtt3 protocol dispatcher
 pub(super) fn factory<St, T, C, E>(
     publish: T,
     control: C,
+    will: Option<WillManager>,
+    registry: Option<ClientRegistry>,
+    stats: Option<Stats>,
 ) -> impl ServiceFactory<
     Config = Session<St>,
     Request = DispatchItem<Rc<MqttShared>>,
@@ -49,15 +56,35 @@ where
 
         let (max_receive, max_topic_alias) = cfg.params();
 
+        // connection's last will
+        let connect = cfg.sink().take_will();
+        let registry = registry
+            .clone()
+            .and_then(|registry| connect.as_ref().map(|c| (registry, c.client_id.clone())));
+        let will = connect.and_then(|mut connect| {
+            will.clone().map(|manager| {
+                manager.connected(&mut connect);
+                (manager, connect)
+            })
+        });
+        let stats = stats.clone();
+
         async move {
             let (publish, control) = fut.await;
+            let (publish, control) = (publish?, control?);
+            if let Some(ref stats) = stats {
+                stats.connection_opened();
+            }
 
             Ok(Dispatcher::<_, _, E, T::Error>::new(
                 cfg.sink().clone(),
                 max_receive as usize,
                 max_topic_alias,
-                publish?,
-                control?,
+                publish,
+                control,
+                will,
+                registry,
+                stats,
             ))
         }
     })
@@ -70,6 +97,9 @@ pub(crate) struct Dispatcher<T, C, E, E2> {
     shutdown: Cell<bool>,
     max_receive: usize,
     max_topic_alias: u16,
+    will: RefCell<Option<(WillManager, ConnectWill)>>,
+    registry: Option<(ClientRegistry, ByteString)>,
+    stats: Option<Stats>,
     inner: Rc<Inner<C>>,
     _t: marker::PhantomData<(E, E2)>,
 }
@@ -82,7 +112,9 @@ struct Inner<C> {
 
 struct PublishInfo {
     inflight: HashSet<num::NonZeroU16>,
-    aliases: HashSet<num::NonZeroU16>,
+    aliases: HashMap<num::NonZeroU16, ByteString>,
+    // qos2 publishes acked with PUBREC, waiting for PUBREL
+    await_release: HashSet<num::NonZeroU16>,
 }
 
 impl<T, C, E, E2> Dispatcher<T, C, E, E2>
@@ -91,12 +123,16 @@ where
     PublishAck: TryFrom<E2, Error = E>,
     C: Service<Request = ControlMessage<E>, Response = ControlResult, Error = E>,
 {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         sink: MqttSink,
         max_receive: usize,
         max_topic_alias: u16,
         publish: T,
         control: C,
+        will: Option<(WillManager, ConnectWill)>,
+        registry: Option<(ClientRegistry, ByteString)>,
+        stats: Option<Stats>,
     ) -> Self {
         Self {
             publish,
@@ -104,12 +140,16 @@ where
             max_topic_alias,
             sink: sink.clone(),
             shutdown: Cell::new(false),
+            will: RefCell::new(will),
+            registry,
+            stats,
             inner: Rc::new(Inner {
                 control,
                 sink,
                 info: RefCell::new(PublishInfo {
-                    aliases: HashSet::default(),
+                    aliases: HashMap::default(),
                     inflight: HashSet::default(),
+                    await_release: HashSet::default(),
                 }),
             }),
             _t: marker::PhantomData,
@@ -148,7 +188,22 @@ where
         if !self.shutdown.get() {
             self.inner.sink.drop_sink();
             self.shutdown.set(true);
-            let fut = self.inner.control.call(ControlMessage::closed(is_error));
+
+            // will message is discarded by normal disconnect
+            if let Some((manager, will)) = self.will.borrow_mut().take() {
+                manager.closed(will);
+            }
+            if let Some((ref registry, ref client_id)) = self.registry {
+                registry.unregister_v5(client_id, &self.inner.sink);
+            }
+            if let Some(ref stats) = self.stats {
+                stats.connection_closed();
+            }
+
+            let fut = self
+                .inner
+                .control
+                .call(ControlMessage::closed(is_error, self.inner.sink.connection_info()));
             ntex::rt::spawn(async move {
                 let _ = fut.await;
             });
@@ -160,14 +215,26 @@ where
         log::trace!("Dispatch v5 packet: {:#?}", request);
 
         match request {
-            DispatchItem::Item(codec::Packet::Publish(publish)) => {
+            DispatchItem::Item(codec::Packet::Publish(mut publish)) => {
                 let info = self.inner.clone();
                 let packet_id = publish.packet_id;
+                let qos = publish.qos;
 
                 {
                     let mut inner = info.info.borrow_mut();
 
                     if let Some(pid) = packet_id {
+                        // qos2 publish is already received, peer did not get PUBREC
+                        if qos == QoS::ExactlyOnce && inner.await_release.contains(&pid) {
+                            log::trace!("Re-delivered qos2 publish packet: {:?}", pid);
+                            return Either::Right(Either::Left(Ready::Ok(Some(
+                                codec::Packet::PublishReceived(codec::PublishAck {
+                                    packet_id: pid,
+                                    ..Default::default()
+                                }),
+                            ))));
+                        }
+
                         // check for receive maximum
                         if self.max_receive != 0 && inner.inflight.len() >= self.max_receive {
                             log::trace!(
@@ -185,11 +252,16 @@ where
 
                         // check for duplicated packet id
                         if !inner.inflight.insert(pid) {
-                            self.sink.send(codec::Packet::PublishAck(codec::PublishAck {
+                            let ack = codec::PublishAck {
                                 packet_id: pid,
                                 reason_code: codec::PublishAckReason::PacketIdentifierInUse,
                                 ..Default::default()
-                            }));
+                            };
+                            if qos == QoS::ExactlyOnce {
+                                self.sink.send(codec::Packet::PublishReceived(ack));
+                            } else {
+                                self.sink.send(codec::Packet::PublishAck(ack));
+                            }
                             return Either::Right(Either::Left(Ready::Ok(None)));
                         }
                     }
@@ -198,7 +270,9 @@ where
                     if let Some(alias) = publish.properties.topic_alias {
                         // check existing topic
                         if publish.topic.is_empty() {
-                            if !inner.aliases.contains(&alias) {
+                            if let Some(topic) = inner.aliases.get(&alias) {
+                                publish.topic = topic.clone();
+                            } else {
                                 return Either::Right(Either::Right(ControlResponse::new(
                                     ControlMessage::proto_error(
                                         ProtocolError::UnknownTopicAlias,
@@ -215,16 +289,19 @@ where
                             }
 
                             // record new alias
-                            inner.aliases.insert(alias);
+                            inner.aliases.insert(alias, publish.topic.clone());
                         }
                     }
                 }
 
                 Either::Left(PublishResponse {
+                    qos,
                     packet_id: packet_id.map(|v| v.get()).unwrap_or(0),
                     inner: info,
                     state: PublishResponseState::Publish {
-                        fut: self.publish.call(Publish::new(publish)),
+                        fut: self
+                            .publish
+                            .call(Publish::new(publish, self.sink.connection_info())),
                     },
                     _t: marker::PhantomData,
                 })
@@ -239,16 +316,79 @@ where
                     Either::Right(Either::Left(Ready::Ok(None)))
                 }
             }
+            DispatchItem::Item(codec::Packet::PublishReceived(packet)) => {
+                if let Err(err) = self.sink.pkt_ack(Ack::Receive(packet)) {
+                    Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(err),
+                        &self.inner,
+                    )))
+                } else {
+                    Either::Right(Either::Left(Ready::Ok(None)))
+                }
+            }
+            DispatchItem::Item(codec::Packet::PublishComplete(packet)) => {
+                if let Err(err) = self.sink.pkt_ack(Ack::Complete(packet)) {
+                    Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(err),
+                        &self.inner,
+                    )))
+                } else {
+                    Either::Right(Either::Left(Ready::Ok(None)))
+                }
+            }
+            DispatchItem::Item(codec::Packet::PublishRelease(pkt)) => {
+                let mut info = self.inner.info.borrow_mut();
+                let reason_code = if info.await_release.remove(&pkt.packet_id) {
+                    info.inflight.remove(&pkt.packet_id);
+                    codec::PublishAck2Reason::Success
+                } else {
+                    log::trace!("Unknown packet id for PUBREL: {:?}", pkt.packet_id);
+                    codec::PublishAck2Reason::PacketIdNotFound
+                };
+                Either::Right(Either::Left(Ready::Ok(Some(codec::Packet::PublishComplete(
+                    codec::PublishAck2 {
+                        reason_code,
+                        packet_id: pkt.packet_id,
+                        properties: codec::UserProperties::default(),
+                        reason_string: None,
+                    },
+                )))))
+            }
             DispatchItem::Item(codec::Packet::Auth(pkt)) => Either::Right(Either::Right(
                 ControlResponse::new(ControlMessage::auth(pkt), &self.inner),
             )),
             DispatchItem::Item(codec::Packet::PingRequest) => Either::Right(Either::Right(
                 ControlResponse::new(ControlMessage::ping(), &self.inner),
             )),
-            DispatchItem::Item(codec::Packet::Disconnect(pkt)) => Either::Right(Either::Right(
-                ControlResponse::new(ControlMessage::dis(pkt), &self.inner),
-            )),
+            DispatchItem::Item(codec::Packet::Disconnect(pkt)) => {
+                // normal disconnect, discard last will
+                if pkt.reason_code == codec::DisconnectReasonCode::NormalDisconnection {
+                    if let Some((_, ref mut will)) = *self.will.borrow_mut() {
+                        will.message = None;
+                    }
+                }
+                Either::Right(Either::Right(ControlResponse::new(
+                    ControlMessage::dis(pkt),
+                    &self.inner,
+                )))
+            }
             DispatchItem::Item(codec::Packet::Subscribe(pkt)) => {
+                // shared subscription cannot set no local option
+                if pkt
+                    .topic_filters
+                    .iter()
+                    .any(|(topic, opts)| opts.no_local && SharedTopic::is_shared(topic))
+                {
+                    log::trace!("No local option is set for shared subscription");
+                    return Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(ProtocolError::Unexpected(
+                            packet_type::SUBSCRIBE,
+                            "MQTT-3.8.3-4: No Local option is set for shared subscription",
+                        )),
+                        &self.inner,
+                    )));
+                }
+
                 // register inflight packet id
                 if !self.inner.info.borrow_mut().inflight.insert(pkt.packet_id) {
                     // duplicated packet id
@@ -328,6 +468,7 @@ pin_project_lite::pin_project! {
         #[pin]
         state: PublishResponseState<T, C, E>,
         packet_id: u16,
+        qos: QoS,
         inner: Rc<Inner<C>>,
         _t: marker::PhantomData<(E, E2)>,
     }
@@ -384,14 +525,25 @@ where
                     Poll::Pending => return Poll::Pending,
                 };
                 if let Some(id) = num::NonZeroU16::new(*this.packet_id) {
-                    this.inner.info.borrow_mut().inflight.remove(&id);
                     let ack = codec::PublishAck {
                         packet_id: id,
                         reason_code: ack.reason_code,
                         reason_string: ack.reason_string,
                         properties: ack.properties,
                     };
-                    Poll::Ready(Ok(Some(codec::Packet::PublishAck(ack))))
+                    let mut info = this.inner.info.borrow_mut();
+                    if *this.qos == QoS::ExactlyOnce {
+                        // failed PUBREC completes qos2 flow
+                        if u8::from(ack.reason_code) < 0x80 {
+                            info.await_release.insert(id);
+                        } else {
+                            info.inflight.remove(&id);
+                        }
+                        Poll::Ready(Ok(Some(codec::Packet::PublishReceived(ack))))
+                    } else {
+                        info.inflight.remove(&id);
+                        Poll::Ready(Ok(Some(codec::Packet::PublishAck(ack))))
+                    }
                 } else {
                     Poll::Ready(Ok(None))
                 }
@@ -1,3 +1,5 @@
+use std::convert::TryFrom;
+
 use derive_more::{Display, From};
 use ntex::util::Either;
 
@@ -22,6 +24,9 @@ pub enum ClientError {
     /// Connect error
     #[display(fmt = "Connect error: {}", _0)]
     Connect(ntex::connect::ConnectError),
+    /// Enhanced authentication failed
+    #[display(fmt = "Authentication failed: {:?}", _0)]
+    Auth(Box<codec::Disconnect>),
 }
 
 impl std::error::Error for ClientError {}
@@ -49,3 +54,49 @@ pub enum PublishQos1Error {
     #[display(fmt = "Peer disconnected")]
     Disconnected,
 }
+
+#[derive(Debug, Display, PartialEq)]
+pub enum PublishQos2Error {
+    /// Negative publish received from peer
+    #[display(fmt = "Negative publish received: {:?}", _0)]
+    Fail(codec::PublishAck),
+    /// Negative publish complete from peer
+    #[display(fmt = "Negative publish complete: {:?}", _0)]
+    Complete(codec::PublishAck2),
+    /// Encoder error
+    Encode(EncodeError),
+    /// Provided packet id is in use
+    #[display(fmt = "Provided packet id is in use")]
+    PacketIdInUse(u16),
+    /// Peer disconnected
+    #[display(fmt = "Peer disconnected")]
+    Disconnected,
+}
+
+impl TryFrom<PublishQos1Error> for SendPacketError {
+    type Error = PublishQos1Error;
+
+    /// Convert to send error, negative ack from peer is returned back
+    fn try_from(err: PublishQos1Error) -> Result<Self, Self::Error> {
+        match err {
+            PublishQos1Error::Encode(err) => Ok(SendPacketError::Encode(err)),
+            PublishQos1Error::PacketIdInUse(id) => Ok(SendPacketError::PacketIdInUse(id)),
+            PublishQos1Error::Disconnected => Ok(SendPacketError::Disconnected),
+            PublishQos1Error::Fail(_) => Err(err),
+        }
+    }
+}
+
+impl TryFrom<PublishQos2Error> for SendPacketError {
+    type Error = PublishQos2Error;
+
+    /// Convert to send error, negative ack from peer is returned back
+    fn try_from(err: PublishQos2Error) -> Result<Self, Self::Error> {
+        match err {
+            PublishQos2Error::Encode(err) => Ok(SendPacketError::Encode(err)),
+            PublishQos2Error::PacketIdInUse(id) => Ok(SendPacketError::PacketIdInUse(id)),
+            PublishQos2Error::Disconnected => Ok(SendPacketError::Disconnected),
+            PublishQos2Error::Fail(_) | PublishQos2Error::Complete(_) => Err(err),
+        }
+    }
+}
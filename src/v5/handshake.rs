@@ -1,6 +1,12 @@
-use std::{fmt, num::NonZeroU16, rc::Rc};
+use std::{fmt, io, num::NonZeroU16, rc::Rc};
+
+use ntex::codec::{AsyncRead, AsyncWrite};
 
 use super::{codec, shared::MqttShared, sink::MqttSink};
+use crate::cert::CertIdentity;
+use crate::info::{ConnectionInfo, Protocol};
+use crate::{error::ProtocolError, types::packet_type};
+use crate::{proxy::ProxyHeader, will::ConnectWill};
 
 /// Handshake message
 pub struct Handshake<Io> {
@@ -10,9 +16,10 @@ pub struct Handshake<Io> {
     pub(super) max_size: u32,
     pub(super) max_receive: u16,
     pub(super) max_topic_alias: u16,
+    info: Rc<ConnectionInfo>,
 }
 
-impl<Io> Handshake<Io> {
+impl<Io: 'static> Handshake<Io> {
     pub(crate) fn new(
         pkt: Box<codec::Connect>,
         io: Io,
@@ -20,10 +27,15 @@ impl<Io> Handshake<Io> {
         max_size: u32,
         max_receive: u16,
         max_topic_alias: u16,
+        proxy: Option<ProxyHeader>,
     ) -> Self {
-        Self { io, pkt, shared, max_size, max_receive, max_topic_alias }
+        let info = Rc::new(ConnectionInfo::new(&io, Protocol::MQTT5, proxy));
+        *shared.info.borrow_mut() = Some(info.clone());
+        Self { io, pkt, shared, max_size, max_receive, max_topic_alias, info }
     }
+}
 
+impl<Io> Handshake<Io> {
     #[inline]
     pub fn packet(&self) -> &codec::Connect {
         &self.pkt
@@ -39,6 +51,26 @@ impl<Io> Handshake<Io> {
         &mut self.io
     }
 
+    #[inline]
+    /// Returns PROXY protocol header
+    ///
+    /// Header is available if PROXY protocol is enabled with
+    /// `MqttServer::proxy_protocol()`.
+    pub fn proxy_header(&self) -> Option<&ProxyHeader> {
+        self.info.proxy_header()
+    }
+
+    #[inline]
+    /// Returns peer connection info
+    pub fn connection_info(&self) -> &ConnectionInfo {
+        &self.info
+    }
+
+    /// Returns identity of the peer certificate, available for tls connections
+    pub fn peer_identity(&self) -> Option<&CertIdentity> {
+        self.info.peer_identity()
+    }
+
     #[inline]
     /// Returns mqtt server sink
     pub fn sink(&self) -> MqttSink {
@@ -48,6 +80,9 @@ impl<Io> Handshake<Io> {
     #[inline]
     /// Ack handshake message and set state
     pub fn ack<St>(self, st: St) -> HandshakeAck<Io, St> {
+        *self.shared.client_id.borrow_mut() = Some(self.pkt.client_id.clone());
+        *self.shared.will.borrow_mut() = Some(ConnectWill::from_v5(&self.pkt));
+
         let mut packet = codec::ConnectAck {
             reason_code: codec::ConnectAckReason::Success,
             topic_alias_max: self.max_topic_alias,
@@ -103,6 +138,63 @@ impl<Io> Handshake<Io> {
     }
 }
 
+impl<Io> Handshake<Io>
+where
+    Io: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Send AUTH challenge to the client and wait for client's response
+    ///
+    /// Enhanced authentication exchange happens before connect ack, method
+    /// could be called multiple times for multi-step authentication. Reason
+    /// code of the packet is set to `ContinueAuth`, authentication method is
+    /// set to the method of the connect packet if it is not specified.
+    /// Client's response must use the same authentication method. Final auth
+    /// data could be passed to the client with `HandshakeAck::with()`.
+    pub async fn auth_challenge(
+        &mut self,
+        mut pkt: codec::Auth,
+    ) -> Result<codec::Auth, ProtocolError> {
+        if self.pkt.auth_method.is_none() {
+            return Err(ProtocolError::Unexpected(
+                packet_type::AUTH,
+                "Authentication method is not specified by the client",
+            ));
+        }
+        pkt.reason_code = codec::AuthReasonCode::ContinueAuth;
+        if pkt.auth_method.is_none() {
+            pkt.auth_method = self.pkt.auth_method.clone();
+        }
+
+        let state = &self.shared.state;
+        log::trace!("Sending auth challenge: {:?}", pkt);
+        state.send(&mut self.io, &self.shared.codec, codec::Packet::Auth(pkt)).await?;
+
+        match state.next(&mut self.io, &self.shared.codec).await? {
+            Some(codec::Packet::Auth(pkt)) => {
+                log::trace!("Auth response from client: {:?}", pkt);
+                if pkt.reason_code != codec::AuthReasonCode::ContinueAuth
+                    || pkt.auth_method != self.pkt.auth_method
+                {
+                    Err(ProtocolError::Unexpected(
+                        packet_type::AUTH,
+                        "Unexpected auth reason code or method",
+                    ))
+                } else {
+                    Ok(pkt)
+                }
+            }
+            Some(pkt) => Err(ProtocolError::Unexpected(
+                pkt.packet_type(),
+                "Expected AUTH packet during authentication",
+            )),
+            None => Err(ProtocolError::Io(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Peer is disconnected during authentication",
+            ))),
+        }
+    }
+}
+
 impl<T> fmt::Debug for Handshake<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         self.pkt.fmt(f)
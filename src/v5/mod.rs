@@ -13,6 +13,7 @@ mod selector;
 mod server;
 mod shared;
 mod sink;
+mod store;
 
 pub type Session<St> = crate::Session<MqttSink, St>;
 
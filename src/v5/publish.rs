@@ -1,4 +1,4 @@
-use std::{mem, num::NonZeroU16};
+use std::{mem, num::NonZeroU16, rc::Rc};
 
 use ntex::router::Path;
 use ntex::util::{ByteString, Bytes};
@@ -6,16 +6,18 @@ use serde::de::DeserializeOwned;
 use serde_json::Error as JsonError;
 
 use super::codec;
+use crate::info::ConnectionInfo;
 
 /// Publish message
 pub struct Publish {
     publish: codec::Publish,
     topic: Path<ByteString>,
+    info: Option<Rc<ConnectionInfo>>,
 }
 
 impl Publish {
-    pub(crate) fn new(publish: codec::Publish) -> Self {
-        Self { topic: Path::new(publish.topic.clone()), publish }
+    pub(crate) fn new(publish: codec::Publish, info: Option<Rc<ConnectionInfo>>) -> Self {
+        Self { topic: Path::new(publish.topic.clone()), publish, info }
     }
 
     #[inline]
@@ -67,6 +69,12 @@ impl Publish {
         &mut self.publish
     }
 
+    #[inline]
+    /// Peer connection info, available for server connections
+    pub fn connection_info(&self) -> Option<&ConnectionInfo> {
+        self.info.as_deref()
+    }
+
     #[inline]
     /// the Application Message that is being published.
     pub fn payload(&self) -> &Bytes {
@@ -10,6 +10,8 @@ use ntex::util::{timeout::Timeout, timeout::TimeoutError, Either, Ready};
 
 use crate::error::{MqttError, ProtocolError};
 use crate::io::{DispatchItem, State};
+use crate::proxy::ProxyHeader;
+use crate::stats::Stats;
 
 use super::control::{ControlMessage, ControlResult};
 use super::default::{DefaultControlService, DefaultPublishService};
@@ -39,6 +41,7 @@ pub struct Selector<Io, Err, InitErr> {
     servers: Vec<ServerFactory<Io, Err, InitErr>>,
     max_size: u32,
     handshake_timeout: Seconds,
+    stats: Option<Stats>,
     pool: Rc<MqttSinkPool>,
     _t: marker::PhantomData<(Io, Err, InitErr)>,
 }
@@ -50,6 +53,7 @@ impl<Io, Err, InitErr> Selector<Io, Err, InitErr> {
             servers: Vec::new(),
             max_size: 0,
             handshake_timeout: Seconds::ZERO,
+            stats: None,
             pool: Default::default(),
             _t: marker::PhantomData,
         }
@@ -80,6 +84,15 @@ where
         self
     }
 
+    /// Set statistics collector
+    ///
+    /// Collector is used by server variants that are added after this call
+    /// and do not have own collector.
+    pub fn stats(mut self, stats: Stats) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
     /// Add server variant
     pub fn variant<F, R, St, C, Cn, P>(
         mut self,
@@ -114,6 +127,9 @@ where
         PublishAck: TryFrom<P::Error, Error = C::Error>,
     {
         server.pool = self.pool.clone();
+        if server.stats.is_none() {
+            server.stats = self.stats.clone();
+        }
         self.servers.push(boxed::factory(server.finish_selector(check)));
         self
     }
@@ -123,7 +139,7 @@ where
         self,
     ) -> impl ServiceFactory<
         Config = (),
-        Request = (Io, State, Option<Sleep>),
+        Request = (Io, State, Option<Sleep>, Option<ProxyHeader>),
         Response = (),
         Error = MqttError<Err>,
         InitError = InitErr,
@@ -131,6 +147,7 @@ where
         Selector2 {
             servers: self.servers,
             max_size: self.max_size,
+            stats: self.stats,
             pool: self.pool,
             _t: marker::PhantomData,
         }
@@ -155,6 +172,7 @@ where
         let futs: Vec<_> = self.servers.iter().map(|srv| srv.new_service(())).collect();
         let max_size = self.max_size;
         let handshake_timeout = self.handshake_timeout;
+        let stats = self.stats.clone();
         let pool = self.pool.clone();
 
         Box::pin(async move {
@@ -162,7 +180,13 @@ where
             for fut in futs {
                 servers.push(fut.await?);
             }
-            Ok(SelectorService { max_size, handshake_timeout, pool, servers: Rc::new(servers) })
+            Ok(SelectorService {
+                max_size,
+                handshake_timeout,
+                stats,
+                pool,
+                servers: Rc::new(servers),
+            })
         })
     }
 }
@@ -171,6 +195,7 @@ pub struct SelectorService<Io, Err> {
     servers: Rc<Vec<Server<Io, Err>>>,
     max_size: u32,
     handshake_timeout: Seconds,
+    stats: Option<Stats>,
     pool: Rc<MqttSinkPool>,
 }
 
@@ -216,7 +241,9 @@ where
         let state = State::new();
         let shared = Rc::new(MqttShared::new(
             state.clone(),
-            mqtt::Codec::default().max_inbound_size(self.max_size),
+            mqtt::Codec::default()
+                .max_inbound_size(self.max_size)
+                .with_stats(self.stats.clone()),
             0,
             self.pool.clone(),
         ));
@@ -250,7 +277,7 @@ where
             };
 
             // call servers
-            let mut item = (Handshake::new(connect, io, shared, 0, 0, 0), state, delay);
+            let mut item = (Handshake::new(connect, io, shared, 0, 0, 0, None), state, delay);
             for srv in servers.iter() {
                 match srv.call(item).await? {
                     Either::Left(result) => {
@@ -268,6 +295,7 @@ where
 pub(crate) struct Selector2<Io, Err, InitErr> {
     servers: Vec<ServerFactory<Io, Err, InitErr>>,
     max_size: u32,
+    stats: Option<Stats>,
     pool: Rc<MqttSinkPool>,
     _t: marker::PhantomData<(Io, Err, InitErr)>,
 }
@@ -279,7 +307,7 @@ where
     InitErr: 'static,
 {
     type Config = ();
-    type Request = (Io, State, Option<Sleep>);
+    type Request = (Io, State, Option<Sleep>, Option<ProxyHeader>);
     type Response = ();
     type Error = MqttError<Err>;
     type InitError = InitErr;
@@ -289,6 +317,7 @@ where
     fn new_service(&self, _: ()) -> Self::Future {
         let futs: Vec<_> = self.servers.iter().map(|srv| srv.new_service(())).collect();
         let max_size = self.max_size;
+        let stats = self.stats.clone();
         let pool = self.pool.clone();
 
         Box::pin(async move {
@@ -296,7 +325,7 @@ where
             for fut in futs {
                 servers.push(fut.await?);
             }
-            Ok(SelectorService2 { max_size, pool, servers: Rc::new(servers) })
+            Ok(SelectorService2 { max_size, stats, pool, servers: Rc::new(servers) })
         })
     }
 }
@@ -304,6 +333,7 @@ where
 pub(crate) struct SelectorService2<Io, Err> {
     servers: Rc<Vec<Server<Io, Err>>>,
     max_size: u32,
+    stats: Option<Stats>,
     pool: Rc<MqttSinkPool>,
 }
 
@@ -312,7 +342,7 @@ where
     Io: AsyncRead + AsyncWrite + Unpin + 'static,
     Err: 'static,
 {
-    type Request = (Io, State, Option<Sleep>);
+    type Request = (Io, State, Option<Sleep>, Option<ProxyHeader>);
     type Response = ();
     type Error = MqttError<Err>;
     type Future = Pin<Box<dyn Future<Output = Result<(), MqttError<Err>>>>>;
@@ -344,11 +374,13 @@ where
     }
 
     #[inline]
-    fn call(&self, (mut io, state, delay): Self::Request) -> Self::Future {
+    fn call(&self, (mut io, state, delay, proxy): Self::Request) -> Self::Future {
         let servers = self.servers.clone();
         let shared = Rc::new(MqttShared::new(
             state.clone(),
-            mqtt::Codec::default().max_inbound_size(self.max_size),
+            mqtt::Codec::default()
+                .max_inbound_size(self.max_size)
+                .with_stats(self.stats.clone()),
             0,
             self.pool.clone(),
         ));
@@ -381,7 +413,7 @@ where
             };
 
             // call servers
-            let mut item = (Handshake::new(connect, io, shared, 0, 0, 0), state, delay);
+            let mut item = (Handshake::new(connect, io, shared, 0, 0, 0, proxy), state, delay);
             for srv in servers.iter() {
                 match srv.call(item).await? {
                     Either::Left(result) => {
@@ -10,8 +10,11 @@ use ntex::util::Either;
 
 use crate::error::{MqttError, ProtocolError};
 use crate::io::{DispatchItem, Dispatcher, State, Timer};
+use crate::proxy::ProxyHeader;
+use crate::registry::ClientRegistry;
 use crate::service::{FramedService, FramedService2};
-use crate::types::QoS;
+use crate::stats::Stats;
+use crate::{types::QoS, will::WillManager};
 
 use super::control::{ControlMessage, ControlResult};
 use super::default::{DefaultControlService, DefaultPublishService};
@@ -32,6 +35,9 @@ pub struct MqttServer<Io, St, C: ServiceFactory, Cn: ServiceFactory, P: ServiceF
     handshake_timeout: Seconds,
     disconnect_timeout: Seconds,
     max_topic_alias: u16,
+    will: Option<WillManager>,
+    registry: Option<ClientRegistry>,
+    pub(super) stats: Option<Stats>,
     pub(super) pool: Rc<MqttSinkPool>,
     _t: marker::PhantomData<(Io, St)>,
 }
@@ -65,6 +71,9 @@ where
             handshake_timeout: Seconds::ZERO,
             disconnect_timeout: Seconds(3),
             max_topic_alias: 32,
+            will: None,
+            registry: None,
+            stats: None,
             pool: Rc::new(MqttSinkPool::default()),
             _t: marker::PhantomData,
         }
@@ -141,6 +150,32 @@ where
         self
     }
 
+    /// Set last will manager
+    ///
+    /// Will of connection that is closed without normal DISCONNECT
+    /// is handed to the manager. By default last will is ignored.
+    pub fn will_manager(mut self, manager: WillManager) -> Self {
+        self.will = Some(manager);
+        self
+    }
+
+    /// Set connected clients registry
+    ///
+    /// Connection with the same client id as new connection get closed
+    /// with `SessionTakenOver` reason before new connection is acknowledged.
+    pub fn client_registry(mut self, registry: ClientRegistry) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    /// Set statistics collector
+    ///
+    /// Collector counts connections, packets and bytes of the server.
+    pub fn stats(mut self, stats: Stats) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
     /// Service to handle control messages
     pub fn control<F, Srv>(self, service: F) -> MqttServer<Io, St, C, Srv, P>
     where
@@ -162,6 +197,9 @@ where
             max_qos: self.max_qos,
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
+            will: self.will,
+            registry: self.registry,
+            stats: self.stats,
             pool: self.pool,
             _t: marker::PhantomData,
         }
@@ -187,6 +225,9 @@ where
             max_qos: self.max_qos,
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
+            will: self.will,
+            registry: self.registry,
+            stats: self.stats,
             pool: self.pool,
             _t: marker::PhantomData,
         }
@@ -233,9 +274,11 @@ where
                 self.max_topic_alias,
                 self.max_qos,
                 self.handshake_timeout,
+                self.registry.clone(),
+                self.stats.clone(),
                 self.pool,
             ),
-            factory(publish, control),
+            factory(publish, control, self.will, self.registry, self.stats),
             self.disconnect_timeout,
         )
     }
@@ -245,7 +288,7 @@ where
         self,
     ) -> impl ServiceFactory<
         Config = (),
-        Request = (Io, State, Option<Sleep>),
+        Request = (Io, State, Option<Sleep>, Option<ProxyHeader>),
         Response = (),
         Error = MqttError<C::Error>,
         InitError = C::InitError,
@@ -265,9 +308,11 @@ where
                 self.max_topic_alias,
                 self.max_qos,
                 self.handshake_timeout,
+                self.registry.clone(),
+                self.stats.clone(),
                 self.pool,
             ),
-            factory(publish, control),
+            factory(publish, control, self.will, self.registry, self.stats),
             self.disconnect_timeout,
         )
     }
@@ -296,18 +341,26 @@ where
         ServerSelector::<St, _, _, Io, _, _> {
             check: Rc::new(check),
             connect: self.handshake,
-            handler: Rc::new(factory(publish, control)),
+            handler: Rc::new(factory(
+                publish,
+                control,
+                self.will,
+                self.registry.clone(),
+                self.stats,
+            )),
             max_size: self.max_size,
             max_receive: self.max_receive,
             max_topic_alias: self.max_topic_alias,
             max_qos: self.max_qos,
             disconnect_timeout: self.disconnect_timeout,
             time: Timer::new(Millis::ONE_SEC),
+            registry: self.registry,
             _t: marker::PhantomData,
         }
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn handshake_service_factory<Io, St, C>(
     factory: C,
     max_size: u32,
@@ -315,6 +368,8 @@ fn handshake_service_factory<Io, St, C>(
     max_topic_alias: u16,
     max_qos: Option<QoS>,
     handshake_timeout: Seconds,
+    registry: Option<ClientRegistry>,
+    stats: Option<Stats>,
     pool: Rc<MqttSinkPool>,
 ) -> impl ServiceFactory<
     Config = (),
@@ -331,6 +386,8 @@ where
         Timeout::new(Millis::from(handshake_timeout)),
         ntex::service::fn_factory(move || {
             let pool = pool.clone();
+            let registry = registry.clone();
+            let stats = stats.clone();
 
             let fut = factory.new_service(());
             async move {
@@ -343,11 +400,14 @@ where
                         handshake(
                             io,
                             None,
+                            None,
                             service.clone(),
                             max_size,
                             max_receive,
                             max_topic_alias,
                             max_qos,
+                            registry.clone(),
+                            stats.clone(),
                             pool.clone(),
                         )
                     },
@@ -361,6 +421,7 @@ where
     })
 }
 
+#[allow(clippy::too_many_arguments)]
 fn handshake_service_factory2<Io, St, C>(
     factory: C,
     max_size: u32,
@@ -368,10 +429,12 @@ fn handshake_service_factory2<Io, St, C>(
     max_topic_alias: u16,
     max_qos: Option<QoS>,
     handshake_timeout: Seconds,
+    registry: Option<ClientRegistry>,
+    stats: Option<Stats>,
     pool: Rc<MqttSinkPool>,
 ) -> impl ServiceFactory<
     Config = (),
-    Request = (Io, State),
+    Request = (Io, State, Option<ProxyHeader>),
     Response = (Io, State, Rc<MqttShared>, Session<St>, Seconds),
     Error = MqttError<C::Error>,
     InitError = C::InitError,
@@ -385,6 +448,8 @@ where
         Timeout::new(Millis::from(handshake_timeout)),
         ntex::service::fn_factory(move || {
             let pool = pool.clone();
+            let registry = registry.clone();
+            let stats = stats.clone();
             let fut = factory.new_service(());
             async move {
                 let service = fut.await?;
@@ -392,15 +457,18 @@ where
                 let service = Rc::new(service.map_err(MqttError::Service));
                 Ok::<_, C::InitError>(ntex::service::apply_fn(
                     service,
-                    move |(io, state), service| {
+                    move |(io, state, proxy), service| {
                         handshake(
                             io,
                             Some(state),
+                            proxy,
                             service.clone(),
                             max_size,
                             max_receive,
                             max_topic_alias,
                             max_qos,
+                            registry.clone(),
+                            stats.clone(),
                             pool.clone(),
                         )
                     },
@@ -418,11 +486,14 @@ where
 async fn handshake<Io, S, St, E>(
     mut io: Io,
     state: Option<State>,
+    proxy: Option<ProxyHeader>,
     service: S,
     max_size: u32,
     mut max_receive: u16,
     mut max_topic_alias: u16,
     max_qos: Option<QoS>,
+    registry: Option<ClientRegistry>,
+    stats: Option<Stats>,
     pool: Rc<MqttSinkPool>,
 ) -> Result<(Io, State, Rc<MqttShared>, Session<St>, Seconds), S::Error>
 where
@@ -432,7 +503,12 @@ where
     log::trace!("Starting mqtt v5 handshake");
 
     let state = state.unwrap_or_else(State::new);
-    let shared = Rc::new(MqttShared::new(state.clone(), mqtt::Codec::default(), 0, pool));
+    let shared = Rc::new(MqttShared::new(
+        state.clone(),
+        mqtt::Codec::default().with_stats(stats),
+        0,
+        pool,
+    ));
 
     // set max inbound (decoder) packet size
     shared.codec.set_max_inbound_size(max_size);
@@ -459,6 +535,7 @@ where
                 shared.codec.set_max_outbound_size(size.get());
             }
             shared.cap.set(connect.receive_max.map(|v| v.get()).unwrap_or(16) as usize);
+            shared.set_topic_alias_max(connect.topic_alias_max);
 
             let keep_alive = connect.keep_alive;
 
@@ -471,6 +548,7 @@ where
                     max_size,
                     max_receive,
                     max_topic_alias,
+                    proxy,
                 ))
                 .await?;
 
@@ -499,6 +577,13 @@ where
                         ack.packet.server_keepalive_sec = Some(ack.keepalive as u16);
                     }
 
+                    assign_client_id(&shared, &ack.packet);
+
+                    // close previous connection of the client
+                    if let Some(ref registry) = registry {
+                        register(registry, &shared);
+                    }
+
                     state.set_buffer_params(ack.read_hw, ack.write_hw, ack.lw);
                     state
                         .send(
@@ -508,6 +593,7 @@ where
                         )
                         .await?;
 
+                    let info = shared.info.borrow().clone();
                     Ok((
                         ack.io,
                         shared.state.clone(),
@@ -517,6 +603,7 @@ where
                             MqttSink::new(shared),
                             max_receive,
                             max_topic_alias,
+                            info,
                         ),
                         Seconds(ack.keepalive),
                     ))
@@ -555,6 +642,23 @@ where
     }
 }
 
+/// Server assigned client id replaces empty one
+fn assign_client_id(shared: &MqttShared, ack: &mqtt::ConnectAck) {
+    if let Some(ref client_id) = ack.assigned_client_id {
+        *shared.client_id.borrow_mut() = Some(client_id.clone());
+        if let Some(ref mut will) = *shared.will.borrow_mut() {
+            will.client_id = client_id.clone();
+        }
+    }
+}
+
+/// Register accepted connection
+fn register(registry: &ClientRegistry, shared: &Rc<MqttShared>) {
+    if let Some(client_id) = shared.client_id() {
+        registry.register_v5(client_id, MqttSink::new(shared.clone()));
+    }
+}
+
 pub(crate) struct ServerSelector<St, C, T, Io, F, R> {
     connect: C,
     handler: Rc<T>,
@@ -565,6 +669,7 @@ pub(crate) struct ServerSelector<St, C, T, Io, F, R> {
     max_qos: Option<QoS>,
     disconnect_timeout: Seconds,
     max_topic_alias: u16,
+    registry: Option<ClientRegistry>,
     _t: marker::PhantomData<(St, Io, R)>,
 }
 
@@ -602,6 +707,7 @@ where
         let max_qos = self.max_qos;
         let max_topic_alias = self.max_topic_alias;
         let disconnect_timeout = self.disconnect_timeout;
+        let registry = self.registry.clone();
 
         // create connect service and then create service impl
         Box::pin(async move {
@@ -614,6 +720,7 @@ where
                 max_qos,
                 max_topic_alias,
                 disconnect_timeout,
+                registry,
                 connect: Rc::new(fut.await?),
                 _t: marker::PhantomData,
             })
@@ -631,6 +738,7 @@ pub(crate) struct ServerSelectorImpl<St, C, T, Io, F, R> {
     disconnect_timeout: Seconds,
     max_topic_alias: u16,
     time: Timer,
+    registry: Option<ClientRegistry>,
     _t: marker::PhantomData<(St, Io, R)>,
 }
 
@@ -677,6 +785,7 @@ where
         let max_size = self.max_size;
         let mut max_receive = self.max_receive;
         let mut max_topic_alias = self.max_topic_alias;
+        let registry = self.registry.clone();
 
         Box::pin(async move {
             let (mut hnd, state, mut delay) = req;
@@ -701,6 +810,7 @@ where
                 hnd.shared
                     .cap
                     .set(hnd.packet().receive_max.map(|v| v.get()).unwrap_or(16) as usize);
+                hnd.shared.set_topic_alias_max(hnd.packet().topic_alias_max);
 
                 let keep_alive = hnd.packet().keep_alive;
                 hnd.max_size = max_size;
@@ -749,6 +859,13 @@ where
                             ack.packet.server_keepalive_sec = Some(ack.keepalive as u16);
                         }
 
+                        assign_client_id(&shared, &ack.packet);
+
+                        // close previous connection of the client
+                        if let Some(ref registry) = registry {
+                            register(registry, &shared);
+                        }
+
                         state.set_buffer_params(ack.read_hw, ack.write_hw, ack.lw);
                         state
                             .send(
@@ -758,11 +875,13 @@ where
                             )
                             .await?;
 
+                        let info = shared.info.borrow().clone();
                         let session = Session::new_v5(
                             session,
                             MqttSink::new(shared.clone()),
                             max_receive,
                             max_topic_alias,
+                            info,
                         );
                         let handler = handler.new_service(session).await?;
                         log::trace!("Connection handler is created, starting dispatcher");
@@ -1,16 +1,24 @@
-use std::{cell::Cell, cell::RefCell, collections::VecDeque, rc::Rc};
+use std::collections::{BTreeMap, VecDeque};
+use std::{cell::Cell, cell::RefCell, num::NonZeroU16, rc::Rc};
 
 use ntex::channel::pool;
 use ntex::codec::{Decoder, Encoder};
-use ntex::util::{BytesMut, HashMap};
+use ntex::util::{ByteString, BytesMut, HashMap};
 
 use super::codec;
-use crate::{error, io::State, types::packet_type};
+use super::store::{Store, StoreItem};
+use crate::{error, info::ConnectionInfo, io::State, types::packet_type, will::ConnectWill};
 
 pub(crate) struct MqttShared {
     pub(super) cap: Cell<usize>,
     queues: RefCell<MqttSharedQueues>,
     pub(super) inflight_idx: Cell<u16>,
+    pub(super) keep_inflight: Cell<bool>,
+    pub(super) client_id: RefCell<Option<ByteString>>,
+    pub(super) will: RefCell<Option<ConnectWill>>,
+    pub(super) info: RefCell<Option<Rc<ConnectionInfo>>>,
+    topic_aliases: RefCell<TopicAliases>,
+    pub(super) store: Option<Rc<dyn Store>>,
     pub(super) pool: Rc<MqttSinkPool>,
     pub(super) state: State,
     pub(super) codec: codec::Codec,
@@ -19,6 +27,7 @@ pub(crate) struct MqttShared {
 pub(super) struct MqttSharedQueues {
     pub(super) inflight: HashMap<u16, (pool::Sender<Ack>, AckType)>,
     pub(super) inflight_order: VecDeque<u16>,
+    pub(super) inflight_packets: HashMap<u16, codec::Publish>,
     pub(super) waiters: VecDeque<pool::Sender<()>>,
 }
 
@@ -48,19 +57,88 @@ impl MqttShared {
             queues: RefCell::new(MqttSharedQueues {
                 inflight: HashMap::default(),
                 inflight_order: VecDeque::with_capacity(8),
+                inflight_packets: HashMap::default(),
                 waiters: VecDeque::new(),
             }),
             inflight_idx: Cell::new(0),
+            keep_inflight: Cell::new(false),
+            client_id: RefCell::new(None),
+            will: RefCell::new(None),
+            info: RefCell::new(None),
+            topic_aliases: RefCell::new(TopicAliases::default()),
+            store: None,
         }
     }
 
+    pub(super) fn with_store(mut self, store: Option<Rc<dyn Store>>) -> Self {
+        self.store = store;
+        self
+    }
+
+    /// Persist outgoing packet
+    pub(super) fn store_put(&self, item: StoreItem) {
+        if let Some(ref store) = self.store {
+            if let Err(err) = store.put(item) {
+                log::error!("Cannot store packet: {:?}", err);
+            }
+        }
+    }
+
+    /// Remove acknowledged packet from the store
+    pub(super) fn store_remove(&self, packet_id: u16) {
+        if let (Some(ref store), Some(id)) = (&self.store, NonZeroU16::new(packet_id)) {
+            if let Err(err) = store.remove(id) {
+                log::error!("Cannot remove stored packet: {:?}", err);
+            }
+        }
+    }
+
+    /// Set max number of outbound topic aliases accepted by the peer
+    pub(super) fn set_topic_alias_max(&self, max: u16) {
+        self.topic_aliases.borrow_mut().max = max;
+    }
+
+    /// Encode publish packet, assign topic alias if `alias` is set
+    ///
+    /// Topic is omitted if alias is already known to the peer. Packets
+    /// with explicitly set alias are not modified.
+    pub(super) fn encode_publish(
+        &self,
+        mut pkt: codec::Publish,
+        alias: bool,
+    ) -> Result<bool, error::EncodeError> {
+        let mut assigned = None;
+        if alias && pkt.properties.topic_alias.is_none() {
+            if let Some((alias, known)) = self.topic_aliases.borrow_mut().get(&pkt.topic) {
+                pkt.properties.topic_alias = Some(alias);
+                if known {
+                    pkt.topic = ByteString::new();
+                } else {
+                    assigned = Some(pkt.topic.clone());
+                }
+            }
+        }
+
+        let result = self.state.write().encode(codec::Packet::Publish(pkt), &self.codec);
+        if let (Err(_), Some(topic)) = (&result, assigned) {
+            // peer does not know new alias
+            self.topic_aliases.borrow_mut().remove(&topic);
+        }
+        result
+    }
+
+    /// Client id of accepted connection
+    pub(super) fn client_id(&self) -> Option<ByteString> {
+        self.client_id.borrow().clone()
+    }
+
     pub(super) fn with_queues<R>(&self, f: impl FnOnce(&mut MqttSharedQueues) -> R) -> R {
         let mut queues = self.queues.borrow_mut();
         f(&mut queues)
     }
 
     pub(super) fn has_credit(&self) -> bool {
-        self.cap.get() - self.queues.borrow().inflight.len() > 0
+        self.cap.get() > self.queues.borrow().inflight.len()
     }
 
     pub(super) fn next_id(&self) -> u16 {
@@ -96,15 +174,69 @@ impl Decoder for MqttShared {
     }
 }
 
-#[derive(Copy, Clone)]
+/// Outbound topic aliases, least recently used alias get re-assigned
+#[derive(Default)]
+struct TopicAliases {
+    max: u16,
+    next: u16,
+    tick: u64,
+    free: Vec<NonZeroU16>,
+    topics: HashMap<ByteString, (NonZeroU16, u64)>,
+    lru: BTreeMap<u64, ByteString>,
+}
+
+impl TopicAliases {
+    /// Get alias for topic, flag indicates if alias is already known to the peer
+    fn get(&mut self, topic: &ByteString) -> Option<(NonZeroU16, bool)> {
+        if self.max == 0 || topic.is_empty() {
+            return None;
+        }
+        self.tick += 1;
+        let tick = self.tick;
+
+        if let Some((alias, last)) = self.topics.get_mut(topic) {
+            self.lru.remove(last);
+            *last = tick;
+            self.lru.insert(tick, topic.clone());
+            return Some((*alias, true));
+        }
+
+        let alias = if let Some(alias) = self.free.pop() {
+            alias
+        } else if self.next < self.max {
+            self.next += 1;
+            NonZeroU16::new(self.next).unwrap()
+        } else {
+            let last = *self.lru.keys().next()?;
+            let topic = self.lru.remove(&last)?;
+            self.topics.remove(&topic)?.0
+        };
+        self.topics.insert(topic.clone(), (alias, tick));
+        self.lru.insert(tick, topic.clone());
+        Some((alias, false))
+    }
+
+    fn remove(&mut self, topic: &ByteString) {
+        if let Some((alias, last)) = self.topics.remove(topic) {
+            self.lru.remove(&last);
+            self.free.push(alias);
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
 pub(super) enum AckType {
     Publish,
+    Receive,
+    Complete,
     Subscribe,
     Unsubscribe,
 }
 
 pub(super) enum Ack {
     Publish(codec::PublishAck),
+    Receive(codec::PublishAck),
+    Complete(codec::PublishAck2),
     Subscribe(codec::SubscribeAck),
     Unsubscribe(codec::UnsubscribeAck),
 }
@@ -113,6 +245,8 @@ impl Ack {
     pub(super) fn packet_type(&self) -> u8 {
         match self {
             Ack::Publish(_) => packet_type::PUBACK,
+            Ack::Receive(_) => packet_type::PUBREC,
+            Ack::Complete(_) => packet_type::PUBCOMP,
             Ack::Subscribe(_) => packet_type::SUBACK,
             Ack::Unsubscribe(_) => packet_type::UNSUBACK,
         }
@@ -121,6 +255,8 @@ impl Ack {
     pub(super) fn packet_id(&self) -> u16 {
         match self {
             Ack::Publish(ref pkt) => pkt.packet_id.get(),
+            Ack::Receive(ref pkt) => pkt.packet_id.get(),
+            Ack::Complete(ref pkt) => pkt.packet_id.get(),
             Ack::Subscribe(ref pkt) => pkt.packet_id.get(),
             Ack::Unsubscribe(ref pkt) => pkt.packet_id.get(),
         }
@@ -134,6 +270,22 @@ impl Ack {
         }
     }
 
+    pub(super) fn receive(self) -> codec::PublishAck {
+        if let Ack::Receive(pkt) = self {
+            pkt
+        } else {
+            panic!()
+        }
+    }
+
+    pub(super) fn complete(self) -> codec::PublishAck2 {
+        if let Ack::Complete(pkt) = self {
+            pkt
+        } else {
+            panic!()
+        }
+    }
+
     pub(super) fn subscribe(self) -> codec::SubscribeAck {
         if let Ack::Subscribe(pkt) = self {
             pkt
@@ -153,6 +305,8 @@ impl Ack {
     pub(super) fn is_match(&self, tp: AckType) -> bool {
         match (self, tp) {
             (Ack::Publish(_), AckType::Publish) => true,
+            (Ack::Receive(_), AckType::Receive) => true,
+            (Ack::Complete(_), AckType::Complete) => true,
             (Ack::Subscribe(_), AckType::Subscribe) => true,
             (Ack::Unsubscribe(_), AckType::Unsubscribe) => true,
             (_, _) => false,
@@ -164,8 +318,38 @@ impl AckType {
     pub(super) fn name(&self) -> &'static str {
         match self {
             AckType::Publish => "PublishAck",
+            AckType::Receive => "PublishReceived",
+            AckType::Complete => "PublishComplete",
             AckType::Subscribe => "SubscribeAck",
             AckType::Unsubscribe => "UnsubscribeAck",
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_topic_aliases() {
+        let alias = |id| NonZeroU16::new(id).unwrap();
+        let (a, b, c) = (ByteString::from("a"), ByteString::from("b"), ByteString::from("c"));
+
+        let mut aliases = TopicAliases::default();
+        assert_eq!(aliases.get(&a), None);
+
+        aliases.max = 2;
+        assert_eq!(aliases.get(&ByteString::new()), None);
+        assert_eq!(aliases.get(&a), Some((alias(1), false)));
+        assert_eq!(aliases.get(&b), Some((alias(2), false)));
+        assert_eq!(aliases.get(&a), Some((alias(1), true)));
+        // least recently used alias is re-assigned
+        assert_eq!(aliases.get(&c), Some((alias(2), false)));
+        assert_eq!(aliases.get(&b), Some((alias(1), false)));
+
+        // removed alias is re-used
+        aliases.remove(&c);
+        assert_eq!(aliases.get(&a), Some((alias(2), false)));
+        assert_eq!(aliases.get(&b), Some((alias(1), true)));
+    }
+}
@@ -1,12 +1,13 @@
 use std::future::{ready, Future};
-use std::{fmt, num::NonZeroU16, num::NonZeroU32, rc::Rc};
+use std::{cmp, fmt, mem, num::NonZeroU16, num::NonZeroU32, rc::Rc};
 
 use ntex::util::{ByteString, Bytes, Either, Ready};
 
 use super::codec;
-use super::error::{ProtocolError, PublishQos1Error, SendPacketError};
+use super::error::{ProtocolError, PublishQos1Error, PublishQos2Error, SendPacketError};
 use super::shared::{Ack, AckType, MqttShared};
-use crate::types::QoS;
+use super::store::StoreItem;
+use crate::{info::ConnectionInfo, types::QoS, will::ConnectWill};
 
 pub struct MqttSink(Rc<MqttShared>);
 
@@ -29,7 +30,7 @@ impl MqttSink {
     /// Get client's receive credit
     pub fn credit(&self) -> usize {
         let cap = self.0.cap.get();
-        cap - self.0.with_queues(|q| q.inflight.len())
+        cap.saturating_sub(self.0.with_queues(|q| q.inflight.len()))
     }
 
     /// Get notification when packet could be send to the peer.
@@ -65,6 +66,7 @@ impl MqttSink {
         }
         self.0.with_queues(|q| {
             q.inflight.clear();
+            q.inflight_packets.clear();
             q.waiters.clear();
         });
     }
@@ -77,6 +79,7 @@ impl MqttSink {
         }
         self.0.with_queues(|q| {
             q.inflight.clear();
+            q.inflight_packets.clear();
             q.waiters.clear();
         });
     }
@@ -92,58 +95,262 @@ impl MqttSink {
 
     /// Close mqtt connection, dont send disconnect message
     pub(super) fn drop_sink(&self) {
+        let keep = self.0.keep_inflight.get();
         self.0.with_queues(|q| {
             q.waiters.clear();
-            q.inflight.clear();
+            if !keep {
+                q.inflight.clear();
+                q.inflight_packets.clear();
+            }
         });
         self.0.state.close();
     }
 
-    pub(super) fn pkt_ack(&self, pkt: Ack) -> Result<(), ProtocolError> {
-        self.0.with_queues(|queues| loop {
-            // check ack order
-            if let Some(idx) = queues.inflight_order.pop_front() {
-                // errored publish
-                if idx == 0 {
+    /// Close connection that is taken over by new connection
+    pub(crate) fn takeover(&self) {
+        if self.is_open() {
+            self.send(codec::Packet::Disconnect(codec::Disconnect {
+                reason_code: codec::DisconnectReasonCode::SessionTakenOver,
+                ..codec::Disconnect::default()
+            }));
+        }
+        self.drop_sink();
+    }
+
+    /// Keep in-flight state after connection get closed
+    pub(crate) fn keep_inflight(&self) {
+        self.0.keep_inflight.set(true);
+    }
+
+    /// Check if both sinks belong to the same connection
+    pub(crate) fn ptr_eq(&self, other: &MqttSink) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+
+    /// Take connection's last will
+    pub(super) fn take_will(&self) -> Option<ConnectWill> {
+        self.0.will.borrow_mut().take()
+    }
+
+    /// Peer connection info, available for server connections
+    pub(crate) fn connection_info(&self) -> Option<Rc<ConnectionInfo>> {
+        self.0.info.borrow().clone()
+    }
+
+    /// Move in-flight state to new connection
+    ///
+    /// Unacknowledged publish packets are re-sent with dup flag set,
+    /// PUBREL is re-sent for pending qos2 packets.
+    pub(crate) fn transfer_inflight(&self, sink: &MqttSink) {
+        let (mut inflight, order, mut packets) = self.0.with_queues(|q| {
+            q.waiters.clear();
+            (
+                mem::take(&mut q.inflight),
+                mem::take(&mut q.inflight_order),
+                mem::take(&mut q.inflight_packets),
+            )
+        });
+        sink.0.inflight_idx.set(cmp::max(sink.0.inflight_idx.get(), self.0.inflight_idx.get()));
+
+        let keep = sink.0.keep_inflight.get();
+        // released qos2 publishes are not ordered
+        let mut released: Vec<_> = inflight
+            .iter()
+            .filter(|(_, (_, tp))| *tp == AckType::Complete)
+            .map(|(idx, _)| *idx)
+            .collect();
+        released.sort_unstable();
+
+        sink.0.with_queues(|q| {
+            for idx in released.into_iter().chain(order) {
+                let (tx, tp) = if let Some(item) = inflight.remove(&idx) {
+                    item
+                } else {
+                    continue;
+                };
+
+                // packet is already re-sent from the store
+                if let Some(item) = q.inflight.get_mut(&idx) {
+                    item.0 = tx;
                     continue;
                 }
 
-                if idx != pkt.packet_id() {
-                    log::trace!(
-                        "MQTT protocol error, packet_id order does not match, expected {}, got: {}",
-                        idx,
-                        pkt.packet_id()
-                    );
-                } else {
-                    // get publish ack channel
-                    log::trace!("Ack packet with id: {}", pkt.packet_id());
-                    let idx = pkt.packet_id();
-                    if let Some((tx, tp)) = queues.inflight.remove(&idx) {
-                        // cleanup ack queue
-                        if !pkt.is_match(tp) {
-                            log::trace!("MQTT protocol error, unexpeted packet");
-                            return Err(ProtocolError::Unexpected(
-                                pkt.packet_type(),
-                                tp.name(),
-                            ));
+                let pkt = match tp {
+                    AckType::Publish | AckType::Receive => {
+                        if let Some(mut pkt) = packets.remove(&idx) {
+                            log::trace!("Re-send publish packet with id: {}", idx);
+                            pkt.dup = true;
+                            if keep {
+                                q.inflight_packets.insert(idx, pkt.clone());
+                            }
+                            codec::Packet::Publish(pkt)
+                        } else {
+                            continue;
                         }
-                        let _ = tx.send(pkt);
+                    }
+                    AckType::Complete => {
+                        log::trace!("Re-send publish release packet with id: {}", idx);
+                        codec::Packet::PublishRelease(codec::PublishAck2 {
+                            packet_id: NonZeroU16::new(idx).unwrap(),
+                            reason_code: codec::PublishAck2Reason::Success,
+                            properties: codec::UserProperties::default(),
+                            reason_string: None,
+                        })
+                    }
+                    // subscribe and unsubscribe requests fail
+                    AckType::Subscribe | AckType::Unsubscribe => continue,
+                };
+                q.inflight.insert(idx, (tx, tp));
+                if tp != AckType::Complete {
+                    q.inflight_order.push_back(idx);
+                }
+                sink.send(pkt);
+            }
+        });
+    }
 
-                        // wake up queued request (receive max limit)
-                        while let Some(tx) = queues.waiters.pop_front() {
-                            if tx.send(()).is_ok() {
-                                break;
+    /// Re-send packets from the store
+    pub(super) fn replay_store(&self, session_present: bool) {
+        let store = if let Some(ref store) = self.0.store {
+            store.clone()
+        } else {
+            return;
+        };
+        let items = match store.load() {
+            Ok(items) => items,
+            Err(err) => {
+                log::error!("Cannot load stored packets: {:?}", err);
+                return;
+            }
+        };
+
+        self.0.with_queues(|q| {
+            for item in items {
+                let idx = if let Some(idx) = item.packet_id() {
+                    idx.get()
+                } else {
+                    continue;
+                };
+                if q.inflight.contains_key(&idx) {
+                    continue;
+                }
+
+                let (pkt, tp) = match item {
+                    StoreItem::Publish(mut pkt) => {
+                        let tp = match pkt.qos {
+                            QoS::AtLeastOnce => AckType::Publish,
+                            QoS::ExactlyOnce => AckType::Receive,
+                            QoS::AtMostOnce => {
+                                self.0.store_remove(idx);
+                                continue;
                             }
+                        };
+                        log::trace!("Re-send stored publish packet with id: {}", idx);
+                        pkt.dup = session_present;
+                        if self.0.keep_inflight.get() {
+                            q.inflight_packets.insert(idx, pkt.clone());
                         }
+                        (codec::Packet::Publish(pkt), tp)
+                    }
+                    // server does not have qos2 state
+                    StoreItem::Release(_) if !session_present => {
+                        self.0.store_remove(idx);
+                        continue;
+                    }
+                    StoreItem::Release(packet_id) => {
+                        log::trace!("Re-send stored publish release packet with id: {}", idx);
+                        let pkt = codec::Packet::PublishRelease(codec::PublishAck2 {
+                            packet_id,
+                            reason_code: codec::PublishAck2Reason::Success,
+                            properties: codec::UserProperties::default(),
+                            reason_string: None,
+                        });
+                        (pkt, AckType::Complete)
+                    }
+                };
+
+                // original publish future does not exist anymore
+                let (tx, _) = self.0.pool.queue.channel();
+                q.inflight.insert(idx, (tx, tp));
+                if tp != AckType::Complete {
+                    q.inflight_order.push_back(idx);
+                }
+                self.0.inflight_idx.set(cmp::max(self.0.inflight_idx.get(), idx));
+                self.send(pkt);
+            }
+        });
+    }
+
+    pub(super) fn pkt_ack(&self, pkt: Ack) -> Result<(), ProtocolError> {
+        self.0.with_queues(|queues| {
+            let idx = pkt.packet_id();
+
+            // check ack order, released qos2 publish is completed in any order
+            if !std::matches!(pkt, Ack::Complete(_)) {
+                loop {
+                    match queues.inflight_order.pop_front() {
+                        // errored publish
+                        Some(0) => continue,
+                        Some(id) if id == idx => break,
+                        Some(id) => {
+                            log::trace!(
+                                "MQTT protocol error, packet_id order does not match, expected {}, got: {}",
+                                id,
+                                idx
+                            );
+                            return Err(ProtocolError::PacketIdMismatch);
+                        }
+                        None => {
+                            log::trace!("Unexpected PublishAck packet");
+                            return Err(ProtocolError::PacketIdMismatch);
+                        }
+                    }
+                }
+            }
+
+            // get publish ack channel
+            log::trace!("Ack packet with id: {}", idx);
+            if let Some((tx, tp)) = queues.inflight.remove(&idx) {
+                queues.inflight_packets.remove(&idx);
+                // cleanup ack queue
+                if !pkt.is_match(tp) {
+                    log::trace!("MQTT protocol error, unexpeted packet");
+                    return Err(ProtocolError::Unexpected(pkt.packet_type(), tp.name()));
+                }
+                // qos2 publish keeps in-flight slot until PUBCOMP
+                if let Ack::Receive(ref pkt) = pkt {
+                    if u8::from(pkt.reason_code) < 0x80 {
+                        queues.inflight.insert(idx, (tx, AckType::Complete));
+                        self.0.store_put(StoreItem::Release(pkt.packet_id));
+                        self.send(codec::Packet::PublishRelease(codec::PublishAck2 {
+                            packet_id: pkt.packet_id,
+                            reason_code: codec::PublishAck2Reason::Success,
+                            properties: codec::UserProperties::default(),
+                            reason_string: None,
+                        }));
                         return Ok(());
-                    } else {
-                        log::error!("In-flight state inconsistency")
                     }
                 }
+                if let AckType::Publish | AckType::Receive | AckType::Complete = tp {
+                    self.0.store_remove(idx);
+                }
+                let _ = tx.send(pkt);
+
+                // wake up queued request (receive max limit)
+                while let Some(tx) = queues.waiters.pop_front() {
+                    if tx.send(()).is_ok() {
+                        break;
+                    }
+                }
+                Ok(())
             } else {
-                log::trace!("Unexpected PublishAck packet");
+                if let Ack::Complete(_) = pkt {
+                    log::trace!("Unexpected PublishComplete packet: {:?}", idx);
+                } else {
+                    log::error!("In-flight state inconsistency");
+                }
+                Err(ProtocolError::PacketIdMismatch)
             }
-            return Err(ProtocolError::PacketIdMismatch);
         })
     }
 
@@ -163,6 +370,7 @@ impl MqttSink {
                 properties: codec::PublishProperties::default(),
             },
             shared: self.0.clone(),
+            topic_alias: true,
         }
     }
 
@@ -203,6 +411,7 @@ impl fmt::Debug for MqttSink {
 pub struct PublishBuilder {
     shared: Rc<MqttShared>,
     packet: codec::Publish,
+    topic_alias: bool,
 }
 
 impl PublishBuilder {
@@ -231,6 +440,16 @@ impl PublishBuilder {
         self
     }
 
+    /// Do not assign topic alias to the packet
+    ///
+    /// By default topic alias is assigned automatically if peer accepts
+    /// topic aliases, least recently used alias is re-assigned if all
+    /// aliases are in use.
+    pub fn no_topic_alias(mut self) -> Self {
+        self.topic_alias = false;
+        self
+    }
+
     /// Set publish packet properties
     pub fn properties<F>(mut self, f: F) -> Self
     where
@@ -255,9 +474,7 @@ impl PublishBuilder {
         if self.shared.state.is_open() {
             log::trace!("Publish (QoS-0) to {:?}", packet.topic);
             self.shared
-                .state
-                .write()
-                .encode(codec::Packet::Publish(packet), &self.shared.codec)
+                .encode_publish(packet, self.topic_alias)
                 .map_err(SendPacketError::Encode)
                 .map(|_| ())
         } else {
@@ -271,6 +488,7 @@ impl PublishBuilder {
         self,
     ) -> impl Future<Output = Result<codec::PublishAck, PublishQos1Error>> {
         let shared = self.shared;
+        let alias = self.topic_alias;
         let mut packet = self.packet;
         packet.qos = QoS::AtLeastOnce;
 
@@ -284,10 +502,10 @@ impl PublishBuilder {
                     if rx.await.is_err() {
                         return Err(PublishQos1Error::Disconnected);
                     }
-                    Self::send_at_least_once_inner(packet, shared).await
+                    Self::send_at_least_once_inner(packet, shared, alias).await
                 }));
             }
-            Either::Right(Self::send_at_least_once_inner(packet, shared))
+            Either::Right(Self::send_at_least_once_inner(packet, shared, alias))
         } else {
             Either::Left(Either::Left(Ready::Err(PublishQos1Error::Disconnected)))
         }
@@ -296,6 +514,7 @@ impl PublishBuilder {
     fn send_at_least_once_inner(
         mut packet: codec::Publish,
         shared: Rc<MqttShared>,
+        alias: bool,
     ) -> impl Future<Output = Result<codec::PublishAck, PublishQos1Error>> {
         // packet id
         let mut idx = packet.packet_id.map(|i| i.get()).unwrap_or(0);
@@ -313,6 +532,9 @@ impl PublishBuilder {
             }
             queues.inflight.insert(idx, (tx, AckType::Publish));
             queues.inflight_order.push_back(idx);
+            if shared.keep_inflight.get() {
+                queues.inflight_packets.insert(idx, packet.clone());
+            }
             Ok(rx)
         });
 
@@ -323,8 +545,10 @@ impl PublishBuilder {
 
         // send publish to client
         log::trace!("Publish (QoS1) to {:#?}", packet);
+        shared.store_put(StoreItem::Publish(packet.clone()));
 
-        match shared.state.write().encode(codec::Packet::Publish(packet), &shared.codec) {
+        // stored and re-sent packets keep full topic
+        match shared.encode_publish(packet, alias) {
             Ok(_) => {
                 // wait ack from peer
                 Either::Right(async move {
@@ -340,6 +564,92 @@ impl PublishBuilder {
             Err(err) => Either::Left(Ready::Err(PublishQos1Error::Encode(err))),
         }
     }
+
+    /// Send publish packet with QoS 2
+    pub fn send_exactly_once(
+        self,
+    ) -> impl Future<Output = Result<codec::PublishAck2, PublishQos2Error>> {
+        let shared = self.shared;
+        let alias = self.topic_alias;
+        let mut packet = self.packet;
+        packet.qos = QoS::ExactlyOnce;
+
+        if shared.state.is_open() {
+            // handle client receive maximum
+            if !shared.has_credit() {
+                let (tx, rx) = shared.pool.waiters.channel();
+                shared.with_queues(|q| q.waiters.push_back(tx));
+
+                return Either::Left(Either::Right(async move {
+                    if rx.await.is_err() {
+                        return Err(PublishQos2Error::Disconnected);
+                    }
+                    Self::send_exactly_once_inner(packet, shared, alias).await
+                }));
+            }
+            Either::Right(Self::send_exactly_once_inner(packet, shared, alias))
+        } else {
+            Either::Left(Either::Left(Ready::Err(PublishQos2Error::Disconnected)))
+        }
+    }
+
+    fn send_exactly_once_inner(
+        mut packet: codec::Publish,
+        shared: Rc<MqttShared>,
+        alias: bool,
+    ) -> impl Future<Output = Result<codec::PublishAck2, PublishQos2Error>> {
+        // packet id
+        let mut idx = packet.packet_id.map(|i| i.get()).unwrap_or(0);
+        if idx == 0 {
+            idx = shared.next_id();
+            packet.packet_id = NonZeroU16::new(idx);
+        }
+
+        let rx = shared.with_queues(|queues| {
+            // publish complete channel
+            let (tx, rx) = shared.pool.queue.channel();
+
+            if queues.inflight.contains_key(&idx) {
+                return Err(PublishQos2Error::PacketIdInUse(idx));
+            }
+            queues.inflight.insert(idx, (tx, AckType::Receive));
+            queues.inflight_order.push_back(idx);
+            if shared.keep_inflight.get() {
+                queues.inflight_packets.insert(idx, packet.clone());
+            }
+            Ok(rx)
+        });
+
+        let rx = match rx {
+            Ok(rx) => rx,
+            Err(e) => return Either::Left(Ready::Err(e)),
+        };
+
+        // send publish to client
+        log::trace!("Publish (QoS2) to {:#?}", packet);
+        shared.store_put(StoreItem::Publish(packet.clone()));
+
+        // stored and re-sent packets keep full topic
+        match shared.encode_publish(packet, alias) {
+            Ok(_) => {
+                // wait PUBREC/PUBCOMP from peer
+                Either::Right(async move {
+                    rx.await.map_err(|_| PublishQos2Error::Disconnected).and_then(|pkt| {
+                        if let Ack::Receive(_) = pkt {
+                            Err(PublishQos2Error::Fail(pkt.receive()))
+                        } else {
+                            let pkt = pkt.complete();
+                            match pkt.reason_code {
+                                codec::PublishAck2Reason::Success => Ok(pkt),
+                                _ => Err(PublishQos2Error::Complete(pkt)),
+                            }
+                        }
+                    })
+                })
+            }
+            Err(err) => Either::Left(Ready::Err(PublishQos2Error::Encode(err))),
+        }
+    }
 }
 
 /// Subscribe packet builder
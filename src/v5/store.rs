@@ -0,0 +1,394 @@
+use std::io::Write;
+use std::{cell::Cell, cell::RefCell, fmt, fs, io, num::NonZeroU16, path::PathBuf, rc::Rc};
+
+use ntex::codec::{Decoder, Encoder};
+use ntex::util::BytesMut;
+
+use super::codec;
+
+/// Outgoing packet that is not acknowledged by the server
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum StoreItem {
+    /// Publish packet with QoS 1 or QoS 2
+    Publish(codec::Publish),
+    /// QoS 2 publish is received by the server, PUBREL is not completed
+    Release(NonZeroU16),
+}
+
+impl StoreItem {
+    /// Packet id of stored item
+    pub fn packet_id(&self) -> Option<NonZeroU16> {
+        match self {
+            StoreItem::Publish(ref pkt) => pkt.packet_id,
+            StoreItem::Release(id) => Some(*id),
+        }
+    }
+}
+
+/// Storage for client's outgoing in-flight packets
+///
+/// Client stores publish packets before sending them to the server and
+/// removes them once publish is acknowledged. Stored packets get re-sent
+/// on next `MqttConnector::connect()` call.
+pub trait Store {
+    /// Store packet, existing item with the same packet id is replaced
+    fn put(&self, item: StoreItem) -> io::Result<()>;
+
+    /// Remove acknowledged packet
+    fn remove(&self, packet_id: NonZeroU16) -> io::Result<()>;
+
+    /// Load stored packets in order they were stored
+    fn load(&self) -> io::Result<Vec<StoreItem>>;
+}
+
+fn put_item(items: &mut Vec<StoreItem>, item: StoreItem) {
+    let id = item.packet_id();
+    items.retain(|i| i.packet_id() != id);
+    items.push(item);
+}
+
+#[derive(Clone, Default)]
+/// In-memory store
+///
+/// Store keeps packets during the process lifetime, clones share same storage.
+pub struct MemoryStore(Rc<RefCell<Vec<StoreItem>>>);
+
+impl MemoryStore {
+    /// Create new in-memory store
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Store for MemoryStore {
+    fn put(&self, item: StoreItem) -> io::Result<()> {
+        put_item(&mut self.0.borrow_mut(), item);
+        Ok(())
+    }
+
+    fn remove(&self, packet_id: NonZeroU16) -> io::Result<()> {
+        self.0.borrow_mut().retain(|i| i.packet_id() != Some(packet_id));
+        Ok(())
+    }
+
+    fn load(&self) -> io::Result<Vec<StoreItem>> {
+        Ok(self.0.borrow().clone())
+    }
+}
+
+impl fmt::Debug for MemoryStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemoryStore").field("items", &self.0.borrow().len()).finish()
+    }
+}
+
+/// File-backed store
+///
+/// Packets are kept in memory, every change is appended to the file as
+/// a log record. `put` appends packet itself, `remove` appends PUBACK
+/// packet for removed packet id. Log gets compacted, re-written with
+/// live packets only, once number of records exceeds `compact_size`
+/// and is twice the number of stored packets.
+///
+/// Every change is synced to disk. Partial record left by interrupted
+/// write is dropped on open. File operations are blocking.
+pub struct FileStore {
+    path: PathBuf,
+    file: RefCell<fs::File>,
+    items: RefCell<Vec<StoreItem>>,
+    records: Cell<usize>,
+    compact_size: Cell<usize>,
+}
+
+impl FileStore {
+    /// Open file store, load existing packets
+    pub fn open<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
+        let path = path.into();
+        let (items, records) = match fs::read(&path) {
+            Ok(data) => {
+                let (items, records, len) = decode(&data)?;
+                if len < data.len() {
+                    log::warn!(
+                        "Drop incomplete record at the end of store {:?}, {} bytes",
+                        path,
+                        data.len() - len
+                    );
+                    fs::OpenOptions::new().write(true).open(&path)?.set_len(len as u64)?;
+                }
+                (items, records)
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (Vec::new(), 0),
+            Err(e) => return Err(e),
+        };
+        let file = fs::OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(FileStore {
+            path,
+            file: RefCell::new(file),
+            items: RefCell::new(items),
+            records: Cell::new(records),
+            compact_size: Cell::new(128),
+        })
+    }
+
+    /// Set minimum number of log records before compaction
+    ///
+    /// By default compaction starts after 128 records.
+    pub fn compact_size(self, size: usize) -> Self {
+        self.compact_size.set(size);
+        self
+    }
+
+    fn append(&self, record: codec::Packet) -> io::Result<()> {
+        let mut buf = BytesMut::new();
+        encode_packet(record, &mut buf)?;
+        {
+            let mut file = self.file.borrow_mut();
+            file.write_all(&buf)?;
+            file.sync_data()?;
+        }
+        self.records.set(self.records.get() + 1);
+
+        let records = self.records.get();
+        if records >= self.compact_size.get() && records >= self.items.borrow().len() * 2 {
+            self.compact()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Re-write log with live packets only
+    fn compact(&self) -> io::Result<()> {
+        let data = encode(&self.items.borrow())?;
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(&data)?;
+        file.sync_data()?;
+        fs::rename(&tmp, &self.path)?;
+        *self.file.borrow_mut() = fs::OpenOptions::new().append(true).open(&self.path)?;
+        self.records.set(self.items.borrow().len());
+        Ok(())
+    }
+}
+
+impl Store for FileStore {
+    fn put(&self, item: StoreItem) -> io::Result<()> {
+        let record = item_packet(&item);
+        put_item(&mut self.items.borrow_mut(), item);
+        self.append(record)
+    }
+
+    fn remove(&self, packet_id: NonZeroU16) -> io::Result<()> {
+        let len = self.items.borrow().len();
+        self.items.borrow_mut().retain(|i| i.packet_id() != Some(packet_id));
+        if self.items.borrow().len() != len {
+            self.append(codec::Packet::PublishAck(codec::PublishAck {
+                packet_id,
+                reason_code: codec::PublishAckReason::Success,
+                properties: codec::UserProperties::default(),
+                reason_string: None,
+            }))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn load(&self) -> io::Result<Vec<StoreItem>> {
+        Ok(self.items.borrow().clone())
+    }
+}
+
+impl fmt::Debug for FileStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileStore")
+            .field("path", &self.path)
+            .field("items", &self.items.borrow().len())
+            .field("records", &self.records.get())
+            .finish()
+    }
+}
+
+fn item_packet(item: &StoreItem) -> codec::Packet {
+    match item {
+        StoreItem::Publish(ref pkt) => codec::Packet::Publish(pkt.clone()),
+        StoreItem::Release(id) => codec::Packet::PublishRelease(codec::PublishAck2 {
+            packet_id: *id,
+            reason_code: codec::PublishAck2Reason::Success,
+            properties: codec::UserProperties::default(),
+            reason_string: None,
+        }),
+    }
+}
+
+fn encode_packet(pkt: codec::Packet, buf: &mut BytesMut) -> io::Result<()> {
+    codec::Codec::new()
+        .encode(pkt, buf)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))
+}
+
+fn encode(items: &[StoreItem]) -> io::Result<BytesMut> {
+    let mut buf = BytesMut::new();
+    for item in items {
+        encode_packet(item_packet(item), &mut buf)?;
+    }
+    Ok(buf)
+}
+
+/// Replay log records
+///
+/// Returns live packets, number of records and length of complete records.
+fn decode(data: &[u8]) -> io::Result<(Vec<StoreItem>, usize, usize)> {
+    let codec = codec::Codec::new();
+    let mut buf = BytesMut::from(data);
+    let mut items = Vec::new();
+    let mut records = 0;
+    let mut len = 0;
+    loop {
+        match codec.decode(&mut buf) {
+            Ok(Some(codec::Packet::Publish(pkt))) => {
+                put_item(&mut items, StoreItem::Publish(pkt))
+            }
+            Ok(Some(codec::Packet::PublishRelease(pkt))) => {
+                put_item(&mut items, StoreItem::Release(pkt.packet_id))
+            }
+            Ok(Some(codec::Packet::PublishAck(pkt))) => {
+                items.retain(|i| i.packet_id() != Some(pkt.packet_id))
+            }
+            Ok(Some(pkt)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unexpected packet: {:?}", pkt),
+                ))
+            }
+            // incomplete record is left by interrupted write
+            Ok(None) => return Ok((items, records, len)),
+            Err(e) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))
+            }
+        }
+        records += 1;
+        len = data.len() - buf.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ntex::util::{ByteString, Bytes};
+
+    use super::*;
+
+    fn publish(id: u16) -> codec::Publish {
+        codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtLeastOnce,
+            topic: ByteString::from_static("test"),
+            packet_id: NonZeroU16::new(id),
+            payload: Bytes::from_static(b"data"),
+            properties: codec::PublishProperties::default(),
+        }
+    }
+
+    #[test]
+    fn test_memory_store() {
+        let store = MemoryStore::new();
+        store.put(StoreItem::Publish(publish(1))).unwrap();
+        store.put(StoreItem::Publish(publish(2))).unwrap();
+        store.put(StoreItem::Release(NonZeroU16::new(1).unwrap())).unwrap();
+        assert_eq!(
+            store.load().unwrap(),
+            vec![
+                StoreItem::Publish(publish(2)),
+                StoreItem::Release(NonZeroU16::new(1).unwrap())
+            ]
+        );
+
+        store.remove(NonZeroU16::new(2).unwrap()).unwrap();
+        assert_eq!(store.clone().load().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_file_store() {
+        let path = std::env::temp_dir().join(format!("ntex-mqtt-store-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let store = FileStore::open(&path).unwrap();
+        assert!(store.load().unwrap().is_empty());
+        store.put(StoreItem::Publish(publish(1))).unwrap();
+        store.put(StoreItem::Publish(publish(2))).unwrap();
+        store.put(StoreItem::Release(NonZeroU16::new(2).unwrap())).unwrap();
+        drop(store);
+
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(
+            store.load().unwrap(),
+            vec![
+                StoreItem::Publish(publish(1)),
+                StoreItem::Release(NonZeroU16::new(2).unwrap())
+            ]
+        );
+        store.remove(NonZeroU16::new(1).unwrap()).unwrap();
+        store.remove(NonZeroU16::new(2).unwrap()).unwrap();
+        assert!(FileStore::open(&path).unwrap().load().unwrap().is_empty());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_file_store_incomplete() {
+        let path = std::env::temp_dir()
+            .join(format!("ntex-mqtt-store-incomplete-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let store = FileStore::open(&path).unwrap();
+        store.put(StoreItem::Publish(publish(1))).unwrap();
+        store.put(StoreItem::Publish(publish(2))).unwrap();
+        drop(store);
+
+        // interrupted write of last record
+        let len = fs::metadata(&path).unwrap().len();
+        fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 3).unwrap();
+
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(store.load().unwrap(), vec![StoreItem::Publish(publish(1))]);
+        store.put(StoreItem::Publish(publish(3))).unwrap();
+        drop(store);
+
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(
+            store.load().unwrap(),
+            vec![StoreItem::Publish(publish(1)), StoreItem::Publish(publish(3))]
+        );
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_file_store_compact() {
+        let path = std::env::temp_dir()
+            .join(format!("ntex-mqtt-store-compact-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let store = FileStore::open(&path).unwrap().compact_size(4);
+        store.put(StoreItem::Publish(publish(1))).unwrap();
+        store.put(StoreItem::Publish(publish(2))).unwrap();
+        store.remove(NonZeroU16::new(1).unwrap()).unwrap();
+        assert_eq!(store.records.get(), 3);
+        store.put(StoreItem::Publish(publish(3))).unwrap();
+        // log is re-written with live packets only
+        assert_eq!(store.records.get(), 2);
+        store.put(StoreItem::Publish(publish(4))).unwrap();
+        drop(store);
+
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(store.records.get(), 3);
+        assert_eq!(
+            store.load().unwrap(),
+            vec![
+                StoreItem::Publish(publish(2)),
+                StoreItem::Publish(publish(3)),
+                StoreItem::Publish(publish(4))
+            ]
+        );
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -4,7 +4,7 @@ use ntex::codec::{Decoder, Encoder};
 use ntex::util::BytesMut;
 
 use crate::error::{DecodeError, EncodeError};
-use crate::types::{packet_type, MQTT, MQTT_LEVEL_3, MQTT_LEVEL_5};
+use crate::types::{packet_type, MQISDP, MQTT, MQTT_LEVEL_3, MQTT_LEVEL_31, MQTT_LEVEL_5};
 use crate::utils;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -37,16 +37,19 @@ impl Decoder for VersionCodec {
                         return Ok(None);
                     }
 
-                    let len =
-                        u16::from_be_bytes(src[consumed..consumed + 2].try_into().unwrap());
-                    ensure!(
-                        len == 4 && &src[consumed + 2..consumed + 6] == MQTT,
-                        DecodeError::InvalidProtocol
-                    );
+                    let name_len =
+                        u16::from_be_bytes(src[consumed..consumed + 2].try_into().unwrap())
+                            as usize;
+                    ensure!(name_len == 4 || name_len == 6, DecodeError::InvalidProtocol);
+                    let level_pos = consumed + 2 + name_len;
+                    if len <= level_pos {
+                        return Ok(None);
+                    }
 
-                    match src[consumed + 6] {
-                        MQTT_LEVEL_3 => Ok(Some(ProtocolVersion::MQTT3)),
-                        MQTT_LEVEL_5 => Ok(Some(ProtocolVersion::MQTT5)),
+                    match (&src[consumed + 2..level_pos], src[level_pos]) {
+                        (MQTT, MQTT_LEVEL_3) => Ok(Some(ProtocolVersion::MQTT3)),
+                        (MQTT, MQTT_LEVEL_5) => Ok(Some(ProtocolVersion::MQTT5)),
+                        (MQISDP, MQTT_LEVEL_31) => Ok(Some(ProtocolVersion::MQTT3)),
                         _ => Err(DecodeError::InvalidProtocol),
                     }
                 } else {
@@ -90,6 +93,15 @@ mod tests {
         let mut buf = BytesMut::from(b"\x10\x98\x02\0\x04MQTT\x05".as_ref());
         assert_eq!(ProtocolVersion::MQTT5, VersionCodec.decode(&mut buf).unwrap().unwrap());
 
+        let mut buf = BytesMut::from(b"\x10\x98\x02\0\x06MQIsdp\x03\x02".as_ref());
+        assert_eq!(ProtocolVersion::MQTT3, VersionCodec.decode(&mut buf).unwrap().unwrap());
+
+        let mut buf = BytesMut::from(b"\x10\x98\x02\0\x06MQIsdp\x05\x02".as_ref());
+        assert_eq!(Err(DecodeError::InvalidProtocol), VersionCodec.decode(&mut buf));
+
+        let mut buf = BytesMut::from(b"\x10\x98\x02\0\x06MQIsd".as_ref());
+        assert_eq!(None, VersionCodec.decode(&mut buf).unwrap());
+
         let mut buf = BytesMut::from(b"\x10\x98\x02\0\x04".as_ref());
         assert_eq!(None, VersionCodec.decode(&mut buf).unwrap());
 
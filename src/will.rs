@@ -0,0 +1,236 @@
+//! Last will messages
+use std::cell::{Cell, RefCell};
+use std::{collections::HashMap, fmt, rc::Rc};
+
+use ntex::time::{sleep, Millis};
+use ntex::util::ByteString;
+
+use crate::sessions::Message;
+use crate::v5::codec::PublishProperties;
+use crate::{v3, v5};
+
+/// Connection's last will, captured on handshake ack
+#[derive(Debug)]
+pub(crate) struct ConnectWill {
+    pub(crate) id: usize,
+    pub(crate) client_id: ByteString,
+    pub(crate) clean_start: bool,
+    pub(crate) message: Option<Message>,
+    /// Will delay interval in seconds
+    pub(crate) delay: u32,
+}
+
+impl ConnectWill {
+    pub(crate) fn from_v3(pkt: &v3::codec::Connect) -> Self {
+        ConnectWill {
+            id: 0,
+            client_id: pkt.client_id.clone(),
+            clean_start: pkt.clean_session,
+            message: pkt.last_will.as_ref().map(|will| Message {
+                topic: will.topic.clone(),
+                qos: will.qos,
+                retain: will.retain,
+                payload: will.message.clone(),
+                properties: PublishProperties::default(),
+            }),
+            delay: 0,
+        }
+    }
+
+    pub(crate) fn from_v5(pkt: &v5::codec::Connect) -> Self {
+        // will is published once session ends
+        let expiry = pkt.session_expiry_interval_secs.unwrap_or(0);
+
+        ConnectWill {
+            id: 0,
+            client_id: pkt.client_id.clone(),
+            clean_start: pkt.clean_start,
+            message: pkt.last_will.as_ref().map(|will| Message {
+                topic: will.topic.clone(),
+                qos: will.qos,
+                retain: will.retain,
+                payload: will.message.clone(),
+                properties: PublishProperties {
+                    correlation_data: will.correlation_data.clone(),
+                    message_expiry_interval: will.message_expiry_interval,
+                    content_type: will.content_type.clone(),
+                    user_properties: will.user_properties.clone(),
+                    is_utf8_payload: will.is_utf8_payload,
+                    response_topic: will.response_topic.clone(),
+                    ..PublishProperties::default()
+                },
+            }),
+            delay: pkt
+                .last_will
+                .as_ref()
+                .and_then(|will| will.will_delay_interval_sec)
+                .unwrap_or(0)
+                .min(expiry),
+        }
+    }
+}
+
+struct Inner {
+    hook: Box<dyn Fn(&ByteString, Message)>,
+    pending: RefCell<HashMap<ByteString, (usize, Message)>>,
+    // open connections, connection id and clean start flag
+    connections: RefCell<HashMap<ByteString, (usize, bool)>>,
+    counter: Cell<usize>,
+}
+
+impl Inner {
+    fn next_id(&self) -> usize {
+        let id = self.counter.get().wrapping_add(1);
+        self.counter.set(id);
+        id
+    }
+}
+
+/// Last will manager
+///
+/// Manager hands will message of a connection that is closed without normal
+/// DISCONNECT (connection error, keep-alive timeout, protocol error or server
+/// initiated disconnect) to the publish hook. Publication is postponed for v5
+/// will delay interval, delayed will is cancelled if client connects again
+/// without clean start. Client with empty client id has no session, its
+/// delayed will cannot be cancelled.
+///
+/// Manager must be registered with `MqttServer::will_manager()`.
+#[derive(Clone)]
+pub struct WillManager(Rc<Inner>);
+
+impl WillManager {
+    /// Create will manager with publish hook
+    ///
+    /// Hook is called with client id and will message.
+    pub fn new<F>(hook: F) -> Self
+    where
+        F: Fn(&ByteString, Message) + 'static,
+    {
+        WillManager(Rc::new(Inner {
+            hook: Box::new(hook),
+            pending: RefCell::new(HashMap::new()),
+            connections: RefCell::new(HashMap::new()),
+            counter: Cell::new(0),
+        }))
+    }
+
+    /// Cancel delayed will, returns `true` if will was pending
+    pub fn cancel(&self, client_id: &str) -> bool {
+        self.0.pending.borrow_mut().remove(client_id).is_some()
+    }
+
+    /// Check if client's will is delayed
+    pub fn is_pending(&self, client_id: &str) -> bool {
+        self.0.pending.borrow().contains_key(client_id)
+    }
+
+    /// New connection is accepted
+    pub(crate) fn connected(&self, connect: &mut ConnectWill) {
+        connect.id = self.0.next_id();
+
+        // empty client id is not unique, there is no session to resume
+        if connect.client_id.is_empty() {
+            return;
+        }
+
+        self.0
+            .connections
+            .borrow_mut()
+            .insert(connect.client_id.clone(), (connect.id, connect.clean_start));
+
+        let item = self.0.pending.borrow_mut().remove(&connect.client_id);
+        if let Some((_, msg)) = item {
+            if connect.clean_start {
+                // clean start ends previous session
+                (self.0.hook)(&connect.client_id, msg);
+            } else {
+                log::trace!("Session is resumed, will is cancelled: {:?}", connect.client_id);
+            }
+        }
+    }
+
+    /// Connection is closed, will message is set if connection is closed
+    /// without normal disconnect
+    pub(crate) fn closed(&self, will: ConnectWill) {
+        let anonymous = will.client_id.is_empty();
+        let taken_over = if anonymous {
+            None
+        } else {
+            let mut connections = self.0.connections.borrow_mut();
+            match connections.get(&will.client_id) {
+                Some((id, _)) if *id == will.id => {
+                    connections.remove(&will.client_id);
+                    None
+                }
+                Some((_, clean_start)) => Some(*clean_start),
+                None => None,
+            }
+        };
+
+        let msg = if let Some(msg) = will.message {
+            msg
+        } else {
+            return;
+        };
+
+        match taken_over {
+            // session is resumed by new connection
+            Some(false) if will.delay != 0 => {
+                log::trace!("Session is taken over, will is cancelled: {:?}", will.client_id);
+                return;
+            }
+            // session is ended by new connection
+            Some(_) => {
+                (self.0.hook)(&will.client_id, msg);
+                return;
+            }
+            None => (),
+        }
+
+        if will.delay == 0 {
+            (self.0.hook)(&will.client_id, msg);
+            return;
+        }
+
+        let client_id = will.client_id;
+        let delay = Millis(will.delay as u64 * 1000);
+        let inner = Rc::downgrade(&self.0);
+
+        // will of anonymous client cannot be cancelled
+        if anonymous {
+            ntex::rt::spawn(async move {
+                sleep(delay).await;
+                if let Some(inner) = inner.upgrade() {
+                    (inner.hook)(&client_id, msg);
+                }
+            });
+            return;
+        }
+
+        let id = self.0.next_id();
+        self.0.pending.borrow_mut().insert(client_id.clone(), (id, msg));
+
+        ntex::rt::spawn(async move {
+            sleep(delay).await;
+            if let Some(inner) = inner.upgrade() {
+                let msg = {
+                    let mut pending = inner.pending.borrow_mut();
+                    match pending.get(&client_id) {
+                        Some((idx, _)) if *idx == id => pending.remove(&client_id),
+                        _ => None,
+                    }
+                };
+                if let Some((_, msg)) = msg {
+                    (inner.hook)(&client_id, msg);
+                }
+            }
+        });
+    }
+}
+
+impl fmt::Debug for WillManager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WillManager").field("pending", &self.0.pending.borrow().len()).finish()
+    }
+}
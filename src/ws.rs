@@ -0,0 +1,628 @@
+//! WebSocket transport (`mqtt` subprotocol)
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::task::{Context, Poll};
+use std::{cmp, fmt, future::Future, io, marker, pin::Pin, rc::Rc, time};
+
+use ntex::codec::{AsyncRead, AsyncWrite, Decoder, Encoder, Framed, ReadBuf};
+use ntex::connect::{Address, Connect, ConnectError, Connector};
+use ntex::service::{Service, ServiceFactory};
+use ntex::time::{timeout, Seconds};
+use ntex::util::{poll_fn, ByteString, Bytes, BytesMut, Either};
+use ntex::ws;
+
+use crate::error::{MqttError, ProtocolError};
+
+/// Max size of http handshake request/response
+const MAX_HANDSHAKE_SIZE: usize = 8 * 1024;
+const SUBPROTOCOL: &str = "mqtt";
+const BAD_REQUEST: &[u8] =
+    b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+
+/// WebSocket stream
+///
+/// Unframes binary messages into a byte stream and frames written data
+/// into binary messages.
+pub struct WsStream<Io> {
+    framed: Framed<Io, ws::Codec>,
+    buf: Bytes,
+    closed: bool,
+}
+
+impl<Io> WsStream<Io> {
+    fn new(framed: Framed<Io, ws::Codec>) -> Self {
+        WsStream { framed, buf: Bytes::new(), closed: false }
+    }
+
+    /// Returns reference to underlying io object
+    pub fn get_ref(&self) -> &Io {
+        self.framed.get_ref()
+    }
+
+    /// Returns mutable reference to underlying io object
+    pub fn get_mut(&mut self) -> &mut Io {
+        self.framed.get_mut()
+    }
+}
+
+impl<Io> fmt::Debug for WsStream<Io> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WsStream").field("closed", &self.closed).finish()
+    }
+}
+
+impl<Io: AsyncRead + AsyncWrite + Unpin> AsyncRead for WsStream<Io> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            if !this.buf.is_empty() {
+                let size = cmp::min(buf.remaining(), this.buf.len());
+                buf.put_slice(&this.buf.split_to(size));
+                return Poll::Ready(Ok(()));
+            }
+            if this.closed {
+                return Poll::Ready(Ok(()));
+            }
+
+            let frame = match this.framed.next_item(cx) {
+                Poll::Ready(Some(Ok(frame))) => frame,
+                Poll::Ready(Some(Err(Either::Left(err)))) => {
+                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, err)))
+                }
+                Poll::Ready(Some(Err(Either::Right(err)))) => return Poll::Ready(Err(err)),
+                Poll::Ready(None) => {
+                    this.closed = true;
+                    continue;
+                }
+                Poll::Pending => return Poll::Pending,
+            };
+
+            match frame {
+                ws::Frame::Binary(data)
+                | ws::Frame::Continuation(ws::Item::FirstBinary(data))
+                | ws::Frame::Continuation(ws::Item::Continue(data))
+                | ws::Frame::Continuation(ws::Item::Last(data)) => this.buf = data,
+                ws::Frame::Ping(data) => {
+                    this.framed.write(ws::Message::Pong(data)).map_err(into_io)?;
+                    let _ = this.framed.flush(cx)?;
+                }
+                ws::Frame::Pong(_) => (),
+                ws::Frame::Close(reason) => {
+                    log::trace!("WebSocket close frame is received: {:?}", reason);
+                    this.closed = true;
+                    this.framed.write(ws::Message::Close(reason)).map_err(into_io)?;
+                    let _ = this.framed.flush(cx)?;
+                }
+                ws::Frame::Text(_) | ws::Frame::Continuation(ws::Item::FirstText(_)) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Text frames are not supported",
+                    )))
+                }
+            }
+        }
+    }
+}
+
+impl<Io: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WsStream<Io> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if !this.framed.is_write_ready() {
+            if let Poll::Ready(res) = this.framed.flush(cx) {
+                res?;
+            }
+            if !this.framed.is_write_ready() {
+                return Poll::Pending;
+            }
+        }
+        this.framed.write(ws::Message::Binary(Bytes::copy_from_slice(buf))).map_err(into_io)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().framed.flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.closed {
+            this.closed = true;
+            this.framed.write(ws::Message::Close(None)).map_err(into_io)?;
+        }
+        match this.framed.flush(cx) {
+            Poll::Ready(Ok(_)) => Pin::new(this.framed.get_mut()).poll_shutdown(cx),
+            res => res,
+        }
+    }
+}
+
+fn into_io(err: ws::ProtocolError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+/// WebSocket server
+///
+/// Performs http upgrade handshake, negotiates `mqtt` subprotocol and
+/// passes websocket stream to inner mqtt server.
+pub struct WsServer<Io, F> {
+    factory: F,
+    handshake_timeout: Seconds,
+    max_size: usize,
+    _t: marker::PhantomData<Io>,
+}
+
+impl<Io, F> WsServer<Io, F> {
+    /// Create websocket server for mqtt server factory
+    pub fn new(factory: F) -> Self {
+        WsServer {
+            factory,
+            handshake_timeout: Seconds(5),
+            max_size: 65_536,
+            _t: marker::PhantomData,
+        }
+    }
+
+    /// Set http handshake timeout.
+    ///
+    /// Handshake includes only http upgrade request. By default handshake timeout is 5 seconds.
+    pub fn handshake_timeout(mut self, timeout: Seconds) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Set max size of websocket frame.
+    ///
+    /// By default max frame size is set to 64Kb.
+    pub fn max_frame_size(mut self, size: usize) -> Self {
+        self.max_size = size;
+        self
+    }
+}
+
+impl<Io, F, E> ServiceFactory for WsServer<Io, F>
+where
+    Io: AsyncRead + AsyncWrite + Unpin + 'static,
+    F: ServiceFactory<Config = (), Request = WsStream<Io>, Response = (), Error = MqttError<E>>,
+    F::Future: 'static,
+    F::Service: 'static,
+    E: 'static,
+{
+    type Config = ();
+    type Request = Io;
+    type Response = ();
+    type Error = MqttError<E>;
+    type Service = WsServerImpl<Io, F::Service>;
+    type InitError = F::InitError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Service, Self::InitError>>>>;
+
+    fn new_service(&self, _: ()) -> Self::Future {
+        let handshake_timeout = self.handshake_timeout;
+        let max_size = self.max_size;
+        let fut = self.factory.new_service(());
+
+        Box::pin(async move {
+            Ok(WsServerImpl {
+                handshake_timeout,
+                max_size,
+                service: Rc::new(fut.await?),
+                _t: marker::PhantomData,
+            })
+        })
+    }
+}
+
+/// WebSocket server service
+pub struct WsServerImpl<Io, S> {
+    service: Rc<S>,
+    handshake_timeout: Seconds,
+    max_size: usize,
+    _t: marker::PhantomData<Io>,
+}
+
+impl<Io, S, E> Service for WsServerImpl<Io, S>
+where
+    Io: AsyncRead + AsyncWrite + Unpin + 'static,
+    S: Service<Request = WsStream<Io>, Response = (), Error = MqttError<E>> + 'static,
+    E: 'static,
+{
+    type Request = Io;
+    type Response = ();
+    type Error = MqttError<E>;
+    type Future = Pin<Box<dyn Future<Output = Result<(), MqttError<E>>>>>;
+
+    #[inline]
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(&self, cx: &mut Context<'_>, is_error: bool) -> Poll<()> {
+        self.service.poll_shutdown(cx, is_error)
+    }
+
+    fn call(&self, io: Io) -> Self::Future {
+        let service = self.service.clone();
+        let handshake_timeout = self.handshake_timeout;
+        let max_size = self.max_size;
+
+        Box::pin(async move {
+            let framed = if handshake_timeout.non_zero() {
+                match timeout(handshake_timeout, server_handshake(io)).await {
+                    Ok(res) => res?,
+                    Err(_) => return Err(MqttError::HandshakeTimeout),
+                }
+            } else {
+                server_handshake(io).await?
+            };
+            let framed = framed.into_framed(ws::Codec::new().max_size(max_size));
+            service.call(WsStream::new(framed)).await
+        })
+    }
+}
+
+async fn server_handshake<Io, E>(io: Io) -> Result<Framed<Io, HandshakeCodec>, MqttError<E>>
+where
+    Io: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut framed = Framed::new(io, HandshakeCodec);
+    let head = match read_head(&mut framed).await {
+        Ok(Some(head)) => head,
+        Ok(None) => return Err(MqttError::Disconnected),
+        Err(err) => return Err(MqttError::Protocol(ProtocolError::Io(err))),
+    };
+
+    let res = Head::parse(&head)
+        .ok_or("Malformed http request")
+        .and_then(|head| verify_request(&head).map(|key| ws::hash_key(key.as_bytes())));
+
+    let (data, result) = match res {
+        Ok(key) => (
+            Bytes::from(format!(
+                "HTTP/1.1 101 Switching Protocols\r\n\
+                 Upgrade: websocket\r\n\
+                 Connection: Upgrade\r\n\
+                 Sec-WebSocket-Accept: {}\r\n\
+                 Sec-WebSocket-Protocol: {}\r\n\r\n",
+                key, SUBPROTOCOL
+            )),
+            Ok(()),
+        ),
+        Err(err) => {
+            log::trace!("WebSocket handshake failed: {}", err);
+            (Bytes::from_static(BAD_REQUEST), Err(handshake_error(err)))
+        }
+    };
+    framed.write(data).map_err(|e| MqttError::Protocol(ProtocolError::Io(e)))?;
+    poll_fn(|cx| framed.flush(cx))
+        .await
+        .map_err(|e| MqttError::Protocol(ProtocolError::Io(e)))?;
+    result.map(|_| framed)
+}
+
+/// Verify http upgrade request, returns websocket key
+fn verify_request<'a>(head: &Head<'a>) -> Result<&'a str, &'static str> {
+    let mut line = head.line.split_whitespace();
+    if line.next() != Some("GET") {
+        return Err("Method is not GET");
+    }
+    if line.nth(1) != Some("HTTP/1.1") {
+        return Err("Unsupported http version");
+    }
+    if !head.has_token("upgrade", "websocket") {
+        return Err("Upgrade header is not websocket");
+    }
+    if !head.has_token("connection", "upgrade") {
+        return Err("Connection header is not upgrade");
+    }
+    if head.header("sec-websocket-version") != Some("13") {
+        return Err("Unsupported websocket version");
+    }
+    if !head.has_token("sec-websocket-protocol", SUBPROTOCOL) {
+        return Err("Mqtt subprotocol is not requested");
+    }
+    head.header("sec-websocket-key").ok_or("Sec-WebSocket-Key header is missing")
+}
+
+async fn read_head<Io>(framed: &mut Framed<Io, HandshakeCodec>) -> io::Result<Option<BytesMut>>
+where
+    Io: AsyncRead + AsyncWrite + Unpin,
+{
+    match poll_fn(|cx| framed.next_item(cx)).await {
+        Some(Ok(head)) => Ok(Some(head)),
+        Some(Err(Either::Left(err))) | Some(Err(Either::Right(err))) => Err(err),
+        None => Ok(None),
+    }
+}
+
+fn handshake_error<E>(err: &'static str) -> MqttError<E> {
+    MqttError::Protocol(ProtocolError::Io(io::Error::new(io::ErrorKind::InvalidData, err)))
+}
+
+/// WebSocket connector
+///
+/// Wraps tcp or tls connector, performs http upgrade handshake and
+/// negotiates `mqtt` subprotocol.
+pub struct WsConnector<A, T> {
+    connector: T,
+    path: ByteString,
+    max_size: usize,
+    _t: marker::PhantomData<A>,
+}
+
+impl<A: Address> WsConnector<A, Connector<A>> {
+    /// Create websocket connector for the url path
+    pub fn new<U>(path: U) -> Self
+    where
+        ByteString: From<U>,
+    {
+        WsConnector::with_connector(path, Connector::default())
+    }
+}
+
+impl<A, T> WsConnector<A, T> {
+    /// Create websocket connector on top of custom connector
+    pub fn with_connector<U>(path: U, connector: T) -> Self
+    where
+        ByteString: From<U>,
+    {
+        WsConnector { connector, path: path.into(), max_size: 65_536, _t: marker::PhantomData }
+    }
+
+    /// Set max size of websocket frame.
+    ///
+    /// By default max frame size is set to 64Kb.
+    pub fn max_frame_size(mut self, size: usize) -> Self {
+        self.max_size = size;
+        self
+    }
+}
+
+impl<A, T> Service for WsConnector<A, T>
+where
+    A: Address,
+    T: Service<Request = Connect<A>, Error = ConnectError>,
+    T::Response: AsyncRead + AsyncWrite + Unpin + 'static,
+    T::Future: 'static,
+{
+    type Request = Connect<A>;
+    type Response = WsStream<T::Response>;
+    type Error = ConnectError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, ConnectError>>>>;
+
+    #[inline]
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.connector.poll_ready(cx)
+    }
+
+    fn call(&self, req: Connect<A>) -> Self::Future {
+        let host = if req.host().is_empty() {
+            req.addrs().next().map(|addr| addr.to_string()).unwrap_or_default()
+        } else {
+            req.host().to_string()
+        };
+        let path = self.path.clone();
+        let max_size = self.max_size;
+        let fut = self.connector.call(req);
+
+        Box::pin(async move {
+            let io = fut.await?;
+            let framed = client_handshake(io, &host, &path).await.map_err(ConnectError::Io)?;
+            Ok(WsStream::new(
+                framed.into_framed(ws::Codec::new().max_size(max_size).client_mode()),
+            ))
+        })
+    }
+}
+
+async fn client_handshake<Io>(
+    io: Io,
+    host: &str,
+    path: &str,
+) -> io::Result<Framed<Io, HandshakeCodec>>
+where
+    Io: AsyncRead + AsyncWrite + Unpin,
+{
+    let key = handshake_key();
+    let mut framed = Framed::new(io, HandshakeCodec);
+    framed.write(Bytes::from(format!(
+        "GET {} HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\n\
+         Sec-WebSocket-Version: 13\r\n\
+         Sec-WebSocket-Protocol: {}\r\n\r\n",
+        path, host, key, SUBPROTOCOL
+    )))?;
+    poll_fn(|cx| framed.flush(cx)).await?;
+
+    let head = read_head(&mut framed).await?.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::UnexpectedEof, "Server is disconnected during handshake")
+    })?;
+    Head::parse(&head)
+        .ok_or("Malformed http response")
+        .and_then(|head| verify_response(&head, &ws::hash_key(key.as_bytes())))
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    Ok(framed)
+}
+
+/// Verify http upgrade response
+fn verify_response(head: &Head<'_>, accept: &str) -> Result<(), &'static str> {
+    if head.line.split_whitespace().nth(1) != Some("101") {
+        return Err("Server does not switch protocols");
+    }
+    if !head.has_token("upgrade", "websocket") {
+        return Err("Upgrade header is not websocket");
+    }
+    if !head.has_token("connection", "upgrade") {
+        return Err("Connection header is not upgrade");
+    }
+    if head.header("sec-websocket-accept") != Some(accept) {
+        return Err("Sec-WebSocket-Accept header does not match");
+    }
+    if !head.has_token("sec-websocket-protocol", SUBPROTOCOL) {
+        return Err("Mqtt subprotocol is not selected");
+    }
+    Ok(())
+}
+
+/// Generate random base64 encoded 16 bytes key
+fn handshake_key() -> String {
+    const CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let nanos = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default();
+    // 16 random bytes, padded to 18 bytes for base64 encoding
+    let mut bytes = [0u8; 18];
+    for chunk in bytes[..16].chunks_mut(8) {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(nanos);
+        chunk.copy_from_slice(&hasher.finish().to_le_bytes());
+    }
+
+    let mut key = String::with_capacity(24);
+    for chunk in bytes.chunks(3) {
+        let n = (chunk[0] as u32) << 16 | (chunk[1] as u32) << 8 | chunk[2] as u32;
+        for shift in &[18, 12, 6, 0] {
+            key.push(CHARS[(n >> shift & 0x3f) as usize] as char);
+        }
+    }
+    key.truncate(22);
+    key.push_str("==");
+    key
+}
+
+/// Http request/response head
+struct Head<'a> {
+    line: &'a str,
+    headers: Vec<(&'a str, &'a str)>,
+}
+
+impl<'a> Head<'a> {
+    fn parse(buf: &'a [u8]) -> Option<Self> {
+        let mut lines = std::str::from_utf8(buf).ok()?.split("\r\n");
+        let line = lines.next()?;
+        let headers = lines
+            .filter(|l| !l.is_empty())
+            .map(|l| {
+                let mut parts = l.splitn(2, ':');
+                Some((parts.next()?.trim(), parts.next()?.trim()))
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(Head { line, headers })
+    }
+
+    fn header(&self, name: &str) -> Option<&'a str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| *v)
+    }
+
+    fn has_token(&self, name: &str, token: &str) -> bool {
+        self.headers
+            .iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+            .flat_map(|(_, v)| v.split(','))
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    }
+}
+
+/// Codec for http handshake, decodes message head
+struct HandshakeCodec;
+
+impl Decoder for HandshakeCodec {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn decode(&self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if let Some(pos) = src.windows(4).position(|w| w == b"\r\n\r\n") {
+            Ok(Some(src.split_to(pos + 4)))
+        } else if src.len() > MAX_HANDSHAKE_SIZE {
+            Err(io::Error::new(io::ErrorKind::InvalidData, "Http handshake is too large"))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl Encoder for HandshakeCodec {
+    type Item = Bytes;
+    type Error = io::Error;
+
+    fn encode(&self, item: Bytes, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.extend_from_slice(&item);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_request() {
+        let req = b"GET /mqtt HTTP/1.1\r\nHost: localhost\r\nUpgrade: WebSocket\r\n\
+                    Connection: keep-alive, Upgrade\r\nSec-WebSocket-Version: 13\r\n\
+                    Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                    Sec-WebSocket-Protocol: mqttv3.1, mqtt\r\n\r\n";
+        let head = Head::parse(req).unwrap();
+        assert_eq!(verify_request(&head), Ok("dGhlIHNhbXBsZSBub25jZQ=="));
+
+        let req = b"GET /mqtt HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                    Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+        let head = Head::parse(req).unwrap();
+        assert_eq!(verify_request(&head), Err("Mqtt subprotocol is not requested"));
+
+        let req = b"POST /mqtt HTTP/1.1\r\n\r\n";
+        let head = Head::parse(req).unwrap();
+        assert_eq!(verify_request(&head), Err("Method is not GET"));
+    }
+
+    #[test]
+    fn test_verify_response() {
+        let key = handshake_key();
+        assert_eq!(key.len(), 24);
+        assert!(key.ends_with("=="));
+        assert_ne!(key, handshake_key());
+
+        let res = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                    Connection: Upgrade\r\nSec-WebSocket-Protocol: mqtt\r\n\
+                    Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n";
+        let head = Head::parse(res).unwrap();
+        assert_eq!(verify_response(&head, "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="), Ok(()));
+        assert_eq!(
+            verify_response(&head, "dGhlIHNhbXBsZSBub25jZQ=="),
+            Err("Sec-WebSocket-Accept header does not match")
+        );
+
+        let res = b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n";
+        let head = Head::parse(res).unwrap();
+        assert_eq!(
+            verify_response(&head, "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="),
+            Err("Server does not switch protocols")
+        );
+    }
+
+    #[test]
+    fn test_handshake_codec() {
+        let codec = HandshakeCodec;
+        let mut buf = BytesMut::from(&b"GET / HTTP/1.1\r\nHost: localhost\r\n"[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        buf.extend_from_slice(b"\r\n\x10\x00");
+        assert_eq!(
+            codec.decode(&mut buf).unwrap().unwrap(),
+            &b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n"[..]
+        );
+        assert_eq!(&buf[..], b"\x10\x00");
+    }
+}
@@ -0,0 +1,255 @@
+#![cfg(feature = "broker")]
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::ok;
+use ntex::server;
+use ntex::time::sleep;
+use ntex::util::{ByteString, Bytes};
+
+use ntex_mqtt::bridge::{Bridge, BridgeTopic, Direction, Endpoint};
+use ntex_mqtt::{broker::Broker, types::QoS, v3, v5};
+
+type Received = Arc<Mutex<Vec<(String, QoS)>>>;
+
+async fn v5_client(addr: std::net::SocketAddr, id: &'static str) -> (v5::MqttSink, Received) {
+    let client = v5::client::MqttConnector::new(addr).client_id(id).connect().await.unwrap();
+    let sink = client.sink();
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received2 = received.clone();
+    ntex::rt::spawn(client.start(move |msg: v5::client::ControlMessage<()>| match msg {
+        v5::client::ControlMessage::Publish(p) => {
+            received2.lock().unwrap().push((p.packet().topic.to_string(), p.packet().qos));
+            if p.packet().qos == QoS::AtMostOnce {
+                ok(p.ack_qos0())
+            } else {
+                ok(p.ack(v5::codec::PublishAckReason::Success))
+            }
+        }
+        _ => ok(msg.disconnect(v5::codec::Disconnect::default())),
+    }));
+    (sink, received)
+}
+
+fn options(qos: QoS) -> v5::codec::SubscriptionOptions {
+    v5::codec::SubscriptionOptions {
+        qos,
+        no_local: false,
+        retain_as_published: false,
+        retain_handling: v5::codec::RetainHandling::AtSubscribe,
+    }
+}
+
+#[ntex::test]
+async fn test_bridge() -> std::io::Result<()> {
+    let local = server::test_server(|| Broker::new().server());
+    let remote = server::test_server(|| Broker::new().server());
+
+    let client = v5::client::ManagedClient::new(
+        v5::client::MqttConnector::new(local.addr()).client_id("bridge"),
+    );
+    let local_sink = client.sink();
+    let local_ep = Endpoint::v5(client);
+    let client = v3::client::ManagedClient::new(
+        v3::client::MqttConnector::new(remote.addr()).client_id("bridge"),
+    );
+    let remote_sink = client.sink();
+    let remote_ep = Endpoint::v3(client);
+
+    ntex::rt::spawn(
+        Bridge::new(local_ep, remote_ep)
+            .topic(
+                BridgeTopic::new("sensors/#".into(), Direction::Out)
+                    .qos(QoS::AtLeastOnce)
+                    .remote_prefix("edge/".into()),
+            )
+            .topic(
+                BridgeTopic::new("cmd/#".into(), Direction::In).remote_prefix("edge/".into()),
+            )
+            .start(),
+    );
+    sleep(Duration::from_millis(200)).await;
+
+    // local v5 client
+    let (l_sink, l_received) = v5_client(local.addr(), "local").await;
+    l_sink
+        .subscribe(None)
+        .topic_filter("cmd/#".into(), options(QoS::AtMostOnce))
+        .send()
+        .await
+        .unwrap();
+
+    // remote v3 client
+    let client = v3::client::MqttConnector::new(remote.addr())
+        .client_id("remote")
+        .connect()
+        .await
+        .unwrap();
+    let r_sink = client.sink();
+    let r_received = Arc::new(Mutex::new(Vec::new()));
+    let r_received2 = r_received.clone();
+    ntex::rt::spawn(client.start(move |msg: v3::client::ControlMessage<()>| match msg {
+        v3::client::ControlMessage::Publish(p) => {
+            r_received2.lock().unwrap().push((p.packet().topic.to_string(), p.packet().qos));
+            ok(p.ack())
+        }
+        _ => ok(msg.disconnect()),
+    }));
+    r_sink
+        .subscribe()
+        .topic_filter("edge/sensors/#".into(), QoS::AtLeastOnce)
+        .send()
+        .await
+        .unwrap();
+
+    l_sink
+        .publish(ByteString::from_static("sensors/1"), Bytes::from_static(b"1"))
+        .send_at_least_once()
+        .await
+        .unwrap();
+    l_sink
+        .publish(ByteString::from_static("other"), Bytes::from_static(b"2"))
+        .send_at_least_once()
+        .await
+        .unwrap();
+    r_sink
+        .publish(ByteString::from_static("edge/cmd/reboot"), Bytes::new())
+        .send_at_least_once()
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(200)).await;
+
+    assert_eq!(
+        *r_received.lock().unwrap(),
+        vec![("edge/sensors/1".to_string(), QoS::AtLeastOnce)]
+    );
+    assert_eq!(*l_received.lock().unwrap(), vec![("cmd/reboot".to_string(), QoS::AtMostOnce)]);
+
+    local_sink.close();
+    remote_sink.close();
+    l_sink.close();
+    r_sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_bridge_no_loop() -> std::io::Result<()> {
+    let local = server::test_server(|| Broker::new().server());
+    let remote = server::test_server(|| Broker::new().server());
+
+    let client = v5::client::ManagedClient::new(
+        v5::client::MqttConnector::new(local.addr()).client_id("bridge"),
+    );
+    let local_sink = client.sink();
+    let local_ep = Endpoint::v5(client);
+    let client = v5::client::ManagedClient::new(
+        v5::client::MqttConnector::new(remote.addr()).client_id("bridge"),
+    );
+    let remote_sink = client.sink();
+    let remote_ep = Endpoint::v5(client);
+
+    ntex::rt::spawn(
+        Bridge::new(local_ep, remote_ep)
+            .topic(BridgeTopic::new("sync/#".into(), Direction::Both).qos(QoS::AtLeastOnce))
+            .start(),
+    );
+    sleep(Duration::from_millis(200)).await;
+
+    let (l_sink, l_received) = v5_client(local.addr(), "local").await;
+    l_sink
+        .subscribe(None)
+        .topic_filter("sync/#".into(), options(QoS::AtLeastOnce))
+        .send()
+        .await
+        .unwrap();
+    let (r_sink, r_received) = v5_client(remote.addr(), "remote").await;
+    r_sink
+        .subscribe(None)
+        .topic_filter("sync/#".into(), options(QoS::AtLeastOnce))
+        .send()
+        .await
+        .unwrap();
+
+    l_sink
+        .publish(ByteString::from_static("sync/a"), Bytes::new())
+        .send_at_least_once()
+        .await
+        .unwrap();
+    r_sink
+        .publish(ByteString::from_static("sync/b"), Bytes::new())
+        .send_at_least_once()
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(200)).await;
+
+    // every message is received once on both sides
+    let expected = vec![
+        ("sync/a".to_string(), QoS::AtLeastOnce),
+        ("sync/b".to_string(), QoS::AtLeastOnce),
+    ];
+    for received in &[l_received, r_received] {
+        let mut received = received.lock().unwrap().clone();
+        received.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(received, expected);
+    }
+
+    local_sink.close();
+    remote_sink.close();
+    l_sink.close();
+    r_sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_bridge_bare_prefix() -> std::io::Result<()> {
+    let local = server::test_server(|| Broker::new().server());
+    let remote = server::test_server(|| Broker::new().server());
+
+    let client = v5::client::ManagedClient::new(
+        v5::client::MqttConnector::new(local.addr()).client_id("bridge"),
+    );
+    let local_sink = client.sink();
+    let local_ep = Endpoint::v5(client);
+    let client = v5::client::ManagedClient::new(
+        v5::client::MqttConnector::new(remote.addr()).client_id("bridge"),
+    );
+    let remote_sink = client.sink();
+    let remote_ep = Endpoint::v5(client);
+
+    ntex::rt::spawn(
+        Bridge::new(local_ep, remote_ep)
+            .topic(BridgeTopic::new("#".into(), Direction::In).remote_prefix("edge/".into()))
+            .start(),
+    );
+    sleep(Duration::from_millis(200)).await;
+
+    let (l_sink, l_received) = v5_client(local.addr(), "local").await;
+    l_sink
+        .subscribe(None)
+        .topic_filter("#".into(), options(QoS::AtMostOnce))
+        .send()
+        .await
+        .unwrap();
+    let (r_sink, _) = v5_client(remote.addr(), "remote").await;
+
+    // "edge/#" matches "edge", but topic has no prefix to strip
+    r_sink
+        .publish(ByteString::from_static("edge"), Bytes::new())
+        .send_at_least_once()
+        .await
+        .unwrap();
+    r_sink
+        .publish(ByteString::from_static("edge/a"), Bytes::new())
+        .send_at_least_once()
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(200)).await;
+
+    assert_eq!(*l_received.lock().unwrap(), vec![("a".to_string(), QoS::AtMostOnce)]);
+
+    local_sink.close();
+    remote_sink.close();
+    l_sink.close();
+    r_sink.close();
+    Ok(())
+}
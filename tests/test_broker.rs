@@ -0,0 +1,164 @@
+#![cfg(feature = "broker")]
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::ok;
+use ntex::server;
+use ntex::time::{sleep, Seconds};
+use ntex::util::{ByteString, Bytes};
+
+use ntex_mqtt::{broker::Broker, v3, v5};
+
+fn options(qos: v5::codec::QoS, no_local: bool) -> v5::codec::SubscriptionOptions {
+    v5::codec::SubscriptionOptions {
+        qos,
+        no_local,
+        retain_as_published: false,
+        retain_handling: v5::codec::RetainHandling::AtSubscribe,
+    }
+}
+
+#[ntex::test]
+async fn test_broker() -> std::io::Result<()> {
+    let srv = server::test_server(|| Broker::new().server());
+
+    // v5 subscriber
+    let client =
+        v5::client::MqttConnector::new(srv.addr()).client_id("sub").connect().await.unwrap();
+    let sub = client.sink();
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received2 = received.clone();
+    ntex::rt::spawn(client.start(move |msg: v5::client::ControlMessage<()>| match msg {
+        v5::client::ControlMessage::Publish(p) => {
+            let pkt = p.packet();
+            received2.lock().unwrap().push((pkt.topic.to_string(), pkt.qos, pkt.retain));
+            if pkt.qos == v5::codec::QoS::AtMostOnce {
+                ok(p.ack_qos0())
+            } else {
+                ok(p.ack(v5::codec::PublishAckReason::Success))
+            }
+        }
+        _ => ok(msg.disconnect(v5::codec::Disconnect::default())),
+    }));
+
+    // v3 publisher
+    let client =
+        v3::client::MqttConnector::new(srv.addr()).client_id("pub").connect().await.unwrap();
+    let publ = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    publ.publish(ByteString::from_static("retained"), Bytes::from_static(b"data"))
+        .retain()
+        .send_at_least_once()
+        .await
+        .unwrap();
+
+    sub.subscribe(None)
+        .topic_filter("a/+".into(), options(v5::codec::QoS::AtLeastOnce, false))
+        .topic_filter("retained".into(), options(v5::codec::QoS::AtMostOnce, false))
+        .send()
+        .await
+        .unwrap();
+
+    publ.publish(ByteString::from_static("a/b"), Bytes::from_static(b"1"))
+        .send_exactly_once()
+        .await
+        .unwrap();
+    publ.publish(ByteString::from_static("b/b"), Bytes::from_static(b"2"))
+        .send_at_least_once()
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    assert_eq!(
+        *received.lock().unwrap(),
+        vec![
+            ("retained".to_string(), v5::codec::QoS::AtMostOnce, true),
+            ("a/b".to_string(), v5::codec::QoS::AtLeastOnce, false),
+        ]
+    );
+
+    sub.close();
+    publ.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_broker_no_local() -> std::io::Result<()> {
+    let srv = server::test_server(|| Broker::new().server());
+
+    let client =
+        v5::client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received2 = received.clone();
+    ntex::rt::spawn(client.start(move |msg: v5::client::ControlMessage<()>| match msg {
+        v5::client::ControlMessage::Publish(p) => {
+            received2.lock().unwrap().push(p.packet().topic.to_string());
+            ok(p.ack_qos0())
+        }
+        _ => ok(msg.disconnect(v5::codec::Disconnect::default())),
+    }));
+
+    sink.subscribe(None)
+        .topic_filter("local".into(), options(v5::codec::QoS::AtMostOnce, false))
+        .topic_filter("no_local".into(), options(v5::codec::QoS::AtMostOnce, true))
+        .send()
+        .await
+        .unwrap();
+
+    for topic in &["local", "no_local"] {
+        sink.publish(ByteString::from_static(topic), Bytes::new())
+            .send_at_least_once()
+            .await
+            .unwrap();
+    }
+    sleep(Duration::from_millis(100)).await;
+
+    assert_eq!(*received.lock().unwrap(), vec!["local".to_string()]);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_broker_sys() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        let broker = Broker::new();
+        let broker2 = broker.clone();
+        broker.stats().start_publisher(Seconds(1), move |msg| broker2.publish(msg));
+        broker.server()
+    });
+
+    let client =
+        v5::client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received2 = received.clone();
+    ntex::rt::spawn(client.start(move |msg: v5::client::ControlMessage<()>| match msg {
+        v5::client::ControlMessage::Publish(p) => {
+            received2.lock().unwrap().push(p.packet().payload.clone());
+            ok(p.ack_qos0())
+        }
+        _ => ok(msg.disconnect(v5::codec::Disconnect::default())),
+    }));
+
+    sink.subscribe(None)
+        .topic_filter(
+            "$SYS/broker/clients/connected".into(),
+            options(v5::codec::QoS::AtMostOnce, false),
+        )
+        .send()
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(1200)).await;
+
+    // retained value of the first tick, then current value
+    assert_eq!(
+        *received.lock().unwrap(),
+        vec![Bytes::from_static(b"0"), Bytes::from_static(b"1")]
+    );
+
+    sink.close();
+    Ok(())
+}
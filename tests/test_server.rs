@@ -1,15 +1,20 @@
-use std::sync::{atomic::AtomicBool, atomic::Ordering::Relaxed, Arc};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering::Relaxed};
+use std::sync::{Arc, Mutex};
 use std::{num::NonZeroU16, time::Duration};
 
 use futures::{future::ok, FutureExt, SinkExt, StreamExt};
-use ntex::codec::Framed;
+use ntex::codec::{Encoder, Framed};
+use ntex::rt::net::TcpStream;
 use ntex::server;
-use ntex::time::{sleep, Seconds};
-use ntex::util::{poll_fn, ByteString, Bytes};
+use ntex::time::{sleep, Millis, Seconds};
+use ntex::util::{poll_fn, ByteString, Bytes, BytesMut};
 
 use ntex_mqtt::v3::{
-    client, codec, ControlMessage, Handshake, HandshakeAck, MqttServer, Publish, Session,
+    client, codec, ControlMessage, Handshake, HandshakeAck, MqttServer, MqttSink, Publish,
+    Session,
 };
+use ntex_mqtt::will::WillManager;
+use ntex_mqtt::{registry::ClientRegistry, sessions::SessionManager, stats::Stats};
 
 struct St;
 
@@ -327,3 +332,640 @@ async fn test_handle_incoming() -> std::io::Result<()> {
 
     Ok(())
 }
+
+#[ntex::test]
+async fn test_qos2() -> std::io::Result<()> {
+    let publish = Arc::new(AtomicUsize::new(0));
+    let publish2 = publish.clone();
+
+    let srv = server::test_server(move || {
+        let publish = publish2.clone();
+        MqttServer::new(handshake)
+            .inflight(2)
+            .publish(move |_| {
+                publish.fetch_add(1, Relaxed);
+                ok(())
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed.send(codec::Connect::default().client_id("user").into()).await.unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    let pkt = codec::Publish {
+        dup: false,
+        retain: false,
+        qos: codec::QoS::ExactlyOnce,
+        topic: ByteString::from("test"),
+        packet_id: Some(NonZeroU16::new(1).unwrap()),
+        payload: Bytes::new(),
+    };
+    framed.send(pkt.clone().into()).await.unwrap();
+    let ack = framed.next().await.unwrap().unwrap();
+    assert_eq!(ack, codec::Packet::PublishReceived { packet_id: NonZeroU16::new(1).unwrap() });
+
+    // re-delivered publish must not reach publish service
+    framed.send(codec::Publish { dup: true, ..pkt.clone() }.into()).await.unwrap();
+    let ack = framed.next().await.unwrap().unwrap();
+    assert_eq!(ack, codec::Packet::PublishReceived { packet_id: NonZeroU16::new(1).unwrap() });
+    assert_eq!(publish.load(Relaxed), 1);
+
+    framed
+        .send(codec::Packet::PublishRelease { packet_id: NonZeroU16::new(1).unwrap() })
+        .await
+        .unwrap();
+    let ack = framed.next().await.unwrap().unwrap();
+    assert_eq!(ack, codec::Packet::PublishComplete { packet_id: NonZeroU16::new(1).unwrap() });
+
+    // unknown packet id
+    framed
+        .send(codec::Packet::PublishRelease { packet_id: NonZeroU16::new(1).unwrap() })
+        .await
+        .unwrap();
+    assert!(framed.next().await.is_none());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_qos2_inflight() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake).inflight(1).publish(|_| ok(())).finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed.send(codec::Connect::default().client_id("user").into()).await.unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    let pkt = codec::Publish {
+        dup: false,
+        retain: false,
+        qos: codec::QoS::ExactlyOnce,
+        topic: ByteString::from("test"),
+        packet_id: Some(NonZeroU16::new(1).unwrap()),
+        payload: Bytes::new(),
+    };
+    framed.send(pkt.clone().into()).await.unwrap();
+    let ack = framed.next().await.unwrap().unwrap();
+    assert_eq!(ack, codec::Packet::PublishReceived { packet_id: NonZeroU16::new(1).unwrap() });
+
+    // publish 1 is still waiting for PUBREL, it does not count against inflight limit
+    framed
+        .send(codec::Publish { packet_id: Some(NonZeroU16::new(2).unwrap()), ..pkt }.into())
+        .await
+        .unwrap();
+    let ack = framed.next().await.unwrap().unwrap();
+    assert_eq!(ack, codec::Packet::PublishReceived { packet_id: NonZeroU16::new(2).unwrap() });
+
+    for id in 1..3 {
+        let packet_id = NonZeroU16::new(id).unwrap();
+        framed.send(codec::Packet::PublishRelease { packet_id }).await.unwrap();
+        let ack = framed.next().await.unwrap().unwrap();
+        assert_eq!(ack, codec::Packet::PublishComplete { packet_id });
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_send_exactly_once() -> std::io::Result<()> {
+    let publish = Arc::new(AtomicUsize::new(0));
+    let publish2 = publish.clone();
+
+    let srv = server::test_server(move || {
+        let publish = publish2.clone();
+        MqttServer::new(handshake)
+            .publish(move |_| {
+                publish.fetch_add(1, Relaxed);
+                ok(())
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let fut1 = sink.publish(ByteString::from_static("test"), Bytes::new()).send_exactly_once();
+    let fut2 = sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once();
+    let fut3 = sink.publish(ByteString::from_static("test"), Bytes::new()).send_exactly_once();
+
+    let (res1, res2, res3) = futures::future::join3(fut1, fut2, fut3).await;
+    assert!(res1.is_ok());
+    assert!(res2.is_ok());
+    assert!(res3.is_ok());
+    assert_eq!(publish.load(Relaxed), 3);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_send_qos1_and_qos2() -> std::io::Result<()> {
+    // peer completes qos2 publish before it acks qos1 publish
+    let srv = server::test_server(|| {
+        ntex::service::fn_service(|io: TcpStream| async move {
+            let mut framed = Framed::new(io, codec::Codec::default());
+            let _ = framed.next().await.unwrap().unwrap();
+            framed
+                .send(codec::Packet::ConnectAck {
+                    session_present: false,
+                    return_code: codec::ConnectAckReason::ConnectionAccepted,
+                })
+                .await
+                .unwrap();
+
+            let id1 = NonZeroU16::new(1).unwrap();
+            let id2 = NonZeroU16::new(2).unwrap();
+            let _ = framed.next().await.unwrap().unwrap();
+            let _ = framed.next().await.unwrap().unwrap();
+            framed.send(codec::Packet::PublishReceived { packet_id: id1 }).await.unwrap();
+            let pkt = framed.next().await.unwrap().unwrap();
+            assert_eq!(pkt, codec::Packet::PublishRelease { packet_id: id1 });
+            framed.send(codec::Packet::PublishComplete { packet_id: id1 }).await.unwrap();
+            framed.send(codec::Packet::PublishAck { packet_id: id2 }).await.unwrap();
+            let _ = framed.next().await;
+            Ok::<_, ()>(())
+        })
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let fut1 = sink.publish(ByteString::from_static("qos2"), Bytes::new()).send_exactly_once();
+    let fut2 = sink.publish(ByteString::from_static("qos1"), Bytes::new()).send_at_least_once();
+
+    let (res1, res2) = futures::future::join(fut1, fut2).await;
+    assert!(res1.is_ok());
+    assert!(res2.is_ok());
+    assert!(sink.is_open());
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_client_receive_qos2() -> std::io::Result<()> {
+    let acked = Arc::new(AtomicBool::new(false));
+    let acked2 = acked.clone();
+
+    let srv = server::test_server(move || {
+        let acked = acked2.clone();
+        MqttServer::new(handshake)
+            .publish(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+                let acked = acked.clone();
+                ok(ntex::service::fn_service(move |_: Publish| {
+                    let acked = acked.clone();
+                    let fut = session
+                        .sink()
+                        .publish(ByteString::from_static("test"), Bytes::new())
+                        .send_exactly_once();
+                    async move {
+                        fut.await.unwrap();
+                        acked.store(true, Relaxed);
+                        Ok(())
+                    }
+                }))
+            }))
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+    let received = Arc::new(AtomicUsize::new(0));
+    let received2 = received.clone();
+
+    ntex::rt::spawn(client.start(move |msg: client::ControlMessage<()>| match msg {
+        client::ControlMessage::Publish(p) => {
+            assert_eq!(p.packet().qos, codec::QoS::ExactlyOnce);
+            received2.fetch_add(1, Relaxed);
+            ok(p.ack())
+        }
+        _ => ok(msg.disconnect()),
+    }));
+
+    sink.publish(ByteString::from_static("trigger"), Bytes::new()).send_at_most_once().unwrap();
+    sleep(Duration::from_millis(200)).await;
+
+    assert_eq!(received.load(Relaxed), 1);
+    assert!(acked.load(Relaxed));
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_managed_client_reconnect() -> std::io::Result<()> {
+    let subscribes = Arc::new(AtomicUsize::new(0));
+    let subscribes2 = subscribes.clone();
+    let published = Arc::new(AtomicUsize::new(0));
+    let published2 = published.clone();
+
+    let srv = server::test_server(move || {
+        let subscribes = subscribes2.clone();
+        let published = published2.clone();
+        MqttServer::new(handshake)
+            .publish(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+                let published = published.clone();
+                ok(ntex::service::fn_service(move |p: Publish| {
+                    if p.topic().path() == "kick" {
+                        session.sink().force_close();
+                    } else {
+                        published.fetch_add(1, Relaxed);
+                    }
+                    ok(())
+                }))
+            }))
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    subscribes.fetch_add(1, Relaxed);
+                    for mut sub in &mut msg {
+                        sub.subscribe(codec::QoS::AtLeastOnce);
+                    }
+                    ok(msg.ack())
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let client =
+        client::ManagedClient::new(client::MqttConnector::new(srv.addr()).client_id("user"))
+            .backoff(client::Backoff::new(Millis(100), Millis(100)).jitter(0.0))
+            .max_buffer(2);
+    let sink = client.sink();
+    let mut states = client.state_changes();
+
+    ntex::rt::spawn(client.start_default());
+    assert!(sink.ready().await);
+    assert_eq!(states.next().await, Some(client::ConnectionState::Connecting));
+    assert_eq!(states.next().await, Some(client::ConnectionState::Connected));
+
+    sink.subscribe()
+        .topic_filter(ByteString::from_static("topic1"), codec::QoS::AtLeastOnce)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(subscribes.load(Relaxed), 1);
+
+    // server drops connection, publishes get buffered until re-connect
+    sink.publish(ByteString::from_static("kick"), Bytes::new()).send_at_most_once().unwrap();
+    assert_eq!(states.next().await, Some(client::ConnectionState::Disconnected));
+    assert!(!sink.is_open());
+
+    sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_most_once().unwrap();
+    let fut = sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once();
+    assert_eq!(sink.buffered(), 2);
+    assert!(sink
+        .publish(ByteString::from_static("test"), Bytes::new())
+        .send_at_most_once()
+        .is_err());
+
+    assert!(fut.await.is_ok());
+    assert_eq!(sink.buffered(), 0);
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(published.load(Relaxed), 2);
+    assert_eq!(subscribes.load(Relaxed), 2);
+
+    sink.close();
+    assert!(!sink.ready().await);
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_managed_client_resend_inflight() -> std::io::Result<()> {
+    let dups = Arc::new(AtomicUsize::new(0));
+    let dups2 = dups.clone();
+
+    let srv = server::test_server(move || {
+        let dups = dups2.clone();
+        MqttServer::new(|packet: Handshake<_>| ok::<_, ()>(packet.ack(St, true)))
+            .publish(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+                let dups = dups.clone();
+                ok(ntex::service::fn_service(move |p: Publish| {
+                    let dup = p.dup();
+                    if dup {
+                        dups.fetch_add(1, Relaxed);
+                    } else {
+                        // drop connection before ack
+                        session.sink().force_close();
+                    }
+                    async move {
+                        if !dup {
+                            sleep(Duration::from_millis(50)).await;
+                        }
+                        Ok(())
+                    }
+                }))
+            }))
+            .finish()
+    });
+
+    let client =
+        client::ManagedClient::new(client::MqttConnector::new(srv.addr()).client_id("user"))
+            .backoff(client::Backoff::new(Millis(10), Millis(50)));
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    assert!(sink.ready().await);
+
+    let res =
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+    assert_eq!(dups.load(Relaxed), 1);
+
+    assert!(sink.ready().await);
+    let res = sink
+        .sink()
+        .publish(ByteString::from_static("test"), Bytes::new())
+        .send_exactly_once()
+        .await;
+    assert!(res.is_ok());
+    assert_eq!(dups.load(Relaxed), 2);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_session_manager() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        let sessions: SessionManager<MqttSink> = SessionManager::new();
+        let sessions2 = sessions.clone();
+        let sessions3 = sessions.clone();
+
+        MqttServer::new(move |h: Handshake<_>| {
+            let present = sessions.connect_v3(&h);
+            let id = h.packet().client_id.clone();
+            ok::<_, ()>(h.ack(id, present))
+        })
+        .publish(move |p: Publish| {
+            if let Some(id) = p.topic().path().strip_prefix("to/") {
+                sessions2.publish(&ByteString::from(id), (&p).into());
+            }
+            ok::<_, ()>(())
+        })
+        .control(ntex::service::fn_factory_with_config(move |session: Session<ByteString>| {
+            // connect ack is sent, re-send stored messages
+            sessions3.resume(session.state());
+            let sessions = sessions3.clone();
+            ok::<_, ()>(ntex::service::fn_service(move |msg| match msg {
+                ControlMessage::Closed(msg) => {
+                    sessions.disconnect(session.state(), session.sink());
+                    ok::<_, ()>(msg.ack())
+                }
+                _ => ok(msg.disconnect()),
+            }))
+        }))
+        .finish()
+    });
+
+    // persistent session
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    assert!(!client.session_present());
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    sink.close();
+    sleep(Millis(100)).await;
+
+    // message is queued while client is offline
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("other").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    sink.publish(ByteString::from_static("to/user"), Bytes::from_static(b"data"))
+        .send_at_least_once()
+        .await
+        .unwrap();
+    sink.close();
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    assert!(client.session_present());
+    let sink = client.sink();
+    let received = Arc::new(AtomicUsize::new(0));
+    let received2 = received.clone();
+    ntex::rt::spawn(client.start(move |msg: client::ControlMessage<()>| match msg {
+        client::ControlMessage::Publish(p) => {
+            assert_eq!(p.packet().topic, "to/user");
+            received2.fetch_add(1, Relaxed);
+            ok(p.ack())
+        }
+        _ => ok(msg.disconnect()),
+    }));
+    // pending message could arrive together with connect ack,
+    // round-trip makes client read buffered packets
+    sink.publish(ByteString::from_static("to/nobody"), Bytes::new())
+        .send_at_least_once()
+        .await
+        .unwrap();
+    assert_eq!(received.load(Relaxed), 1);
+    sink.close();
+    sleep(Millis(100)).await;
+
+    // clean session discards stored session
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .clean_session()
+        .connect()
+        .await
+        .unwrap();
+    assert!(!client.session_present());
+    client.sink().close();
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_last_will() -> std::io::Result<()> {
+    let wills = Arc::new(Mutex::new(Vec::new()));
+    let wills2 = wills.clone();
+
+    let srv = server::test_server(move || {
+        let wills = wills2.clone();
+        MqttServer::new(handshake)
+            .will_manager(WillManager::new(move |client_id, msg| {
+                assert_eq!(msg.payload, Bytes::from_static(b"bye"));
+                wills.lock().unwrap().push((client_id.to_string(), msg.topic.to_string()));
+            }))
+            .publish(|_| ok(()))
+            .finish()
+    });
+
+    let connect = |id: &'static str| {
+        let mut pkt = codec::Connect::default().client_id(id);
+        pkt.last_will = Some(codec::LastWill {
+            qos: codec::QoS::AtLeastOnce,
+            retain: false,
+            topic: ByteString::from(format!("will/{}", id)),
+            message: Bytes::from_static(b"bye"),
+        });
+        codec::Packet::Connect(pkt.into())
+    };
+
+    // connection is dropped
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed.send(connect("user1")).await.unwrap();
+    framed.next().await.unwrap().unwrap();
+    drop(framed);
+
+    // normal disconnect
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed.send(connect("user2")).await.unwrap();
+    framed.next().await.unwrap().unwrap();
+    framed.send(codec::Packet::Disconnect).await.unwrap();
+    drop(framed);
+
+    sleep(Millis(200)).await;
+    assert_eq!(*wills.lock().unwrap(), vec![("user1".to_string(), "will/user1".to_string())]);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_session_takeover() -> std::io::Result<()> {
+    let wills = Arc::new(Mutex::new(Vec::new()));
+    let wills2 = wills.clone();
+
+    let srv = server::test_server(move || {
+        let wills = wills2.clone();
+        MqttServer::new(handshake)
+            .client_registry(ClientRegistry::new())
+            .will_manager(WillManager::new(move |client_id, _| {
+                wills.lock().unwrap().push(client_id.to_string());
+            }))
+            .publish(|_| ok(()))
+            .finish()
+    });
+
+    let mut pkt = codec::Connect::default().client_id("user");
+    pkt.last_will = Some(codec::LastWill {
+        qos: codec::QoS::AtMostOnce,
+        retain: false,
+        topic: ByteString::from_static("will"),
+        message: Bytes::from_static(b"bye"),
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed1 = Framed::new(io, codec::Codec::default());
+    framed1.send(codec::Packet::Connect(pkt.clone().into())).await.unwrap();
+    framed1.next().await.unwrap().unwrap();
+
+    let io = srv.connect().await.unwrap();
+    let mut framed2 = Framed::new(io, codec::Codec::default());
+    framed2.send(codec::Packet::Connect(pkt.into())).await.unwrap();
+    let ack = framed2.next().await.unwrap().unwrap();
+    assert!(matches!(ack, codec::Packet::ConnectAck { .. }));
+
+    // previous connection is closed
+    assert!(framed1.next().await.is_none());
+    sleep(Millis(100)).await;
+    assert_eq!(*wills.lock().unwrap(), vec!["user".to_string()]);
+
+    // new connection is still open
+    framed2.send(codec::Packet::PingRequest).await.unwrap();
+    let pong = framed2.next().await.unwrap().unwrap();
+    assert_eq!(pong, codec::Packet::PingResponse);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_stats() -> std::io::Result<()> {
+    let counters = Arc::new(Mutex::new(Vec::new()));
+    let counters2 = counters.clone();
+
+    let srv = server::test_server(move || {
+        let counters = counters2.clone();
+        let stats = Stats::new();
+        let stats2 = stats.clone();
+        MqttServer::new(handshake)
+            .stats(stats)
+            .publish(move |_| {
+                counters.lock().unwrap().push((
+                    stats2.connected(),
+                    stats2.connections(),
+                    stats2.messages_received(),
+                    stats2.publish_received(),
+                    stats2.bytes_received(),
+                    stats2.messages_sent(),
+                ));
+                ok(())
+            })
+            .finish()
+    });
+
+    let connect = codec::Packet::Connect(codec::Connect::default().client_id("user").into());
+    let publish = codec::Packet::Publish(codec::Publish {
+        dup: false,
+        retain: false,
+        qos: codec::QoS::AtMostOnce,
+        topic: ByteString::from_static("test"),
+        packet_id: None,
+        payload: Bytes::from_static(b"data"),
+    });
+    let mut buf = BytesMut::new();
+    codec::Codec::default().encode(connect.clone(), &mut buf).unwrap();
+    codec::Codec::default().encode(publish.clone(), &mut buf).unwrap();
+    let size = buf.len() as u64;
+
+    for _ in 0..2 {
+        let io = srv.connect().await.unwrap();
+        let mut framed = Framed::new(io, codec::Codec::default());
+        framed.send(connect.clone()).await.unwrap();
+        framed.next().await.unwrap().unwrap();
+        framed.send(publish.clone()).await.unwrap();
+        framed.send(codec::Packet::PingRequest).await.unwrap();
+        let pong = framed.next().await.unwrap().unwrap();
+        assert_eq!(pong, codec::Packet::PingResponse);
+        drop(framed);
+        sleep(Millis(100)).await;
+    }
+
+    assert_eq!(
+        *counters.lock().unwrap(),
+        vec![(1, 1, 2, 1, size, 1), (1, 2, 5, 2, size * 2 + 2, 3)]
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_mqtt31_session_present() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(|h: Handshake<_>| ok::<_, ()>(h.ack(St, true)))
+            .publish(|_| ok::<_, ()>(()))
+            .finish()
+    });
+
+    let mut pkt = codec::Connect::default().client_id("user");
+    pkt.protocol = codec::ProtocolLevel::MQTT31;
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed.send(pkt.into()).await.unwrap();
+    let ack = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        ack,
+        codec::Packet::ConnectAck {
+            session_present: false,
+            return_code: codec::ConnectAckReason::ConnectionAccepted
+        }
+    );
+
+    Ok(())
+}
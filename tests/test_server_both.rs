@@ -1,10 +1,12 @@
 use std::convert::TryFrom;
 
-use futures::future::ok;
-use ntex::server;
-use ntex::util::{ByteString, Bytes};
+use futures::{future::ok, SinkExt, StreamExt};
+use ntex::codec::{BytesCodec, Decoder, Encoder, Framed};
+use ntex::rt::net::TcpStream;
+use ntex::util::{poll_fn, ByteString, Bytes, BytesMut};
+use ntex::{server, ws};
 
-use ntex_mqtt::{v3, v5, MqttServer};
+use ntex_mqtt::{v3, v5, ws::WsServer, MqttServer};
 
 struct St;
 
@@ -61,3 +63,258 @@ async fn test_simple() -> std::io::Result<()> {
 
     Ok(())
 }
+
+#[ntex::test]
+async fn test_mqtt31() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new()
+            .v3(v3::MqttServer::new(|con: v3::Handshake<_>| {
+                assert_eq!(con.protocol(), v3::codec::ProtocolLevel::MQTT31);
+                // mqtt 3.1 connect ack has no session present flag
+                ok::<_, TestError>(con.ack(St, true))
+            })
+            .publish(|_| ok::<_, TestError>(())))
+            .v5(v5::MqttServer::new(|con: v5::Handshake<_>| ok::<_, TestError>(con.ack(St)))
+                .publish(|p: v5::Publish| ok::<_, TestError>(p.ack())))
+    });
+
+    let mut pkt = v3::codec::Connect::default().client_id("sensor");
+    pkt.protocol = v3::codec::ProtocolLevel::MQTT31;
+    let io = TcpStream::connect(srv.addr()).await.unwrap();
+    let mut framed = Framed::new(io, v3::codec::Codec::default());
+    framed.send(v3::codec::Packet::Connect(pkt.into())).await.unwrap();
+    let ack = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        ack,
+        v3::codec::Packet::ConnectAck {
+            session_present: false,
+            return_code: v3::codec::ConnectAckReason::ConnectionAccepted
+        }
+    );
+
+    // mqtt 3.1 client id must be between 1 and 23 characters long
+    for id in &["012345678901234567890123", ""] {
+        let mut pkt = v3::codec::Connect::default().client_id(*id);
+        pkt.protocol = v3::codec::ProtocolLevel::MQTT31;
+        let io = TcpStream::connect(srv.addr()).await.unwrap();
+        let mut framed = Framed::new(io, v3::codec::Codec::default());
+        framed.send(v3::codec::Packet::Connect(pkt.into())).await.unwrap();
+        let ack = framed.next().await.unwrap().unwrap();
+        assert_eq!(
+            ack,
+            v3::codec::Packet::ConnectAck {
+                session_present: false,
+                return_code: v3::codec::ConnectAckReason::IdentifierRejected
+            }
+        );
+        assert!(framed.next().await.is_none());
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_proxy_protocol() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new()
+            .v3(v3::MqttServer::new(|con: v3::Handshake<_>| {
+                let hdr = con.proxy_header().unwrap();
+                assert_eq!(hdr.version(), 1);
+                assert_eq!(hdr.source(), Some("10.0.0.1:40000".parse().unwrap()));
+                ok::<_, TestError>(con.ack(St, false))
+            })
+            .publish(|_| ok::<_, TestError>(())))
+            .v5(v5::MqttServer::new(|con: v5::Handshake<_>| {
+                let hdr = con.proxy_header().unwrap();
+                assert_eq!(hdr.version(), 2);
+                assert_eq!(hdr.source(), Some("10.0.0.2:40000".parse().unwrap()));
+                assert_eq!(hdr.authority(), Some("mqtt.example.com"));
+                ok::<_, TestError>(con.ack(St))
+            })
+            .publish(|p: v5::Publish| ok::<_, TestError>(p.ack())))
+            .proxy_protocol()
+    });
+
+    // v1 header
+    let io = TcpStream::connect(srv.addr()).await?;
+    let mut framed = Framed::new(io, BytesCodec);
+    framed.write(Bytes::from_static(b"PROXY TCP4 10.0.0.1 10.0.0.10 40000 1883\r\n")).unwrap();
+    poll_fn(|cx| framed.flush(cx)).await?;
+    let mut framed = framed.into_framed(v3::codec::Codec::new());
+    framed
+        .send(v3::codec::Packet::Connect(
+            v3::codec::Connect::default().client_id("user").into(),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(
+        framed.next().await.unwrap().unwrap(),
+        v3::codec::Packet::ConnectAck {
+            session_present: false,
+            return_code: v3::codec::ConnectAckReason::ConnectionAccepted
+        }
+    );
+
+    // v2 header with authority tlv
+    let io = TcpStream::connect(srv.addr()).await?;
+    let mut framed = Framed::new(io, BytesCodec);
+    framed
+        .write(Bytes::from_static(
+            b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x1f\x0a\x00\x00\x02\x0a\x00\x00\x0a\x9c\x40\x07\x5b\
+              \x02\x00\x10mqtt.example.com",
+        ))
+        .unwrap();
+    poll_fn(|cx| framed.flush(cx)).await?;
+    let mut framed = framed.into_framed(v5::codec::Codec::new());
+    framed
+        .send(v5::codec::Packet::Connect(Box::new(
+            v5::codec::Connect::default().client_id("user"),
+        )))
+        .await
+        .unwrap();
+    match framed.next().await.unwrap().unwrap() {
+        v5::codec::Packet::ConnectAck(ack) => {
+            assert_eq!(ack.reason_code, v5::codec::ConnectAckReason::Success)
+        }
+        pkt => panic!("Unexpected packet: {:?}", pkt),
+    }
+
+    // header is required
+    let io = TcpStream::connect(srv.addr()).await?;
+    let mut framed = Framed::new(io, v3::codec::Codec::new());
+    framed
+        .send(v3::codec::Packet::Connect(
+            v3::codec::Connect::default().client_id("user").into(),
+        ))
+        .await
+        .unwrap();
+    assert!(framed.next().await.is_none());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_ws_server() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        WsServer::new(
+            MqttServer::new()
+                .v3(v3::MqttServer::new(|con: v3::Handshake<_>| {
+                    ok::<_, TestError>(con.ack(St, false))
+                })
+                .publish(|_| ok::<_, TestError>(())))
+                .v5(v5::MqttServer::new(|con: v5::Handshake<_>| {
+                    ok::<_, TestError>(con.ack(St))
+                })
+                .publish(|p: v5::Publish| ok::<_, TestError>(p.ack()))),
+        )
+    });
+
+    // mqtt subprotocol is required
+    let io = TcpStream::connect(srv.addr()).await?;
+    let mut framed = Framed::new(io, BytesCodec);
+    framed
+        .write(Bytes::from_static(
+            b"GET /mqtt HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+              Connection: Upgrade\r\nSec-WebSocket-Version: 13\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+        ))
+        .unwrap();
+    poll_fn(|cx| framed.flush(cx)).await?;
+    let res = poll_fn(|cx| framed.next_item(cx)).await.unwrap().unwrap();
+    assert!(res.starts_with(b"HTTP/1.1 400 Bad Request\r\n"));
+
+    let io = TcpStream::connect(srv.addr()).await?;
+    let mut framed = Framed::new(io, BytesCodec);
+    framed
+        .write(Bytes::from_static(
+            b"GET /mqtt HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+              Connection: Upgrade\r\nSec-WebSocket-Version: 13\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+              Sec-WebSocket-Protocol: mqtt\r\n\r\n",
+        ))
+        .unwrap();
+    poll_fn(|cx| framed.flush(cx)).await?;
+    let res = poll_fn(|cx| framed.next_item(cx)).await.unwrap().unwrap();
+    let res = std::str::from_utf8(&res).unwrap();
+    assert!(res.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+    assert!(res.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+    assert!(res.contains("Sec-WebSocket-Protocol: mqtt\r\n"));
+
+    // mqtt packets are sent as binary messages
+    let mut framed = framed.into_framed(ws::Codec::new().client_mode());
+    let codec = v3::codec::Codec::new();
+    let mut buf = BytesMut::new();
+    codec
+        .encode(
+            v3::codec::Packet::Connect(Box::new(
+                v3::codec::Connect::default().client_id("user"),
+            )),
+            &mut buf,
+        )
+        .unwrap();
+    framed.write(ws::Message::Binary(buf.freeze())).unwrap();
+    poll_fn(|cx| framed.flush(cx)).await?;
+
+    let mut buf = match poll_fn(|cx| framed.next_item(cx)).await.unwrap().unwrap() {
+        ws::Frame::Binary(data) => BytesMut::from(&data[..]),
+        frame => panic!("Unexpected frame: {:?}", frame),
+    };
+    assert_eq!(
+        codec.decode(&mut buf).unwrap().unwrap(),
+        v3::codec::Packet::ConnectAck {
+            session_present: false,
+            return_code: v3::codec::ConnectAckReason::ConnectionAccepted
+        }
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_ws_client() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        WsServer::new(
+            MqttServer::new()
+                .v3(v3::MqttServer::new(|con: v3::Handshake<_>| {
+                    ok::<_, TestError>(con.ack(St, false))
+                })
+                .publish(|_| ok::<_, TestError>(())))
+                .v5(v5::MqttServer::new(|con: v5::Handshake<_>| {
+                    ok::<_, TestError>(con.ack(St))
+                })
+                .publish(|p: v5::Publish| ok::<_, TestError>(p.ack()))),
+        )
+    });
+
+    // connect to v5 server
+    let client = v5::client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .websocket("/mqtt")
+        .connect()
+        .await
+        .unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res =
+        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+    sink.close();
+
+    // connect to v3 server
+    let client = v3::client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .websocket("/mqtt")
+        .connect()
+        .await
+        .unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res =
+        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+    sink.close();
+
+    Ok(())
+}
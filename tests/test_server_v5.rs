@@ -1,16 +1,22 @@
-use std::sync::{atomic::AtomicBool, atomic::Ordering::Relaxed, Arc};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering::Relaxed};
+use std::sync::{Arc, Mutex};
+use std::{cell::Cell, cell::RefCell, collections::HashMap, rc::Rc};
 use std::{convert::TryFrom, num::NonZeroU16, time::Duration};
 
-use futures::{future::ok, FutureExt, SinkExt, StreamExt};
+use futures::{future::err, future::ok, FutureExt, SinkExt, StreamExt};
 use ntex::codec::Framed;
+use ntex::rt::net::TcpStream;
 use ntex::server;
-use ntex::time::sleep;
+use ntex::time::{sleep, Millis};
 use ntex::util::{poll_fn, ByteString, Bytes};
 
+use ntex_mqtt::retain::{self, MemoryRetainStore, RetainStore};
+use ntex_mqtt::subs::{Subscription, Subscriptions};
 use ntex_mqtt::v5::{
-    client, codec, error, ControlMessage, Handshake, HandshakeAck, MqttServer, Publish,
-    PublishAck, Session,
+    client, codec, error, ControlMessage, Handshake, HandshakeAck, MqttServer, MqttSink,
+    Publish, PublishAck, Session,
 };
+use ntex_mqtt::{info::Protocol, registry::ClientRegistry, will::WillManager, Topic};
 
 struct St;
 
@@ -742,3 +748,998 @@ async fn test_handle_incoming() -> std::io::Result<()> {
 
     Ok(())
 }
+
+#[ntex::test]
+async fn test_qos2() -> std::io::Result<()> {
+    let publish = Arc::new(AtomicUsize::new(0));
+    let publish2 = publish.clone();
+
+    let srv = server::test_server(move || {
+        let publish = publish2.clone();
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                publish.fetch_add(1, Relaxed);
+                ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    let pkt = codec::Publish { qos: codec::QoS::ExactlyOnce, ..pkt_publish() };
+    framed.send(pkt.clone().into()).await.unwrap();
+    let ack = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        ack,
+        codec::Packet::PublishReceived(codec::PublishAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            ..Default::default()
+        })
+    );
+
+    // re-delivered publish must not reach publish service
+    framed.send(codec::Publish { dup: true, ..pkt }.into()).await.unwrap();
+    let ack = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        ack,
+        codec::Packet::PublishReceived(codec::PublishAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            ..Default::default()
+        })
+    );
+    assert_eq!(publish.load(Relaxed), 1);
+
+    let rel = codec::PublishAck2 {
+        packet_id: NonZeroU16::new(1).unwrap(),
+        reason_code: codec::PublishAck2Reason::Success,
+        properties: Default::default(),
+        reason_string: None,
+    };
+    framed.send(codec::Packet::PublishRelease(rel.clone())).await.unwrap();
+    let ack = framed.next().await.unwrap().unwrap();
+    assert_eq!(ack, codec::Packet::PublishComplete(rel.clone()));
+
+    // unknown packet id
+    framed.send(codec::Packet::PublishRelease(rel.clone())).await.unwrap();
+    let ack = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        ack,
+        codec::Packet::PublishComplete(codec::PublishAck2 {
+            reason_code: codec::PublishAck2Reason::PacketIdNotFound,
+            ..rel
+        })
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_send_exactly_once() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| {
+                if p.topic().path() == "fail" {
+                    ok::<_, TestError>(
+                        p.ack().reason_code(codec::PublishAckReason::NotAuthorized),
+                    )
+                } else {
+                    ok::<_, TestError>(p.ack())
+                }
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let credit = sink.credit();
+    let res =
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_exactly_once().await;
+    assert_eq!(res.unwrap().reason_code, codec::PublishAck2Reason::Success);
+    assert_eq!(sink.credit(), credit);
+
+    let res =
+        sink.publish(ByteString::from_static("fail"), Bytes::new()).send_exactly_once().await;
+    if let Err(error::PublishQos2Error::Fail(ack)) = res {
+        assert_eq!(ack.reason_code, codec::PublishAckReason::NotAuthorized);
+    } else {
+        panic!("Expected negative publish received: {:?}", res);
+    }
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_send_qos1_and_qos2() -> std::io::Result<()> {
+    // peer completes qos2 publish before it acks qos1 publish
+    let srv = server::test_server(|| {
+        ntex::service::fn_service(|io: TcpStream| async move {
+            let mut framed = Framed::new(io, codec::Codec::default());
+            let _ = framed.next().await.unwrap().unwrap();
+            framed
+                .send(codec::Packet::ConnectAck(Box::new(codec::ConnectAck {
+                    receive_max: NonZeroU16::new(16),
+                    ..Default::default()
+                })))
+                .await
+                .unwrap();
+
+            let id1 = NonZeroU16::new(1).unwrap();
+            let id2 = NonZeroU16::new(2).unwrap();
+            let _ = framed.next().await.unwrap().unwrap();
+            let _ = framed.next().await.unwrap().unwrap();
+            framed
+                .send(codec::Packet::PublishReceived(codec::PublishAck {
+                    packet_id: id1,
+                    ..Default::default()
+                }))
+                .await
+                .unwrap();
+            let pkt = framed.next().await.unwrap().unwrap();
+            assert!(std::matches!(pkt, codec::Packet::PublishRelease(_)));
+            framed
+                .send(codec::Packet::PublishComplete(codec::PublishAck2 {
+                    packet_id: id1,
+                    reason_code: codec::PublishAck2Reason::Success,
+                    properties: Default::default(),
+                    reason_string: None,
+                }))
+                .await
+                .unwrap();
+            framed
+                .send(codec::Packet::PublishAck(codec::PublishAck {
+                    packet_id: id2,
+                    ..Default::default()
+                }))
+                .await
+                .unwrap();
+            let _ = framed.next().await;
+            Ok::<_, ()>(())
+        })
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let fut1 = sink.publish(ByteString::from_static("qos2"), Bytes::new()).send_exactly_once();
+    let fut2 = sink.publish(ByteString::from_static("qos1"), Bytes::new()).send_at_least_once();
+
+    let (res1, res2) = futures::future::join(fut1, fut2).await;
+    assert_eq!(res1.unwrap().reason_code, codec::PublishAck2Reason::Success);
+    assert!(res2.is_ok());
+    assert!(sink.is_open());
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_client_receive_qos2() -> std::io::Result<()> {
+    let acked = Arc::new(AtomicBool::new(false));
+    let acked2 = acked.clone();
+
+    let srv = server::test_server(move || {
+        let acked = acked2.clone();
+        MqttServer::new(handshake)
+            .publish(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+                let acked = acked.clone();
+                ok::<_, TestError>(ntex::service::fn_service(move |p: Publish| {
+                    let acked = acked.clone();
+                    let fut = session
+                        .sink()
+                        .publish(ByteString::from_static("test"), Bytes::new())
+                        .send_exactly_once();
+                    async move {
+                        let ack = fut.await.unwrap();
+                        assert_eq!(ack.reason_code, codec::PublishAck2Reason::Success);
+                        acked.store(true, Relaxed);
+                        Ok::<_, TestError>(p.ack())
+                    }
+                }))
+            }))
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+    let received = Arc::new(AtomicUsize::new(0));
+    let received2 = received.clone();
+
+    ntex::rt::spawn(client.start(move |msg: client::ControlMessage<()>| match msg {
+        client::ControlMessage::Publish(p) => {
+            assert_eq!(p.packet().qos, codec::QoS::ExactlyOnce);
+            received2.fetch_add(1, Relaxed);
+            ok(p.ack(codec::PublishAckReason::Success))
+        }
+        _ => ok(msg.disconnect(codec::Disconnect::default())),
+    }));
+
+    sink.publish(ByteString::from_static("trigger"), Bytes::new()).send_at_most_once().unwrap();
+    sleep(Duration::from_millis(200)).await;
+
+    assert_eq!(received.load(Relaxed), 1);
+    assert!(acked.load(Relaxed));
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_managed_client_reconnect() -> std::io::Result<()> {
+    let subscribes = Arc::new(AtomicUsize::new(0));
+    let subscribes2 = subscribes.clone();
+
+    let srv = server::test_server(move || {
+        let subscribes = subscribes2.clone();
+        MqttServer::new(handshake)
+            .publish(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+                ok::<_, TestError>(ntex::service::fn_service(move |p: Publish| {
+                    if p.topic().path() == "kick" {
+                        session.sink().close();
+                    }
+                    ok::<_, TestError>(p.ack())
+                }))
+            }))
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    subscribes.fetch_add(1, Relaxed);
+                    msg.iter_mut().for_each(|mut s| s.confirm(codec::QoS::AtLeastOnce));
+                    ok::<_, TestError>(msg.ack())
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let client =
+        client::ManagedClient::new(client::MqttConnector::new(srv.addr()).client_id("user"))
+            .backoff(client::Backoff::new(Millis(10), Millis(50)));
+    let sink = client.sink();
+    let mut states = client.state_changes();
+    assert!(!sink.is_open());
+
+    ntex::rt::spawn(client.start_default());
+    assert!(sink.ready().await);
+    assert_eq!(states.next().await, Some(client::ConnectionState::Connecting));
+    assert_eq!(states.next().await, Some(client::ConnectionState::Connected));
+
+    sink.subscribe(None)
+        .topic_filter(
+            "topic1".into(),
+            codec::SubscriptionOptions {
+                qos: codec::QoS::AtLeastOnce,
+                no_local: false,
+                retain_as_published: false,
+                retain_handling: codec::RetainHandling::AtSubscribe,
+            },
+        )
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(subscribes.load(Relaxed), 1);
+
+    // server drops connection, client re-connects and re-subscribes
+    let _ = sink.publish(ByteString::from_static("kick"), Bytes::new()).send_at_most_once();
+    assert_eq!(states.next().await, Some(client::ConnectionState::Disconnected));
+    assert_eq!(states.next().await, Some(client::ConnectionState::Connecting));
+    assert_eq!(states.next().await, Some(client::ConnectionState::Connected));
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(subscribes.load(Relaxed), 2);
+
+    let res =
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+
+    sink.close();
+    assert_eq!(states.next().await, Some(client::ConnectionState::Disconnected));
+    assert_eq!(states.next().await, Some(client::ConnectionState::Stopped));
+    assert!(!sink.ready().await);
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_managed_client_resend_inflight() -> std::io::Result<()> {
+    let dups = Arc::new(AtomicUsize::new(0));
+    let dups2 = dups.clone();
+
+    let srv = server::test_server(move || {
+        let dups = dups2.clone();
+        MqttServer::new(|packet: Handshake<_>| async move {
+            Ok::<_, TestError>(packet.ack(St).with(|ack| ack.session_present = true))
+        })
+        .publish(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+            let dups = dups.clone();
+            ok::<_, TestError>(ntex::service::fn_service(move |p: Publish| {
+                let dup = p.dup();
+                if dup {
+                    dups.fetch_add(1, Relaxed);
+                } else {
+                    // drop connection before ack
+                    session.sink().close();
+                }
+                async move {
+                    if !dup {
+                        sleep(Duration::from_millis(50)).await;
+                    }
+                    Ok::<_, TestError>(p.ack())
+                }
+            }))
+        }))
+        .finish()
+    });
+
+    let client =
+        client::ManagedClient::new(client::MqttConnector::new(srv.addr()).client_id("user"))
+            .backoff(client::Backoff::new(Millis(10), Millis(50)));
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    assert!(sink.ready().await);
+
+    let res =
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+    assert_eq!(dups.load(Relaxed), 1);
+
+    assert!(sink.ready().await);
+    let res =
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_exactly_once().await;
+    assert!(res.is_ok());
+    assert_eq!(dups.load(Relaxed), 2);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_client_store_replay() -> std::io::Result<()> {
+    use ntex_mqtt::v5::client::Store;
+
+    let published = Arc::new(AtomicUsize::new(0));
+    let published2 = published.clone();
+
+    let srv = server::test_server(move || {
+        let published = published2.clone();
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                published.fetch_add(1, Relaxed);
+                ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    // packet stored by previous process
+    let store = client::MemoryStore::new();
+    let mut pkt = pkt_publish();
+    pkt.packet_id = NonZeroU16::new(5);
+    store.put(client::StoreItem::Publish(pkt)).unwrap();
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .store(store.clone())
+        .connect()
+        .await
+        .unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res =
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+    assert_eq!(published.load(Relaxed), 2);
+    assert!(store.load().unwrap().is_empty());
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_retained_delivery() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        let store = MemoryRetainStore::new();
+        let store2 = store.clone();
+
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                if p.retain() {
+                    store.update((&p).into());
+                }
+                ok::<_, TestError>(p.ack())
+            })
+            .control(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+                let store = store2.clone();
+                ok::<_, TestError>(ntex::service::fn_service(move |msg| match msg {
+                    ControlMessage::Subscribe(mut msg) => {
+                        for mut s in &mut msg {
+                            s.confirm(s.options().qos);
+                            let sub = Subscription::from(s.options().clone());
+                            let filter = s.topic().parse::<Topic>().unwrap();
+                            ntex::rt::spawn(retain::deliver_v5(
+                                &store,
+                                session.sink(),
+                                &filter,
+                                &sub,
+                                true,
+                            ));
+                        }
+                        ok::<_, TestError>(msg.ack())
+                    }
+                    _ => ok(msg.disconnect()),
+                }))
+            }))
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    let received = Arc::new(AtomicUsize::new(0));
+    let received2 = received.clone();
+    ntex::rt::spawn(client.start(move |msg: client::ControlMessage<()>| match msg {
+        client::ControlMessage::Publish(p) => {
+            assert!(p.packet().retain);
+            assert_eq!(p.packet().qos, codec::QoS::AtMostOnce);
+            assert_eq!(p.packet().topic, "retained/1");
+            received2.fetch_add(1, Relaxed);
+            ok(p.ack_qos0())
+        }
+        _ => ok(msg.disconnect(codec::Disconnect::default())),
+    }));
+
+    sink.publish(ByteString::from_static("retained/1"), Bytes::from_static(b"data"))
+        .retain()
+        .send_at_least_once()
+        .await
+        .unwrap();
+    sink.publish(ByteString::from_static("retained/2"), Bytes::from_static(b"data"))
+        .retain()
+        .send_at_least_once()
+        .await
+        .unwrap();
+    // empty payload clears retained message
+    sink.publish(ByteString::from_static("retained/2"), Bytes::new())
+        .retain()
+        .send_at_least_once()
+        .await
+        .unwrap();
+
+    let options = |retain_handling| codec::SubscriptionOptions {
+        qos: codec::QoS::AtMostOnce,
+        no_local: false,
+        retain_as_published: false,
+        retain_handling,
+    };
+    sink.subscribe(None)
+        .topic_filter("retained/+".into(), options(codec::RetainHandling::AtSubscribe))
+        .send()
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(received.load(Relaxed), 1);
+
+    sink.subscribe(None)
+        .topic_filter("retained/#".into(), options(codec::RetainHandling::NoAtSubscribe))
+        .send()
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(received.load(Relaxed), 1);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_last_will_delay() -> std::io::Result<()> {
+    let wills = Arc::new(Mutex::new(Vec::new()));
+    let wills2 = wills.clone();
+
+    let srv = server::test_server(move || {
+        let wills = wills2.clone();
+        MqttServer::new(handshake)
+            .will_manager(WillManager::new(move |client_id, _| {
+                wills.lock().unwrap().push(client_id.to_string());
+            }))
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let connect = |id: &'static str, clean_start: bool, delay: u32| {
+        let mut pkt = codec::Connect::default().client_id(id);
+        pkt.clean_start = clean_start;
+        pkt.session_expiry_interval_secs = Some(30);
+        pkt.last_will = Some(codec::LastWill {
+            qos: codec::QoS::AtMostOnce,
+            retain: false,
+            topic: ByteString::from_static("will"),
+            message: Bytes::from_static(b"bye"),
+            will_delay_interval_sec: Some(delay),
+            correlation_data: None,
+            message_expiry_interval: None,
+            content_type: None,
+            user_properties: Vec::new(),
+            is_utf8_payload: None,
+            response_topic: None,
+        });
+        codec::Packet::Connect(Box::new(pkt))
+    };
+
+    // will is delayed, session is resumed within delay
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed.send(connect("user1", true, 1)).await.unwrap();
+    framed.next().await.unwrap().unwrap();
+    drop(framed);
+    sleep(Millis(200)).await;
+    assert!(wills.lock().unwrap().is_empty());
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed.send(connect("user1", false, 1)).await.unwrap();
+    framed.next().await.unwrap().unwrap();
+
+    // disconnect with will message
+    let io = srv.connect().await.unwrap();
+    let mut framed2 = Framed::new(io, codec::Codec::default());
+    framed2.send(connect("user2", true, 0)).await.unwrap();
+    framed2.next().await.unwrap().unwrap();
+    framed2
+        .send(codec::Packet::Disconnect(codec::Disconnect {
+            reason_code: codec::DisconnectReasonCode::DisconnectWithWillMessage,
+            ..codec::Disconnect::default()
+        }))
+        .await
+        .unwrap();
+    drop(framed2);
+
+    sleep(Millis(1200)).await;
+    assert_eq!(*wills.lock().unwrap(), vec!["user2".to_string()]);
+
+    // delayed will is published
+    drop(framed);
+    sleep(Millis(1200)).await;
+    assert_eq!(*wills.lock().unwrap(), vec!["user2".to_string(), "user1".to_string()]);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_last_will_delay_anonymous() -> std::io::Result<()> {
+    let wills = Arc::new(Mutex::new(Vec::new()));
+    let wills2 = wills.clone();
+
+    let srv = server::test_server(move || {
+        let wills = wills2.clone();
+        MqttServer::new(handshake)
+            .will_manager(WillManager::new(move |_, msg| {
+                wills.lock().unwrap().push(msg.payload);
+            }))
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let connect = |message: &'static [u8]| {
+        let pkt = codec::Connect {
+            clean_start: true,
+            session_expiry_interval_secs: Some(30),
+            last_will: Some(codec::LastWill {
+                qos: codec::QoS::AtMostOnce,
+                retain: false,
+                topic: ByteString::from_static("will"),
+                message: Bytes::from_static(message),
+                will_delay_interval_sec: Some(1),
+                correlation_data: None,
+                message_expiry_interval: None,
+                content_type: None,
+                user_properties: Vec::new(),
+                is_utf8_payload: None,
+                response_topic: None,
+            }),
+            ..codec::Connect::default()
+        };
+        codec::Packet::Connect(Box::new(pkt))
+    };
+
+    // anonymous clients do not share session
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed.send(connect(b"a")).await.unwrap();
+    framed.next().await.unwrap().unwrap();
+    let io = srv.connect().await.unwrap();
+    let mut framed2 = Framed::new(io, codec::Codec::default());
+    framed2.send(connect(b"b")).await.unwrap();
+    framed2.next().await.unwrap().unwrap();
+
+    drop(framed);
+    sleep(Millis(200)).await;
+    assert!(wills.lock().unwrap().is_empty());
+
+    // new anonymous client does not end delayed will
+    let io = srv.connect().await.unwrap();
+    let mut framed3 = Framed::new(io, codec::Codec::default());
+    framed3.send(connect(b"c")).await.unwrap();
+    framed3.next().await.unwrap().unwrap();
+    sleep(Millis(200)).await;
+    assert!(wills.lock().unwrap().is_empty());
+
+    drop(framed2);
+    drop(framed3);
+    sleep(Millis(200)).await;
+    assert!(wills.lock().unwrap().is_empty());
+
+    // wills are published after delay
+    sleep(Millis(1200)).await;
+    let mut received = wills.lock().unwrap().clone();
+    received.sort();
+    assert_eq!(
+        received,
+        vec![Bytes::from_static(b"a"), Bytes::from_static(b"b"), Bytes::from_static(b"c")]
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_session_takeover() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .client_registry(ClientRegistry::new())
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let connect =
+        || codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user")));
+
+    let io = srv.connect().await.unwrap();
+    let mut framed1 = Framed::new(io, codec::Codec::default());
+    framed1.send(connect()).await.unwrap();
+    framed1.next().await.unwrap().unwrap();
+
+    let io = srv.connect().await.unwrap();
+    let mut framed2 = Framed::new(io, codec::Codec::default());
+    framed2.send(connect()).await.unwrap();
+    framed2.next().await.unwrap().unwrap();
+
+    // previous connection receives disconnect
+    let pkt = framed1.next().await.unwrap().unwrap();
+    match pkt {
+        codec::Packet::Disconnect(pkt) => {
+            assert_eq!(pkt.reason_code, codec::DisconnectReasonCode::SessionTakenOver)
+        }
+        pkt => panic!("Unexpected packet: {:?}", pkt),
+    }
+    assert!(framed1.next().await.is_none());
+
+    framed2.send(codec::Packet::PingRequest).await.unwrap();
+    let pong = framed2.next().await.unwrap().unwrap();
+    assert_eq!(pong, codec::Packet::PingResponse);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_shared_subscription() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        let subs = Rc::new(RefCell::new(Subscriptions::new()));
+        let sinks: Rc<RefCell<HashMap<usize, MqttSink>>> = Rc::default();
+        let counter = Rc::new(Cell::new(0));
+        let (subs2, sinks2) = (subs.clone(), sinks.clone());
+
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                for (id, sub) in subs.borrow().matches(p.topic().path()) {
+                    assert_eq!(sub.qos(), codec::QoS::AtMostOnce);
+                    sinks.borrow()[id]
+                        .publish(p.packet().topic.clone(), p.packet().payload.clone())
+                        .send_at_most_once()
+                        .unwrap();
+                }
+                ok::<_, TestError>(p.ack())
+            })
+            .control(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+                let id = counter.get();
+                counter.set(id + 1);
+                sinks2.borrow_mut().insert(id, session.sink().clone());
+
+                let subs = subs2.clone();
+                ok::<_, TestError>(ntex::service::fn_service(move |msg| match msg {
+                    ControlMessage::Subscribe(mut msg) => {
+                        for mut s in &mut msg {
+                            let filter = s.shared().unwrap();
+                            assert_eq!(filter.group(), "group");
+                            let sub = s.subscription(codec::QoS::AtMostOnce);
+                            subs.borrow_mut().subscribe_shared(id, &filter, sub);
+                            s.confirm(codec::QoS::AtMostOnce);
+                        }
+                        ok::<_, TestError>(msg.ack())
+                    }
+                    ControlMessage::ProtocolError(msg) => ok(msg.ack()),
+                    _ => ok(msg.disconnect()),
+                }))
+            }))
+            .finish()
+    });
+
+    let mut counters = Vec::new();
+    let mut clients = Vec::new();
+    for id in &["user1", "user2"] {
+        let client =
+            client::MqttConnector::new(srv.addr()).client_id(*id).connect().await.unwrap();
+        let sink = client.sink();
+        let received = Arc::new(AtomicUsize::new(0));
+        let received2 = received.clone();
+        ntex::rt::spawn(client.start(move |msg: client::ControlMessage<()>| match msg {
+            client::ControlMessage::Publish(p) => {
+                received2.fetch_add(1, Relaxed);
+                ok(p.ack_qos0())
+            }
+            _ => ok(msg.disconnect(codec::Disconnect::default())),
+        }));
+
+        sink.subscribe(None)
+            .topic_filter(
+                "$share/group/topic/+".into(),
+                codec::SubscriptionOptions {
+                    qos: codec::QoS::AtMostOnce,
+                    no_local: false,
+                    retain_as_published: false,
+                    retain_handling: codec::RetainHandling::AtSubscribe,
+                },
+            )
+            .send()
+            .await
+            .unwrap();
+        counters.push(received);
+        clients.push(sink);
+    }
+
+    for _ in 0..4 {
+        clients[0]
+            .publish(ByteString::from_static("topic/1"), Bytes::new())
+            .send_at_least_once()
+            .await
+            .unwrap();
+    }
+    sleep(Duration::from_millis(100)).await;
+
+    // messages are distributed between group members
+    assert_eq!(counters[0].load(Relaxed), 2);
+    assert_eq!(counters[1].load(Relaxed), 2);
+
+    // no local option is protocol error for shared subscription
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user3"))))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    framed
+        .send(codec::Packet::Subscribe(codec::Subscribe {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            id: None,
+            user_properties: Vec::new(),
+            topic_filters: vec![(
+                "$share/group/topic/+".into(),
+                codec::SubscriptionOptions {
+                    qos: codec::QoS::AtMostOnce,
+                    no_local: true,
+                    retain_as_published: false,
+                    retain_handling: codec::RetainHandling::AtSubscribe,
+                },
+            )],
+        }))
+        .await
+        .unwrap();
+    match framed.next().await.unwrap().unwrap() {
+        codec::Packet::Disconnect(pkt) => {
+            assert_eq!(pkt.reason_code, codec::DisconnectReasonCode::ProtocolError)
+        }
+        pkt => panic!("Unexpected packet: {:?}", pkt),
+    }
+    assert!(framed.next().await.is_none());
+
+    clients.iter().for_each(|sink| sink.close());
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_outbound_topic_alias() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(|con: Handshake<_>| async move {
+            let sink = con.sink();
+            ntex::rt::spawn(async move {
+                sleep(Millis(50)).await;
+                for topic in &["a", "b", "a", "c", "b"] {
+                    sink.publish(*topic, Bytes::new()).send_at_most_once().unwrap();
+                }
+                sink.publish("c", Bytes::new()).no_topic_alias().send_at_most_once().unwrap();
+            });
+            Ok::<_, TestError>(con.ack(St))
+        })
+        .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    let connect = codec::Connect { topic_alias_max: 2, ..codec::Connect::default() };
+    framed.send(codec::Packet::Connect(Box::new(connect.client_id("user")))).await.unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    let mut received = Vec::new();
+    for _ in 0..6 {
+        match framed.next().await.unwrap().unwrap() {
+            codec::Packet::Publish(pkt) => received
+                .push((pkt.topic.to_string(), pkt.properties.topic_alias.map(|a| a.get()))),
+            pkt => panic!("Unexpected packet: {:?}", pkt),
+        }
+    }
+
+    // least recently used alias is re-assigned
+    assert_eq!(
+        received,
+        vec![
+            ("a".to_string(), Some(1)),
+            ("b".to_string(), Some(2)),
+            ("".to_string(), Some(1)),
+            ("c".to_string(), Some(2)),
+            ("b".to_string(), Some(1)),
+            ("c".to_string(), None),
+        ]
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_connection_info() -> std::io::Result<()> {
+    let checked = Arc::new(AtomicUsize::new(0));
+    let checked2 = checked.clone();
+
+    let srv = server::test_server(move || {
+        let checked = checked2.clone();
+        MqttServer::new(|h: Handshake<_>| {
+            let info = h.connection_info();
+            assert_eq!(info.protocol(), Protocol::MQTT5);
+            assert!(info.peer_addr().is_some());
+            assert!(info.peer_certs().is_empty());
+            assert!(info.proxy_header().is_none());
+            ok::<_, TestError>(h.ack(St))
+        })
+        .publish(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+            let peer = session.connection_info().unwrap().peer_addr();
+            assert!(peer.is_some());
+            ok::<_, TestError>(ntex::service::fn_service(move |p: Publish| {
+                assert_eq!(p.connection_info().unwrap().peer_addr(), peer);
+                ok::<_, TestError>(p.ack())
+            }))
+        }))
+        .control(move |msg| match msg {
+            ControlMessage::Closed(msg) => {
+                let info = msg.connection_info().unwrap();
+                assert_eq!(info.protocol(), Protocol::MQTT5);
+                assert!(info.local_addr().is_some());
+                checked.fetch_add(1, Relaxed);
+                ok::<_, TestError>(msg.ack())
+            }
+            _ => ok(msg.disconnect()),
+        })
+        .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    sink.publish(ByteString::from_static("test"), Bytes::new())
+        .send_at_least_once()
+        .await
+        .unwrap();
+    sink.close();
+    sleep(Millis(100)).await;
+    assert_eq!(checked.load(Relaxed), 1);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_enhanced_auth() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(|mut h: Handshake<_>| async move {
+            assert_eq!(h.packet().auth_data, Some(Bytes::from_static(b"client-first")));
+
+            // two step challenge
+            for step in &[&b"step-1"[..], &b"step-2"[..]] {
+                let pkt = codec::Auth {
+                    auth_data: Some(Bytes::copy_from_slice(step)),
+                    ..Default::default()
+                };
+                let res = match h.auth_challenge(pkt).await {
+                    Ok(res) => res,
+                    Err(_) => return Ok(h.failed(codec::ConnectAckReason::ProtocolError)),
+                };
+                if res.auth_data.as_deref() != Some(&[b"re-", *step].concat()[..]) {
+                    return Ok(h.failed(codec::ConnectAckReason::NotAuthorized));
+                }
+            }
+            let method = h.packet().auth_method.clone();
+            Ok::<_, TestError>(h.ack(St).with(|ack| {
+                ack.auth_method = method;
+                ack.auth_data = Some(Bytes::from_static(b"server-final"));
+            }))
+        })
+        .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let steps = Rc::new(Cell::new(0));
+    let steps2 = steps.clone();
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .auth(ByteString::from_static("test"), Bytes::from_static(b"client-first"))
+        .authenticator(move |pkt: codec::Auth| {
+            steps2.set(steps2.get() + 1);
+            assert_eq!(pkt.auth_method, Some(ByteString::from_static("test")));
+            let data = [&b"re-"[..], &pkt.auth_data.unwrap()].concat();
+            ok(codec::Auth { auth_data: Some(Bytes::from(data)), ..Default::default() })
+        })
+        .connect()
+        .await
+        .unwrap();
+    assert_eq!(steps.get(), 2);
+    assert_eq!(client.packet().auth_data, Some(Bytes::from_static(b"server-final")));
+    client.sink().close();
+
+    // client rejects server's challenge
+    let err = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .auth(ByteString::from_static("test"), Bytes::from_static(b"client-first"))
+        .authenticator(|_| {
+            err(codec::Disconnect {
+                reason_code: codec::DisconnectReasonCode::NotAuthorized,
+                ..Default::default()
+            })
+        })
+        .connect()
+        .await
+        .err()
+        .unwrap();
+    assert!(matches!(err, client::error::ClientError::Auth(_)));
+
+    // wrong response
+    let err = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .auth(ByteString::from_static("test"), Bytes::from_static(b"client-first"))
+        .authenticator(|_| ok(codec::Auth::default()))
+        .connect()
+        .await
+        .err()
+        .unwrap();
+    if let client::error::ClientError::Ack(ack) = err {
+        assert_eq!(ack.reason_code, codec::ConnectAckReason::NotAuthorized);
+    } else {
+        panic!("unexpected error: {:?}", err);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,110 @@
+#![cfg(feature = "openssl")]
+use futures::future::ok;
+use ntex::rt::net::TcpStream;
+use ntex::server::{self, openssl::Acceptor, openssl::SslStream};
+use ntex::service::pipeline_factory;
+
+use openssl::asn1::Asn1Time;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::ssl::{SslAcceptor, SslConnector, SslMethod, SslVerifyMode};
+use openssl::x509::{extension::SubjectAlternativeName, X509NameBuilder, X509};
+
+use ntex_mqtt::cert::RequireCertIdentity;
+use ntex_mqtt::v3::{self, client, codec, Handshake, HandshakeAck};
+use ntex_mqtt::{MqttError, MqttServer};
+
+struct St;
+
+fn self_signed(cn: &str) -> (X509, PKey<Private>) {
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", cn).unwrap();
+    let name = name.build();
+
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_issuer_name(&name).unwrap();
+    builder.set_pubkey(&key).unwrap();
+    builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+    let san = SubjectAlternativeName::new()
+        .dns(&format!("{}.example.com", cn))
+        .build(&builder.x509v3_context(None, None))
+        .unwrap();
+    builder.append_extension(san).unwrap();
+    builder.sign(&key, MessageDigest::sha256()).unwrap();
+    (builder.build(), key)
+}
+
+async fn handshake(
+    h: Handshake<SslStream<TcpStream>>,
+) -> Result<HandshakeAck<SslStream<TcpStream>, St>, ()> {
+    let id = h.peer_identity().unwrap();
+    assert_eq!(id.common_name(), Some("device-1"));
+    assert_eq!(id.dns_names(), &["device-1.example.com".to_string()]);
+    assert_eq!(id.fingerprint().len(), 32);
+    assert_eq!(h.connection_info().peer_certs().len(), 1);
+    Ok(h.ack(St, false))
+}
+
+#[ntex::test]
+async fn test_cert_identity() -> std::io::Result<()> {
+    let (srv_cert, srv_key) = self_signed("localhost");
+    let (cert, key) = self_signed("device-1");
+
+    let srv = server::test_server(move || {
+        let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+        builder.set_certificate(&srv_cert).unwrap();
+        builder.set_private_key(&srv_key).unwrap();
+        builder.set_verify_callback(
+            SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+            |_, _| true,
+        );
+
+        pipeline_factory(Acceptor::new(builder.build()))
+            .map_err(|_| MqttError::Service(()))
+            .and_then(
+                MqttServer::new()
+                    .v3(v3::MqttServer::new(RequireCertIdentity::client_id(handshake))
+                        .publish(|_: v3::Publish| ok::<_, ()>(()))),
+            )
+    });
+
+    let connector = || {
+        let mut builder = SslConnector::builder(SslMethod::tls()).unwrap();
+        builder.set_verify(SslVerifyMode::NONE);
+        builder.set_certificate(&cert).unwrap();
+        builder.set_private_key(&key).unwrap();
+        builder.build()
+    };
+
+    let addr = format!("localhost:{}", srv.addr().port());
+
+    // client id matches certificate common name
+    let client = client::MqttConnector::new(addr.clone())
+        .client_id("device-1")
+        .openssl(connector())
+        .connect()
+        .await
+        .unwrap();
+    client.sink().close();
+
+    // client id does not match
+    let err = client::MqttConnector::new(addr.clone())
+        .client_id("device-2")
+        .openssl(connector())
+        .connect()
+        .await
+        .err()
+        .unwrap();
+    if let client::ClientError::Ack { return_code, .. } = err {
+        assert_eq!(return_code, codec::ConnectAckReason::NotAuthorized);
+    } else {
+        panic!("unexpected error: {:?}", err);
+    }
+
+    Ok(())
+}